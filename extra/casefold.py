@@ -0,0 +1,104 @@
+#!/usr/bin/env python3
+# casefold.py --- generate the case folding tables used by the VM
+#
+# Writes langlang_lib/src/casefold.rs with the Unicode simple case
+# folding (statuses C and S of CaseFolding.txt) of every character
+# that doesn't fold to itself.  The mappings are derived from the
+# Unicode database shipped with Python: the full folding of a
+# character is used when it's a single character, otherwise its
+# lowercase form is when that's a single character.  That leaves out
+# the Turkic mappings (status T), as CaseFolding.txt does by default.
+#
+# Usage: python3 extra/casefold.py > langlang_lib/src/casefold.rs
+import sys
+import unicodedata
+
+
+def simple_fold(c):
+    folded = c.casefold()
+    if len(folded) == 1:
+        return folded
+    lower = c.lower()
+    if len(lower) == 1:
+        return lower
+    return c
+
+
+FUNCTIONS = """
+/// Simple case folding of `c`
+pub(crate) fn fold(c: char) -> char {
+    match FOLD.binary_search_by_key(&c, |&(from, _)| from) {
+        Ok(i) => FOLD[i].1,
+        Err(_) => c,
+    }
+}
+
+/// Whether `a` and `b` are the same once case folded
+pub(crate) fn eq(a: char, b: char) -> bool {
+    a == b || fold(a) == fold(b)
+}
+
+/// Whether `a` and `b` have the same characters once case folded
+pub(crate) fn str_eq(a: &str, b: &str) -> bool {
+    a.chars().count() == b.chars().count() && a.chars().zip(b.chars()).all(|(a, b)| eq(a, b))
+}
+
+/// Character between `start` and `end` that is the same as `c` once
+/// case folded, which is `c` itself if it's within the range
+pub(crate) fn in_range(c: char, start: char, end: char) -> Option<char> {
+    let within = |c: &char| (start..=end).contains(c);
+    let folded = fold(c);
+    if within(&c) {
+        return Some(c);
+    }
+    if within(&folded) {
+        return Some(folded);
+    }
+    let first = UNFOLD.partition_point(|&(to, _)| to < folded);
+    UNFOLD[first..]
+        .iter()
+        .take_while(|&&(to, _)| to == folded)
+        .map(|&(_, from)| from)
+        .find(within)
+}
+
+"""
+
+
+def rust_char(c):
+    return "'\\u{%x}'" % ord(c)
+
+
+def main():
+    folds = []
+    for code in range(sys.maxunicode + 1):
+        if 0xD800 <= code <= 0xDFFF:
+            continue
+        c = chr(code)
+        folded = simple_fold(c)
+        if folded != c:
+            folds.append((c, folded))
+    unfolds = sorted((f, c) for c, f in folds)
+
+    out = sys.stdout
+    out.write("// casefold.rs --- Unicode simple case folding\n")
+    out.write("//\n")
+    out.write("// Generated by extra/casefold.py from the Unicode %s database;\n" % unicodedata.unidata_version)
+    out.write("// do not edit.\n")
+    out.write("//\n")
+    out.write(FUNCTIONS)
+    out.write("/// Characters that don't fold to themselves and what they fold\n")
+    out.write("/// to, sorted by the first item\n")
+    out.write("pub(crate) const FOLD: &[(char, char)] = &[\n")
+    for c, f in folds:
+        out.write("    (%s, %s),\n" % (rust_char(c), rust_char(f)))
+    out.write("];\n\n")
+    out.write("/// The same pairs of `FOLD` swapped, sorted by what they fold to\n")
+    out.write("pub(crate) const UNFOLD: &[(char, char)] = &[\n")
+    for f, c in unfolds:
+        out.write("    (%s, %s),\n" % (rust_char(f), rust_char(c)))
+    out.write("];\n")
+
+
+if __name__ == "__main__":
+    main()
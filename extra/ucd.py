@@ -0,0 +1,177 @@
+#!/usr/bin/env python3
+# ucd.py --- generate the tables of the `\p{..}` class escapes
+#
+# Writes langlang_lib/src/ucd.rs with the ranges of characters of
+# each Unicode general category, and of the groups of categories
+# sharing the same first letter, derived from the Unicode database
+# shipped with Python.  The ranges of each script are read from the
+# `Scripts.txt` file of the Unicode Character Database given as the
+# only argument, leaving out the characters the database shipped
+# with Python doesn't assign, so both tables describe the same
+# characters.
+#
+# Usage: python3 extra/ucd.py Scripts.txt > langlang_lib/src/ucd.rs
+import re
+import sys
+import unicodedata
+
+# short and long names of each general category, from
+# PropertyValueAliases.txt
+CATEGORIES = [
+    ("Lu", "Uppercase_Letter"),
+    ("Ll", "Lowercase_Letter"),
+    ("Lt", "Titlecase_Letter"),
+    ("Lm", "Modifier_Letter"),
+    ("Lo", "Other_Letter"),
+    ("Mn", "Nonspacing_Mark"),
+    ("Mc", "Spacing_Mark"),
+    ("Me", "Enclosing_Mark"),
+    ("Nd", "Decimal_Number"),
+    ("Nl", "Letter_Number"),
+    ("No", "Other_Number"),
+    ("Pc", "Connector_Punctuation"),
+    ("Pd", "Dash_Punctuation"),
+    ("Ps", "Open_Punctuation"),
+    ("Pe", "Close_Punctuation"),
+    ("Pi", "Initial_Punctuation"),
+    ("Pf", "Final_Punctuation"),
+    ("Po", "Other_Punctuation"),
+    ("Sm", "Math_Symbol"),
+    ("Sc", "Currency_Symbol"),
+    ("Sk", "Modifier_Symbol"),
+    ("So", "Other_Symbol"),
+    ("Zs", "Space_Separator"),
+    ("Zl", "Line_Separator"),
+    ("Zp", "Paragraph_Separator"),
+    ("Cc", "Control"),
+    ("Cf", "Format"),
+    ("Co", "Private_Use"),
+    ("Cn", "Unassigned"),
+]
+
+# groups of categories, and the ones within each of them
+GROUPS = [
+    ("L", "Letter", ["Lu", "Ll", "Lt", "Lm", "Lo"]),
+    ("LC", "Cased_Letter", ["Lu", "Ll", "Lt"]),
+    ("M", "Mark", ["Mn", "Mc", "Me"]),
+    ("N", "Number", ["Nd", "Nl", "No"]),
+    ("P", "Punctuation", ["Pc", "Pd", "Ps", "Pe", "Pi", "Pf", "Po"]),
+    ("S", "Symbol", ["Sm", "Sc", "Sk", "So"]),
+    ("Z", "Separator", ["Zs", "Zl", "Zp"]),
+    ("C", "Other", ["Cc", "Cf", "Co", "Cn"]),
+]
+
+INDEX = """
+use crate::properties::Ranges;
+
+/// Short name, long name and ranges of the characters of each
+/// general category, and of each group of categories
+pub(crate) const CATEGORIES: &[(&str, &str, Ranges)] = &[
+%s];
+
+/// Name and ranges of the characters of each script
+pub(crate) const SCRIPTS: &[(&str, Ranges)] = &[
+%s];
+"""
+
+
+def chars():
+    for code in range(sys.maxunicode + 1):
+        if 0xD800 <= code <= 0xDFFF:
+            continue
+        yield chr(code)
+
+
+def ranges(codes):
+    out = []
+    for code in sorted(codes):
+        # the surrogates between two ranges don't split them
+        if out and (out[-1][1] + 1 == code or (out[-1][1] == 0xD7FF and code == 0xE000)):
+            out[-1][1] = code
+        else:
+            out.append([code, code])
+    return out
+
+
+def read_scripts(path):
+    scripts = {}
+    line_re = re.compile(r"^([0-9A-F]+)(?:\.\.([0-9A-F]+))?\s*;\s*(\w+)")
+    version = None
+    with open(path) as f:
+        for line in f:
+            if version is None:
+                m = re.match(r"^# Scripts-(\S+)\.txt", line)
+                if m:
+                    version = m.group(1)
+            m = line_re.match(line)
+            if not m:
+                continue
+            start = int(m.group(1), 16)
+            end = int(m.group(2) or m.group(1), 16)
+            scripts.setdefault(m.group(3), set()).update(range(start, end + 1))
+    return version, scripts
+
+
+def table_name(name):
+    return re.sub(r"[^A-Z0-9_]", "_", name.upper())
+
+
+def rust_char(code):
+    return "'\\u{%x}'" % code
+
+
+def table(prefix, name, codes):
+    lines = ["const %s_%s: Ranges = &[\n" % (prefix, table_name(name))]
+    for start, end in ranges(codes):
+        lines.append("    (%s, %s),\n" % (rust_char(start), rust_char(end)))
+    lines.append("];\n")
+    return "".join(lines)
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: %s Scripts.txt" % sys.argv[0])
+    by_category = {}
+    for c in chars():
+        by_category.setdefault(unicodedata.category(c), set()).add(ord(c))
+    assigned = set().union(*(v for k, v in by_category.items() if k != "Cn"))
+    version, scripts = read_scripts(sys.argv[1])
+
+    out = sys.stdout
+    out.write("// ucd.rs --- Unicode general categories and scripts\n")
+    out.write("//\n")
+    out.write(
+        "// Generated by extra/ucd.py from the Unicode %s database and\n"
+        % unicodedata.unidata_version
+    )
+    out.write("// Scripts-%s.txt; do not edit.\n" % version)
+    out.write("//\n")
+    categories = []
+    scripts_rows = []
+    for short, long in CATEGORIES:
+        categories.append((short, long, "GC_%s" % table_name(short)))
+    for short, long, _ in GROUPS:
+        categories.append((short, long, "GC_%s" % table_name(short)))
+    for name in sorted(scripts):
+        scripts_rows.append((name, "SC_%s" % table_name(name)))
+    out.write(
+        INDEX
+        % (
+            "".join('    ("%s", "%s", %s),\n' % row for row in categories),
+            "".join('    ("%s", %s),\n' % row for row in scripts_rows),
+        )
+    )
+    tables = []
+    for short, _ in CATEGORIES:
+        tables.append(table("GC", short, by_category.get(short, set())))
+    for short, _, members in GROUPS:
+        codes = set().union(*(by_category.get(m, set()) for m in members))
+        tables.append(table("GC", short, codes))
+    for name in sorted(scripts):
+        tables.append(table("SC", name, scripts[name] & assigned))
+    out.write("\n")
+    out.write("\n".join(tables))
+
+
+if __name__ == "__main__":
+    main()
@@ -1,46 +1,559 @@
-use std::io::Write;
+use std::io::{BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::{fs, io};
 
-use langlang_lib::vm::VM;
-use langlang_lib::{compiler, import};
+use langlang_lib::diagnostic::{Diagnostic, Severity};
+use langlang_lib::interp::Interpreter;
+use langlang_lib::reduce::Reducer;
+use langlang_lib::vm::{self, VM};
+use langlang_lib::{analysis, bytecode, compiler, import};
+use langlang_syntax::ast;
 use langlang_value::format;
 use langlang_value::value::Value;
 
 use clap::{Parser, Subcommand};
 
+mod serve;
+
 /// Enumeration of all sub commands supported by this binary
 #[derive(Subcommand)]
 enum Command {
     /// Run a grammar file against an input file.  If the input file
     /// is not provided, the user will be dropped into an interactive
     /// shell.
-    Run {
-        /// Path to the grammar file to be executed
-        #[arg(short, long)]
-        grammar_file: std::path::PathBuf,
+    Run(RunArgs),
+
+    /// Parse, validate and compile grammar files without running
+    /// them, printing out the errors and warnings found.  Exits with
+    /// a non-zero status if any of the files fail to compile, or have
+    /// rules that never match or repetitions that loop forever.
+    Check(CheckArgs),
+
+    /// Compile a grammar file and save the program to a file, which
+    /// `run` loads without compiling the grammar again.
+    Compile(CompileArgs),
+
+    /// Match a corpus of example inputs against a grammar, then
+    /// report the rules and the alternatives none of them exercised.
+    /// Exits with a non-zero status if any of the inputs doesn't
+    /// match the grammar.
+    Coverage(CoverageArgs),
+
+    /// Print the reference of the instruction set of the virtual
+    /// machine, in markdown.
+    Instructions,
+
+    /// Shrink a grammar and an input that crash or hang the engine
+    /// down to a minimal reproducer, saved as `grammar.peg` and
+    /// `input` within the output directory.  Exits with a non-zero
+    /// status if they don't crash or hang it.
+    Reduce(ReduceArgs),
+
+    /// Host a web playground on the local machine, with panes to
+    /// edit a grammar and an input and to see the tree matched and
+    /// the errors found as they're typed.  With `--api`, serve a
+    /// JSON API to compile grammars and match inputs instead.
+    Serve(ServeArgs),
+}
+
+/// Options of the `serve` sub command
+#[derive(clap::Args)]
+struct ServeArgs {
+    /// Address and port to listen on
+    #[arg(short, long, default_value = "127.0.0.1:8000")]
+    address: String,
+
+    /// Path to a grammar file the grammar pane starts with
+    #[arg(short, long)]
+    grammar_file: Option<PathBuf>,
+
+    /// Serve `POST /compile` and `POST /match`, which take and reply
+    /// with JSON, instead of the playground.  Requests are limited
+    /// in size and in how long matching takes
+    #[arg(long, conflicts_with = "grammar_file")]
+    api: bool,
+}
+
+/// Options of the `coverage` sub command
+#[derive(clap::Args)]
+struct CoverageArgs {
+    /// Path to the grammar file to be exercised
+    #[arg(short, long)]
+    grammar_file: PathBuf,
+
+    /// Choose what's the first production to run
+    #[arg(short, long)]
+    start_rule: Option<String>,
+
+    /// Paths to the example inputs to be matched against the grammar
+    #[arg(required = true)]
+    input_files: Vec<PathBuf>,
+
+    #[command(flatten)]
+    imports: ImportArgs,
+}
+
+/// Options of the `reduce` sub command
+#[derive(clap::Args)]
+struct ReduceArgs {
+    /// Path to the grammar file that crashes or hangs the engine
+    #[arg(short, long)]
+    grammar_file: PathBuf,
+
+    /// Path to the input that crashes or hangs the engine
+    #[arg(short, long)]
+    input_file: PathBuf,
+
+    /// Directory the reduced grammar and input are saved in
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Consider matching hung after running this many instructions
+    #[arg(long, value_name = "N")]
+    max_steps: Option<usize>,
+}
+
+/// Options of the `check` sub command
+#[derive(clap::Args)]
+struct CheckArgs {
+    /// Paths to the grammar files to be checked
+    #[arg(required = true)]
+    grammar_files: Vec<PathBuf>,
+
+    /// Check that this production exists and can be used as the
+    /// first one to run
+    #[arg(short, long)]
+    start_rule: Option<String>,
+
+    #[command(flatten)]
+    imports: ImportArgs,
+
+    #[command(flatten)]
+    lints: LintArgs,
+}
+
+/// Options of the `compile` sub command
+#[derive(clap::Args)]
+struct CompileArgs {
+    /// Path to the grammar file to be compiled
+    #[arg(short, long)]
+    grammar_file: PathBuf,
+
+    /// Choose what's the first production to run
+    #[arg(short, long)]
+    start_rule: Option<String>,
+
+    /// Path to the file the compiled program gets saved to
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Run rules that call themselves right before returning in
+    /// constant stack space, flattening the values they capture
+    #[arg(long)]
+    tail_calls: bool,
+
+    /// Inline the rules of at most this many expressions where
+    /// they're called within predicates, zero disables inlining
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    inline_threshold: usize,
+
+    /// Leave the rules the start rule never uses out of the program
+    #[arg(long)]
+    remove_unused_rules: bool,
+
+    /// Print more about how the grammar got compiled to stderr, can
+    /// be repeated
+    #[arg(long, value_enum)]
+    emit: Vec<Emit>,
+
+    #[command(flatten)]
+    imports: ImportArgs,
+
+    #[command(flatten)]
+    lints: LintArgs,
+}
+
+/// What `compile --emit` prints out
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Emit {
+    /// How long each pass of the compiler took, and how many
+    /// expressions and instructions it left behind
+    PassReport,
+}
+
+/// Options of the `run` sub command
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Path to the grammar file to be executed, or to a program
+    /// saved by `compile`.  Saved programs are mapped into memory
+    /// instead of being compiled, so the options that change how
+    /// grammars get compiled don't apply to them
+    #[arg(short, long)]
+    grammar_file: std::path::PathBuf,
+
+    /// Choose what's the first production to run.  Saved programs
+    /// start from any of the rules they were compiled with
+    #[arg(short, long)]
+    start_rule: Option<String>,
+
+    /// Path to the content to be matched against the grammar;
+    /// Omitting it will drop you in an interactive shell
+    #[arg(short, long)]
+    input_file: Option<std::path::PathBuf>,
+
+    /// Configure the output before printing it out in the screen:
+    /// raw, compact, indented, html, json, jsonl, tree-json, sexpr,
+    /// dot or nil.
+    /// `tree-json` includes the type and the span of every value
+    #[arg(short, long, visible_alias = "output")]
+    output_format: Option<String>,
+
+    /// Match each line read from stdin as an independent input,
+    /// printing one result per line.  With `--output jsonl`, each
+    /// result is a JSON object with the `line` number and either a
+    /// `value` or an `error`
+    #[arg(long, conflicts_with = "input_file")]
+    stdin_lines: bool,
+
+    #[command(flatten)]
+    imports: ImportArgs,
+
+    #[command(flatten)]
+    errors: ErrorArgs,
+
+    #[command(flatten)]
+    limits: LimitArgs,
+
+    /// Print how far matching got to stderr every N characters of
+    /// the input file
+    #[arg(long, value_name = "N")]
+    progress: Option<usize>,
+
+    /// Print the optimizations applied to the grammar to stderr,
+    /// e.g.: alternatives committed to before they fully match
+    #[arg(long)]
+    report_optimizations: bool,
+
+    /// Print each instruction run, along with the productions entered
+    /// and exited, to stderr
+    #[arg(long)]
+    trace: bool,
 
-        /// Choose what's the first production to run
-        #[arg(short, long)]
-        start_rule: Option<String>,
+    /// Run rules that call themselves right before returning in
+    /// constant stack space, flattening the values they capture
+    #[arg(long)]
+    tail_calls: bool,
 
-        /// Path to the content to be matched against the grammar;
-        /// Omitting it will drop you in an interactive shell
-        #[arg(short, long)]
-        input_file: Option<std::path::PathBuf>,
+    /// Inline the rules of at most this many expressions where
+    /// they're called within predicates, zero disables inlining
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    inline_threshold: usize,
 
-        /// Configure the output before printing it out in the screen
-        #[arg(short, long)]
-        output_format: Option<String>,
-    },
+    /// Memoize whether each rule matched at each position of the
+    /// input, so grammars that backtrack over the same rules many
+    /// times get matched in linear time
+    #[arg(long)]
+    packrat: bool,
+
+    /// Keep the whitespace and comments in the tree, failing if it
+    /// doesn't hold every char of the input it matched
+    #[arg(long)]
+    lossless: bool,
+
+    #[command(flatten)]
+    lints: LintArgs,
+}
+
+/// How errors and warnings get printed out
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ErrorFormat {
+    /// Text meant to be read by people
+    Human,
+    /// One JSON object per line, meant to be read by other programs
+    Json,
+}
+
+/// Exit status of the binary, so scripts can tell a broken grammar
+/// from an input that doesn't match it.  Clap exits with `2` on
+/// invalid command line arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Success = 0,
+    // The input doesn't match the grammar
+    InputRejected = 1,
+    // The grammar can't be parsed, imported or compiled
+    GrammarError = 3,
+    // Files can't be read or written
+    IOError = 4,
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// How the commands print out their results, warnings and errors
+struct Output {
+    error_format: ErrorFormat,
+    // Only errors get printed out
+    quiet: bool,
+}
+
+/// Error that happened while processing `file`
+struct Failure {
+    error: langlang_lib::Error,
+    file: Option<PathBuf>,
+}
+
+impl Failure {
+    fn to_diagnostic(&self) -> Diagnostic {
+        let diagnostic = self.error.to_diagnostic();
+        match &self.file {
+            Some(file) => diagnostic.with_file(&file.display().to_string()),
+            None => diagnostic,
+        }
+    }
+
+    /// Underline the location of the error within its file, falling
+    /// back to the error message if the file can't be read
+    fn render(&self) -> String {
+        let source = self.file.as_ref().and_then(|f| fs::read_to_string(f).ok());
+        match source {
+            Some(source) => self
+                .to_diagnostic()
+                .render(&source, io::stderr().is_terminal()),
+            None => format!("{}\n", self.error),
+        }
+    }
+
+    fn status(&self) -> Status {
+        use langlang_lib::Error;
+        match &self.error {
+            Error::RuntimeError(_) => Status::InputRejected,
+            Error::IOError(_) => Status::IOError,
+            Error::ImportError(
+                import::Error::FileNotFound(_)
+                | import::Error::PermissionDenied(_)
+                | import::Error::OtherIOError(_),
+            ) => Status::IOError,
+            _ => Status::GrammarError,
+        }
+    }
+}
+
+/// Attach the file being processed to errors
+trait At<T> {
+    fn at(self, file: Option<&Path>) -> Result<T, Failure>;
+}
+
+impl<T, E: Into<langlang_lib::Error>> At<T> for Result<T, E> {
+    fn at(self, file: Option<&Path>) -> Result<T, Failure> {
+        self.map_err(|e| Failure {
+            error: e.into(),
+            file: file.map(|f| f.to_path_buf()),
+        })
+    }
+}
+
+/// Options that set where imported grammars are searched in
+#[derive(clap::Args)]
+struct ImportArgs {
+    /// Directory to search for imported grammars in; can be
+    /// repeated.  Directories listed in `LANGLANG_PATH` are searched
+    /// after these
+    #[arg(short = 'I', long = "include", value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Verify imported modules against the hashes in this lockfile.
+    /// The lockfile is generated if it doesn't exist yet
+    #[arg(long, value_name = "FILE")]
+    lockfile: Option<PathBuf>,
+}
+
+impl ImportArgs {
+    fn loader(&self) -> import::SearchPathImportLoader<import::OsFileSystem> {
+        let mut loader = import::SearchPathImportLoader::new(import::OsFileSystem);
+        for dir in &self.include {
+            loader.add_search_path(dir);
+        }
+        loader.add_env_search_paths();
+        loader
+    }
+
+    /// Resolve the imports of `grammar_file`, checking them against
+    /// the lockfile when there's one
+    fn resolve(&self, grammar_file: &Path) -> Result<ast::Grammar, Failure> {
+        let importer = import::ImportResolver::new(self.loader());
+        let lockfile = match &self.lockfile {
+            Some(path) if path.exists() => path,
+            Some(path) => {
+                let ast = importer.resolve(grammar_file).at(Some(grammar_file))?;
+                fs::write(path, importer.lockfile().to_string()).at(Some(path))?;
+                return Ok(ast);
+            }
+            None => return importer.resolve(grammar_file).at(Some(grammar_file)),
+        };
+        let content = fs::read_to_string(lockfile).at(Some(lockfile))?;
+        let locked = import::Lockfile::parse(&content).at(Some(lockfile))?;
+        importer
+            .with_lockfile(locked)
+            .resolve(grammar_file)
+            .at(Some(grammar_file))
+    }
+}
+
+/// Options that set what matching does when a label is thrown
+#[derive(clap::Args)]
+struct ErrorArgs {
+    /// Stop at the first error instead of recovering from labels
+    #[arg(long, conflicts_with = "max_errors")]
+    fail_fast: bool,
+
+    /// Give up after recovering from this many errors
+    #[arg(long, value_name = "N")]
+    max_errors: Option<usize>,
+
+    /// Exit successfully when the input matched after recovering
+    /// from errors.  Otherwise the number of errors gets printed to
+    /// stderr and the exit status is 1
+    #[arg(long, conflicts_with = "fail_fast")]
+    allow_errors: bool,
+}
+
+impl ErrorArgs {
+    fn on_error(&self) -> vm::OnError {
+        match (self.fail_fast, self.max_errors) {
+            (true, _) => vm::OnError::FailFast,
+            (false, Some(max)) => vm::OnError::RecoverWithLimit(max),
+            (false, None) => vm::OnError::Recover,
+        }
+    }
+
+    /// Summary of the errors recovered from within `value`, unless
+    /// there are none or they're allowed
+    fn recovered(&self, value: &Value) -> Option<Diagnostic> {
+        let count = count_errors(value);
+        if count == 0 || self.allow_errors {
+            return None;
+        }
+        let plural = if count == 1 { "" } else { "s" };
+        let diagnostic = Diagnostic::new(
+            Severity::Error,
+            "recovered_errors",
+            format!("{} syntax error{}", count, plural),
+        );
+        Some(diagnostic.with_help("pass `--allow-errors` to exit successfully anyway"))
+    }
+}
+
+/// Number of error values within `value`, each one left behind by
+/// recovering from a label
+fn count_errors(value: &Value) -> usize {
+    match value {
+        Value::Error(_) => 1,
+        Value::List(v) => v.values.iter().map(count_errors).sum(),
+        Value::Node(v) => v.items.iter().map(count_errors).sum(),
+        Value::Char(_) | Value::String(_) => 0,
+    }
+}
+
+/// Options that bound the resources a single match can use
+#[derive(clap::Args)]
+struct LimitArgs {
+    /// Stop matching once the stack of the virtual machine holds
+    /// more than this many frames, e.g.: on deeply nested input
+    #[arg(long, value_name = "N")]
+    max_stack_depth: Option<usize>,
+
+    /// Stop matching after running this many instructions
+    #[arg(long, value_name = "N")]
+    max_steps: Option<usize>,
+}
+
+impl LimitArgs {
+    fn apply(&self, config: vm::Config) -> vm::Config {
+        let config = match self.max_stack_depth {
+            Some(depth) => config.with_max_stack_depth(depth),
+            None => config,
+        };
+        match self.max_steps {
+            Some(steps) => config.with_max_steps(steps),
+            None => config,
+        }
+    }
+}
+
+/// Options that set the level of the lints checked at compile time
+#[derive(clap::Args)]
+struct LintArgs {
+    /// Fail on any warning that isn't explicitly allowed
+    #[arg(long)]
+    strict: bool,
+
+    /// Don't report warnings with this code (e.g.: unused_rule)
+    #[arg(long, value_name = "CODE")]
+    allow: Vec<String>,
+
+    /// Fail on warnings with this code
+    #[arg(long, value_name = "CODE")]
+    deny: Vec<String>,
+}
+
+impl LintArgs {
+    fn config(&self) -> Result<compiler::Config, langlang_lib::Error> {
+        let mut config = compiler::Config::default();
+        if self.strict {
+            config = config.strict();
+        }
+        for code in &self.allow {
+            config = config.with_lint_level(code.parse()?, compiler::LintLevel::Allow);
+        }
+        for code in &self.deny {
+            config = config.with_lint_level(code.parse()?, compiler::LintLevel::Deny);
+        }
+        Ok(config)
+    }
 }
 
 /// langlang provides a set of subcommands with different functionality.
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(after_help = "Exit status:
+  0  success
+  1  the input doesn't match the grammar, or only matches after
+     recovering from errors, unless `--allow-errors` is given
+  2  invalid command line arguments
+  3  the grammar can't be parsed, imported or compiled
+  4  files can't be read or written")]
 struct Cli {
     #[command(subcommand)]
     command: Command,
+
+    /// Print errors and warnings as text or as JSON
+    #[arg(long, value_enum, global = true, default_value = "human")]
+    error_format: ErrorFormat,
+
+    /// Print errors and warnings as JSON; same as `--error-format json`
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Only print out errors; the exit status tells how it went
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+impl Cli {
+    fn output(&self) -> Output {
+        Output {
+            error_format: match self.json {
+                true => ErrorFormat::Json,
+                false => self.error_format,
+            },
+            quiet: self.quiet,
+        }
+    }
 }
 
 type FormattingFunc = fn(v: &Value);
@@ -52,35 +565,229 @@ fn outputfn(name: &str) -> FormattingFunc {
         "html" => |v| println!("{}", format::html(v)),
         "indented" => |v| println!("{}", format::indented(v)),
         "raw" => |v| println!("{}", format::raw(v)),
-        _ => |_| println!(""),
+        "json" | "jsonl" => |v| println!("{}", format::json(v)),
+        "tree-json" => |v| println!("{}", format::value_to_json(v)),
+        "sexpr" => |v| println!("{}", format::value_to_sexpr(v)),
+        "dot" => |v| print!("{}", format::value_to_dot(v)),
+        _ => |_| println!(),
     }
 }
 
-fn command_run(
+/// Print out the warnings found within `grammar_file` to stderr
+fn print_warnings(
+    warnings: &[compiler::Warning],
     grammar_file: &Path,
-    start_rule: &Option<String>,
-    input_file: &Option<PathBuf>,
-    output_format: &Option<String>,
-) -> Result<(), langlang_lib::Error> {
-    let importer = import::ImportResolver::new(import::RelativeImportLoader::default());
-    let ast = importer.resolve(grammar_file)?;
-    // This is a little ugly but it's converting from &Option<String> to Option<&str>
-    let program = compiler::Compiler::default().compile(
-        &ast,
-        match start_rule {
-            Some(n) => Some(&n),
-            None => None,
-        },
-    )?;
-    let fmt = outputfn(output_format.as_ref().unwrap_or(&"raw".to_string()));
+    out: &Output,
+) -> Result<(), Failure> {
+    let diagnostics: Vec<_> = warnings.iter().map(|w| w.to_diagnostic()).collect();
+    print_diagnostics(&diagnostics, grammar_file, out)
+}
+
+/// Print out diagnostics that point within `grammar_file` to stderr.
+/// Only errors get printed out when the output is quiet.
+fn print_diagnostics(
+    diagnostics: &[Diagnostic],
+    grammar_file: &Path,
+    out: &Output,
+) -> Result<(), Failure> {
+    let diagnostics: Vec<_> = diagnostics
+        .iter()
+        .filter(|d| !out.quiet || d.severity == Severity::Error)
+        .collect();
+    if diagnostics.is_empty() {
+        return Ok(());
+    }
+    let grammar_source = fs::read_to_string(grammar_file).at(Some(grammar_file))?;
+    for diagnostic in diagnostics {
+        let diagnostic = diagnostic.with_file(&grammar_file.display().to_string());
+        match out.error_format {
+            ErrorFormat::Human => eprint!(
+                "{}",
+                diagnostic.render(&grammar_source, io::stderr().is_terminal())
+            ),
+            ErrorFormat::Json => eprintln!("{}", diagnostic.to_json()),
+        }
+    }
+    Ok(())
+}
+
+/// Print out an error that doesn't point within a file to stderr
+fn print_error(diagnostic: &Diagnostic, out: &Output) {
+    match out.error_format {
+        ErrorFormat::Human => eprintln!("{}", diagnostic),
+        ErrorFormat::Json => eprintln!("{}", diagnostic.to_json()),
+    }
+}
+
+/// Print out an error that stopped a command to stderr
+fn print_failure(failure: &Failure, out: &Output) {
+    match out.error_format {
+        ErrorFormat::Human => eprint!("{}", failure.render()),
+        ErrorFormat::Json => eprintln!("{}", failure.to_diagnostic().to_json()),
+    }
+}
+
+/// Match each line of stdin against `program`.  A line that doesn't
+/// match gets reported and the next ones still get matched, with the
+/// status telling if any line got rejected
+fn command_run_lines(
+    program: &vm::Program,
+    entry: Option<&str>,
+    vm_config: vm::Config,
+    trace: bool,
+    errors: &ErrorArgs,
+    output_format: &str,
+    out: &Output,
+) -> Result<Status, Failure> {
+    let fmt = outputfn(if out.quiet { "nil" } else { output_format });
+    let mut m = new_vm(program, vm_config, trace);
+    let mut status = Status::Success;
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        let line = line.at(None)?;
+        let result = run_vm(&mut m, entry, &line).at(None);
+        if result.is_err() {
+            status = Status::InputRejected;
+        }
+        if let Ok(Some(value)) = &result {
+            if let Some(mut d) = errors.recovered(value) {
+                d.notes.push(format!("on line {}", i + 1));
+                print_error(&d, out);
+                status = Status::InputRejected;
+            }
+        }
+        match (output_format, result) {
+            (_, Ok(_)) if out.quiet => {}
+            ("jsonl", Ok(value)) => println!(
+                "{{\"line\":{},\"value\":{}}}",
+                i + 1,
+                value.map_or("null".to_string(), |v| format::json(&v))
+            ),
+            ("jsonl", Err(failure)) => println!(
+                "{{\"line\":{},\"error\":{}}}",
+                i + 1,
+                failure.to_diagnostic().to_json()
+            ),
+            (_, Ok(None)) => println!("not much"),
+            (_, Ok(Some(v))) => fmt(&v),
+            (_, Err(failure)) => print_failure(&failure, out),
+        }
+    }
+    Ok(status)
+}
+
+/// Machine that prints a trace of its steps out to stderr if `trace`
+/// is set
+fn new_vm(program: &vm::Program, config: vm::Config, trace: bool) -> VM<'_> {
+    let mut m = VM::new_with_config(program, config);
+    if trace {
+        m.set_tracer(vm::PrintTracer::stderr());
+    }
+    m
+}
+
+/// Match `input` from the `entry` rule, or from the program's start
+/// rule when none is given
+fn run_vm(m: &mut VM, entry: Option<&str>, input: &str) -> Result<Option<Value>, vm::Error> {
+    match entry {
+        Some(rule) => m.run_from(rule, input),
+        None => m.run(input),
+    }
+}
+
+fn command_run(args: &RunArgs, out: &Output) -> Result<Status, Failure> {
+    let RunArgs {
+        grammar_file,
+        start_rule,
+        input_file,
+        output_format,
+        stdin_lines,
+        imports,
+        errors,
+        limits,
+        progress,
+        report_optimizations,
+        trace,
+        tail_calls,
+        inline_threshold,
+        packrat,
+        lossless,
+        lints,
+    } = args;
+    // programs saved by `compile` were built from their own start
+    // rule, so the one asked for here gets chosen at match time
+    let compiled = is_compiled(grammar_file).at(Some(grammar_file))?;
+    let entry = start_rule.as_deref().filter(|_| compiled);
+    let program = match compiled {
+        true => vm::Program::map_file(grammar_file).at(Some(grammar_file))?,
+        false => {
+            let ast = imports.resolve(grammar_file)?;
+            let mut config = lints.config().at(None)?;
+            if *tail_calls {
+                config = config.enable_tail_calls();
+            }
+            config = config.with_inline_threshold(*inline_threshold);
+            let output = compiler::Compiler::new(config)
+                .compile_with_warnings(&ast, start_rule.as_deref())
+                .at(Some(grammar_file))?;
+            print_warnings(&output.warnings, grammar_file, out)?;
+            if *report_optimizations && !out.quiet {
+                for optimization in &output.optimizations {
+                    eprintln!("{}: {}", grammar_file.display(), optimization);
+                }
+            }
+            output.program
+        }
+    };
+    let vm_config = limits.apply(vm::Config::default().with_on_error(errors.on_error()));
+    let vm_config = match packrat {
+        true => vm_config.with_packrat(),
+        false => vm_config,
+    };
+    let vm_config = match lossless {
+        true => vm_config.with_lossless(),
+        false => vm_config,
+    };
+    let output_format = output_format.as_deref().unwrap_or("raw");
+    if *stdin_lines {
+        return command_run_lines(
+            &program,
+            entry,
+            vm_config,
+            *trace,
+            errors,
+            output_format,
+            out,
+        );
+    }
+    let fmt = match out.quiet {
+        true => outputfn("nil"),
+        false => outputfn(output_format),
+    };
 
     match input_file {
         Some(input_file) => {
-            let input_data = fs::read_to_string(input_file)?;
-            let mut m = VM::new(&program);
-            match m.run_str(&input_data)? {
-                None => println!("not much"),
-                Some(v) => fmt(&v),
+            let input_data = fs::read_to_string(input_file).at(Some(input_file))?;
+            let mut m = new_vm(&program, vm_config.clone(), *trace);
+            if let Some(every) = progress.filter(|_| !out.quiet) {
+                let total = input_data.chars().count();
+                m.on_progress(every, move |p| {
+                    eprintln!(
+                        "progress: {}/{} (ffp: {}, stack depth: {})",
+                        p.cursor, total, p.ffp, p.stack_depth
+                    );
+                    true
+                });
+            }
+            match run_vm(&mut m, entry, &input_data).at(Some(input_file))? {
+                None if !out.quiet => println!("not much"),
+                None => {}
+                Some(v) => {
+                    fmt(&v);
+                    if let Some(d) = errors.recovered(&v) {
+                        print_error(&d.with_file(&input_file.display().to_string()), out);
+                        return Ok(Status::InputRejected);
+                    }
+                }
             }
         }
         None => {
@@ -92,7 +799,7 @@ fn command_run(
 
                 // read the next line typed in
                 let mut line = String::new();
-                io::stdin().read_line(&mut line)?;
+                io::stdin().read_line(&mut line).at(None)?;
 
                 // handle Ctrl-D
                 if line.as_str() == "" {
@@ -109,36 +816,213 @@ fn command_run(
                 line.pop();
 
                 // run the line
-                let mut m = VM::new(&program);
-                match m.run_str(&line)? {
+                let mut m = new_vm(&program, vm_config.clone(), *trace);
+                match run_vm(&mut m, entry, &line).at(None)? {
                     None => println!("not much"),
                     Some(v) => fmt(&v),
                 }
             }
         }
     }
-    Ok(())
+    Ok(Status::Success)
 }
 
-fn run() -> Result<(), langlang_lib::Error> {
-    let cli = Cli::parse();
-    match &cli.command {
-        Command::Run {
-            grammar_file,
-            start_rule,
-            input_file,
-            output_format,
-        } => {
-            command_run(grammar_file, start_rule, input_file, output_format)?;
+/// Tell if `path` holds a program saved by `compile` rather than a
+/// grammar
+fn is_compiled(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = fs::File::open(path)?;
+    Ok(match io::Read::read_exact(&mut file, &mut magic) {
+        Ok(()) => &magic == bytecode::MAGIC,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    })
+}
+
+fn command_compile(args: &CompileArgs, out: &Output) -> Result<Status, Failure> {
+    let grammar_file = &args.grammar_file;
+    let ast = args.imports.resolve(grammar_file)?;
+    let mut config = args.lints.config().at(None)?;
+    if args.tail_calls {
+        config = config.enable_tail_calls();
+    }
+    config = config.with_inline_threshold(args.inline_threshold);
+    if args.remove_unused_rules {
+        config = config.remove_unused_rules();
+    }
+    let report_passes = args.emit.contains(&Emit::PassReport);
+    if report_passes {
+        config = config.report_passes();
+    }
+    let output = compiler::Compiler::new(config)
+        .compile_with_warnings(&ast, args.start_rule.as_deref())
+        .at(Some(grammar_file))?;
+    print_warnings(&output.warnings, grammar_file, out)?;
+    if report_passes && !out.quiet {
+        for pass in &output.passes {
+            eprintln!("{}", pass);
         }
+        let total: std::time::Duration = output.passes.iter().map(|p| p.elapsed).sum();
+        eprintln!("{:<20} {:>12?}", "total", total);
     }
-    Ok(())
+    fs::write(&args.output, output.program.to_bytes()).at(Some(&args.output))?;
+    Ok(Status::Success)
+}
+
+fn check_file(args: &CheckArgs, grammar_file: &Path, out: &Output) -> Result<Status, Failure> {
+    let ast = args.imports.resolve(grammar_file)?;
+    // every problem gets reported at once, rather than only the
+    // first one that stops compilation
+    let problems = analysis::analyze(&ast);
+    if !problems.is_empty() {
+        let diagnostics: Vec<_> = problems.iter().map(|p| p.to_diagnostic()).collect();
+        print_diagnostics(&diagnostics, grammar_file, out)?;
+        return Ok(Status::GrammarError);
+    }
+    let config = args.lints.config().at(None)?;
+    let output = compiler::Compiler::new(config)
+        .compile_with_warnings(&ast, args.start_rule.as_deref())
+        .at(Some(grammar_file))?;
+    print_warnings(&output.warnings, grammar_file, out)?;
+    Ok(Status::Success)
 }
 
-fn main() {
+fn command_check(args: &CheckArgs, out: &Output) -> Status {
+    // all the files get checked, so a single run reports every
+    // broken grammar.  The status is the one of the first failure
+    let mut status = Status::Success;
+    for grammar_file in &args.grammar_files {
+        let file_status = match check_file(args, grammar_file, out) {
+            Ok(file_status) => file_status,
+            Err(failure) => {
+                print_failure(&failure, out);
+                failure.status()
+            }
+        };
+        if status == Status::Success {
+            status = file_status;
+        }
+    }
+    status
+}
+
+fn command_reduce(args: &ReduceArgs, out: &Output) -> Result<Status, Failure> {
+    let grammar = fs::read_to_string(&args.grammar_file).at(Some(&args.grammar_file))?;
+    let input = fs::read_to_string(&args.input_file).at(Some(&args.input_file))?;
+    let reducer = match args.max_steps {
+        Some(steps) => Reducer::new().with_max_steps(steps),
+        None => Reducer::new(),
+    };
+    // most attempts panic when the failure is a panic, and the one
+    // left is reported once it's done
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let reduced = reducer.reduce(&grammar, &input);
+    std::panic::set_hook(hook);
+    let Some(reduced) = reduced else {
+        let diagnostic = Diagnostic::new(
+            Severity::Error,
+            "not_reproduced",
+            "matching the input doesn't crash or hang the engine".to_string(),
+        );
+        print_error(
+            &diagnostic.with_file(&args.grammar_file.display().to_string()),
+            out,
+        );
+        return Ok(Status::InputRejected);
+    };
+    fs::create_dir_all(&args.output).at(Some(&args.output))?;
+    let grammar_file = args.output.join("grammar.peg");
+    fs::write(&grammar_file, &reduced.grammar).at(Some(&grammar_file))?;
+    let input_file = args.output.join("input");
+    fs::write(&input_file, &reduced.input).at(Some(&input_file))?;
+    if !out.quiet {
+        eprintln!(
+            "{}\nreduced to {} lines of grammar and {} chars of input after {} attempts",
+            reduced.failure,
+            reduced.grammar.lines().count(),
+            reduced.input.chars().count(),
+            reduced.attempts
+        );
+    }
+    Ok(Status::Success)
+}
+
+fn command_coverage(args: &CoverageArgs, out: &Output) -> Result<Status, Failure> {
+    let grammar_file = &args.grammar_file;
+    let ast = args.imports.resolve(grammar_file)?;
+    let mut interp = Interpreter::new(
+        &ast,
+        args.start_rule.as_deref(),
+        &compiler::Config::default(),
+        vm::Config::default(),
+    )
+    .at(Some(grammar_file))?
+    .with_coverage();
+
+    // every input gets matched, so a single run reports every input
+    // the grammar rejects
+    let mut status = Status::Success;
+    for input_file in &args.input_files {
+        let input_data = fs::read_to_string(input_file).at(Some(input_file))?;
+        if let Err(failure) = interp.run(&input_data).at(Some(input_file)) {
+            print_failure(&failure, out);
+            status = Status::InputRejected;
+        }
+    }
+
+    let report = match interp.coverage() {
+        Some(coverage) => coverage.report(&ast),
+        None => return Ok(status),
+    };
+    let diagnostics: Vec<_> = report.gaps.iter().map(|g| g.to_diagnostic()).collect();
+    print_diagnostics(&diagnostics, grammar_file, out)?;
+    if !out.quiet {
+        println!(
+            "rules: {}/{}, alternatives: {}/{}",
+            report.rules.0, report.rules.1, report.alternatives.0, report.alternatives.1
+        );
+    }
+    Ok(status)
+}
+
+fn command_serve(args: &ServeArgs) -> Result<Status, Failure> {
+    let mode = match (&args.grammar_file, args.api) {
+        (_, true) => serve::Mode::Api,
+        (Some(file), false) => {
+            serve::Mode::Playground(fs::read_to_string(file).at(Some(file))?.into())
+        }
+        (None, false) => serve::Mode::Playground("".into()),
+    };
+    serve::serve(&args.address, mode).at(None)?;
+    Ok(Status::Success)
+}
+
+fn run(cli: &Cli, out: &Output) -> Result<Status, Failure> {
+    Ok(match &cli.command {
+        Command::Run(args) => command_run(args, out)?,
+        Command::Check(args) => command_check(args, out),
+        Command::Compile(args) => command_compile(args, out)?,
+        Command::Coverage(args) => command_coverage(args, out)?,
+        Command::Instructions => {
+            print!("{}", vm::instruction_reference());
+            Status::Success
+        }
+        Command::Reduce(args) => command_reduce(args, out)?,
+        Command::Serve(args) => command_serve(args)?,
+    })
+}
+
+fn main() -> ExitCode {
     env_logger::init();
 
-    if let Err(e) = run() {
-        println!("{}", e);
+    let cli = Cli::parse();
+    let out = cli.output();
+    match run(&cli, &out) {
+        Ok(status) => status.into(),
+        Err(failure) => {
+            print_failure(&failure, &out);
+            failure.status().into()
+        }
     }
 }
@@ -0,0 +1,901 @@
+// serve.rs --- the web playground and the HTTP API of `serve`
+//
+// A small HTTP/1.1 server built on the standard library, so neither
+// needs anything besides the binary.  Each connection is handled on
+// its own thread and closed after a single response.  Connections past
+// `MAX_CONNECTIONS` are answered with `503` right away, and the ones
+// that stop sending or receiving for `IO_TIMEOUT` are dropped.
+//
+// The playground serves the page from playground.html, which posts
+// the grammar and the input to `/run` as a form while they're edited,
+// and renders the JSON reply: the value matched and the diagnostics
+// of both the grammar and the input.
+//
+// The API takes JSON objects with string fields: `POST /compile`
+// checks a `grammar`, and `POST /match` also matches an `input`
+// against it.  Requests come from other programs, which may not be
+// trusted, so they're refused past the sizes of `Limits::untrusted`,
+// and matching stops once it runs too many instructions, nests too
+// deep or takes too long.
+//
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use langlang_lib::diagnostic::Diagnostic;
+use langlang_lib::vm::{self, VM};
+use langlang_lib::{compiler, import};
+use langlang_value::format;
+
+const PLAYGROUND: &str = include_str!("playground.html");
+
+// largest request body accepted, which is about the size of the
+// largest grammar and input anyone would type in a browser
+const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+// largest line of the request line and the headers
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+// stack of the threads handling connections.  Formatting and dropping
+// the value matched recurse once per level of nesting, and the stack
+// of the threads spawned by default doesn't have room for values
+// nested as deep as `Limits::untrusted` lets matching capture them
+const STACK_SIZE: usize = 32 * 1024 * 1024;
+
+// most connections handled at once
+const MAX_CONNECTIONS: usize = 64;
+
+// time reading the request or writing the response can wait for the
+// other end of the connection
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the server answers to
+#[derive(Clone)]
+pub(crate) enum Mode {
+    /// The web playground, with the grammar its pane starts with
+    Playground(Arc<str>),
+    /// The JSON API
+    Api,
+}
+
+/// Limits of the work a single request can make the server do
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    // largest grammar, in bytes
+    max_grammar_size: usize,
+    // largest input, in bytes
+    max_input_size: usize,
+    // errors recovered from before matching gives up
+    max_errors: usize,
+    // frames the stack of the virtual machine can hold
+    max_stack_depth: usize,
+    // instructions matching can execute
+    max_steps: usize,
+    // time matching can take, checked as the cursor moves forward
+    timeout: Duration,
+}
+
+impl Limits {
+    /// Limits of requests that come from programs that aren't
+    /// trusted, which the API serves
+    fn untrusted() -> Self {
+        Self {
+            max_grammar_size: 64 * 1024,
+            max_input_size: 1024 * 1024,
+            max_errors: 100,
+            max_stack_depth: 10_000,
+            max_steps: 100_000_000,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Accept connections on `address` until the process is stopped
+pub(crate) fn serve(address: &str, mode: Mode) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let what = match mode {
+        Mode::Playground(_) => "the playground",
+        Mode::Api => "the API",
+    };
+    eprintln!("serving {} at http://{}", what, listener.local_addr()?);
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream.and_then(|stream| {
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+            stream.set_write_timeout(Some(IO_TIMEOUT))?;
+            Ok(stream)
+        }) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("can't accept connection: {}", e);
+                continue;
+            }
+        };
+        let slot = match Slot::acquire(&connections, MAX_CONNECTIONS) {
+            Some(slot) => slot,
+            None => {
+                let response = match mode {
+                    Mode::Playground(_) => {
+                        Response::text("503 Service Unavailable", "too many connections")
+                    }
+                    Mode::Api => {
+                        Response::json_error("503 Service Unavailable", "too many connections")
+                    }
+                };
+                if let Err(e) = write_response(&mut stream, &response) {
+                    log::warn!("can't refuse connection: {}", e);
+                }
+                continue;
+            }
+        };
+        let mode = mode.clone();
+        let spawned = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                let _slot = slot;
+                if let Err(e) = handle(stream, &mode) {
+                    log::warn!("can't handle request: {}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            log::warn!("can't spawn thread for connection: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// One of the connections counted by a shared counter, which is
+/// given back once the slot is dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Take one of the `max` slots of `counter`, if there's one left
+    fn acquire(counter: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self(counter.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn new(status: &'static str, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    fn text(status: &'static str, body: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", format!("{}\n", body))
+    }
+
+    fn json(status: &'static str, body: String) -> Self {
+        Self::new(status, "application/json", body)
+    }
+
+    /// JSON object with the `error` that stopped the request
+    fn json_error(status: &'static str, error: &str) -> Self {
+        Self::json(status, format!("{{\"error\":{}}}", json_string(error)))
+    }
+}
+
+fn handle(stream: TcpStream, mode: &Mode) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let response = match read_request(&mut reader) {
+        Ok(request) => match mode {
+            Mode::Playground(grammar) => route_playground(&request, grammar),
+            Mode::Api => route_api(&request, &Limits::untrusted()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            Response::text("400 Bad Request", &e.to_string())
+        }
+        Err(e) => return Err(e),
+    };
+    write_response(reader.get_mut(), &response)
+}
+
+fn route_playground(request: &Request, grammar: &str) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => Response::new("200 OK", "text/html; charset=utf-8", PLAYGROUND.to_string()),
+        ("GET", "/grammar") => {
+            Response::new("200 OK", "text/plain; charset=utf-8", grammar.to_string())
+        }
+        ("POST", "/run") => {
+            let fields = parse_form(&String::from_utf8_lossy(&request.body));
+            let field = |name: &str| fields.get(name).map(String::as_str).unwrap_or("");
+            let start_rule = Some(field("start_rule")).filter(|s| !s.is_empty());
+            let mut diagnostics = vec![];
+            let value = compile(field("grammar"), start_rule, &mut diagnostics)
+                .and_then(|program| run(&program, field("input"), None, &mut diagnostics));
+            Response::json("200 OK", reply(&value, &diagnostics))
+        }
+        (_, "/" | "/grammar" | "/run") => Response::text("405 Method Not Allowed", "not allowed"),
+        _ => Response::text("404 Not Found", "not found"),
+    }
+}
+
+fn route_api(request: &Request, limits: &Limits) -> Response {
+    let matching = match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/compile") => false,
+        ("POST", "/match") => true,
+        (_, "/compile" | "/match") => {
+            return Response::json_error("405 Method Not Allowed", "not allowed")
+        }
+        _ => return Response::json_error("404 Not Found", "not found"),
+    };
+    let fields = match std::str::from_utf8(&request.body)
+        .map_err(|_| "request isn't valid UTF-8".to_string())
+        .and_then(parse_json_object)
+    {
+        Ok(fields) => fields,
+        Err(e) => return Response::json_error("400 Bad Request", &e),
+    };
+    let grammar = match fields.get("grammar") {
+        Some(grammar) => grammar,
+        None => return Response::json_error("400 Bad Request", "missing field `grammar`"),
+    };
+    if grammar.len() > limits.max_grammar_size {
+        return Response::json_error(
+            "413 Content Too Large",
+            &format!("grammar is larger than {} bytes", limits.max_grammar_size),
+        );
+    }
+    let start_rule = fields.get("start_rule").map(String::as_str);
+    let mut diagnostics = vec![];
+    let program = compile(grammar, start_rule, &mut diagnostics);
+    if !matching {
+        let ok = format!("\"ok\":{}", program.is_some());
+        return Response::json("200 OK", json_object(&ok, &diagnostics));
+    }
+    let input = match fields.get("input") {
+        Some(input) => input,
+        None => return Response::json_error("400 Bad Request", "missing field `input`"),
+    };
+    if input.len() > limits.max_input_size {
+        return Response::json_error(
+            "413 Content Too Large",
+            &format!("input is larger than {} bytes", limits.max_input_size),
+        );
+    }
+    let value = program.and_then(|p| run(&p, input, Some(limits), &mut diagnostics));
+    Response::json("200 OK", reply(&value, &diagnostics))
+}
+
+/// Compile `grammar`, collecting its warnings and errors within
+/// `diagnostics`
+fn compile(
+    grammar: &str,
+    start_rule: Option<&str>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<vm::Program> {
+    let mut loader = import::InMemoryImportLoader::default();
+    loader.add_grammar("grammar", grammar);
+    let output = import::ImportResolver::new(loader)
+        .resolve(Path::new("grammar"))
+        .map_err(langlang_lib::Error::from)
+        .and_then(|ast| {
+            compiler::Compiler::new(compiler::Config::default())
+                .compile_with_warnings(&ast, start_rule)
+                .map_err(langlang_lib::Error::from)
+        });
+    match output {
+        Ok(output) => {
+            diagnostics.extend(output.warnings.iter().map(|w| w.to_diagnostic()));
+            Some(output.program)
+        }
+        Err(e) => {
+            diagnostics.push(e.to_diagnostic());
+            None
+        }
+    }
+}
+
+/// Match `input` against `program`, returning the JSON of the value
+/// matched and collecting the error within `diagnostics` if there's
+/// one.  Matching gives up once it reaches any of the `limits`
+fn run(
+    program: &vm::Program,
+    input: &str,
+    limits: Option<&Limits>,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<String> {
+    let config = match limits {
+        Some(limits) => vm::Config::default()
+            .with_max_errors(limits.max_errors)
+            .with_max_stack_depth(limits.max_stack_depth)
+            .with_max_steps(limits.max_steps),
+        None => vm::Config::default(),
+    };
+    let mut m = VM::new_with_config(program, config);
+    if let Some(limits) = limits {
+        let deadline = Instant::now() + limits.timeout;
+        m.on_progress(256, move |_| Instant::now() < deadline);
+    }
+    match m.run(input) {
+        Ok(value) => value.map(|v| format::json(&v)),
+        Err(e) => {
+            let e = langlang_lib::Error::from(e);
+            diagnostics.push(e.to_diagnostic().with_file("input"));
+            None
+        }
+    }
+}
+
+/// JSON object with the `value` matched, or `null`, and the
+/// `diagnostics` found
+fn reply(value: &Option<String>, diagnostics: &[Diagnostic]) -> String {
+    let value = format!("\"value\":{}", value.as_deref().unwrap_or("null"));
+    json_object(&value, diagnostics)
+}
+
+/// JSON object with `fields` followed by the `diagnostics` found.
+/// The file of each diagnostic tells what it points at: the
+/// `grammar` or the `input`
+fn json_object(fields: &str, diagnostics: &[Diagnostic]) -> String {
+    let diagnostics: Vec<String> = diagnostics
+        .iter()
+        .map(|d| match d.file {
+            Some(_) => d.to_json(),
+            None => d.with_file("grammar").to_json(),
+        })
+        .collect();
+    format!("{{{},\"diagnostics\":[{}]}}", fields, diagnostics.join(","))
+}
+
+fn read_request(reader: &mut impl BufRead) -> io::Result<Request> {
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method.to_string(), target)
+        }
+        _ => return Err(invalid("malformed request line")),
+    };
+    // the query string isn't used by any of the routes
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut content_length = 0;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid("malformed content length"))?;
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Err(invalid("request body is too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, body })
+}
+
+// read a line without its line break, refusing the ones that are
+// too long or that aren't valid UTF-8
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = vec![];
+    reader
+        .take(MAX_LINE_SIZE as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if line.len() > MAX_LINE_SIZE {
+        return Err(invalid("request line or header is too long"));
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed within the request",
+        ));
+    }
+    let line = String::from_utf8(line).map_err(|_| invalid("request isn't valid UTF-8"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Fields of an `application/x-www-form-urlencoded` body
+fn parse_form(body: &str) -> HashMap<String, String> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+// decode `+` as space and `%HH` escapes as bytes, leaving malformed
+// escapes as they are
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => output.push(b' '),
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                Some(byte) => {
+                    output.push(byte);
+                    i += 2;
+                }
+                None => output.push(b'%'),
+            },
+            byte => output.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+fn json_string(s: &str) -> String {
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+/// Fields of a JSON object whose values are all strings.  Fields set
+/// to `null` are left out, as if they weren't there.
+fn parse_json_object(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut reader = JsonReader {
+        chars: text.chars().peekable(),
+    };
+    let mut fields = HashMap::new();
+    reader.expect('{')?;
+    if !reader.eat('}') {
+        loop {
+            let name = reader.string()?;
+            reader.expect(':')?;
+            if !reader.eat_null() {
+                if reader.peek() != Some('"') {
+                    return Err(format!("field `{}` must be a string", name));
+                }
+                fields.insert(name, reader.string()?);
+            }
+            if reader.eat('}') {
+                break;
+            }
+            reader.expect(',')?;
+        }
+    }
+    match reader.peek() {
+        None => Ok(fields),
+        Some(c) => Err(format!("unexpected `{}` after the object", c)),
+    }
+}
+
+struct JsonReader<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonReader<'_> {
+    // next char that isn't white space
+    fn peek(&mut self) -> Option<char> {
+        while self
+            .chars
+            .next_if(|c| matches!(c, ' ' | '\t' | '\n' | '\r'))
+            .is_some()
+        {}
+        self.chars.peek().copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.peek() == Some(c) && self.chars.next().is_some()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(format!("expected `{}` in JSON object", c)),
+        }
+    }
+
+    fn eat_null(&mut self) -> bool {
+        if self.peek() != Some('n') {
+            return false;
+        }
+        let rest: String = self.chars.clone().take(4).collect();
+        rest == "null" && self.chars.nth(3).is_some()
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut output = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(output),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => output.push('"'),
+                    Some('\\') => output.push('\\'),
+                    Some('/') => output.push('/'),
+                    Some('b') => output.push('\u{8}'),
+                    Some('f') => output.push('\u{c}'),
+                    Some('n') => output.push('\n'),
+                    Some('r') => output.push('\r'),
+                    Some('t') => output.push('\t'),
+                    Some('u') => output.push(self.unicode_escape()?),
+                    _ => return Err("invalid escape in string".to_string()),
+                },
+                Some(c) if (c as u32) < 0x20 => {
+                    return Err("control character in string".to_string())
+                }
+                Some(c) => output.push(c),
+            }
+        }
+    }
+
+    // the char of a `\uHHHH` escape, which takes two of them for
+    // chars outside of the basic multilingual plane
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if self.chars.next() != Some('\\') || self.chars.next() != Some('u') {
+                    return Err("unpaired surrogate in string".to_string());
+                }
+                match self.hex4()? {
+                    low @ 0xDC00..=0xDFFF => 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00),
+                    _ => return Err("unpaired surrogate in string".to_string()),
+                }
+            }
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| "unpaired surrogate in string".to_string())
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits: String = (0..4).filter_map(|_| self.chars.next()).collect();
+        match digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            true => Ok(u32::from_str_radix(&digits, 16).unwrap_or_default()),
+            false => Err("invalid `\\u` escape in string".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn read(input: &[u8]) -> io::Result<Request> {
+        read_request(&mut BufReader::new(input))
+    }
+
+    // kind and message of the error reading `input` fails with
+    fn read_err(input: &[u8]) -> (io::ErrorKind, String) {
+        let e = read(input).err().expect("reading should fail");
+        (e.kind(), e.to_string())
+    }
+
+    #[test]
+    fn read_request_parts() {
+        let r =
+            read(b"POST /run?x=1 HTTP/1.1\r\nHost: a\r\ncontent-length:  3\r\n\r\nabcd").unwrap();
+        assert_eq!(("POST", "/run"), (r.method.as_str(), r.path.as_str()));
+        assert_eq!(b"abc".to_vec(), r.body);
+
+        // bare line feeds end lines too, and there may be no body
+        let r = read(b"GET / HTTP/1.0\n\n").unwrap();
+        assert_eq!(("GET", "/"), (r.method.as_str(), r.path.as_str()));
+        assert!(r.body.is_empty());
+    }
+
+    #[test]
+    fn read_request_malformed() {
+        let invalid = |message: &str| (io::ErrorKind::InvalidData, message.to_string());
+        assert_eq!(
+            invalid("malformed request line"),
+            read_err(b"GET /\r\n\r\n")
+        );
+        assert_eq!(
+            invalid("malformed request line"),
+            read_err(b"GET / SPDY/3\r\n\r\n")
+        );
+        assert_eq!(
+            invalid("malformed header"),
+            read_err(b"GET / HTTP/1.1\r\nHost\r\n\r\n")
+        );
+        assert_eq!(
+            invalid("malformed content length"),
+            read_err(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n")
+        );
+        assert_eq!(
+            invalid("request isn't valid UTF-8"),
+            read_err(b"GET /\xff HTTP/1.1\r\n\r\n")
+        );
+
+        // connections closed before the request ends aren't replied to
+        let eof = read_err(b"GET / HTTP/1.1\r\nHost: a");
+        assert_eq!(io::ErrorKind::UnexpectedEof, eof.0);
+        let eof = read_err(b"POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nab");
+        assert_eq!(io::ErrorKind::UnexpectedEof, eof.0);
+    }
+
+    #[test]
+    fn read_request_limits() {
+        let line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_SIZE));
+        assert_eq!(
+            (
+                io::ErrorKind::InvalidData,
+                "request line or header is too long".to_string()
+            ),
+            read_err(line.as_bytes())
+        );
+        let header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE_SIZE));
+        assert_eq!(io::ErrorKind::InvalidData, read_err(header.as_bytes()).0);
+
+        // the body isn't read when it's announced to be too large
+        let body = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(
+            (
+                io::ErrorKind::InvalidData,
+                "request body is too large".to_string()
+            ),
+            read_err(body.as_bytes())
+        );
+    }
+
+    #[test]
+    fn parse_form_fields() {
+        let fields =
+            parse_form("grammar=A+%3C-+%27a%27&input=%E2%99%A1&start_rule=&flag&&bad=%zz%4");
+        assert_eq!("A <- 'a'", fields["grammar"]);
+        assert_eq!("♡", fields["input"]);
+        assert_eq!("", fields["start_rule"]);
+        assert_eq!("", fields["flag"]);
+        // malformed escapes are kept as they are
+        assert_eq!("%zz%4", fields["bad"]);
+        assert_eq!(5, fields.len());
+        assert!(parse_form("").is_empty());
+    }
+
+    #[test]
+    fn percent_decode_bytes() {
+        assert_eq!("a b+c", percent_decode("a+b%2Bc"));
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("%g0", percent_decode("%g0"));
+        // bytes that aren't valid UTF-8 are replaced
+        assert_eq!("\u{fffd}a", percent_decode("%FFa"));
+    }
+
+    #[test]
+    fn parse_json_object_fields() {
+        let fields =
+            parse_json_object(r#" { "a" : "q\"b\\s\/\b\f\n\r\té" , "b":null, "c":"😀", "": "" } "#)
+                .unwrap();
+        assert_eq!("q\"b\\s/\u{8}\u{c}\n\r\té", fields["a"]);
+        assert_eq!("😀", fields["c"]);
+        assert_eq!("", fields[""]);
+        // fields set to null are left out
+        assert!(!fields.contains_key("b"));
+        assert!(parse_json_object("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_json_object_errors() {
+        let err = |text: &str| parse_json_object(text).unwrap_err();
+        assert_eq!("expected `{` in JSON object", err(""));
+        assert_eq!("expected `{` in JSON object", err("[]"));
+        assert_eq!("field `a` must be a string", err(r#"{"a": 1}"#));
+        assert_eq!("field `a` must be a string", err(r#"{"a": nul}"#));
+        assert_eq!("expected `:` in JSON object", err(r#"{"a" "b"}"#));
+        assert_eq!("expected `\"` in JSON object", err(r#"{"a": "b",}"#));
+        assert_eq!("unexpected `x` after the object", err(r#"{} x"#));
+        assert_eq!("invalid escape in string", err(r#"{"a": "\q"}"#));
+        assert_eq!("control character in string", err("{\"a\": \"\u{1}\"}"));
+
+        // surrogates only make a char in pairs
+        assert_eq!("unpaired surrogate in string", err(r#"{"a": "\ud83d"}"#));
+        assert_eq!("unpaired surrogate in string", err(r#"{"a": "\ud83dx"}"#));
+        assert_eq!("unpaired surrogate in string", err(r#"{"a": "\ud83dA"}"#));
+        assert_eq!("unpaired surrogate in string", err(r#"{"a": "\ude00"}"#));
+        assert_eq!("invalid `\\u` escape in string", err(r#"{"a": "\u00g1"}"#));
+
+        // truncated input
+        assert_eq!("expected `,` in JSON object", err(r#"{"a": "b""#));
+        assert_eq!("unterminated string", err(r#"{"a": "b"#));
+        assert_eq!("invalid escape in string", err(r#"{"a": "b\"#));
+        assert_eq!("invalid `\\u` escape in string", err(r#"{"a": "\u00"#));
+        assert_eq!("expected `\"` in JSON object", err("{"));
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        let s = "q\"b\\s\n\r\t\u{1}\u{1f}é😀";
+        assert_eq!(r#""q\"b\\s\n\r\t\u0001\u001fé😀""#, json_string(s));
+        // what's written is read back the same
+        let object = format!("{{\"s\":{}}}", json_string(s));
+        assert_eq!(s, parse_json_object(&object).unwrap()["s"]);
+    }
+
+    #[test]
+    fn route_playground_status() {
+        let route = |method: &str, path: &str, body: &str| {
+            route_playground(&request(method, path, body), "A <- 'a'")
+        };
+        let r = route("GET", "/", "");
+        assert_eq!(
+            ("200 OK", "text/html; charset=utf-8"),
+            (r.status, r.content_type)
+        );
+        assert_eq!("A <- 'a'", route("GET", "/grammar", "").body);
+        let r = route("POST", "/run", "grammar=A+<-+'a'&input=a");
+        assert_eq!(("200 OK", "application/json"), (r.status, r.content_type));
+        assert!(r.body.starts_with("{\"value\":{"), "{}", r.body);
+        assert_eq!("405 Method Not Allowed", route("GET", "/run", "").status);
+        assert_eq!("405 Method Not Allowed", route("POST", "/", "").status);
+        assert_eq!("404 Not Found", route("GET", "/nope", "").status);
+    }
+
+    #[test]
+    fn route_api_status() {
+        let limits = Limits {
+            max_grammar_size: 16,
+            max_input_size: 4,
+            ..Limits::untrusted()
+        };
+        let route = |method: &str, path: &str, body: &str| {
+            let r = route_api(&request(method, path, body), &limits);
+            assert_eq!("application/json", r.content_type);
+            (r.status, r.body)
+        };
+        let ok = route("POST", "/compile", r#"{"grammar": "A <- 'a'"}"#);
+        assert_eq!(
+            ("200 OK", "{\"ok\":true,\"diagnostics\":[]}"),
+            (ok.0, ok.1.as_str())
+        );
+        let ok = route("POST", "/match", r#"{"grammar": "A <- 'a'", "input": "a"}"#);
+        assert_eq!("200 OK", ok.0);
+        assert!(ok.1.starts_with("{\"value\":{"), "{}", ok.1);
+
+        // grammars and inputs that don't work are still replied to
+        // with their diagnostics
+        let bad = route("POST", "/compile", r#"{"grammar": "A <- B"}"#);
+        assert_eq!("200 OK", bad.0);
+        assert!(bad.1.starts_with("{\"ok\":false,"), "{}", bad.1);
+        let bad = route("POST", "/match", r#"{"grammar": "A <- 'a'", "input": "b"}"#);
+        assert_eq!("200 OK", bad.0);
+        assert!(bad.1.starts_with("{\"value\":null,"), "{}", bad.1);
+
+        let status = |method: &str, path: &str, body: &str| route(method, path, body).0;
+        assert_eq!("400 Bad Request", status("POST", "/compile", "{"));
+        assert_eq!("400 Bad Request", status("POST", "/compile", "{}"));
+        assert_eq!(
+            "400 Bad Request",
+            status("POST", "/match", r#"{"grammar": "A <- 'a'"}"#)
+        );
+        let r = route_api(
+            &Request {
+                body: vec![0xff],
+                ..request("POST", "/compile", "")
+            },
+            &limits,
+        );
+        assert_eq!("400 Bad Request", r.status);
+        assert_eq!(
+            "413 Content Too Large",
+            status("POST", "/compile", r#"{"grammar": "A <- 'aaaaaaaaaaaa'"}"#)
+        );
+        assert_eq!(
+            "413 Content Too Large",
+            status(
+                "POST",
+                "/match",
+                r#"{"grammar": "A <- 'a'", "input": "aaaaa"}"#
+            )
+        );
+        assert_eq!("405 Method Not Allowed", status("GET", "/match", ""));
+        assert_eq!("405 Method Not Allowed", status("PUT", "/compile", ""));
+        assert_eq!("404 Not Found", status("POST", "/run", ""));
+    }
+
+    #[test]
+    fn slots() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let first = Slot::acquire(&counter, 2).unwrap();
+        let second = Slot::acquire(&counter, 2).unwrap();
+        assert!(Slot::acquire(&counter, 2).is_none());
+
+        // dropping a slot gives it back
+        drop(first);
+        let third = Slot::acquire(&counter, 2).unwrap();
+        assert!(Slot::acquire(&counter, 2).is_none());
+        drop((second, third));
+        assert_eq!(0, counter.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn route_api_deep_left_recursion() {
+        // each term nests the tree matched one level deeper, which
+        // used to overflow the stack of the thread and abort the server
+        let grammar = "E <- E '+' N / N\nN <- [0-9]";
+        let input = format!("1{}", "+1".repeat(60_000));
+        let body = format!(
+            "{{\"grammar\":{},\"input\":{}}}",
+            json_string(grammar),
+            json_string(&input)
+        );
+        let response = thread::Builder::new()
+            .stack_size(STACK_SIZE)
+            .spawn(move || route_api(&request("POST", "/match", &body), &Limits::untrusted()))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!("200 OK", response.status);
+        assert!(response.body.starts_with("{\"value\":null,"));
+        assert!(response.body.contains("\"code\":\"resource_limit\""));
+    }
+}
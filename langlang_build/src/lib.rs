@@ -0,0 +1,69 @@
+// lib.rs --- compile grammars from build scripts
+//
+// Projects that parse with a grammar they ship can compile it while
+// building, rather than every time they start, and load the program
+// with `Program::from_bytes`.  This crate is all a `build.rs` needs
+// for that, without the dependencies of the command line tool.  The
+// same grammar always compiles to the same bytes, and cargo gets told
+// about every file the program was compiled from, so the build script
+// only runs again when one of them changes.
+//
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use langlang_lib::compiler::{Compiler, Config};
+use langlang_lib::import::{ImportResolver, RelativeImportLoader};
+
+pub use langlang_lib::Error;
+
+/// Compile the grammar file `input` and save the program to `output`,
+/// returning the path it got saved to.  A relative `output` is taken
+/// to be within `OUT_DIR` when cargo sets it, as it does for build
+/// scripts, and the file is only written if its content changed.
+///
+/// `@import` directives are resolved relative to the file importing
+/// them; `LANGLANG_PATH` isn't looked at, so the program doesn't
+/// depend on the environment the build runs in.  Warnings of the
+/// compiler are reported to cargo, which shows them without failing
+/// the build.
+///
+/// ```no_run
+/// // within the `main` function of `build.rs`
+/// langlang_build::compile_grammar("grammars/json.peg", "json.llb")?;
+/// # Ok::<(), langlang_build::Error>(())
+/// ```
+///
+/// The program gets loaded back with
+/// `Program::from_bytes(include_bytes!(concat!(env!("OUT_DIR"), "/json.llb")))`.
+pub fn compile_grammar(
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> Result<PathBuf, Error> {
+    let input = input.as_ref();
+    println!("cargo:rerun-if-changed={}", input.display());
+    let importer = ImportResolver::new(RelativeImportLoader);
+    let grammar = importer.resolve(input)?;
+    for path in importer.lockfile().paths() {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+
+    let compiled = Compiler::new(Config::default()).compile_with_warnings(&grammar, None)?;
+    for warning in &compiled.warnings {
+        println!("cargo:warning={}: {}", input.display(), warning);
+    }
+
+    let output = match env::var_os("OUT_DIR") {
+        Some(dir) if output.as_ref().is_relative() => Path::new(&dir).join(output),
+        _ => output.as_ref().to_path_buf(),
+    };
+    let bytes = compiled.program.to_bytes();
+    // rewriting the same bytes would still make whatever includes
+    // the file get rebuilt
+    if fs::read(&output).ok().as_deref() != Some(&bytes[..]) {
+        if let Some(dir) = output.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&output, &bytes)?;
+    }
+    Ok(output)
+}
@@ -0,0 +1,153 @@
+// alloc_stats.rs --- count the allocations made while matching
+//
+// `CountingAllocator` wraps the system allocator and keeps counters of
+// the allocations made by each thread.  Once a program installs it
+// with `#[global_allocator]`, `vm::Stats::allocations` reports what a
+// single match allocated, and `measure` reports the same for any other
+// piece of code, e.g.: to assert that building and dropping a tree
+// doesn't leave memory behind.
+//
+// Counters are kept per thread, so matches running concurrently on
+// other threads don't show up in each other's reports.
+//
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Global allocator that counts the allocations of each thread
+/// before handing them to the system allocator, e.g.:
+///
+/// ```
+/// #[global_allocator]
+/// static ALLOCATOR: langlang_lib::alloc_stats::CountingAllocator =
+///     langlang_lib::alloc_stats::CountingAllocator;
+/// # fn main() {}
+/// ```
+pub struct CountingAllocator;
+
+// whether `CountingAllocator` was ever called, which tells counters
+// that stay at zero apart from an allocator that isn't installed
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static COUNTERS: Cell<Allocations> = const { Cell::new(Allocations::ZERO) };
+}
+
+/// Allocations made by a thread, or within a stretch of code when
+/// returned by `measure` or `Allocations::since`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Allocations {
+    /// Blocks allocated, including the ones grown or shrunk
+    pub count: usize,
+    /// Bytes allocated
+    pub bytes: usize,
+    /// Blocks released, including the ones grown or shrunk
+    pub frees: usize,
+    /// Bytes released
+    pub freed_bytes: usize,
+}
+
+impl Allocations {
+    const ZERO: Allocations = Allocations {
+        count: 0,
+        bytes: 0,
+        frees: 0,
+        freed_bytes: 0,
+    };
+
+    /// Allocations made between `start` and `self`, both taken with
+    /// `snapshot` on the same thread
+    pub fn since(&self, start: &Allocations) -> Allocations {
+        Allocations {
+            count: self.count - start.count,
+            bytes: self.bytes - start.bytes,
+            frees: self.frees - start.frees,
+            freed_bytes: self.freed_bytes - start.freed_bytes,
+        }
+    }
+
+    /// Bytes allocated but not released yet.  It's negative when more
+    /// memory allocated before the counting started got released.
+    pub fn live_bytes(&self) -> isize {
+        self.bytes as isize - self.freed_bytes as isize
+    }
+}
+
+impl std::fmt::Display for Allocations {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} allocations ({} bytes), {} frees ({} bytes)",
+            self.count, self.bytes, self.frees, self.freed_bytes
+        )
+    }
+}
+
+/// True once `CountingAllocator` served an allocation, which is only
+/// the case if it's installed as the global allocator
+pub fn installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Allocations made by the current thread so far
+pub fn snapshot() -> Allocations {
+    COUNTERS.try_with(Cell::get).unwrap_or_default()
+}
+
+/// Run `f`, returning its output along with the allocations it made.
+/// The output is returned after counting stops, so it only adds to
+/// `Allocations::live_bytes` what it holds on to.
+pub fn measure<T>(f: impl FnOnce() -> T) -> (T, Allocations) {
+    let start = snapshot();
+    let output = f();
+    (output, snapshot().since(&start))
+}
+
+// counters are left alone while the thread is being torn down
+fn count(allocated: usize, freed: usize) {
+    let _ = COUNTERS.try_with(|counters| {
+        let mut c = counters.get();
+        if allocated > 0 {
+            c.count += 1;
+            c.bytes += allocated;
+        }
+        if freed > 0 {
+            c.frees += 1;
+            c.freed_bytes += freed;
+        }
+        counters.set(c);
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size(), 0);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        count(0, layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            count(new_size, layout.size());
+        }
+        new
+    }
+}
@@ -0,0 +1,189 @@
+// analysis.rs --- problems that make a grammar unusable
+//
+// Lints point at what's most likely a mistake, but the grammar still
+// does something sensible.  The problems found here don't: a rule
+// that's called but not defined can't be compiled, a repetition of
+// something that matches without consuming input loops forever once
+// that happens, and a left recursive rule with no alternative to
+// start from never matches anything.  `analyze` finds them all before
+// the grammar gets compiled, pointing at where each one is, and the
+// compiler fails with them rather than generating a program that
+// can't work.
+//
+use std::collections::HashMap;
+
+use langlang_syntax::ast;
+use langlang_syntax::stack;
+use langlang_value::source_map::Span;
+
+use crate::compiler::{self, DetectLeftRec, UnresolvedReference};
+use crate::lint;
+
+/// Problem found within a grammar by `analyze`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    // Call to a rule the grammar doesn't define
+    UnresolvedReference(UnresolvedReference),
+    // Left recursive rule without an alternative that matches before
+    // calling the rule again (rule, span)
+    UnguardedLeftRecursion(String, Span),
+    // Repetition of an expression that can match without consuming
+    // input (rule, expression, span)
+    EmptyLoop(String, String, Span),
+}
+
+impl Problem {
+    /// Location within the grammar the problem refers to
+    pub fn span(&self) -> &Span {
+        match self {
+            Problem::UnresolvedReference(r) => &r.span,
+            Problem::UnguardedLeftRecursion(_, span) | Problem::EmptyLoop(_, _, span) => span,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Problem::UnresolvedReference(r) => write!(f, "{}", r),
+            Problem::UnguardedLeftRecursion(rule, _) => write!(
+                f,
+                "rule `{}` never matches, as each one of its alternatives calls it again first",
+                rule
+            ),
+            Problem::EmptyLoop(rule, expr, _) => write!(
+                f,
+                "`{}` within rule `{}` loops forever once it matches without consuming input",
+                expr, rule
+            ),
+        }
+    }
+}
+
+/// Find the problems within `grammar` that would make compiling it
+/// fail, or that would make matching loop forever or never succeed.
+/// Problems are listed in the order the rules that have them are
+/// defined.  Rules that come from the builtins aren't checked.
+pub fn analyze(grammar: &ast::Grammar) -> Vec<Problem> {
+    let builtins = lint::builtin_names();
+    let mut problems: Vec<Problem> = compiler::unresolved_references(grammar)
+        .into_iter()
+        .map(Problem::UnresolvedReference)
+        .collect();
+
+    let mut left_rec = HashMap::new();
+    if DetectLeftRec::default()
+        .run(grammar, &mut left_rec)
+        .is_err()
+    {
+        left_rec.clear();
+    }
+    let nullable_rules = lint::fixpoint(grammar, lint::nullable);
+    let productive_rules = lint::fixpoint(grammar, productive);
+
+    for name in &grammar.definition_names {
+        if builtins.contains(name) {
+            continue;
+        }
+        let def = &grammar.definitions[name];
+        if left_rec.get(name).copied().unwrap_or(false) && !productive_rules[name] {
+            problems.push(Problem::UnguardedLeftRecursion(
+                name.clone(),
+                def.span.clone(),
+            ));
+        }
+        lint::walk(&def.expr, &mut |expr| match expr {
+            ast::Expression::ZeroOrMore(ast::ZeroOrMore { expr: inner, .. })
+            | ast::Expression::OneOrMore(ast::OneOrMore { expr: inner, .. })
+            | ast::Expression::Repeat(ast::Repeat {
+                expr: inner,
+                max: None,
+                ..
+            }) if lint::nullable(inner, &nullable_rules) => {
+                problems.push(Problem::EmptyLoop(
+                    name.clone(),
+                    expr.to_string(),
+                    expr.span(),
+                ));
+            }
+            _ => {}
+        });
+    }
+    problems
+}
+
+/// True if the expression matches some input.  Labels are assumed to
+/// be recovered from, and calls to rules that aren't defined to match.
+fn productive(expr: &ast::Expression, rules: &HashMap<String, bool>) -> bool {
+    stack::guard(|| match expr {
+        ast::Expression::Sequence(n) => n.items.iter().all(|e| productive(e, rules)),
+        ast::Expression::Choice(n) => n.items.iter().any(|e| productive(e, rules)),
+        ast::Expression::Lex(n) => productive(&n.expr, rules),
+        ast::Expression::And(n) => productive(&n.expr, rules),
+        ast::Expression::Not(_) => true,
+        ast::Expression::Optional(_) | ast::Expression::ZeroOrMore(_) => true,
+        ast::Expression::OneOrMore(n) => productive(&n.expr, rules),
+        ast::Expression::Repeat(n) => n.min == 0 || productive(&n.expr, rules),
+        ast::Expression::Precedence(n) => productive(&n.expr, rules),
+        ast::Expression::Label(_) => true,
+        ast::Expression::List(n) => n.items.iter().all(|e| productive(e, rules)),
+        ast::Expression::Node(n) => productive(&n.expr, rules),
+        ast::Expression::Identifier(n) => rules.get(&n.name).copied().unwrap_or(true),
+        ast::Expression::Literal(_) | ast::Expression::Empty(_) => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use langlang_syntax::parser;
+
+    fn analyze_str(grammar: &str) -> Vec<String> {
+        let grammar = parser::parse(grammar).unwrap();
+        analyze(&grammar).iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn unguarded_left_recursion() {
+        assert_eq!(
+            vec!["rule `E` never matches, as each one of its alternatives calls it again first"],
+            analyze_str("E <- E '+' 'n' / E '-' 'n'"),
+        );
+        // through other rules
+        assert_eq!(
+            vec![
+                "rule `A` never matches, as each one of its alternatives calls it again first",
+                "rule `B` never matches, as each one of its alternatives calls it again first",
+            ],
+            analyze_str("A <- B 'x'\nB <- A 'y'"),
+        );
+        // an alternative to start from is all it takes
+        assert!(analyze_str("E <- E '+' 'n' / 'n'").is_empty());
+        assert!(analyze_str("A <- B 'x'\nB <- A 'y' / 'z'").is_empty());
+        assert!(analyze_str("E <- E¹ '+' E² / 'n'").is_empty());
+    }
+
+    #[test]
+    fn empty_loops() {
+        assert_eq!(
+            vec![
+                "`(\"\"*)*` within rule `A` loops forever once it matches without consuming input",
+                "`\"\"*` within rule `A` loops forever once it matches without consuming input",
+            ],
+            analyze_str("A <- (''*)* 'x'"),
+        );
+        assert_eq!(
+            vec!["`B+` within rule `A` loops forever once it matches without consuming input"],
+            analyze_str("A <- B+\nB <- 'b'?"),
+        );
+        assert!(analyze_str("A <- ('a' 'b'?)*").is_empty());
+    }
+
+    #[test]
+    fn unresolved_references() {
+        assert_eq!(
+            vec!["rule `Digt` referenced from `A` at 0:5 isn't defined; did you mean `Digit`?"],
+            analyze_str("A <- Digt\nDigit <- [0-9]"),
+        );
+    }
+}
@@ -0,0 +1,571 @@
+// bytecode.rs --- file format of compiled programs
+//
+// Programs are saved as a header followed by their tables and their
+// code, so grammars can be compiled ahead of time and shipped without
+// the compiler.  The header is the magic `\0LLB` and the version of
+// the format as a little endian `u16`.  Readers reject versions they
+// don't know, so the version must change along with anything that
+// changes how programs are encoded.
+//
+// Numbers after the header are encoded as unsigned LEB128, text as
+// its length followed by UTF-8 bytes, and chars as their code point.
+// Tables keyed by addresses or IDs are sorted by their keys, so the
+// same program is always saved to the same bytes:
+//
+//     unnamed:     0 for a synthetic name, 1 to unwrap
+//     version:     0 without one, 1 followed by text
+//     strings:     count, text
+//     identifiers: count, (address, string ID)
+//     labels:      count, (label ID, message ID)
+//     recovery:    count, (label ID, address, precedence)
+//     externals:   count, (address, string ID)
+//     classes:     count, (number of ranges, (start, end))
+//     code:        count, padding, (opcode, operand, operand)
+//
+// The code is saved last, as a flat array of records of 12 bytes: the
+// opcode and two operands, each a little endian `u32`, with the
+// operands an instruction doesn't have set to zero.  Zeros before the
+// first record pad it to an offset that's a multiple of four, so the
+// records of a file mapped into memory line up with its words.  Since
+// records have a fixed size, the code doesn't have to be decoded
+// before it runs, and `Program::from_mapped` reads each instruction
+// from the bytes the program was saved to as it gets executed.
+//
+// Code is position independent: jumps, calls and the targets of
+// backtracking are offsets relative to the address of the instruction
+// holding them, so the code of a program can be appended to another
+// one without being rewritten.  The addresses within the identifiers,
+// recovery and externals tables are the only absolute ones, and are
+// what gets rebased when programs are linked.
+//
+// Saved programs can come from anywhere, so loading them checks their
+// code with `Program::validate`, but for calls that aren't linked yet.
+// Bytes that decode but would make the virtual machine run out of the
+// code are rejected before they get executed.
+//
+// Opcodes are listed below, and operands are saved in the order they
+// appear within the instruction.  Endianness and container types are
+// saved as 0 for little/list and 1 for big/node.
+//
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::vm::{self, Code, ContainerType, Endian, Instruction, Program, UnnamedProductions};
+
+/// First bytes of every saved program
+pub const MAGIC: &[u8; 4] = b"\0LLB";
+
+/// Version of the format written by [`encode`]
+pub const VERSION: u16 = 4;
+
+// size of the magic and of the version
+const HEADER_SIZE: usize = 6;
+
+// size of the record of each instruction within the code
+const RECORD_SIZE: usize = 12;
+
+const OP_HALT: u8 = 0;
+const OP_ANY: u8 = 1;
+const OP_CHAR: u8 = 2;
+const OP_SPAN: u8 = 3;
+const OP_STRING: u8 = 4;
+const OP_CLASS: u8 = 5;
+const OP_CHAR_CI: u8 = 6;
+const OP_SPAN_CI: u8 = 7;
+const OP_STRING_CI: u8 = 8;
+const OP_INT: u8 = 9;
+const OP_VARINT: u8 = 10;
+const OP_TAKE: u8 = 11;
+const OP_HOST: u8 = 12;
+const OP_CHOICE: u8 = 13;
+const OP_CHOICE_P: u8 = 14;
+const OP_COMMIT: u8 = 15;
+const OP_COMMIT_B: u8 = 16;
+const OP_FAIL: u8 = 17;
+const OP_FAIL_TWICE: u8 = 18;
+const OP_PARTIAL_COMMIT: u8 = 19;
+const OP_BACK_COMMIT: u8 = 20;
+const OP_JUMP: u8 = 21;
+const OP_JUMP_B: u8 = 22;
+const OP_CALL: u8 = 23;
+const OP_CALL_B: u8 = 24;
+const OP_RETURN: u8 = 25;
+const OP_RETURN_VALUES: u8 = 26;
+const OP_THROW: u8 = 27;
+const OP_OPEN: u8 = 28;
+const OP_CLOSE: u8 = 29;
+const OP_CAP_PUSH: u8 = 30;
+const OP_CAP_POP: u8 = 31;
+const OP_CAP_COMMIT: u8 = 32;
+const OP_CAP_JOIN: u8 = 33;
+const OP_CAP_TEXT: u8 = 34;
+const OP_CAP_LIST: u8 = 35;
+const OP_CAP_DROP: u8 = 36;
+const OP_PROPERTY: u8 = 37;
+const OP_TEST_CHAR: u8 = 38;
+const OP_TEST_ANY: u8 = 39;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // Input doesn't start with the magic of the format
+    InvalidMagic,
+    // Input was saved with a version of the format that isn't known
+    UnsupportedVersion(u16),
+    // Input ended before the program was complete (offset)
+    Truncated(usize),
+    // Instruction isn't known (offset, opcode)
+    InvalidOpcode(usize, u32),
+    // Text isn't valid UTF-8 or isn't a valid char (offset)
+    InvalidText(usize),
+    // Number doesn't fit within the target type (offset)
+    Overflow(usize),
+    // Table refers to a string that isn't within the strings table,
+    // or a flag isn't one of the values it can take (offset)
+    InvalidReference(usize),
+    // Input has bytes past the end of the program (offset)
+    TrailingBytes(usize),
+    // Code would make the virtual machine index out of bounds, see
+    // `Program::validate` (address, reason)
+    InvalidCode(usize, String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Bytecode Error")?;
+        match self {
+            Error::InvalidMagic => write!(f, "[InvalidMagic]: not a compiled program"),
+            Error::UnsupportedVersion(v) => write!(
+                f,
+                "[UnsupportedVersion]: version {} isn't supported, expected {}",
+                v, VERSION
+            ),
+            Error::Truncated(offset) => write!(f, "[Truncated]: {}", offset),
+            Error::InvalidOpcode(offset, op) => write!(f, "[InvalidOpcode]: {}: {}", offset, op),
+            Error::InvalidText(offset) => write!(f, "[InvalidText]: {}", offset),
+            Error::Overflow(offset) => write!(f, "[Overflow]: {}", offset),
+            Error::InvalidReference(offset) => write!(f, "[InvalidReference]: {}", offset),
+            Error::TrailingBytes(offset) => write!(f, "[TrailingBytes]: {}", offset),
+            Error::InvalidCode(addr, reason) => write!(f, "[InvalidCode]: {}: {}", addr, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Encode `program` with its tables and its code
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut output = MAGIC.to_vec();
+    output.extend_from_slice(&VERSION.to_le_bytes());
+    output.push(match program.unnamed {
+        UnnamedProductions::SyntheticName => 0,
+        UnnamedProductions::Unwrap => 1,
+    });
+    match &program.version {
+        None => output.push(0),
+        Some(version) => {
+            output.push(1);
+            write_text(version, &mut output);
+        }
+    }
+    write_uint(program.strings.len(), &mut output);
+    program
+        .strings
+        .iter()
+        .for_each(|s| write_text(s, &mut output));
+    write_pairs(&program.identifiers, &mut output);
+    write_pairs(&program.labels, &mut output);
+    let mut recovery: Vec<_> = program.recovery.iter().collect();
+    recovery.sort();
+    write_uint(recovery.len(), &mut output);
+    for (label, (address, precedence)) in recovery {
+        write_uint(*label, &mut output);
+        write_uint(*address, &mut output);
+        write_uint(*precedence, &mut output);
+    }
+    write_pairs(&program.externals, &mut output);
+    write_uint(program.classes.len(), &mut output);
+    for ranges in &program.classes {
+        write_uint(ranges.len(), &mut output);
+        for (start, end) in ranges {
+            write_uint(*start as usize, &mut output);
+            write_uint(*end as usize, &mut output);
+        }
+    }
+    write_uint(program.code.len(), &mut output);
+    output.resize(output.len().next_multiple_of(4), 0);
+    for instruction in program.code.iter() {
+        write_instruction(&instruction, &mut output);
+    }
+    output
+}
+
+/// Decode the program saved by `encode`.  The tables of the program
+/// are checked to refer to existing strings, and its code to pass
+/// `Program::validate`, but for calls that aren't linked yet.
+pub fn decode(bytes: &[u8]) -> Result<Program, Error> {
+    let (program, start, len) = decode_tables(bytes)?;
+    let code = (0..len)
+        .map(|pc| read_record(bytes, start + pc * RECORD_SIZE))
+        .collect::<Result<Vec<_>, _>>()?;
+    checked(program.with_code(Code::Owned(code)))
+}
+
+/// Load the program saved by `encode` without copying its code out
+/// of `bytes`.  Each instruction is checked like `decode` checks
+/// them, then read again from `bytes` every time it gets executed.
+pub fn load(bytes: Arc<dyn AsRef<[u8]> + Send + Sync>) -> Result<Program, Error> {
+    let data = (*bytes).as_ref();
+    let (program, start, len) = decode_tables(data)?;
+    for pc in 0..len {
+        read_record(data, start + pc * RECORD_SIZE)?;
+    }
+    checked(program.with_code(Code::Mapped(Mapped { bytes, start, len })))
+}
+
+// loaded programs can come from anywhere, so their code gets checked
+// before the virtual machine gets to run it
+fn checked(program: Program) -> Result<Program, Error> {
+    match program.validate_code() {
+        Ok(()) => Ok(program),
+        Err(vm::Error::InvalidProgram(addr, reason)) => Err(Error::InvalidCode(addr, reason)),
+        Err(e) => Err(Error::InvalidCode(0, e.to_string())),
+    }
+}
+
+/// Code of a program that stays within the bytes it was saved to,
+/// e.g. a file mapped into memory, and is read one instruction at a
+/// time
+#[derive(Clone)]
+pub(crate) struct Mapped {
+    bytes: Arc<dyn AsRef<[u8]> + Send + Sync>,
+    // offset of the first record
+    start: usize,
+    // number of records
+    len: usize,
+}
+
+impl Mapped {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Instruction at `pc`, which must be within the code.  Loading
+    /// checks that execution never leaves it, so the assertion only
+    /// catches bugs of the virtual machine, not crafted programs
+    pub(crate) fn instruction(&self, pc: usize) -> Instruction {
+        assert!(pc < self.len, "address {} out of bounds", pc);
+        read_record((*self.bytes).as_ref(), self.start + pc * RECORD_SIZE)
+            .expect("instructions are checked when the program is loaded")
+    }
+}
+
+// read the header and the tables of a program, leaving its code out.
+// Returns the program, the offset of its first instruction and the
+// number of instructions
+fn decode_tables(bytes: &[u8]) -> Result<(Program, usize, usize), Error> {
+    if bytes.get(..MAGIC.len()) != Some(MAGIC) {
+        return Err(Error::InvalidMagic);
+    }
+    let version = bytes
+        .get(MAGIC.len()..HEADER_SIZE)
+        .ok_or(Error::Truncated(bytes.len()))?;
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut r = Reader {
+        bytes,
+        offset: HEADER_SIZE,
+    };
+    let unnamed = match r.byte()? {
+        0 => UnnamedProductions::SyntheticName,
+        1 => UnnamedProductions::Unwrap,
+        _ => return Err(Error::InvalidReference(r.offset - 1)),
+    };
+    let version = match r.byte()? {
+        0 => None,
+        1 => Some(r.text()?),
+        _ => return Err(Error::InvalidReference(r.offset - 1)),
+    };
+    let strings = (0..r.count()?)
+        .map(|_| r.text())
+        .collect::<Result<Vec<_>, _>>()?;
+    let string = |r: &mut Reader| -> Result<usize, Error> {
+        let offset = r.offset;
+        let id = r.uint()?;
+        match id < strings.len() {
+            true => Ok(id),
+            false => Err(Error::InvalidReference(offset)),
+        }
+    };
+    let mut identifiers = HashMap::new();
+    for _ in 0..r.count()? {
+        identifiers.insert(r.uint()?, string(&mut r)?);
+    }
+    let mut labels = HashMap::new();
+    for _ in 0..r.count()? {
+        labels.insert(string(&mut r)?, string(&mut r)?);
+    }
+    let mut recovery = HashMap::new();
+    for _ in 0..r.count()? {
+        recovery.insert(string(&mut r)?, (r.uint()?, r.uint()?));
+    }
+    let mut externals = HashMap::new();
+    for _ in 0..r.count()? {
+        externals.insert(r.uint()?, string(&mut r)?);
+    }
+    let mut classes = vec![];
+    for _ in 0..r.count()? {
+        let ranges = (0..r.count()?)
+            .map(|_| Ok((r.char()?, r.char()?)))
+            .collect::<Result<Vec<_>, _>>()?;
+        classes.push(ranges);
+    }
+    let len = r.uint()?;
+    while !r.offset.is_multiple_of(4) {
+        if r.byte()? != 0 {
+            return Err(Error::InvalidReference(r.offset - 1));
+        }
+    }
+    let start = r.offset;
+    let end = len
+        .checked_mul(RECORD_SIZE)
+        .and_then(|size| size.checked_add(start))
+        .ok_or(Error::Overflow(start))?;
+    if end > bytes.len() {
+        return Err(Error::Truncated(bytes.len()));
+    }
+    if end < bytes.len() {
+        return Err(Error::TrailingBytes(end));
+    }
+    let program = Program::new(identifiers, labels, recovery, strings, vec![], unnamed)
+        .with_externals(externals)
+        .with_classes(classes)
+        .with_version(version);
+    Ok((program, start, len))
+}
+
+fn write_instruction(instruction: &Instruction, output: &mut Vec<u8>) {
+    let (op, operands): (u8, &[usize]) = match instruction {
+        Instruction::Halt => (OP_HALT, &[]),
+        Instruction::Any => (OP_ANY, &[]),
+        Instruction::Char(c) => (OP_CHAR, &[*c as usize]),
+        Instruction::Span(a, b) => (OP_SPAN, &[*a as usize, *b as usize]),
+        Instruction::String(id) => (OP_STRING, &[*id]),
+        Instruction::Class(id) => (OP_CLASS, &[*id]),
+        Instruction::Property(id) => (OP_PROPERTY, &[*id]),
+        Instruction::CharCI(c) => (OP_CHAR_CI, &[*c as usize]),
+        Instruction::SpanCI(a, b) => (OP_SPAN_CI, &[*a as usize, *b as usize]),
+        Instruction::StringCI(id) => (OP_STRING_CI, &[*id]),
+        Instruction::Int(width, Endian::Little) => (OP_INT, &[*width, 0]),
+        Instruction::Int(width, Endian::Big) => (OP_INT, &[*width, 1]),
+        Instruction::Varint => (OP_VARINT, &[]),
+        Instruction::Take(id) => (OP_TAKE, &[*id]),
+        Instruction::Host(id) => (OP_HOST, &[*id]),
+        Instruction::Choice(offset) => (OP_CHOICE, &[*offset]),
+        Instruction::ChoiceP(offset) => (OP_CHOICE_P, &[*offset]),
+        Instruction::Commit(offset) => (OP_COMMIT, &[*offset]),
+        Instruction::CommitB(offset) => (OP_COMMIT_B, &[*offset]),
+        Instruction::Fail => (OP_FAIL, &[]),
+        Instruction::FailTwice => (OP_FAIL_TWICE, &[]),
+        Instruction::PartialCommit(offset) => (OP_PARTIAL_COMMIT, &[*offset]),
+        Instruction::BackCommit(offset) => (OP_BACK_COMMIT, &[*offset]),
+        Instruction::TestChar(c, offset) => (OP_TEST_CHAR, &[*c as usize, *offset]),
+        Instruction::TestAny(offset) => (OP_TEST_ANY, &[*offset]),
+        Instruction::Jump(offset) => (OP_JUMP, &[*offset]),
+        Instruction::JumpB(offset) => (OP_JUMP_B, &[*offset]),
+        Instruction::Call(offset, k) => (OP_CALL, &[*offset, *k]),
+        Instruction::CallB(offset, k) => (OP_CALL_B, &[*offset, *k]),
+        Instruction::Return => (OP_RETURN, &[]),
+        Instruction::ReturnValues => (OP_RETURN_VALUES, &[]),
+        Instruction::Throw(id) => (OP_THROW, &[*id]),
+        Instruction::Open => (OP_OPEN, &[]),
+        Instruction::Close(ContainerType::List) => (OP_CLOSE, &[0]),
+        Instruction::Close(ContainerType::Node) => (OP_CLOSE, &[1]),
+        Instruction::CapPush => (OP_CAP_PUSH, &[]),
+        Instruction::CapPop => (OP_CAP_POP, &[]),
+        Instruction::CapCommit => (OP_CAP_COMMIT, &[]),
+        Instruction::CapJoin => (OP_CAP_JOIN, &[]),
+        Instruction::CapText => (OP_CAP_TEXT, &[]),
+        Instruction::CapList => (OP_CAP_LIST, &[]),
+        Instruction::CapDrop => (OP_CAP_DROP, &[]),
+    };
+    let mut record = [u32::from(op), 0, 0];
+    for (word, operand) in record[1..].iter_mut().zip(operands) {
+        *word = u32::try_from(*operand).expect("operands are saved as 32 bit numbers");
+    }
+    record
+        .iter()
+        .for_each(|word| output.extend_from_slice(&word.to_le_bytes()));
+}
+
+// decode the instruction saved at `offset`
+fn read_record(bytes: &[u8], offset: usize) -> Result<Instruction, Error> {
+    let word = |i: usize| {
+        let at = offset + i * 4;
+        bytes
+            .get(at..at + 4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .ok_or(Error::Truncated(bytes.len()))
+    };
+    let (op, a, b) = (word(0)?, word(1)?, word(2)?);
+    let char = |i: usize, n: u32| char::from_u32(n).ok_or(Error::InvalidText(offset + i * 4));
+    let flag = |i: usize, n: u32| match n {
+        0 | 1 => Ok(n == 0),
+        _ => Err(Error::InvalidReference(offset + i * 4)),
+    };
+    let (x, y) = (a as usize, b as usize);
+    let instruction = match u8::try_from(op).unwrap_or(u8::MAX) {
+        OP_HALT => Instruction::Halt,
+        OP_ANY => Instruction::Any,
+        OP_CHAR => Instruction::Char(char(1, a)?),
+        OP_SPAN => Instruction::Span(char(1, a)?, char(2, b)?),
+        OP_STRING => Instruction::String(x),
+        OP_CLASS => Instruction::Class(x),
+        OP_CHAR_CI => Instruction::CharCI(char(1, a)?),
+        OP_SPAN_CI => Instruction::SpanCI(char(1, a)?, char(2, b)?),
+        OP_STRING_CI => Instruction::StringCI(x),
+        OP_INT => match flag(2, b)? {
+            true => Instruction::Int(x, Endian::Little),
+            false => Instruction::Int(x, Endian::Big),
+        },
+        OP_VARINT => Instruction::Varint,
+        OP_TAKE => Instruction::Take(x),
+        OP_HOST => Instruction::Host(x),
+        OP_CHOICE => Instruction::Choice(x),
+        OP_CHOICE_P => Instruction::ChoiceP(x),
+        OP_COMMIT => Instruction::Commit(x),
+        OP_COMMIT_B => Instruction::CommitB(x),
+        OP_FAIL => Instruction::Fail,
+        OP_FAIL_TWICE => Instruction::FailTwice,
+        OP_PARTIAL_COMMIT => Instruction::PartialCommit(x),
+        OP_BACK_COMMIT => Instruction::BackCommit(x),
+        OP_TEST_CHAR => Instruction::TestChar(char(1, a)?, y),
+        OP_TEST_ANY => Instruction::TestAny(x),
+        OP_JUMP => Instruction::Jump(x),
+        OP_JUMP_B => Instruction::JumpB(x),
+        OP_CALL => Instruction::Call(x, y),
+        OP_CALL_B => Instruction::CallB(x, y),
+        OP_RETURN => Instruction::Return,
+        OP_RETURN_VALUES => Instruction::ReturnValues,
+        OP_THROW => Instruction::Throw(x),
+        OP_OPEN => Instruction::Open,
+        OP_CLOSE => match flag(1, a)? {
+            true => Instruction::Close(ContainerType::List),
+            false => Instruction::Close(ContainerType::Node),
+        },
+        OP_CAP_PUSH => Instruction::CapPush,
+        OP_CAP_POP => Instruction::CapPop,
+        OP_CAP_COMMIT => Instruction::CapCommit,
+        OP_CAP_JOIN => Instruction::CapJoin,
+        OP_CAP_TEXT => Instruction::CapText,
+        OP_CAP_LIST => Instruction::CapList,
+        OP_CAP_DROP => Instruction::CapDrop,
+        OP_PROPERTY => Instruction::Property(x),
+        _ => return Err(Error::InvalidOpcode(offset, op)),
+    };
+    // operands the instruction doesn't have are zero, so a program
+    // is always saved to the same bytes
+    let operands = instruction.metadata().operands.len();
+    for (i, word) in [(1, a), (2, b)] {
+        if i > operands && word != 0 {
+            return Err(Error::InvalidReference(offset + i * 4));
+        }
+    }
+    Ok(instruction)
+}
+
+fn write_pairs(table: &HashMap<usize, usize>, output: &mut Vec<u8>) {
+    let mut pairs: Vec<_> = table.iter().collect();
+    pairs.sort();
+    write_uint(pairs.len(), output);
+    for (key, value) in pairs {
+        write_uint(*key, output);
+        write_uint(*value, output);
+    }
+}
+
+fn write_text(text: &str, output: &mut Vec<u8>) {
+    write_uint(text.len(), output);
+    output.extend_from_slice(text.as_bytes());
+}
+
+fn write_uint(n: usize, output: &mut Vec<u8>) {
+    let mut n = n as u64;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+// cursor over the bytes of a program being decoded
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self
+            .bytes
+            .get(self.offset)
+            .ok_or(Error::Truncated(self.offset))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn uint(&mut self) -> Result<usize, Error> {
+        let start = self.offset;
+        let (mut n, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.byte()?;
+            if shift >= 64 || (shift == 63 && byte & 0x7e != 0) {
+                return Err(Error::Overflow(start));
+            }
+            n |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(n).map_err(|_| Error::Overflow(start));
+            }
+            shift += 7;
+        }
+    }
+
+    // number of items of a table, which can't be more than the bytes
+    // left as each item takes at least one, so broken input doesn't
+    // make room for more than it holds
+    fn count(&mut self) -> Result<usize, Error> {
+        let start = self.offset;
+        let count = self.uint()?;
+        match count <= self.bytes.len() - self.offset {
+            true => Ok(count),
+            false => Err(Error::Truncated(start)),
+        }
+    }
+
+    fn char(&mut self) -> Result<char, Error> {
+        let start = self.offset;
+        let n = self.uint()?;
+        u32::try_from(n)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(Error::InvalidText(start))
+    }
+
+    fn text(&mut self) -> Result<String, Error> {
+        let start = self.offset;
+        let len = self.uint()?;
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or(Error::Truncated(self.bytes.len()))?;
+        let text = std::str::from_utf8(bytes).map_err(|_| Error::InvalidText(start))?;
+        self.offset += len;
+        Ok(text.to_string())
+    }
+}
@@ -0,0 +1,23 @@
+// casefold_ascii.rs --- ASCII only case folding
+//
+// Used instead of the Unicode tables when the `unicode` feature is
+// disabled.  Characters outside of ASCII only match themselves.
+//
+
+/// Whether `a` and `b` are the same once case folded
+pub(crate) fn eq(a: char, b: char) -> bool {
+    a.eq_ignore_ascii_case(&b)
+}
+
+/// Whether `a` and `b` have the same characters once case folded
+pub(crate) fn str_eq(a: &str, b: &str) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Character between `start` and `end` that is the same as `c` once
+/// case folded, which is `c` itself if it's within the range
+pub(crate) fn in_range(c: char, start: char, end: char) -> Option<char> {
+    [c, c.to_ascii_lowercase(), c.to_ascii_uppercase()]
+        .into_iter()
+        .find(|c| (start..=end).contains(c))
+}
@@ -1,32 +1,367 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::vm::{ContainerType, Instruction, Program};
+use crate::analysis::{self, Problem};
+use crate::consts::WHITE_SPACE_RULE_NAME;
+use crate::lint;
+use crate::properties;
+use crate::vm::{ContainerType, Endian, Instruction, Program, UnnamedProductions};
 use crate::wsrewrite::WhiteSpaceHandlerInjector;
 
 use langlang_syntax::ast;
 use langlang_syntax::ast::IsSyntactic;
+use langlang_syntax::fold::Folder;
+use langlang_syntax::stack;
 use langlang_syntax::visitor::Visitor;
+use langlang_value::source_map::Span;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     NotFound(String),
     Semantic(String),
+    // Warnings promoted to errors by their lint level
+    Lint(Vec<Warning>),
+    // Definition that `expand` can't wrap within a node (rule, reason)
+    Expand(String, String),
+    // References to rules that aren't defined, in the order they
+    // appear within the grammar
+    UnresolvedReference(Vec<UnresolvedReference>),
+    // Problems found by `analysis::analyze` that would make matching
+    // loop forever or never succeed, in the order they're found
+    Analysis(Vec<Problem>),
+    // Class range that doesn't match anything because its end comes
+    // before its start (rule, range)
+    EmptyRange(String, ast::Range),
+    // Repetition with counts that, multiplied by the counts of the
+    // repetitions around it, unroll into more copies of an expression
+    // than `MAX_REPEAT_COPIES` (rule, span)
+    RepeatTooLarge(String, Span),
+    // Named class that none of the registered providers define
+    UnknownClass(ast::NamedClass),
+    // Unicode property of a `\p{..}` class item that isn't a general
+    // category, a script or a binary property
+    UnknownProperty(ast::Property),
 }
 
+impl std::error::Error for Error {}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "Compiler Error")?;
         match self {
             Error::NotFound(msg) => write!(f, "[NotFound]: {}", msg),
             Error::Semantic(msg) => write!(f, "[Semantic]: {}", msg),
+            Error::Lint(warnings) => {
+                write!(f, "[Lint]:")?;
+                for w in warnings {
+                    write!(f, "\n{}", w)?;
+                }
+                Ok(())
+            }
+            Error::Expand(rule, reason) => write!(f, "[Expand]: rule `{}`: {}", rule, reason),
+            Error::UnresolvedReference(references) => {
+                write!(f, "[UnresolvedReference]:")?;
+                for r in references {
+                    write!(f, "\n{}", r)?;
+                }
+                Ok(())
+            }
+            Error::Analysis(problems) => {
+                write!(f, "[Analysis]:")?;
+                for p in problems {
+                    write!(f, "\n{} at {}", p, p.span().start)?;
+                }
+                Ok(())
+            }
+            Error::EmptyRange(rule, range) => write!(
+                f,
+                "[EmptyRange]: range `{}` in `{}` at {} is empty",
+                ast::Literal::Range(range.clone()),
+                rule,
+                range.span.start
+            ),
+            Error::RepeatTooLarge(rule, span) => write!(
+                f,
+                "[RepeatTooLarge]: repetition in `{}` at {} unrolls into more than {} copies",
+                rule, span.start, MAX_REPEAT_COPIES
+            ),
+            Error::UnknownClass(class) => write!(
+                f,
+                "[UnknownClass]: class `%{}` at {} isn't provided",
+                class.name, class.span.start
+            ),
+            Error::UnknownProperty(property) => write!(
+                f,
+                "[UnknownProperty]: `{}` at {} isn't a known Unicode property",
+                property, property.span.start
+            ),
+        }
+    }
+}
+
+/// Most copies of an expression that counted repetitions, e.g.:
+/// `'a'{3,5}`, can unroll into, counting the copies unrolled by the
+/// repetitions around them
+pub const MAX_REPEAT_COPIES: usize = 1 << 16;
+
+/// Reference to a rule that isn't defined within the grammar
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnresolvedReference {
+    /// Name of the rule that isn't defined
+    pub name: String,
+    /// Name of the rule the reference is made from
+    pub referenced_from: String,
+    /// Where the reference is within the grammar
+    pub span: Span,
+    /// Defined rule with a name close enough to `name` to be what
+    /// was meant
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for UnresolvedReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "rule `{}` referenced from `{}` at {} isn't defined",
+            self.name, self.referenced_from, self.span.start
+        )?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, "; did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// WarningCode identifies each one of the checks that report
+/// constructions that are valid but most likely mistakes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WarningCode {
+    // Rule can't be reached from the start rule
+    UnusedRule,
+    // Alternative follows another one that never fails
+    UnreachableAlternative,
+    // Repetition of an expression that doesn't consume input.  It's
+    // not reported anymore, as compiling fails with `Error::Analysis`
+    // instead, but lint levels set for it are still accepted
+    NullableRepetition,
+    // Alternative that starts with everything a previous one matches
+    ShadowedAlternative,
+}
+
+impl WarningCode {
+    /// Name of the code as it's written in configuration and output
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WarningCode::UnusedRule => "unused_rule",
+            WarningCode::UnreachableAlternative => "unreachable_alternative",
+            WarningCode::NullableRepetition => "nullable_repetition",
+            WarningCode::ShadowedAlternative => "shadowed_alternative",
+        }
+    }
+}
+
+impl std::str::FromStr for WarningCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unused_rule" => Ok(WarningCode::UnusedRule),
+            "unreachable_alternative" => Ok(WarningCode::UnreachableAlternative),
+            "nullable_repetition" => Ok(WarningCode::NullableRepetition),
+            "shadowed_alternative" => Ok(WarningCode::ShadowedAlternative),
+            _ => {
+                let codes = [
+                    WarningCode::UnusedRule,
+                    WarningCode::UnreachableAlternative,
+                    WarningCode::NullableRepetition,
+                    WarningCode::ShadowedAlternative,
+                ];
+                let mut msg = format!("unknown lint `{}`", s);
+                if let Some(closest) = did_you_mean(s, codes.iter().map(|c| c.as_str())) {
+                    msg.push_str(&format!("; did you mean `{}`?", closest));
+                }
+                Err(Error::NotFound(msg))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for WarningCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub code: WarningCode,
+    // location within the grammar the warning refers to
+    pub span: Span,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(code: WarningCode, span: Span, message: String) -> Self {
+        Self {
+            code,
+            span,
+            message,
         }
     }
 }
 
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "warning[{}]: {} at {}",
+            self.code, self.message, self.span.start
+        )
+    }
+}
+
+/// LintLevel defines what happens when a check finds something: it
+/// can be ignored, reported as a warning or fail the compilation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LintLevel {
+    Allow,
+    #[default]
+    Warn,
+    Deny,
+}
+
+/// Optimization is a place where the compiled program does less
+/// work than a literal translation of the grammar would, reported so
+/// grammar authors learn what the compiler already takes care of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Optimization {
+    pub span: Span,
+    pub message: String,
+}
+
+impl std::fmt::Display for Optimization {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "optimization: {} at {}", self.message, self.span.start)
+    }
+}
+
+/// PassReport is how long one of the passes of the compiler took and
+/// how it changed the size of what's being compiled, so the passes
+/// that dominate the compile time of a grammar can be found
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassReport {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    // number of expressions within the grammar the pass worked on,
+    // before and after it ran
+    pub expressions: (usize, usize),
+    // number of instructions generated before and after the pass ran
+    pub instructions: (usize, usize),
+}
+
+impl std::fmt::Display for PassReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let delta =
+            |(before, after): (usize, usize)| format!("({:+})", after as i64 - before as i64);
+        write!(
+            f,
+            "{:<20} {:>12?} {:>8} expressions {:<8} {:>8} instructions {}",
+            self.name,
+            self.elapsed,
+            self.expressions.1,
+            delta(self.expressions),
+            self.instructions.1,
+            delta(self.instructions),
+        )
+    }
+}
+
+/// CompileOutput is what compiling a grammar produces besides errors:
+/// the program, the warnings found along the way, the optimizations
+/// applied to it, and a report of each pass when enabled with
+/// `Config::report_passes`.
+#[derive(Debug)]
+pub struct CompileOutput {
+    pub program: Program,
+    pub warnings: Vec<Warning>,
+    pub optimizations: Vec<Optimization>,
+    pub passes: Vec<PassReport>,
+}
+
+/// ClassProvider is where the compiler finds the ranges of the
+/// classes grammars refer to by name, e.g.: `%greek`, so alphabets
+/// specific to a domain don't need to be spelled out as ranges
+pub trait ClassProvider: Send + Sync {
+    /// Ranges of the characters within the class `name`, or `None` if
+    /// the provider doesn't define it
+    fn ranges(&self, name: &str) -> Option<Vec<(char, char)>>;
+}
+
+/// Classes is a provider of the classes registered with it by name
+#[derive(Clone, Debug, Default)]
+pub struct Classes {
+    classes: HashMap<String, Vec<(char, char)>>,
+}
+
+impl Classes {
+    /// Generate a new instance that also provides the class `name`
+    /// with the characters within `ranges`
+    pub fn with(self, name: &str, ranges: &[(char, char)]) -> Self {
+        let mut classes = self.classes;
+        classes.insert(name.to_string(), ranges.to_vec());
+        Self { classes }
+    }
+}
+
+impl ClassProvider for Classes {
+    fn ranges(&self, name: &str) -> Option<Vec<(char, char)>> {
+        self.classes.get(name).cloned()
+    }
+}
+
+// providers of named classes, asked in the order they're registered
+#[derive(Clone, Default)]
+struct ClassProviders(Vec<Arc<dyn ClassProvider>>);
+
+impl std::fmt::Debug for ClassProviders {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ClassProviders({})", self.0.len())
+    }
+}
+
+impl ClassProviders {
+    fn ranges(&self, name: &str) -> Option<Vec<(char, char)>> {
+        self.0.iter().find_map(|p| p.ranges(name))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     optimize: u8,
     emit_wsh: bool,
+    // replace calls rules make to themselves in tail position with
+    // jumps, flattening the values they capture
+    tail_calls: bool,
+    // leave calls to rules that aren't defined within the grammar to
+    // be resolved by linking the program with others
+    defer_unresolved: bool,
+    // literals match bytes rather than characters
+    bytes: bool,
+    unnamed: UnnamedProductions,
+    // level of each lint that doesn't use the default one
+    lints: HashMap<WarningCode, LintLevel>,
+    // level of the lints that aren't within `lints`
+    lint_default: LintLevel,
+    class_providers: ClassProviders,
+    // time each pass and measure what it changed
+    report_passes: bool,
+    // largest number of expressions a rule can have to be inlined
+    // within predicates, zero disables inlining
+    inline_threshold: usize,
+    // leave the rules the start rule can't reach out of the program
+    remove_unused_rules: bool,
 }
 
 impl Default for Config {
@@ -41,6 +376,16 @@ impl Config {
         Self {
             optimize: 0,
             emit_wsh: true,
+            tail_calls: false,
+            defer_unresolved: false,
+            bytes: false,
+            unnamed: UnnamedProductions::default(),
+            lints: HashMap::new(),
+            lint_default: LintLevel::default(),
+            class_providers: ClassProviders::default(),
+            report_passes: false,
+            inline_threshold: 0,
+            remove_unused_rules: false,
         }
     }
 
@@ -50,6 +395,16 @@ impl Config {
         Self {
             optimize: 1,
             emit_wsh: true,
+            tail_calls: false,
+            defer_unresolved: false,
+            bytes: false,
+            unnamed: UnnamedProductions::default(),
+            lints: HashMap::new(),
+            lint_default: LintLevel::default(),
+            class_providers: ClassProviders::default(),
+            report_passes: false,
+            inline_threshold: 0,
+            remove_unused_rules: false,
         }
     }
 
@@ -57,10 +412,139 @@ impl Config {
     /// generating code that handle whitespaces automatically
     pub fn disable_injecting_whitespace_handling(&self) -> Self {
         Self {
-            optimize: self.optimize,
             emit_wsh: false,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that turns a rule calling
+    /// itself right before returning into a jump back to its start,
+    /// so right recursive rules like `List <- Item List / Item` run in
+    /// constant stack space where the recursive call is the last thing
+    /// the rule does.  The values captured by each recursive call end
+    /// up flattened within the outermost node, e.g.: `List[a a b]`
+    /// instead of `List[a List[a List[b]]]`.
+    pub fn enable_tail_calls(&self) -> Self {
+        Self {
+            tail_calls: true,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that compiles calls to rules
+    /// the grammar doesn't define instead of failing.  The program
+    /// can only be executed after being linked with the programs that
+    /// define these rules with `Program::concat`.
+    pub fn defer_unresolved(&self) -> Self {
+        Self {
+            defer_unresolved: true,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance for grammars that match binary
+    /// input with `VM::run_bytes`.  Literals can only have ASCII
+    /// characters and `\xHH` escapes, e.g.: `'\x7fELF'` and
+    /// `[\x00-\x1f]`.  Without it, `\xHH` escapes are rejected.
+    pub fn byte_mode(&self) -> Self {
+        Self {
+            bytes: true,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that tells the virtual machine
+    /// what to do with values captured by productions that have no
+    /// name associated with their address.  The choice is recorded
+    /// within the compiled program.
+    pub fn with_unnamed_productions(&self, unnamed: UnnamedProductions) -> Self {
+        Self {
+            unnamed,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that reports the warnings with
+    /// `code` according to `level`
+    pub fn with_lint_level(&self, code: WarningCode, level: LintLevel) -> Self {
+        let mut lints = self.lints.clone();
+        lints.insert(code, level);
+        Self {
+            lints,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that fails compilation on any
+    /// warning that wasn't explicitly set to another level
+    pub fn strict(&self) -> Self {
+        Self {
+            lint_default: LintLevel::Deny,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that resolves named classes,
+    /// e.g.: `%greek`, with `provider` when the providers registered
+    /// before it don't define them
+    pub fn with_class_provider(&self, provider: impl ClassProvider + 'static) -> Self {
+        let mut class_providers = self.class_providers.clone();
+        class_providers.0.push(Arc::new(provider));
+        Self {
+            class_providers,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that times each pass of the
+    /// compiler and counts the expressions and the instructions it
+    /// leaves behind, see `CompileOutput::passes`
+    pub fn report_passes(&self) -> Self {
+        Self {
+            report_passes: true,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that replaces calls to rules
+    /// of at most `threshold` expressions with the rules themselves,
+    /// saving the call and the return.  Only rules made of literals
+    /// and the operators around them get inlined, so no recursive rule
+    /// ever is.
+    ///
+    /// Calls are only inlined within predicates, e.g.: `!Digit`,
+    /// where the values captured are thrown away, so the output of
+    /// the program doesn't change: every node still comes from the
+    /// rule that has its name.  Outside of predicates the node, its
+    /// span and its capture mode are made by the `Return` of the
+    /// call, so an inlined body would leave its values behind within
+    /// the caller's node instead, e.g.: `A[x]` rather than
+    /// `A[Letter[x]]`.  What does change is that inlined calls don't
+    /// show up as rule events, so tracers and coverage reports don't
+    /// see them.
+    pub fn with_inline_threshold(&self, threshold: usize) -> Self {
+        Self {
+            inline_threshold: threshold,
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new Config instance that doesn't generate code for
+    /// the rules that can't be reached from the start rule, including
+    /// the builtins the grammar doesn't use.  They're still reported
+    /// with `WarningCode::UnusedRule`.  Programs compiled this way
+    /// can't be started from, or linked against, the rules removed.
+    pub fn remove_unused_rules(&self) -> Self {
+        Self {
+            remove_unused_rules: true,
+            ..self.clone()
         }
     }
+
+    /// Level the warnings with `code` are reported with
+    pub fn lint_level(&self, code: WarningCode) -> LintLevel {
+        self.lints.get(&code).copied().unwrap_or(self.lint_default)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -88,6 +572,9 @@ pub struct Compiler {
     // calls that need to be patched because they occurred syntaticaly
     // before the definition of the production
     addrs: HashMap<usize /* addr */, usize /* string id */>,
+    // call sites of rules that aren't defined within the grammar,
+    // left to be resolved when the program gets linked
+    externals: HashMap<usize /* addr */, usize /* string id */>,
     // Map from the set of labels to the set of messages for error
     // reporting
     labels: HashMap<usize, usize>,
@@ -101,6 +588,19 @@ pub struct Compiler {
     left_rec: HashMap<String, bool>,
     // depth of the use of the lex ('#') operator
     lex_level: usize,
+    // true while visiting the items of a case-insensitive class
+    ignore_case: bool,
+    // expressions of the rules being compiled, used to find out what
+    // the first character matched by a call can be
+    rules: HashMap<String, ast::Expression>,
+    // optimizations applied while generating code
+    optimizations: Vec<Optimization>,
+    // ranges of each class matched by a `Class` instruction, and the
+    // index of each set of ranges within that table
+    classes: Vec<Vec<(char, char)>>,
+    class_ids: HashMap<Vec<(char, char)>, usize>,
+    // reports of the passes run so far
+    passes: Vec<PassReport>,
 }
 
 impl Compiler {
@@ -117,11 +617,18 @@ impl Compiler {
             identifier_names: Vec::new(),
             funcs: HashMap::new(),
             addrs: HashMap::new(),
+            externals: HashMap::new(),
             labels: HashMap::new(),
             label_ids: HashSet::new(),
             recovery: HashMap::new(),
             left_rec: HashMap::new(),
             lex_level: 0,
+            ignore_case: false,
+            rules: HashMap::new(),
+            optimizations: vec![],
+            classes: vec![],
+            class_ids: HashMap::new(),
+            passes: vec![],
         }
     }
 
@@ -132,31 +639,478 @@ impl Compiler {
         grammar: &ast::Grammar,
         main: Option<&str>,
     ) -> Result<Program, Error> {
-        DetectLeftRec::default().run(grammar, &mut self.left_rec)?;
-        self.code_gen(grammar);
-        self.backpatch_callsites()?;
-        self.map_recovery_exprs()?;
+        Ok(self.compile_with_warnings(grammar, main)?.program)
+    }
+
+    /// compile a Grammar in its AST form into a program executable by
+    /// the virtual machine, and also return the warnings found within
+    /// the grammar
+    pub fn compile_with_warnings(
+        &mut self,
+        grammar: &ast::Grammar,
+        main: Option<&str>,
+    ) -> Result<CompileOutput, Error> {
+        match main {
+            Some(main) if !grammar.definitions.contains_key(main) => {
+                return Err(Error::NotFound(format!(
+                    "Production {:?} doesnt exist",
+                    main
+                )))
+            }
+            None if grammar.definition_names.is_empty() => {
+                return Err(Error::NotFound("Grammar is empty".to_string()))
+            }
+            _ => {}
+        }
+        let started = self.pass_start(grammar);
+        let grammar = &add_label_recovery(grammar)?;
+        self.pass_end("add_label_recovery", started, grammar);
+        let started = self.pass_start(grammar);
+        let grammar = &self.resolve_classes(grammar)?;
+        self.pass_end("resolve_classes", started, grammar);
+        self.check_pass("resolve_references", grammar, |c| {
+            c.resolve_references(grammar)
+        })?;
+        self.check_pass("analyze", grammar, |c| c.analyze(grammar))?;
+        self.check_pass("check_literals", grammar, |c| c.check_literals(grammar))?;
+        self.check_pass("check_ranges", grammar, |c| c.check_ranges(grammar))?;
+        self.check_pass("check_repeats", grammar, |c| c.check_repeats(grammar))?;
+        self.check_pass("check_takes", grammar, |c| c.check_takes(grammar))?;
+        self.check_pass("check_labels", grammar, |c| c.check_labels(grammar))?;
+        let started = self.pass_start(grammar);
+        let grammar = &add_sync_recovery(grammar);
+        self.pass_end("add_sync_recovery", started, grammar);
+        self.check_pass("detect_left_rec", grammar, |c| {
+            DetectLeftRec::default().run(grammar, &mut c.left_rec)
+        })?;
+        self.check_pass("check_captures", grammar, |c| {
+            c.check_captures(grammar, &c.left_rec)
+        })?;
+        match self.config.remove_unused_rules {
+            true => {
+                let started = self.pass_start(grammar);
+                let used = self.remove_unused_rules(grammar, main);
+                self.pass_end("remove_unused_rules", started, &used);
+                self.code_gen(&used);
+            }
+            false => self.code_gen(grammar),
+        }
+        self.check_pass("backpatch_callsites", grammar, |c| c.backpatch_callsites())?;
+        self.check_pass("map_recovery_exprs", grammar, |c| c.map_recovery_exprs())?;
+        self.check_pass("map_label_messages", grammar, |c| {
+            c.map_label_messages(grammar);
+            Ok(())
+        })?;
         self.pick_main(main);
 
-        Ok(Program::new(
+        let main = match main {
+            Some(main) => main,
+            None => &grammar.definition_names[0],
+        };
+        let program = Program::new(
             self.identifiers.clone(),
             self.labels.clone(),
             self.recovery.clone(),
             self.strings.clone(),
             self.code.clone(),
-        ))
+            self.config.unnamed,
+        )
+        .with_externals(self.externals.clone())
+        .with_classes(self.classes.clone())
+        .with_version(grammar.version.clone());
+        let mut warnings = vec![];
+        let mut denied = vec![];
+        let started = self.pass_start(grammar);
+        let found = lint::run(grammar, main)?;
+        self.pass_end("lint", started, grammar);
+        for warning in found {
+            match self.config.lint_level(warning.code) {
+                LintLevel::Allow => {}
+                LintLevel::Warn => warnings.push(warning),
+                LintLevel::Deny => denied.push(warning),
+            }
+        }
+        if !denied.is_empty() {
+            return Err(Error::Lint(denied));
+        }
+        Ok(CompileOutput {
+            program,
+            warnings,
+            optimizations: self.optimizations.clone(),
+            passes: self.passes.clone(),
+        })
+    }
+
+    /// Take the time and the sizes a pass over `grammar` starts
+    /// with, if passes get reported
+    fn pass_start(&self, grammar: &ast::Grammar) -> Option<(Instant, usize, usize)> {
+        // sizes are counted before the clock starts, so counting
+        // doesn't add up to the time of the pass
+        self.config.report_passes.then(|| {
+            let expressions = count_expressions(grammar);
+            (Instant::now(), expressions, self.code.len())
+        })
+    }
+
+    /// Record the report of the pass named `name` that started at
+    /// `started` and left `grammar` behind
+    fn pass_end(
+        &mut self,
+        name: &'static str,
+        started: Option<(Instant, usize, usize)>,
+        grammar: &ast::Grammar,
+    ) {
+        if let Some((instant, expressions, instructions)) = started {
+            self.passes.push(PassReport {
+                name,
+                elapsed: instant.elapsed(),
+                expressions: (expressions, count_expressions(grammar)),
+                instructions: (instructions, self.code.len()),
+            });
+        }
+    }
+
+    /// Run `pass`, which doesn't rewrite `grammar`, and record its
+    /// report
+    fn check_pass(
+        &mut self,
+        name: &'static str,
+        grammar: &ast::Grammar,
+        pass: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let started = self.pass_start(grammar);
+        pass(self)?;
+        self.pass_end(name, started, grammar);
+        Ok(())
+    }
+
+    /// Apply the rewrites done before generating code to `grammar`,
+    /// returning the grammar that actually gets compiled along with
+    /// whether each one of its rules is left recursive
+    #[cfg(feature = "interp")]
+    pub(crate) fn prepare(
+        &self,
+        grammar: &ast::Grammar,
+    ) -> Result<(ast::Grammar, HashMap<String, bool>), Error> {
+        let grammar = &add_label_recovery(grammar)?;
+        let grammar = &self.resolve_classes(grammar)?;
+        self.resolve_references(grammar)?;
+        self.check_literals(grammar)?;
+        self.check_ranges(grammar)?;
+        self.check_takes(grammar)?;
+        self.check_labels(grammar)?;
+        let grammar = add_sync_recovery(grammar);
+        let mut left_rec = HashMap::new();
+        DetectLeftRec::default().run(&grammar, &mut left_rec)?;
+        self.check_captures(&grammar, &left_rec)?;
+        let grammar = match self.config.emit_wsh {
+            true => WhiteSpaceHandlerInjector::default().run(&grammar),
+            false => grammar,
+        };
+        if self.config.inline_threshold == 0 {
+            return Ok((grammar, left_rec));
+        }
+        let (grammar, _) = inline_rules(&grammar, self.config.inline_threshold);
+        Ok((grammar, left_rec))
+    }
+
+    /// Copy `grammar` without the rules that can't be reached from
+    /// `main`, or from its first rule if `main` isn't given
+    fn remove_unused_rules(&mut self, grammar: &ast::Grammar, main: Option<&str>) -> ast::Grammar {
+        let main = main.unwrap_or(&grammar.definition_names[0]);
+        let reachable = lint::reachable(grammar, main);
+        let builtins = lint::builtin_names();
+        let mut definitions = HashMap::new();
+        let mut definition_names = vec![];
+        for name in &grammar.definition_names {
+            let def = &grammar.definitions[name];
+            if !reachable.contains(name.as_str()) {
+                // like with lints, unused builtins aren't reported
+                if !builtins.contains(name) {
+                    self.optimizations.push(Optimization {
+                        span: def.span.clone(),
+                        message: format!("removed `{}`, which is never used", name),
+                    });
+                }
+                continue;
+            }
+            definitions.insert(name.clone(), def.clone());
+            definition_names.push(name.clone());
+        }
+        ast::Grammar::new(
+            grammar.span.clone(),
+            grammar.imports.clone(),
+            definition_names,
+            definitions,
+        )
+        .with_version(grammar.version.clone())
+        .with_labels(grammar.labels.clone())
+    }
+
+    /// Replace the named classes within `grammar` with classes of the
+    /// ranges their providers define for them
+    fn resolve_classes(&self, grammar: &ast::Grammar) -> Result<ast::Grammar, Error> {
+        let mut resolver = ClassResolver {
+            providers: &self.config.class_providers,
+            error: None,
+        };
+        let resolved = resolver.fold_grammar(grammar);
+        match resolver.error {
+            Some(err) => Err(err),
+            None => Ok(resolved),
+        }
+    }
+
+    /// Find the references to rules `grammar` doesn't define, and
+    /// fail listing all of them unless they're left to be resolved
+    /// by linking.  Rules can be referenced before being defined, as
+    /// the calls to them get patched once all rules are generated.
+    fn resolve_references(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        if self.config.defer_unresolved {
+            return Ok(());
+        }
+        let references = unresolved_references(grammar);
+        if references.is_empty() {
+            return Ok(());
+        }
+        Err(Error::UnresolvedReference(references))
+    }
+
+    /// Fail with the problems `analysis::analyze` finds, which would
+    /// make matching loop forever or never succeed.  References to
+    /// rules that aren't defined are left to `resolve_references`.
+    fn analyze(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        let problems: Vec<_> = analysis::analyze(grammar)
+            .into_iter()
+            .filter(|p| !matches!(p, Problem::UnresolvedReference(_)))
+            .collect();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Analysis(problems))
+    }
+
+    /// Check that literals fit the mode the grammar is compiled in:
+    /// `\xHH` escapes are only allowed in byte mode, where all the
+    /// other characters must be ASCII
+    fn check_literals(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        for name in &grammar.definition_names {
+            let mut error = None;
+            lint::walk(&grammar.definitions[name].expr, &mut |expr| {
+                let literal = match expr {
+                    ast::Expression::Literal(l) if error.is_none() => l,
+                    _ => return,
+                };
+                let (bytes, mut chars): (bool, Vec<char>) = match literal {
+                    ast::Literal::String(s) => (s.bytes, s.value.chars().collect()),
+                    ast::Literal::Char(c) => (c.bytes, vec![c.value]),
+                    ast::Literal::Range(r) => (r.bytes, vec![r.start, r.end]),
+                    ast::Literal::Class(c) => {
+                        c.literals
+                            .iter()
+                            .fold((false, vec![]), |(bytes, mut chars), l| match l {
+                                ast::Literal::Char(c) => {
+                                    chars.push(c.value);
+                                    (bytes || c.bytes, chars)
+                                }
+                                ast::Literal::Range(r) => {
+                                    chars.extend([r.start, r.end]);
+                                    (bytes || r.bytes, chars)
+                                }
+                                _ => (bytes, chars),
+                            })
+                    }
+                    ast::Literal::Any(_)
+                    | ast::Literal::Take(_)
+                    | ast::Literal::Host(_)
+                    | ast::Literal::NamedClass(_)
+                    | ast::Literal::Property(_) => return,
+                    ast::Literal::Int(_) => (true, vec![]),
+                };
+                let properties = match literal {
+                    ast::Literal::Class(c) => c
+                        .literals
+                        .iter()
+                        .any(|l| matches!(l, ast::Literal::Property(_))),
+                    _ => false,
+                };
+                let reason = if properties && self.config.bytes {
+                    "`\\p{..}` items are only allowed in text mode"
+                } else if bytes && !self.config.bytes {
+                    match literal {
+                        ast::Literal::Int(_) => "integer terminals are only allowed in byte mode",
+                        _ => "`\\xHH` escapes are only allowed in byte mode",
+                    }
+                } else if self.config.bytes {
+                    chars.retain(|c| match bytes {
+                        true => u32::from(*c) > 0xff,
+                        false => !c.is_ascii(),
+                    });
+                    if chars.is_empty() {
+                        return;
+                    }
+                    "only ASCII characters and `\\xHH` escapes are allowed in byte mode"
+                } else {
+                    return;
+                };
+                error = Some(Error::Semantic(format!(
+                    "{} in `{}`, referenced from `{}` at {}",
+                    reason,
+                    expr,
+                    name,
+                    expr.span().start
+                )));
+            });
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the end of each range within classes doesn't come
+    /// before its start, e.g.: `[z-a]`, and that the properties of
+    /// `\p{..}` items are known
+    fn check_ranges(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        for name in &grammar.definition_names {
+            let mut error = None;
+            lint::walk(&grammar.definitions[name].expr, &mut |expr| {
+                let literals = match expr {
+                    ast::Expression::Literal(ast::Literal::Class(c)) => &c.literals,
+                    ast::Expression::Literal(l) => std::slice::from_ref(l),
+                    _ => return,
+                };
+                for literal in literals {
+                    match literal {
+                        ast::Literal::Range(r) if error.is_none() && r.start > r.end => {
+                            error = Some(Error::EmptyRange(name.clone(), r.clone()));
+                        }
+                        ast::Literal::Property(p)
+                            if error.is_none() && properties::lookup(&p.name).is_none() =>
+                        {
+                            error = Some(Error::UnknownProperty(p.clone()));
+                        }
+                        _ => {}
+                    }
+                }
+            });
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that counted repetitions don't unroll into more than
+    /// `MAX_REPEAT_COPIES` copies of their expression, e.g.:
+    /// `'a'{4294967296}`, which would take more memory than there is
+    fn check_repeats(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        for name in &grammar.definition_names {
+            let mut checker = RepeatChecker {
+                rule: name,
+                copies: 1,
+                error: None,
+            };
+            checker.visit_expression(&grammar.definitions[name].expr);
+            if let Some(err) = checker.error {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the rule each `%take(X)` gets its count from is
+    /// referenced before it within the same definition, which is
+    /// where the virtual machine looks for the node `X` captured
+    fn check_takes(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        for name in &grammar.definition_names {
+            let mut seen = HashSet::new();
+            let mut error = None;
+            lint::walk(&grammar.definitions[name].expr, &mut |expr| match expr {
+                ast::Expression::Identifier(id) => {
+                    seen.insert(id.name.as_str());
+                }
+                ast::Expression::Literal(ast::Literal::Take(t))
+                    if error.is_none() && !seen.contains(t.name.as_str()) =>
+                {
+                    error = Some(Error::Semantic(format!(
+                        "`{}` in `{}` at {} needs `{}` to be matched before it in the same rule",
+                        expr, name, t.span.start, t.name
+                    )));
+                }
+                _ => {}
+            });
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that each label has at most one message declared with
+    /// `label name = "message"`
+    fn check_labels(&self, grammar: &ast::Grammar) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+        for label in &grammar.labels {
+            if !seen.insert(&label.name) {
+                return Err(Error::Semantic(format!(
+                    "label `{}` at {} already has a message",
+                    label.name, label.span.start
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that left recursive rules don't set `@capture(...)` to
+    /// anything other than `node`, as growing their match depends on
+    /// nesting the previous one within a node
+    fn check_captures(
+        &self,
+        grammar: &ast::Grammar,
+        left_rec: &HashMap<String, bool>,
+    ) -> Result<(), Error> {
+        for name in &grammar.definition_names {
+            let def = &grammar.definitions[name];
+            if def.capture != ast::CaptureMode::Node && left_rec.get(name) == Some(&true) {
+                return Err(Error::Semantic(format!(
+                    "`@capture({})` in `{}` at {} can't be used on a left recursive rule",
+                    def.capture, name, def.span.start
+                )));
+            }
+        }
+        Ok(())
     }
 
     /// First tries decides if whitespace handling will be emitted, if
     /// so, rewrites the AST to.  Then traverse the ast to generate
     /// the bytecode into the internal code vector.
     fn code_gen(&mut self, grammar: &ast::Grammar) {
-        if !self.config.emit_wsh {
-            self.visit_grammar(grammar);
-            return;
-        }
-        let g = WhiteSpaceHandlerInjector::default().run(grammar);
-        self.visit_grammar(&g);
+        let injected;
+        let grammar = match self.config.emit_wsh {
+            true => {
+                let started = self.pass_start(grammar);
+                injected = WhiteSpaceHandlerInjector::default().run(grammar);
+                self.pass_end("inject_whitespace", started, &injected);
+                &injected
+            }
+            false => grammar,
+        };
+        let inlined;
+        let grammar = match self.config.inline_threshold {
+            0 => grammar,
+            threshold => {
+                let started = self.pass_start(grammar);
+                let optimizations;
+                (inlined, optimizations) = inline_rules(grammar, threshold);
+                self.optimizations.extend(optimizations);
+                self.pass_end("inline_rules", started, &inlined);
+                &inlined
+            }
+        };
+        let started = self.pass_start(grammar);
+        self.visit_grammar(grammar);
+        self.pass_end("code_gen", started, grammar);
     }
 
     /// Try to find string `s` within the table of interned strings.
@@ -178,7 +1132,10 @@ impl Compiler {
     /// offset that could not be figured out in the first pass of the
     /// compilation.
     fn backpatch_callsites(&mut self) -> Result<(), Error> {
-        for (addr, id) in &self.addrs {
+        // visit call sites in order so the first missing rule is reported
+        let mut addrs: Vec<(&usize, &usize)> = self.addrs.iter().collect();
+        addrs.sort();
+        for (addr, id) in addrs {
             match self.funcs.get(id) {
                 Some(func_addr) => {
                     self.code[*addr] = match self.code[*addr] {
@@ -192,12 +1149,20 @@ impl Compiler {
                         _ => unreachable!(),
                     };
                 }
+                None if self.config.defer_unresolved => {
+                    self.externals.insert(*addr, *id);
+                }
                 None => {
-                    let name = self.strings[*id].clone();
-                    return Err(Error::NotFound(format!(
-                        "Production {:?} doesnt exist",
-                        name
-                    )));
+                    let name = &self.strings[*id];
+                    let defined = self
+                        .identifier_names
+                        .iter()
+                        .map(|i| self.strings[*i].as_str());
+                    let mut msg = format!("Production {:?} doesnt exist", name);
+                    if let Some(closest) = did_you_mean(name, defined) {
+                        msg.push_str(&format!("; did you mean `{}`?", closest));
+                    }
+                    return Err(Error::NotFound(msg));
                 }
             }
         }
@@ -218,6 +1183,16 @@ impl Compiler {
         Ok(())
     }
 
+    /// Point the labels declared with `label name = "message"` to
+    /// their messages within the strings table
+    fn map_label_messages(&mut self, grammar: &ast::Grammar) {
+        for label in &grammar.labels {
+            let label_id = self.push_string(&label.name);
+            let message_id = self.push_string(&label.message);
+            self.labels.insert(label_id, message_id);
+        }
+    }
+
     /// Find the address of the production `main` and write a call
     /// instruction pointing to such address at the first entry of the
     /// code vector.
@@ -235,7 +1210,7 @@ impl Compiler {
         // let id = self.push_string(main);
         let addr = self.funcs[&id];
         // Mark Ps as left recursive if the detector marked it as such
-        let lr = if self.left_rec.get(name).is_some() && self.left_rec[name] {
+        let lr = if self.left_rec.contains_key(name) && self.left_rec[name] {
             1
         } else {
             0
@@ -247,6 +1222,105 @@ impl Compiler {
         }
     }
 
+    /// When `choice` is a sequence that starts with a terminal, and
+    /// none of the alternatives that come after it can start with the
+    /// characters that terminal matches, return the terminal and the
+    /// rest of the sequence.  Once the terminal matches, the choice
+    /// can commit to the alternative, as the others can't match.
+    fn early_commit<'a>(
+        &mut self,
+        choice: &'a ast::Expression,
+        others: &[ast::Expression],
+    ) -> Option<(&'a ast::Expression, &'a [ast::Expression])> {
+        if self.config.optimize < 1 {
+            return None;
+        }
+        let seq = match choice {
+            ast::Expression::Sequence(seq) if seq.items.len() > 1 => seq,
+            _ => return None,
+        };
+        let head = match &seq.items[0] {
+            head @ ast::Expression::Literal(_) => head,
+            _ => return None,
+        };
+        let head_first = first_set(head, &self.rules, &mut vec![])?;
+        for other in others {
+            let other_first = first_set(other, &self.rules, &mut vec![])?;
+            if overlaps(&head_first, &other_first) {
+                return None;
+            }
+        }
+        self.optimizations.push(Optimization {
+            span: seq.span.clone(),
+            message: format!(
+                "committed to the alternative once `{}` matched, as the alternatives after it can't start with it",
+                head
+            ),
+        });
+        Some((head, &seq.items[1..]))
+    }
+
+    /// Instruction that tells upfront whether `choice` can't start
+    /// matching at the cursor, so the choice skips to the next
+    /// alternative without saving the state of the VM.  It's only
+    /// emitted when `choice` starts with a single character or with
+    /// `.`, with the offset filled in once the next alternative's
+    /// address is known.
+    fn head_test(&self, choice: &ast::Expression) -> Option<Instruction> {
+        if self.config.optimize < 1 {
+            return None;
+        }
+        let head = match choice {
+            ast::Expression::Sequence(seq) => seq.items.first()?,
+            expr => expr,
+        };
+        match head {
+            ast::Expression::Literal(ast::Literal::String(s)) if !s.ignore_case => {
+                let mut chars = s.value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(Instruction::TestChar(c, 0)),
+                    _ => None,
+                }
+            }
+            ast::Expression::Literal(ast::Literal::Char(c)) if !self.ignore_case => {
+                Some(Instruction::TestChar(c.value, 0))
+            }
+            ast::Expression::Literal(ast::Literal::Any(_)) => Some(Instruction::TestAny(0)),
+            _ => None,
+        }
+    }
+
+    /// Replace the calls the rule at `addr` makes to itself with
+    /// jumps to `addr` when nothing but its return would run after
+    /// them.  The body of the rule ends at the cursor.
+    fn eliminate_tail_calls(&mut self, n: &ast::Definition, addr: usize, strid: usize) {
+        for pc in addr..self.cursor {
+            let is_self_call = matches!(self.code[pc], Instruction::Call(_, 0))
+                && self.addrs.get(&pc) == Some(&strid);
+            if !is_self_call {
+                continue;
+            }
+            // follow the jumps that close choices
+            let mut next = pc + 1;
+            while let Instruction::Jump(offset) = self.code.get(next).unwrap_or(&Instruction::Halt)
+            {
+                next += *offset;
+            }
+            if next != self.cursor {
+                continue;
+            }
+            self.addrs.remove(&pc);
+            self.code[pc] = Instruction::JumpB(pc - addr);
+            self.optimizations.push(Optimization {
+                span: n.span.clone(),
+                message: format!(
+                    "replaced the call `{}` makes to itself right before returning with a jump",
+                    n.name
+                ),
+            });
+        }
+    }
+
     /// Generate bytecode for both ZeroOrMore and OneOrMore
     fn compile_seq<'ast>(
         &mut self,
@@ -281,6 +1355,15 @@ impl Compiler {
 
 impl<'ast> Visitor<'ast> for Compiler {
     fn visit_grammar(&mut self, n: &'ast ast::Grammar) {
+        self.rules = n
+            .definitions
+            .iter()
+            .map(|(name, d)| (name.clone(), d.expr.clone()))
+            .collect();
+        // every program calls the start rule and halts once it
+        // returns.  Failures end by unwinding the stack instead, so
+        // `Halt` needs no reason, and every other path ends in a
+        // `Return` or a failure, which `Program::validate` checks
         self.emit(Instruction::Call(2, 0));
         self.emit(Instruction::Halt);
         for d in &n.definition_names {
@@ -294,32 +1377,95 @@ impl<'ast> Visitor<'ast> for Compiler {
         self.identifiers.insert(addr, strid);
         self.identifier_names.push(strid);
         self.visit_expression(&n.expr);
-        if n.is_syntactic() {
-            self.emit(Instruction::CapJoin);
+        if self.config.tail_calls && !self.left_rec.get(&n.name).copied().unwrap_or(false) {
+            self.eliminate_tail_calls(n, addr, strid);
+        }
+        match n.capture {
+            ast::CaptureMode::Node => {
+                if n.is_syntactic() {
+                    self.emit(Instruction::CapJoin);
+                }
+                self.emit(Instruction::Return);
+            }
+            ast::CaptureMode::String => {
+                self.emit(Instruction::CapText);
+                self.emit(Instruction::ReturnValues);
+            }
+            ast::CaptureMode::List => {
+                self.emit(Instruction::CapList);
+                self.emit(Instruction::ReturnValues);
+            }
+            ast::CaptureMode::Skip => {
+                self.emit(Instruction::CapDrop);
+                self.emit(Instruction::Return);
+            }
         }
-        self.emit(Instruction::Return);
         self.funcs.insert(strid, addr);
     }
 
     fn visit_choice(&mut self, n: &'ast ast::Choice) {
         let (mut i, last_choice) = (0, n.items.len() - 1);
         let mut commits = vec![];
+        let mut jumps = vec![];
         for choice in &n.items {
             if i == last_choice {
                 self.visit_expression(choice);
                 break;
             }
             i += 1;
+            let test = self.head_test(choice).map(|t| (self.cursor, t));
+            if let Some((_, t)) = &test {
+                self.emit(t.clone());
+            }
             let pos = self.cursor;
-            self.emit(Instruction::Choice(0));
-            self.visit_expression(choice);
-            self.code[pos] = Instruction::Choice(self.cursor - pos + 1);
-            commits.push(self.cursor);
-            self.emit(Instruction::Commit(0));
+            if let Some((head, rest)) = self.early_commit(choice, &n.items[i..]) {
+                // Once `head` matches, none of the next alternatives
+                // can, so the backtrack entry is dropped right away
+                self.emit(Instruction::Choice(0));
+                self.visit_expression(head);
+                self.emit(Instruction::Commit(1));
+                for item in rest {
+                    self.visit_expression(item);
+                }
+                jumps.push(self.cursor);
+                self.emit(Instruction::Jump(0));
+                self.code[pos] = Instruction::Choice(self.cursor - pos);
+            } else {
+                self.emit(Instruction::Choice(0));
+                self.visit_expression(choice);
+                self.code[pos] = Instruction::Choice(self.cursor - pos + 1);
+                commits.push(self.cursor);
+                self.emit(Instruction::Commit(0));
+            }
+            // the test skips to the next alternative, right where
+            // the choice would resume after failing
+            if let Some((at, t)) = test {
+                let (test, unless) = match t {
+                    Instruction::TestChar(c, _) => (
+                        Instruction::TestChar(c, self.cursor - at),
+                        format!("when the next character isn't {:?}", c),
+                    ),
+                    _ => (
+                        Instruction::TestAny(self.cursor - at),
+                        "at the end of the input".to_string(),
+                    ),
+                };
+                self.code[at] = test;
+                self.optimizations.push(Optimization {
+                    span: choice.span(),
+                    message: format!(
+                        "skipped the alternative without saving a backtrack entry {}",
+                        unless
+                    ),
+                });
+            }
         }
         for commit in commits {
             self.code[commit] = Instruction::Commit(self.cursor - commit);
         }
+        for jump in jumps {
+            self.code[jump] = Instruction::Jump(self.cursor - jump);
+        }
     }
 
     fn visit_lex(&mut self, n: &'ast ast::Lex) {
@@ -335,7 +1481,7 @@ impl<'ast> Visitor<'ast> for Compiler {
                 self.emit(Instruction::ChoiceP(0));
                 self.visit_expression(&n.expr);
                 let pos1 = self.cursor;
-                self.code[pos0] = Instruction::ChoiceP(pos1 - pos0);
+                self.code[pos0] = Instruction::ChoiceP(pos1 - pos0 + 1);
                 self.emit(Instruction::BackCommit(0));
                 self.emit(Instruction::Fail);
                 self.code[pos1] = Instruction::BackCommit(self.cursor - pos1);
@@ -381,6 +1527,27 @@ impl<'ast> Visitor<'ast> for Compiler {
         self.emit(Instruction::Commit(1));
     }
 
+    fn visit_repeat(&mut self, n: &'ast ast::Repeat) {
+        for _ in 0..n.min {
+            self.visit_expression(&n.expr);
+        }
+        let max = match n.max {
+            Some(max) => max,
+            None => return self.compile_seq(None, &n.expr),
+        };
+        // each optional copy fails straight to the end, since the
+        // copies after it would fail at the same position too
+        let mut choices = vec![];
+        for _ in n.min..max {
+            choices.push(self.cursor);
+            self.emit(Instruction::Choice(0));
+            self.visit_expression(&n.expr);
+            self.emit(Instruction::Commit(1));
+        }
+        for pos in choices {
+            self.code[pos] = Instruction::Choice(self.cursor - pos);
+        }
+    }
     fn visit_zero_or_more(&mut self, n: &'ast ast::ZeroOrMore) {
         self.compile_seq(None, &n.expr);
     }
@@ -447,31 +1614,124 @@ impl<'ast> Visitor<'ast> for Compiler {
 
     fn visit_string(&mut self, n: &'ast ast::String) {
         let id = self.push_string(&n.value);
-        self.emit(Instruction::String(id));
+        if n.ignore_case {
+            self.emit(Instruction::StringCI(id));
+        } else {
+            self.emit(Instruction::String(id));
+        }
     }
 
     fn visit_class(&mut self, n: &'ast ast::Class) {
-        let choice = ast::Choice::new(
-            n.span.clone(),
-            n.literals
+        let ranges = class_ranges(n).map(fold_ranges);
+        let ranges = match ranges {
+            Some(ranges) if n.negated && !n.ignore_case => {
+                Some(complement_ranges(&ranges)).filter(|c| !c.is_empty())
+            }
+            ranges if !n.negated => ranges,
+            // the characters a negated class doesn't list aren't
+            // known upfront, so it's matched as `![...] .`
+            _ => {
+                let listed = n.clone().with_negated(false);
+                let listed = ast::Expression::Literal(ast::Literal::Class(listed));
+                self.visit_not(&ast::Not::new(n.span.clone(), Box::new(listed)));
+                self.visit_any(&ast::Any {
+                    span: n.span.clone(),
+                });
+                return;
+            }
+        };
+        if let Some(ranges) = &ranges {
+            // all the ranges are checked by a single instruction,
+            // instead of by a choice with one alternative per range
+            if self.config.optimize > 0 && !n.ignore_case && ranges.len() > 1 {
+                let id = match self.class_ids.get(ranges) {
+                    Some(id) => *id,
+                    None => {
+                        self.classes.push(ranges.clone());
+                        self.class_ids
+                            .insert(ranges.clone(), self.classes.len() - 1);
+                        self.classes.len() - 1
+                    }
+                };
+                self.emit(Instruction::Class(id));
+                return;
+            }
+        }
+        let items = match ranges {
+            // one `Char` or `Span` per range of the canonical set
+            Some(ranges) => ranges
+                .into_iter()
+                .map(|(start, end)| match start == end {
+                    true => ast::Literal::Char(ast::Char::new(n.span.clone(), start)),
+                    false => ast::Literal::Range(ast::Range::new(n.span.clone(), start, end)),
+                })
+                .map(ast::Expression::Literal)
+                .collect(),
+            None => n
+                .literals
                 .iter()
                 .map(|i| ast::Expression::Literal(i.clone()))
                 .collect(),
-        );
+        };
+        let choice = ast::Choice::new(n.span.clone(), items);
+        let ignore_case = std::mem::replace(&mut self.ignore_case, n.ignore_case);
         self.visit_choice(&choice);
+        self.ignore_case = ignore_case;
     }
 
     fn visit_range(&mut self, n: &'ast ast::Range) {
-        self.emit(Instruction::Span(n.start, n.end));
+        if self.ignore_case {
+            self.emit(Instruction::SpanCI(n.start, n.end));
+        } else {
+            self.emit(Instruction::Span(n.start, n.end));
+        }
     }
 
     fn visit_char(&mut self, n: &'ast ast::Char) {
-        self.emit(Instruction::Char(n.value));
+        if self.ignore_case {
+            self.emit(Instruction::CharCI(n.value));
+        } else {
+            self.emit(Instruction::Char(n.value));
+        }
     }
 
     fn visit_any(&mut self, _: &'ast ast::Any) {
         self.emit(Instruction::Any);
     }
+
+    fn visit_property(&mut self, n: &'ast ast::Property) {
+        // names were checked before generating code
+        let id = properties::lookup(&n.name).unwrap_or_default();
+        if !n.negated {
+            self.emit(Instruction::Property(id));
+            return;
+        }
+        // `\P{..}` is matched as `!\p{..} .`
+        let property = n.clone().with_negated(false);
+        let property = ast::Expression::Literal(ast::Literal::Property(property));
+        self.visit_not(&ast::Not::new(n.span.clone(), Box::new(property)));
+        self.visit_any(&ast::Any {
+            span: n.span.clone(),
+        });
+    }
+
+    fn visit_int(&mut self, n: &'ast ast::Int) {
+        self.emit(match n.kind.width() {
+            Some(width) if n.kind.big_endian() => Instruction::Int(width, Endian::Big),
+            Some(width) => Instruction::Int(width, Endian::Little),
+            None => Instruction::Varint,
+        });
+    }
+
+    fn visit_take(&mut self, n: &'ast ast::Take) {
+        let id = self.push_string(&n.name);
+        self.emit(Instruction::Take(id));
+    }
+
+    fn visit_host(&mut self, n: &'ast ast::Host) {
+        let id = self.push_string(&n.name);
+        self.emit(Instruction::Host(id));
+    }
 }
 
 impl Default for Compiler {
@@ -480,13 +1740,127 @@ impl Default for Compiler {
     }
 }
 
+/// Turn the recovery expressions declared along with the messages of
+/// labels, e.g.: `label semi = "..." recover (!';' .)*`, into rules
+/// named after the labels, which is where the recovery of a label is
+/// looked up.  A label can't have both.
+fn add_label_recovery(grammar: &ast::Grammar) -> Result<ast::Grammar, Error> {
+    let mut labels = grammar.labels.clone();
+    let mut output = ast::Grammar::new(
+        grammar.span.clone(),
+        grammar.imports.clone(),
+        grammar.definition_names.clone(),
+        grammar.definitions.clone(),
+    )
+    .with_version(grammar.version.clone())
+    .with_comments(grammar.comments.clone());
+    for label in labels.iter_mut() {
+        let expr = match label.recover.take() {
+            Some(expr) => expr,
+            None => continue,
+        };
+        if grammar.definitions.contains_key(&label.name) {
+            return Err(Error::Semantic(format!(
+                "label `{}` at {} has a recovery expression and a rule named after it",
+                label.name, label.span.start
+            )));
+        }
+        output.add_definition(&ast::Definition::new(expr.span(), label.name.clone(), expr));
+    }
+    Ok(output.with_labels(labels))
+}
+
+/// Add recovery expressions for the labels thrown within definitions
+/// that have a synchronization expression set with `@sync(S)`.  Each
+/// label without a recovery expression of its own gets one that
+/// discards the input up to the synchronization point: `label <- (!S .)*`
+fn add_sync_recovery(grammar: &ast::Grammar) -> ast::Grammar {
+    let mut definitions = grammar.definitions.clone();
+    let mut definition_names = grammar.definition_names.clone();
+    for name in &grammar.definition_names {
+        let def = &grammar.definitions[name];
+        let sync = match &def.sync {
+            Some(sync) => sync,
+            None => continue,
+        };
+        let mut collector = LabelCollector::default();
+        collector.visit_expression(&def.expr);
+        for label in collector.labels {
+            if definitions.contains_key(label) {
+                continue;
+            }
+            let span = def.span.clone();
+            let skip = ast::ZeroOrMore::new_expr(
+                span.clone(),
+                Box::new(ast::Sequence::new_expr(
+                    span.clone(),
+                    vec![
+                        ast::Not::new_expr(span.clone(), Box::new(sync.clone())),
+                        ast::Any::new_expr(span.clone()),
+                    ],
+                )),
+            );
+            definitions.insert(
+                label.clone(),
+                ast::Definition::new(span, label.clone(), skip),
+            );
+            definition_names.push(label.clone());
+        }
+    }
+    ast::Grammar::new(
+        grammar.span.clone(),
+        grammar.imports.clone(),
+        definition_names,
+        definitions,
+    )
+    .with_version(grammar.version.clone())
+    .with_labels(grammar.labels.clone())
+}
+
 #[derive(Default)]
-struct DetectLeftRec<'a> {
+struct LabelCollector<'a> {
+    labels: Vec<&'a String>,
+}
+
+impl<'ast> Visitor<'ast> for LabelCollector<'ast> {
+    fn visit_label(&mut self, n: &'ast ast::Label) {
+        self.labels.push(&n.label);
+        self.visit_expression(&n.expr);
+    }
+}
+
+struct RepeatChecker<'a> {
+    rule: &'a str,
+    // copies of the expression being visited that the repetitions
+    // around it unroll into
+    copies: usize,
+    error: Option<Error>,
+}
+
+impl<'ast> Visitor<'ast> for RepeatChecker<'_> {
+    fn visit_repeat(&mut self, n: &'ast ast::Repeat) {
+        if self.error.is_some() {
+            return;
+        }
+        let count = n.max.unwrap_or(n.min).max(1);
+        let copies = self.copies.saturating_mul(count);
+        if copies > MAX_REPEAT_COPIES {
+            self.error = Some(Error::RepeatTooLarge(self.rule.to_string(), n.span.clone()));
+            return;
+        }
+        let outer = std::mem::replace(&mut self.copies, copies);
+        self.visit_expression(&n.expr);
+        self.copies = outer;
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct DetectLeftRec<'a> {
     stack: Vec<&'a str>,
 }
 
 impl<'a> DetectLeftRec<'a> {
-    fn run(
+    pub(crate) fn run(
         &mut self,
         node: &'a ast::Grammar,
         found: &mut HashMap<String, bool>,
@@ -510,7 +1884,7 @@ impl<'a> DetectLeftRec<'a> {
         expr: &'a ast::Expression,
         rules: &HashMap<&'a String, &'a ast::Expression>,
     ) -> Result<bool, Error> {
-        match expr {
+        stack::guard(|| match expr {
             ast::Expression::Identifier(n) => {
                 // for detecting mutual recursion
                 if !self.stack.is_empty() && self.stack[self.stack.len() - 1] == n.name {
@@ -520,12 +1894,10 @@ impl<'a> DetectLeftRec<'a> {
                     self.stack.push(&n.name);
                     let r = match rules.get(&n.name) {
                         Some(rule) => self.is_left_recursive(name, rule, rules)?,
-                        None => {
-                            return Err(Error::Semantic(format!(
-                                "Rule {:#?} not found in grammar",
-                                n.name
-                            )))
-                        }
+                        // rules that aren't found are defined by other
+                        // programs, and left recursion through them
+                        // isn't detected
+                        None => false,
                     };
                     self.stack.pop();
                     return Ok(r);
@@ -552,42 +1924,457 @@ impl<'a> DetectLeftRec<'a> {
             }
             ast::Expression::Precedence(n) => self.is_left_recursive(name, &n.expr, rules),
             _ => Ok(false),
-        }
+        })
     }
 }
 
 fn is_empty_possible(node: &ast::Expression) -> bool {
     matches!(
         node,
-        ast::Expression::ZeroOrMore(..) | ast::Expression::Optional(..)
+        ast::Expression::ZeroOrMore(..)
+            | ast::Expression::Optional(..)
+            | ast::Expression::Repeat(ast::Repeat { min: 0, .. })
     )
 }
 
-pub fn expand(grammar: &ast::Grammar) -> ast::Grammar {
-    let defs = grammar.definitions.values().map(expand_def).collect();
-    let def_names = grammar.definition_names.to_vec();
-    let imports = grammar.imports.to_vec();
-    ast::Grammar::new(grammar.span.clone(), imports, def_names, defs)
+/// Ranges of characters `expr` can start with when it matches.
+/// `None` means it's not known, either because `expr` can match
+/// without consuming input or because it can start with anything.
+fn first_set<'a>(
+    expr: &'a ast::Expression,
+    rules: &'a HashMap<String, ast::Expression>,
+    visiting: &mut Vec<&'a str>,
+) -> Option<Vec<(char, char)>> {
+    stack::guard(|| match expr {
+        ast::Expression::Literal(literal) => literal_first_set(literal),
+        ast::Expression::Sequence(seq) => first_set(seq.items.first()?, rules, visiting),
+        ast::Expression::Choice(choice) => {
+            let mut ranges = vec![];
+            for item in &choice.items {
+                ranges.extend(first_set(item, rules, visiting)?);
+            }
+            Some(ranges)
+        }
+        ast::Expression::Lex(n) => first_set(&n.expr, rules, visiting),
+        ast::Expression::OneOrMore(n) => first_set(&n.expr, rules, visiting),
+        ast::Expression::Repeat(n) if n.min > 0 => first_set(&n.expr, rules, visiting),
+        ast::Expression::Precedence(n) => first_set(&n.expr, rules, visiting),
+        ast::Expression::Identifier(n) => {
+            // left recursion, or a rule that's still being compiled
+            if visiting.contains(&n.name.as_str()) {
+                return None;
+            }
+            visiting.push(&n.name);
+            let ranges = first_set(rules.get(&n.name)?, rules, visiting);
+            visiting.pop();
+            ranges
+        }
+        // labels can recover without consuming input, and the other
+        // expressions can match without consuming input
+        _ => None,
+    })
+}
+
+fn literal_first_set(literal: &ast::Literal) -> Option<Vec<(char, char)>> {
+    match literal {
+        // the other cases of the characters aren't tracked
+        ast::Literal::String(s) if s.ignore_case => None,
+        ast::Literal::Class(class) if class.ignore_case => None,
+        ast::Literal::String(s) => {
+            let c = s.value.chars().next()?;
+            Some(vec![(c, c)])
+        }
+        ast::Literal::Char(c) => Some(vec![(c.value, c.value)]),
+        ast::Literal::Range(r) => Some(vec![(r.start, r.end)]),
+        ast::Literal::Class(class) if class.negated => {
+            Some(complement_ranges(&fold_ranges(class_ranges(class)?)))
+        }
+        ast::Literal::Class(class) => {
+            let mut ranges = vec![];
+            for literal in &class.literals {
+                ranges.extend(literal_first_set(literal)?);
+            }
+            Some(ranges)
+        }
+        ast::Literal::Any(_)
+        | ast::Literal::Int(_)
+        | ast::Literal::Take(_)
+        | ast::Literal::Host(_)
+        | ast::Literal::NamedClass(_)
+        | ast::Literal::Property(_) => None,
+    }
+}
+
+/// Ranges of characters matched by each item of `class`.  `None`
+/// means the class has items that aren't characters or ranges.
+fn class_ranges(class: &ast::Class) -> Option<Vec<(char, char)>> {
+    class
+        .literals
+        .iter()
+        .map(|literal| match literal {
+            ast::Literal::Char(c) => Some((c.value, c.value)),
+            ast::Literal::Range(r) => Some((r.start, r.end)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Sort `ranges` and merge the ones that overlap or are adjacent,
+/// so each character is covered by a single range of the output
+fn fold_ranges(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.sort();
+    let mut folded: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match folded.last_mut() {
+            Some(last) if start <= last.1 || next_char(last.1) == Some(start) => {
+                last.1 = last.1.max(end)
+            }
+            _ => folded.push((start, end)),
+        }
+    }
+    folded
+}
+
+/// Ranges of the Unicode scalar values that `ranges` don't cover.
+/// `ranges` must be sorted and can't overlap, like the output of
+/// `fold_ranges`.
+fn complement_ranges(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut complement = vec![];
+    let mut next = Some('\0');
+    for &(start, end) in ranges {
+        match next {
+            Some(n) if n < start => complement.push((n, prev_char(start))),
+            _ => {}
+        }
+        next = next_char(end);
+    }
+    if let Some(n) = next {
+        complement.push((n, char::MAX));
+    }
+    complement
+}
+
+/// Scalar value right after `c`, skipping the surrogates
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        c => char::from_u32(u32::from(c) + 1),
+    }
+}
+
+/// Scalar value right before `c`, skipping the surrogates.  `c`
+/// can't be the first one.
+fn prev_char(c: char) -> char {
+    match c {
+        '\u{E000}' => '\u{D7FF}',
+        c => char::from_u32(u32::from(c) - 1).unwrap_or('\0'),
+    }
+}
+
+fn overlaps(a: &[(char, char)], b: &[(char, char)]) -> bool {
+    a.iter()
+        .any(|(a0, a1)| b.iter().any(|(b0, b1)| a0 <= b1 && b0 <= a1))
+}
+
+// Replace named classes with the ranges of their providers
+struct ClassResolver<'a> {
+    providers: &'a ClassProviders,
+    // first class none of the providers define
+    error: Option<Error>,
+}
+
+impl Folder for ClassResolver<'_> {
+    fn fold_literal(&mut self, n: &ast::Literal) -> ast::Expression {
+        let class = match n {
+            ast::Literal::NamedClass(class) => class,
+            _ => return ast::Expression::Literal(n.clone()),
+        };
+        match self.providers.ranges(&class.name) {
+            Some(ranges) => {
+                let span = &class.span;
+                let literals = ranges
+                    .into_iter()
+                    .map(|(start, end)| {
+                        ast::Literal::Range(ast::Range::new(span.clone(), start, end))
+                    })
+                    .collect();
+                ast::Class::new_expr(span.clone(), literals)
+            }
+            None => {
+                if self.error.is_none() {
+                    self.error = Some(Error::UnknownClass(class.clone()));
+                }
+                ast::Expression::Literal(n.clone())
+            }
+        }
+    }
+}
+
+/// Replace the calls made within predicates to the rules of `grammar`
+/// that have at most `threshold` expressions with the rules
+/// themselves.  It runs after white space handling is injected, so
+/// the calls to `Spacing` are what they'd be without inlining.
+fn inline_rules(grammar: &ast::Grammar, threshold: usize) -> (ast::Grammar, Vec<Optimization>) {
+    let mut bodies = HashMap::new();
+    for (name, definition) in &grammar.definitions {
+        if definition.sync.is_some() || name == WHITE_SPACE_RULE_NAME {
+            continue;
+        }
+        let mut size = 0;
+        let mut inlinable = true;
+        lint::walk(&definition.expr, &mut |e| {
+            size += 1;
+            inlinable &= matches!(
+                e,
+                ast::Expression::Sequence(_)
+                    | ast::Expression::Choice(_)
+                    | ast::Expression::Optional(_)
+                    | ast::Expression::ZeroOrMore(_)
+                    | ast::Expression::OneOrMore(_)
+                    | ast::Expression::Repeat(_)
+                    | ast::Expression::Not(_)
+                    | ast::Expression::And(_)
+                    | ast::Expression::Lex(_)
+                    | ast::Expression::Empty(_)
+                    | ast::Expression::Literal(
+                        ast::Literal::String(_)
+                            | ast::Literal::Class(_)
+                            | ast::Literal::Range(_)
+                            | ast::Literal::Char(_)
+                            | ast::Literal::Any(_)
+                            | ast::Literal::Property(_)
+                    )
+            );
+        });
+        if inlinable && size <= threshold {
+            bodies.insert(name.as_str(), &definition.expr);
+        }
+    }
+    let mut inliner = RuleInliner {
+        bodies,
+        predicates: 0,
+        optimizations: vec![],
+    };
+    let inlined = inliner.fold_grammar(grammar);
+    (inlined, inliner.optimizations)
+}
+
+struct RuleInliner<'a> {
+    // expressions of the rules small enough to be inlined
+    bodies: HashMap<&'a str, &'a ast::Expression>,
+    // depth of the predicates being folded
+    predicates: usize,
+    optimizations: Vec<Optimization>,
+}
+
+impl Folder for RuleInliner<'_> {
+    fn fold_and(&mut self, n: &ast::And) -> ast::Expression {
+        self.predicates += 1;
+        let expr = self.fold_expression(&n.expr);
+        self.predicates -= 1;
+        ast::And::new_expr(n.span.clone(), Box::new(expr))
+    }
+
+    fn fold_not(&mut self, n: &ast::Not) -> ast::Expression {
+        self.predicates += 1;
+        let expr = self.fold_expression(&n.expr);
+        self.predicates -= 1;
+        ast::Not::new_expr(n.span.clone(), Box::new(expr))
+    }
+
+    // the precedence levels of left recursive calls are kept
+    fn fold_precedence(&mut self, n: &ast::Precedence) -> ast::Expression {
+        ast::Expression::Precedence(n.clone())
+    }
+
+    // calls outside of predicates are kept, as the node named after
+    // the rule is only made when the call returns
+    fn fold_identifier(&mut self, n: &ast::Identifier) -> ast::Expression {
+        match self.bodies.get(n.name.as_str()) {
+            Some(body) if self.predicates > 0 => {
+                self.optimizations.push(Optimization {
+                    span: n.span.clone(),
+                    message: format!("inlined `{}` within the predicate", n.name),
+                });
+                (*body).clone()
+            }
+            _ => ast::Expression::Identifier(n.clone()),
+        }
+    }
+}
+
+/// What `expand_with` does with a definition it can't wrap within a
+/// node
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpandMode {
+    /// Fail with `Error::Expand`
+    #[default]
+    Strict,
+    /// Keep the definition as it is.  The grammar may not match the
+    /// output of the original one, which is fine for tools that only
+    /// need a best effort, like editors and formatters.
+    Lenient,
+}
+
+/// Rewrite `grammar` into one that matches the values output by the
+/// original grammar.  Fails on the first definition that doesn't
+/// output a node of its own.
+pub fn expand(grammar: &ast::Grammar) -> Result<ast::Grammar, Error> {
+    expand_with(grammar, ExpandMode::Strict)
+}
+
+/// Same as `expand`, but `mode` decides what happens to definitions
+/// that can't be expanded
+pub fn expand_with(grammar: &ast::Grammar, mode: ExpandMode) -> Result<ast::Grammar, Error> {
+    let mut expander = Expander {
+        mode,
+        silent: lint::fixpoint(grammar, silent),
+        error: None,
+    };
+    let expanded = expander.fold_grammar(grammar);
+    match expander.error {
+        Some(err) => Err(err),
+        None => Ok(expanded),
+    }
+}
+
+// Wrap the expression of each definition within a node named after
+// the definition, so the grammar matches the output of the original
+struct Expander {
+    mode: ExpandMode,
+    // rules that never capture any values
+    silent: HashMap<String, bool>,
+    // first definition that couldn't be expanded
+    error: Option<Error>,
 }
 
-fn expand_def(def: &ast::Definition) -> (String, ast::Definition) {
-    (
-        def.name.clone(),
-        ast::Definition::new(
-            def.span.clone(),
-            def.name.clone(),
-            ast::Node::new_expr(
+impl Folder for Expander {
+    fn fold_definition(&mut self, def: &ast::Definition) -> ast::Definition {
+        // the virtual machine drops nodes without items, and the
+        // values captured by the white space rule
+        let reason = if def.name == WHITE_SPACE_RULE_NAME {
+            Some("values captured by the white space rule are dropped")
+        } else if self.silent[&def.name] {
+            Some("it never captures values, so no node is output for it")
+        } else {
+            None
+        };
+        match (reason, self.mode) {
+            (None, _) => ast::Definition::new(
                 def.span.clone(),
                 def.name.clone(),
-                Box::new(def.expr.clone()),
+                ast::Node::new_expr(
+                    def.span.clone(),
+                    def.name.clone(),
+                    Box::new(def.expr.clone()),
+                ),
             ),
-        ),
-    )
+            (Some(_), ExpandMode::Lenient) => def.clone(),
+            (Some(reason), ExpandMode::Strict) => {
+                if self.error.is_none() {
+                    self.error = Some(Error::Expand(def.name.clone(), reason.to_string()));
+                }
+                def.clone()
+            }
+        }
+    }
+}
+
+/// True if the expression can only succeed without capturing values
+fn silent(expr: &ast::Expression, rules: &HashMap<String, bool>) -> bool {
+    stack::guard(|| match expr {
+        ast::Expression::Sequence(n) => n.items.iter().all(|e| silent(e, rules)),
+        ast::Expression::Choice(n) => n.items.iter().all(|e| silent(e, rules)),
+        ast::Expression::Lex(n) => silent(&n.expr, rules),
+        ast::Expression::And(_) | ast::Expression::Not(_) => true,
+        ast::Expression::Optional(n) => silent(&n.expr, rules),
+        ast::Expression::ZeroOrMore(n) => silent(&n.expr, rules),
+        ast::Expression::OneOrMore(n) => silent(&n.expr, rules),
+        ast::Expression::Repeat(n) => silent(&n.expr, rules),
+        ast::Expression::Precedence(n) => silent(&n.expr, rules),
+        // recovering from the label captures an error
+        ast::Expression::Label(_) => false,
+        ast::Expression::List(_) | ast::Expression::Node(_) => false,
+        ast::Expression::Identifier(n) => {
+            n.name == WHITE_SPACE_RULE_NAME || rules.get(&n.name).copied().unwrap_or(false)
+        }
+        ast::Expression::Literal(_) => false,
+        ast::Expression::Empty(_) => true,
+    })
+}
+
+/// References to rules `grammar` doesn't define, in the order the
+/// rules that make them are defined
+pub(crate) fn unresolved_references(grammar: &ast::Grammar) -> Vec<UnresolvedReference> {
+    let mut references = vec![];
+    for name in &grammar.definition_names {
+        lint::walk(&grammar.definitions[name].expr, &mut |expr| {
+            if let ast::Expression::Identifier(n) = expr {
+                if !grammar.definitions.contains_key(&n.name) {
+                    let defined = grammar.definition_names.iter().map(|d| d.as_str());
+                    references.push(UnresolvedReference {
+                        name: n.name.clone(),
+                        referenced_from: name.clone(),
+                        span: n.span.clone(),
+                        suggestion: did_you_mean(&n.name, defined).map(|s| s.to_string()),
+                    });
+                }
+            }
+        });
+    }
+    references
+}
+
+/// Number of expressions within the definitions of `grammar`
+fn count_expressions(grammar: &ast::Grammar) -> usize {
+    let mut count = 0;
+    for definition in grammar.definitions.values() {
+        lint::walk(&definition.expr, &mut |_| count += 1);
+    }
+    count
+}
+
+/// Find the candidate closest to `name`, as long as it's close enough
+/// to be a typo: at most a third of the characters of `name` (and at
+/// least one) can be different.  Ties go to the first candidate.
+pub(crate) fn did_you_mean<'a, I>(name: &str, candidates: I) -> Option<&'a str>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let threshold = std::cmp::max(1, name.chars().count() / 3);
+    let mut closest = None;
+    for candidate in candidates {
+        let distance = edit_distance(name, candidate);
+        if distance > threshold {
+            continue;
+        }
+        match closest {
+            Some((_, d)) if d <= distance => {}
+            _ => closest = Some((candidate, distance)),
+        }
+    }
+    closest.map(|(candidate, _)| candidate)
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::xorshift::XorShift;
     use langlang_syntax::parser;
 
     fn assert_detectlr(input: &str, expected: HashMap<String, bool>) {
@@ -702,4 +2489,295 @@ mod tests {
             HashMap::from([("E".to_string(), true)]),
         );
     }
+
+    #[test]
+    fn report_passes() {
+        let grammar = parser::Parser::new("A <- 'a' B\nB <- 'b'\nSpacing <- ' '*")
+            .parse_grammar()
+            .unwrap();
+        let output = Compiler::new(Config::default())
+            .compile_with_warnings(&grammar, None)
+            .unwrap();
+        assert!(output.passes.is_empty());
+
+        let output = Compiler::new(Config::default().report_passes())
+            .compile_with_warnings(&grammar, None)
+            .unwrap();
+        let names: Vec<_> = output.passes.iter().map(|p| p.name).collect();
+        assert_eq!("add_label_recovery", names[0]);
+        assert_eq!("lint", names[names.len() - 1]);
+        let pass = |name: &str| output.passes.iter().find(|p| p.name == name).unwrap();
+        // whitespace handling adds calls to `Spacing` to the grammar,
+        // and code is only generated by `code_gen`
+        let injected = pass("inject_whitespace");
+        assert!(injected.expressions.1 > injected.expressions.0);
+        assert_eq!((0, 0), injected.instructions);
+        let code_gen = pass("code_gen");
+        assert_eq!((0, output.program.code.len()), code_gen.instructions);
+    }
+
+    // messages of the optimizations that start with `kind`
+    fn optimizations(input: &str, config: Config, kind: &str) -> Vec<String> {
+        let mut p = parser::Parser::new(input);
+        let grammar = p.parse_grammar().unwrap();
+        let output = Compiler::new(config)
+            .compile_with_warnings(&grammar, None)
+            .unwrap();
+        output
+            .optimizations
+            .iter()
+            .map(|o| o.message.clone())
+            .filter(|m| m.starts_with(kind))
+            .collect()
+    }
+
+    #[test]
+    fn early_commit_on_disjoint_alternatives() {
+        let config = Config::o1().disable_injecting_whitespace_handling();
+        assert_eq!(
+            vec![
+                "committed to the alternative once `\"a\"` matched, as the alternatives after it can't start with it",
+                "committed to the alternative once `[0-9]` matched, as the alternatives after it can't start with it",
+            ],
+            optimizations("A <- 'a' 'b' / [0-9] 'c' / B 'd'\nB <- 'x' / 'y'", config.clone(), "committed"),
+        );
+        // the next alternative could start with `a`
+        assert!(optimizations("A <- 'a' 'b' / 'ac'", config.clone(), "committed").is_empty());
+        assert!(optimizations("A <- 'a' 'b' / [a-c]", config.clone(), "committed").is_empty());
+        // what the next alternative starts with isn't known
+        assert!(optimizations("A <- 'a' 'b' / .", config.clone(), "committed").is_empty());
+        assert!(optimizations("A <- 'a' 'b' / 'c'? 'd'", config.clone(), "committed").is_empty());
+        // only done when optimizing
+        assert!(optimizations("A <- 'a' 'b' / 'c'", Config::o0(), "committed").is_empty());
+    }
+
+    #[test]
+    fn skip_alternatives_by_their_first_character() {
+        let config = Config::o1().disable_injecting_whitespace_handling();
+        assert_eq!(
+            vec![
+                "skipped the alternative without saving a backtrack entry when the next character isn't 'a'",
+                "skipped the alternative without saving a backtrack entry at the end of the input",
+            ],
+            optimizations("A <- 'a' 'b' / . 'c' / 'd'", config.clone(), "skipped"),
+        );
+        // the last alternative doesn't save the state of the VM
+        assert!(optimizations("A <- 'a'", config.clone(), "skipped").is_empty());
+        // strings longer than a character and case insensitive ones
+        // aren't checked upfront
+        assert!(optimizations("A <- 'ab' / 'c'", config.clone(), "skipped").is_empty());
+        assert!(optimizations("A <- 'a'i / 'c'", config.clone(), "skipped").is_empty());
+        // only done when optimizing
+        assert!(optimizations("A <- 'a' / 'c'", Config::o0(), "skipped").is_empty());
+    }
+
+    #[test]
+    fn inline_small_rules_within_predicates() {
+        let config = Config::o1()
+            .disable_injecting_whitespace_handling()
+            .with_inline_threshold(3);
+        assert_eq!(
+            vec!["inlined `Digit` within the predicate"],
+            optimizations(
+                "Number <- !Zero Digit+ / Zero\nDigit <- [0-9]\nZero <- '0' !Digit",
+                config.clone(),
+                "inlined",
+            ),
+        );
+        // calls outside of predicates keep their nodes
+        assert!(optimizations("A <- B 'x'\nB <- 'y'", config.clone(), "inlined").is_empty());
+        // rules bigger than the threshold aren't inlined
+        assert!(optimizations("A <- !B\nB <- 'a' 'b' 'c'", config.clone(), "inlined").is_empty());
+        // nor are the ones that call other rules or throw labels
+        assert!(optimizations("A <- !B\nB <- C\nC <- 'c'", config.clone(), "inlined").is_empty());
+        assert!(optimizations("A <- !B\nB <- 'b'^l", config.clone(), "inlined").is_empty());
+        // a threshold of zero disables inlining
+        assert!(optimizations(
+            "A <- !B\nB <- 'b'",
+            config.with_inline_threshold(0),
+            "inlined"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn remove_rules_that_are_never_used() {
+        let config = Config::o1()
+            .disable_injecting_whitespace_handling()
+            .remove_unused_rules();
+        assert_eq!(
+            vec!["removed `C`, which is never used"],
+            optimizations(
+                "A <- B^l\nB <- 'b'\nC <- 'c'\nl <- 'x'",
+                config.clone(),
+                "removed"
+            ),
+        );
+        let grammar = parser::parse("A <- 'a'\nB <- A 'b'").unwrap();
+        let program = Compiler::new(Config::o1().disable_injecting_whitespace_handling())
+            .compile(&grammar, Some("A"))
+            .unwrap();
+        assert!(program.to_string().contains("B:"));
+        let program = Compiler::new(config.clone())
+            .compile(&grammar, Some("A"))
+            .unwrap();
+        assert!(!program.to_string().contains("B:"));
+        // without a start rule, the first rule is the one kept
+        let program = Compiler::new(config.clone())
+            .compile(&grammar, None)
+            .unwrap();
+        assert!(!program.to_string().contains("B:"));
+        // and without rules, there's none to start from
+        let empty = parser::parse("").unwrap();
+        for config in [config.clone(), Config::o1()] {
+            let r = Compiler::new(config.clone()).compile(&empty, None);
+            assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+            let r = Compiler::new(config).compile(&grammar, Some("C"));
+            assert!(matches!(r, Err(Error::NotFound(_))), "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn expand_wraps_definitions_in_nodes() {
+        let grammar = parser::parse("A <- B 'x'\nB <- 'y'+").unwrap();
+        let expanded = expand(&grammar).unwrap();
+        assert_eq!("A <- A {B \"x\"}", expanded.definitions["A"].to_string());
+        assert_eq!("B <- B {\"y\"+}", expanded.definitions["B"].to_string());
+    }
+
+    #[test]
+    fn expand_rejects_definitions_without_nodes() {
+        let grammar = parser::parse("A <- B 'x' C\nB <- !'y' C?\nC <- &B").unwrap();
+        assert_eq!(
+            "Compiler Error[Expand]: rule `B`: it never captures values, so no node is output for it",
+            expand(&grammar).unwrap_err().to_string()
+        );
+
+        // lenient expansion keeps them as they are
+        let expanded = expand_with(&grammar, ExpandMode::Lenient).unwrap();
+        assert_eq!("A <- A {B \"x\" C}", expanded.definitions["A"].to_string());
+        assert_eq!("B <- !\"y\" C?", expanded.definitions["B"].to_string());
+        assert_eq!("C <- &B", expanded.definitions["C"].to_string());
+
+        let grammar = parser::parse("A <- 'a' Spacing\nSpacing <- ' '*").unwrap();
+        assert!(matches!(
+            expand(&grammar),
+            Err(Error::Expand(rule, _)) if rule == WHITE_SPACE_RULE_NAME
+        ));
+    }
+
+    #[test]
+    fn fold_ranges_merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(
+            vec![('0', '9'), ('a', 'z')],
+            fold_ranges(vec![('a', 'm'), ('0', '9'), ('n', 'z'), ('c', 'f')]),
+        );
+        assert_eq!(
+            vec![('a', 'c'), ('x', 'x')],
+            fold_ranges(vec![
+                ('x', 'x'),
+                ('b', 'b'),
+                ('a', 'a'),
+                ('c', 'c'),
+                ('x', 'x')
+            ]),
+        );
+        assert!(fold_ranges(vec![]).is_empty());
+    }
+
+    fn random_char(rng: &mut XorShift) -> char {
+        // half of the picks close to the edges that matter most
+        let edges = [0, 0x7f, 0xff, 0xd7ff, 0xe000, 0x10ffff];
+        let n = match rng.below(2) {
+            0 => {
+                let edge = edges[rng.below(edges.len())];
+                (edge + rng.below(5) as u32).saturating_sub(2)
+            }
+            _ => rng.below(0x110000) as u32,
+        };
+        char::from_u32(n).unwrap_or('\u{E000}')
+    }
+
+    #[test]
+    fn complement_ranges_cover_what_ranges_dont() {
+        let within =
+            |ranges: &[(char, char)], c: char| ranges.iter().any(|(a, b)| *a <= c && c <= *b);
+        let mut rng = XorShift::new(0x9e3779b97f4a7c15);
+        for _ in 0..500 {
+            let ranges: Vec<_> = (0..rng.below(6))
+                .map(|_| {
+                    let (a, b) = (random_char(&mut rng), random_char(&mut rng));
+                    (a.min(b), a.max(b))
+                })
+                .collect();
+            let folded = fold_ranges(ranges.clone());
+            let complement = complement_ranges(&folded);
+            for _ in 0..50 {
+                let c = random_char(&mut rng);
+                assert_eq!(
+                    within(&ranges, c),
+                    within(&folded, c),
+                    "{:?} {:?}",
+                    ranges,
+                    c
+                );
+                assert_eq!(
+                    !within(&ranges, c),
+                    within(&complement, c),
+                    "{:?} {:?}",
+                    ranges,
+                    c
+                );
+            }
+            // the output is canonical, so folding it changes nothing
+            assert_eq!(complement, fold_ranges(complement.clone()));
+        }
+
+        let all = vec![('\0', char::MAX)];
+        assert!(complement_ranges(&all).is_empty());
+        assert_eq!(all, complement_ranges(&[]));
+        assert_eq!(
+            vec![('\0', '\u{D7FE}'), ('\u{E001}', char::MAX)],
+            complement_ranges(&fold_ranges(vec![
+                ('\u{D7FF}', '\u{D7FF}'),
+                ('\u{E000}', '\u{E000}')
+            ]))
+        );
+    }
+
+    #[test]
+    fn classes_compile_into_class_instructions() {
+        let ast = parser::parse("A <- [a-z_] B [_a-z]\nB <- [^\\n] / [a-fx]i / [x]").unwrap();
+        let classes = |config: Config| -> Vec<String> {
+            let config = config.disable_injecting_whitespace_handling();
+            let program = Compiler::new(config).compile(&ast, None).unwrap();
+            program
+                .to_string()
+                .lines()
+                .filter_map(|l| l.split_once(" class ").map(|(_, c)| c.to_string()))
+                .collect()
+        };
+        // classes with a single range or that ignore case are still
+        // matched with `Span` and `Char` instructions
+        assert_eq!(
+            vec!["[_a-z]", "[_a-z]", "[\\0-\\t\\u{b}-\\u{10ffff}]"],
+            classes(Config::default())
+        );
+        assert!(classes(Config::o0()).is_empty());
+    }
+
+    #[test]
+    fn did_you_mean_picks_closest_rule() {
+        let rules = ["Identifier", "Identity", "Number"];
+        assert_eq!(
+            Some("Identifier"),
+            did_you_mean("Identifer", rules.iter().copied())
+        );
+        assert_eq!(
+            Some("Number"),
+            did_you_mean("number", rules.iter().copied())
+        );
+        assert_eq!(None, did_you_mean("String", rules.iter().copied()));
+        assert_eq!(3, edit_distance("kitten", "sitting"));
+    }
 }
@@ -1,2 +1,13 @@
 // Names of built-in productions used within this library
-pub(crate) const WHITE_SPACE_RULE_NAME: &str = "Spacing";
+pub(crate) use langlang_syntax::ast::WHITE_SPACE_RULE_NAME;
+#[cfg(feature = "pipeline")]
+pub(crate) const TOKEN_RULE_NAME: &str = "Token";
+pub(crate) const COMMENT_RULE_NAME: &str = "Comment";
+
+// Definitions added to every grammar by the import resolver and by
+// `compile`
+pub(crate) const BUILTINS: &str = include_str!("./builtins.peg");
+
+// Environment variable with the directories imports are searched in
+#[cfg(feature = "import")]
+pub(crate) const LANGLANG_PATH_VAR: &str = "LANGLANG_PATH";
@@ -0,0 +1,140 @@
+// coverage.rs --- find the parts of a grammar inputs never exercise
+//
+// While matching, the interpreter can record which rules matched and
+// which alternative of each choice matched.  Running a corpus of
+// example inputs through the same interpreter adds up what each one
+// of them exercised, and the report lists the rules and alternatives
+// none of them did.  Those are either gaps in the corpus or
+// candidates for simplifying the grammar.
+//
+// Alternatives are identified by the rule they're written in, the
+// span of their choice and their position within it, so the report
+// can be computed from the grammar as written, before the rewrites
+// applied by the compiler.
+//
+use std::collections::HashSet;
+
+use langlang_syntax::ast;
+use langlang_value::source_map::Span;
+
+use crate::lint;
+
+/// Rules and alternatives exercised by the inputs matched so far
+#[derive(Clone, Debug, Default)]
+pub struct Coverage {
+    rules: HashSet<String>,
+    alternatives: HashSet<(String, Span, usize)>,
+}
+
+impl Coverage {
+    /// Record that the rule `name` matched
+    pub(crate) fn rule(&mut self, name: &str) {
+        if !self.rules.contains(name) {
+            self.rules.insert(name.to_string());
+        }
+    }
+
+    /// Record that the alternative at `index` of the choice at `span`
+    /// within `rule` matched
+    pub(crate) fn alternative(&mut self, rule: &str, span: &Span, index: usize) {
+        self.alternatives
+            .insert((rule.to_string(), span.clone(), index));
+    }
+
+    /// Add what `other` exercised to what was exercised so far
+    pub(crate) fn merge(&mut self, other: Coverage) {
+        self.rules.extend(other.rules);
+        self.alternatives.extend(other.alternatives);
+    }
+
+    /// List the rules of `grammar` that never matched, and the
+    /// alternatives of the rules that did match but were never taken.
+    /// Rules that come from the builtins are never reported.
+    pub fn report(&self, grammar: &ast::Grammar) -> Report {
+        let builtins = lint::builtin_names();
+        let mut report = Report::default();
+        for name in &grammar.definition_names {
+            if builtins.contains(name) {
+                continue;
+            }
+            let def = &grammar.definitions[name];
+            report.rules.1 += 1;
+            let matched = self.rules.contains(name);
+            if matched {
+                report.rules.0 += 1;
+            } else {
+                report.gaps.push(Gap {
+                    kind: GapKind::Rule,
+                    rule: name.clone(),
+                    span: def.span.clone(),
+                    expr: name.clone(),
+                });
+            }
+            lint::walk(&def.expr, &mut |expr| {
+                let choice = match expr {
+                    ast::Expression::Choice(choice) => choice,
+                    _ => return,
+                };
+                for (i, item) in choice.items.iter().enumerate() {
+                    report.alternatives.1 += 1;
+                    let key = (name.clone(), choice.span.clone(), i);
+                    if self.alternatives.contains(&key) {
+                        report.alternatives.0 += 1;
+                    } else if matched {
+                        report.gaps.push(Gap {
+                            kind: GapKind::Alternative(i + 1),
+                            rule: name.clone(),
+                            span: item.span(),
+                            expr: item.to_string(),
+                        });
+                    }
+                }
+            });
+        }
+        report
+    }
+}
+
+/// What a corpus of inputs didn't exercise within a grammar
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub gaps: Vec<Gap>,
+    // how many rules were exercised, out of how many there are
+    pub rules: (usize, usize),
+    // how many alternatives were exercised, out of how many there are
+    pub alternatives: (usize, usize),
+}
+
+/// Part of a grammar no input exercised
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gap {
+    pub kind: GapKind,
+    // rule that wasn't exercised, or that contains the alternative
+    // that wasn't
+    pub rule: String,
+    pub span: Span,
+    // the alternative as written in the grammar
+    pub expr: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GapKind {
+    // The rule never matched
+    Rule,
+    // The alternative at the given position, starting from 1, was
+    // never taken, even though its rule matched
+    Alternative(usize),
+}
+
+impl std::fmt::Display for Gap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            GapKind::Rule => write!(f, "rule `{}` never matched", self.rule),
+            GapKind::Alternative(n) => write!(
+                f,
+                "alternative {} of `{}` never matched: `{}`",
+                n, self.rule, self.expr
+            ),
+        }
+    }
+}
@@ -0,0 +1,540 @@
+// diagnostic.rs --- errors and warnings in a machine-readable shape
+//
+// Every error and warning produced by the library can be converted
+// into a `Diagnostic`, which carries where the problem happened, a
+// stable code that identifies its kind, how severe it is, and a
+// message.  Diagnostics can be serialized as JSON so editors and CI
+// can annotate grammars without scraping human-oriented text, or, with
+// the `fancy` feature, rendered with the offending span underlined
+// within its source.
+//
+use langlang_syntax::{ast, parser};
+use langlang_value::source_map::{Position, Span};
+
+use crate::analysis::Problem;
+use crate::compiler::{self, Warning};
+#[cfg(feature = "interp")]
+use crate::coverage;
+#[cfg(feature = "import")]
+use crate::import;
+#[cfg(feature = "unparse")]
+use crate::unparse;
+use crate::{vm, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    // path of the grammar or of the input the diagnostic refers to
+    pub file: Option<String>,
+    // location within the file, when it's fully known
+    pub span: Option<Span>,
+    // offset within the file, for errors that only carry that
+    pub offset: Option<usize>,
+    pub code: String,
+    pub severity: Severity,
+    pub message: String,
+    // related information, like each one of the denied warnings
+    pub notes: Vec<String>,
+    // suggestion of how to fix the problem
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &str, message: String) -> Self {
+        Self {
+            file: None,
+            span: None,
+            offset: None,
+            code: code.to_string(),
+            severity,
+            message,
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    /// Generate a new diagnostic that refers to `file`
+    pub fn with_file(&self, file: &str) -> Self {
+        Self {
+            file: Some(file.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Generate a new diagnostic with a suggestion of how to fix it
+    pub fn with_help(&self, help: &str) -> Self {
+        Self {
+            help: Some(help.to_string()),
+            ..self.clone()
+        }
+    }
+
+    fn with_span(self, span: Span) -> Self {
+        Self {
+            offset: Some(span.start.offset),
+            span: Some(span),
+            ..self
+        }
+    }
+
+    fn with_position(self, position: &Position) -> Self {
+        self.with_span(Span::new(position.clone(), position.clone()))
+    }
+
+    /// Serialize the diagnostic as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let span = match &self.span {
+            Some(span) => format!(
+                "{{\"start\":{},\"end\":{}}}",
+                position_to_json(&span.start),
+                position_to_json(&span.end)
+            ),
+            None => "null".to_string(),
+        };
+        let notes: Vec<String> = self.notes.iter().map(|n| json_string(n)).collect();
+        format!(
+            "{{\"file\":{},\"span\":{},\"offset\":{},\"code\":{},\"severity\":{},\"message\":{},\"notes\":[{}],\"help\":{}}}",
+            match &self.file {
+                Some(file) => json_string(file),
+                None => "null".to_string(),
+            },
+            span,
+            match self.offset {
+                Some(offset) => offset.to_string(),
+                None => "null".to_string(),
+            },
+            json_string(&self.code),
+            json_string(self.severity.as_str()),
+            json_string(&self.message),
+            notes.join(","),
+            match &self.help {
+                Some(help) => json_string(help),
+                None => "null".to_string(),
+            },
+        )
+    }
+
+    /// Render the diagnostic along with the excerpt of `source` it
+    /// points at, underlining the span and listing notes and help.
+    /// Diagnostics without a location are rendered by `Display`.
+    #[cfg(feature = "fancy")]
+    pub fn render(&self, source: &str, color: bool) -> String {
+        use ariadne::{Config, Label, Report, ReportKind, Source};
+
+        let (start, end) = match (&self.span, self.offset) {
+            (Some(span), _) => (
+                span.start.offset,
+                span.end.offset.max(span.start.offset + 1),
+            ),
+            (None, Some(offset)) => (offset, offset + 1),
+            (None, None) => return format!("{}\n", self),
+        };
+        let file = self.file.clone().unwrap_or_else(|| "<input>".to_string());
+        let kind = match self.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+        };
+        let mut report = Report::build(kind, file.clone(), start)
+            .with_config(Config::default().with_color(color))
+            .with_code(&self.code)
+            .with_message(&self.message)
+            .with_label(Label::new((file.clone(), start..end)).with_message(&self.message));
+        for note in &self.notes {
+            report = report.with_note(note);
+        }
+        if let Some(help) = &self.help {
+            report = report.with_help(help);
+        }
+        let mut output = Vec::new();
+        match report
+            .finish()
+            .write((file, Source::from(source)), &mut output)
+        {
+            Ok(()) => String::from_utf8_lossy(&output).into_owned(),
+            Err(_) => format!("{}\n", self),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}[{}]: {}",
+            self.severity.as_str(),
+            self.code,
+            self.message
+        )?;
+        match (&self.file, &self.span, self.offset) {
+            (Some(file), Some(span), _) => write!(f, " at {}:{}", file, span.start)?,
+            (Some(file), None, Some(offset)) => write!(f, " at {} (offset {})", file, offset)?,
+            (Some(file), None, None) => write!(f, " at {}", file)?,
+            (None, Some(span), _) => write!(f, " at {}", span.start)?,
+            (None, None, Some(offset)) => write!(f, " at offset {}", offset)?,
+            (None, None, None) => {}
+        }
+        for note in &self.notes {
+            write!(f, "\n  note: {}", note)?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    /// Convert the error into a diagnostic with a stable code
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let error =
+            |code: &str, message: &str| Diagnostic::new(Severity::Error, code, message.to_string());
+        match self {
+            Error::ParserError(
+                parser::Error::BacktrackError(position, message)
+                | parser::Error::Fatal(position, message),
+            ) => {
+                error("syntax_error", message).with_position(position)
+            }
+            Error::ParserError(e) => error("syntax_error", &e.to_string()),
+            Error::CompilerError(e) => match e {
+                compiler::Error::NotFound(message) => with_suggestion(error, "not_found", message),
+                compiler::Error::Semantic(message) => with_suggestion(error, "semantic", message),
+                compiler::Error::UnresolvedReference(references) => {
+                    let mut d = error(
+                        "unresolved_reference",
+                        &format!(
+                            "{} reference(s) to rules that aren't defined",
+                            references.len()
+                        ),
+                    );
+                    d.notes = references.iter().map(|r| r.to_string()).collect();
+                    match references.first() {
+                        Some(r) => d.with_span(r.span.clone()),
+                        None => d,
+                    }
+                }
+                compiler::Error::Analysis(problems) => match &problems[..] {
+                    [problem] => problem.to_diagnostic(),
+                    _ => {
+                        let mut d = error(
+                            "analysis",
+                            &format!(
+                                "{} problem(s) make the grammar loop forever or never match",
+                                problems.len()
+                            ),
+                        );
+                        d.notes = problems.iter().map(|p| p.to_string()).collect();
+                        match problems.first() {
+                            Some(p) => d.with_span(p.span().clone()),
+                            None => d,
+                        }
+                    }
+                },
+                compiler::Error::EmptyRange(rule, range) => error(
+                    "empty_range",
+                    &format!(
+                        "range `{}` in `{}` is empty",
+                        ast::Literal::Range(range.clone()),
+                        rule
+                    ),
+                )
+                .with_span(range.span.clone())
+                .with_help("write the range from its lowest to its highest character"),
+                compiler::Error::RepeatTooLarge(rule, span) => error(
+                    "repeat_too_large",
+                    &format!(
+                        "repetition in `{}` unrolls into more than {} copies",
+                        rule,
+                        compiler::MAX_REPEAT_COPIES
+                    ),
+                )
+                .with_span(span.clone())
+                .with_help("use `*` or `+` for repetitions without a small bound"),
+                compiler::Error::UnknownClass(class) => error(
+                    "unknown_class",
+                    &format!("class `%{}` isn't provided", class.name),
+                )
+                .with_span(class.span.clone())
+                .with_help("register a class provider that defines it with the compiler"),
+                compiler::Error::UnknownProperty(property) => error(
+                    "unknown_property",
+                    &format!("`{}` isn't a known Unicode property", property),
+                )
+                .with_span(property.span.clone())
+                .with_help("use a general category, e.g.: `L` or `Nd`, a script, e.g.: `Greek`, or one of `Alphabetic`, `Lowercase`, `Uppercase` and `White_Space`"),
+                compiler::Error::Expand(rule, reason) => {
+                    error("expand", &format!("rule `{}`: {}", rule, reason))
+                }
+                compiler::Error::Lint(warnings) => {
+                    let mut d = error(
+                        "denied_warnings",
+                        &format!("{} warning(s) denied by their lint level", warnings.len()),
+                    );
+                    d.notes = warnings.iter().map(|w| w.to_string()).collect();
+                    match warnings.first() {
+                        Some(w) => d.with_span(w.span.clone()),
+                        None => d,
+                    }
+                }
+            },
+            #[cfg(feature = "import")]
+            Error::ImportError(e) => match e {
+                import::Error::NameError(message) => error("name_error", message),
+                import::Error::FileNotFound(message) => error("file_not_found", message),
+                import::Error::PermissionDenied(message) => error("permission_denied", message),
+                import::Error::OtherIOError(message) => error("io_error", message),
+                import::Error::InvalidArgument(message) => error("invalid_argument", message),
+                import::Error::ParsingError(message) => error("syntax_error", message),
+                import::Error::LockMismatch(message) => error("lock_mismatch", message),
+            },
+            Error::RuntimeError(e) => match e {
+                vm::Error::Matching(position, message) => {
+                    error("matching", message).with_position(position)
+                }
+                vm::Error::Label(label, message, cursor) => match message {
+                    Some(message) => error("label", message),
+                    None => error("label", &format!("label `{}` was thrown", label)),
+                }
+                .with_position(cursor),
+                vm::Error::InvalidProgram(address, message) => error(
+                    "invalid_program",
+                    &format!("{} (instruction {})", message, address),
+                ),
+                vm::Error::EOF => error("eof", "unexpected end of input"),
+                vm::Error::TooManyErrors(max, cursor) => error(
+                    "too_many_errors",
+                    &format!("gave up after recovering from {} errors", max),
+                )
+                .with_position(cursor),
+                vm::Error::Cancelled(cursor) => {
+                    error("cancelled", "matching was cancelled").with_position(cursor)
+                }
+                vm::Error::Read(reason) => error("read_error", reason),
+                vm::Error::RuleNotFound(name) => {
+                    error("rule_not_found", &format!("rule `{}` isn't defined", name))
+                }
+                vm::Error::ResourceLimit(limit, max, rule, cursor) => error(
+                    "resource_limit",
+                    &format!("{} of {} reached within `{}`", limit, max, rule),
+                )
+                .with_position(cursor),
+                vm::Error::Lossless(at) => error(
+                    "lossless",
+                    "tree doesn't cover the input it matched exactly once",
+                )
+                .with_position(at),
+                e => error("runtime", &format!("{:?}", e)),
+            },
+            #[cfg(feature = "unparse")]
+            Error::UnparseError(e) => match e {
+                unparse::Error::NotFound(message) => error("not_found", message),
+                unparse::Error::Mismatch(message) => error("mismatch", message),
+            },
+            Error::BytecodeError(e) => error("invalid_bytecode", &e.to_string()),
+            Error::IOError(e) => error("io_error", &e.to_string()),
+        }
+    }
+}
+
+impl Warning {
+    /// Convert the warning into a diagnostic.  The code of the
+    /// diagnostic is the same used to set the lint level.
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, self.code.as_str(), self.message.clone())
+            .with_span(self.span.clone())
+    }
+}
+
+impl Problem {
+    /// Convert the problem into an error that points at where it is
+    /// within the grammar
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let (code, help) = match self {
+            Problem::UnresolvedReference(r) => match &r.suggestion {
+                Some(s) => ("unresolved_reference", format!("did you mean `{}`?", s)),
+                None => ("unresolved_reference", format!("define the rule `{}`", r.name)),
+            },
+            Problem::UnguardedLeftRecursion(rule, _) => (
+                "unguarded_left_recursion",
+                format!(
+                    "add an alternative that doesn't start by calling `{}`, e.g.: `{} <- {} 'x' / 'x'`",
+                    rule, rule, rule
+                ),
+            ),
+            Problem::EmptyLoop(..) => (
+                "empty_loop",
+                "make sure the repeated expression consumes input whenever it matches".to_string(),
+            ),
+        };
+        Diagnostic::new(Severity::Error, code, self.to_string())
+            .with_span(self.span().clone())
+            .with_help(&help)
+    }
+}
+
+#[cfg(feature = "interp")]
+impl coverage::Gap {
+    /// Convert the gap into a warning that points at the rule or at
+    /// the alternative that wasn't exercised
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        let code = match self.kind {
+            coverage::GapKind::Rule => "unexercised_rule",
+            coverage::GapKind::Alternative(_) => "unexercised_alternative",
+        };
+        Diagnostic::new(Severity::Warning, code, self.to_string()).with_span(self.span.clone())
+    }
+}
+
+/// Render the line of `source` that `position` is in, numbered from
+/// one, with a caret under the char at `position`:
+///
+/// ```text
+/// 2 | B <- 'b
+///   |      ^
+/// ```
+pub fn snippet(source: &str, position: &Position) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    let offset = position.offset.min(chars.len());
+    let start = chars[..offset]
+        .iter()
+        .rposition(|c| *c == '\n')
+        .map_or(0, |i| i + 1);
+    let end = chars[offset..]
+        .iter()
+        .position(|c| *c == '\n')
+        .map_or(chars.len(), |i| offset + i);
+    let number = (chars[..start].iter().filter(|c| **c == '\n').count() + 1).to_string();
+    let gutter = " ".repeat(number.len());
+    let line: String = chars[start..end].iter().collect();
+    // tabs are kept so the caret lines up with the char above it
+    let indent: String = chars[start..offset]
+        .iter()
+        .map(|c| if *c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!("{} | {}\n{} | {}^\n", number, line, gutter, indent)
+}
+
+/// Move the "did you mean" suggestion appended to messages of
+/// undefined names into the help of the diagnostic
+fn with_suggestion<F>(error: F, code: &str, message: &str) -> Diagnostic
+where
+    F: Fn(&str, &str) -> Diagnostic,
+{
+    match message.split_once("; did you mean") {
+        Some((message, help)) => error(code, message).with_help(&format!("did you mean{}", help)),
+        None => error(code, message),
+    }
+}
+
+fn position_to_json(p: &Position) -> String {
+    format!(
+        "{{\"offset\":{},\"line\":{},\"column\":{}}}",
+        p.offset, p.line, p.column
+    )
+}
+
+fn json_string(s: &str) -> String {
+    let mut output = String::with_capacity(s.len() + 2);
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escapes_strings() {
+        assert_eq!("\"a\\\"b\\\\c\\nd\\u0001\"", json_string("a\"b\\c\nd\u{1}"));
+    }
+
+    #[test]
+    fn runtime_error_to_json() {
+        let position = Position::new(3, 0, 4);
+        let e = Error::RuntimeError(vm::Error::Matching(position, "expecting: 'a'".to_string()));
+        assert_eq!(
+            "{\"file\":\"in.txt\",\"span\":{\"start\":{\"offset\":3,\"line\":0,\"column\":4},\"end\":{\"offset\":3,\"line\":0,\"column\":4}},\"offset\":3,\"code\":\"matching\",\"severity\":\"error\",\"message\":\"expecting: 'a'\",\"notes\":[],\"help\":null}",
+            e.to_diagnostic().with_file("in.txt").to_json(),
+        );
+    }
+
+    #[test]
+    fn warning_to_json() {
+        let span = Span::new(Position::new(4, 1, 0), Position::new(12, 1, 8));
+        let w = Warning::new(
+            compiler::WarningCode::UnusedRule,
+            span,
+            "rule `B` is never used".to_string(),
+        );
+        assert_eq!(
+            "{\"file\":null,\"span\":{\"start\":{\"offset\":4,\"line\":1,\"column\":0},\"end\":{\"offset\":12,\"line\":1,\"column\":8}},\"offset\":4,\"code\":\"unused_rule\",\"severity\":\"warning\",\"message\":\"rule `B` is never used\",\"notes\":[],\"help\":null}",
+            w.to_diagnostic().to_json(),
+        );
+    }
+
+    #[test]
+    fn problem_to_diagnostic() {
+        let grammar = parser::parse("A <- A 'a'").unwrap();
+        let problems = crate::analysis::analyze(&grammar);
+        assert_eq!(
+            "error[unguarded_left_recursion]: rule `A` never matches, as each one of its alternatives calls it again first at 0:0\n  help: add an alternative that doesn't start by calling `A`, e.g.: `A <- A 'x' / 'x'`",
+            problems[0].to_diagnostic().to_string(),
+        );
+    }
+
+    #[test]
+    fn snippet_points_at_position() {
+        let source = "A <- B\nB <- 'b' ]\n";
+        let e: Error = parser::parse(source).unwrap_err().into();
+        assert_eq!(Some(Position::new(16, 1, 9)), e.position());
+        assert_eq!(
+            Some("2 | B <- 'b' ]\n  |          ^\n".to_string()),
+            e.snippet(source)
+        );
+
+        // tabs are kept so the caret lines up
+        let p = Position::new(2, 0, 2);
+        assert_eq!("1 | \ta b\n  | \t ^\n", snippet("\ta b", &p));
+    }
+
+    #[cfg(feature = "fancy")]
+    #[test]
+    fn render_underlines_span() {
+        let e = Error::RuntimeError(vm::Error::Matching(
+            Position::new(2, 0, 3),
+            "expecting: 'c'".to_string(),
+        ));
+        let output = e.to_diagnostic().with_file("in.txt").render("abd", false);
+        assert!(output.contains("[matching] Error: expecting: 'c'"));
+        assert!(output.contains("in.txt:1:3"));
+        assert!(output.contains("abd"));
+        assert!(output.contains("1 │ abd"));
+        assert!(output.contains("╰── expecting: 'c'"));
+    }
+}
@@ -1,11 +1,16 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 use langlang_syntax::visitor::Visitor;
 use langlang_syntax::{ast, parser};
 
+use crate::consts::LANGLANG_PATH_VAR;
+use crate::sha256;
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     NameError(String),
     FileNotFound(String),
@@ -13,6 +18,25 @@ pub enum Error {
     OtherIOError(String),
     InvalidArgument(String),
     ParsingError(String),
+    // An imported module doesn't match the hash in the lockfile
+    LockMismatch(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Import Error")?;
+        match self {
+            Error::NameError(msg) => write!(f, "[NameError]: {}", msg),
+            Error::FileNotFound(msg) => write!(f, "[FileNotFound]: {}", msg),
+            Error::PermissionDenied(msg) => write!(f, "[PermissionDenied]: {}", msg),
+            Error::OtherIOError(msg) => write!(f, "[OtherIOError]: {}", msg),
+            Error::InvalidArgument(msg) => write!(f, "[InvalidArgument]: {}", msg),
+            Error::ParsingError(msg) => write!(f, "[ParsingError]: {}", msg),
+            Error::LockMismatch(msg) => write!(f, "[LockMismatch]: {}", msg),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -38,21 +62,160 @@ pub trait ImportLoader {
     fn get_content(&self, path: &Path) -> Result<String, Error>;
 }
 
+/// Registry holds grammars that are available to imports by name,
+/// like the ones applications embed within their binaries.  Grammars
+/// registered as `json` are used by `@import Value from "json"`
+/// without touching the loader.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    grammars: HashMap<String, String>,
+}
+
+impl Registry {
+    pub fn register(&mut self, name: &str, source: &str) {
+        self.grammars.insert(name.to_string(), source.to_string());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.grammars.get(name).map(|s| s.as_str())
+    }
+}
+
+/// Lockfile maps the path of each module imported by a grammar to the
+/// hash of its content.  Resolving imports against a lockfile fails
+/// if any module changed since the lockfile was generated, so edits
+/// to shared modules can't silently change how a grammar parses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lockfile {
+    modules: BTreeMap<String, String>,
+}
+
+impl Lockfile {
+    /// Parse the output of `Lockfile::to_string`: one module per line,
+    /// with the hash followed by the path, and `#` starting comments
+    pub fn parse(input: &str) -> Result<Self, Error> {
+        let mut modules = BTreeMap::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(' ') {
+                Some((hash, path)) if hash.starts_with("sha256:") => {
+                    modules.insert(path.trim().to_string(), hash.to_string());
+                }
+                _ => {
+                    return Err(Error::InvalidArgument(format!(
+                        "Malformed lockfile entry at line {}: {}",
+                        i + 1,
+                        line
+                    )))
+                }
+            }
+        }
+        Ok(Self { modules })
+    }
+
+    pub fn hash(&self, path: &str) -> Option<&str> {
+        self.modules.get(path).map(|h| h.as_str())
+    }
+
+    /// Paths of the locked modules, in order
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(|p| p.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    fn insert(&mut self, path: &str, content: &str) {
+        self.modules.insert(path.to_string(), content_hash(content));
+    }
+
+    fn verify(&self, path: &str, content: &str) -> Result<(), Error> {
+        match self.modules.get(path) {
+            Some(hash) if *hash == content_hash(content) => Ok(()),
+            Some(hash) => Err(Error::LockMismatch(format!(
+                "{} changed since it was locked; expected {}, got {}",
+                path,
+                hash,
+                content_hash(content),
+            ))),
+            None => Err(Error::LockMismatch(format!(
+                "{} isn't in the lockfile",
+                path
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Lockfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "# generated by langlang; do not edit")?;
+        for (path, hash) in &self.modules {
+            writeln!(f, "{} {}", hash, path)?;
+        }
+        Ok(())
+    }
+}
+
+fn content_hash(content: &str) -> String {
+    format!("sha256:{}", sha256::hex_digest(content.as_bytes()))
+}
+
 pub struct ImportResolver<T: ImportLoader> {
     loader: T,
+    registry: Registry,
+    lockfile: Option<Lockfile>,
+    // modules loaded by the last call to `resolve`
+    loaded: RefCell<Lockfile>,
+    // modules whose imports are being resolved, used to stop at
+    // modules that import each other
+    resolving: RefCell<Vec<PathBuf>>,
 }
 
 impl<T: ImportLoader> ImportResolver<T> {
     pub fn new(loader: T) -> Self {
-        Self { loader }
+        Self::new_with_registry(loader, Registry::default())
+    }
+
+    /// Create a resolver that looks imports up in `registry` before
+    /// asking the loader for them
+    pub fn new_with_registry(loader: T, registry: Registry) -> Self {
+        Self {
+            loader,
+            registry,
+            lockfile: None,
+            loaded: RefCell::new(Lockfile::default()),
+            resolving: RefCell::new(vec![]),
+        }
+    }
+
+    /// Generate a new resolver that fails to import modules that
+    /// aren't in `lockfile` or that don't match their locked hash
+    pub fn with_lockfile(self, lockfile: Lockfile) -> Self {
+        Self {
+            lockfile: Some(lockfile),
+            ..self
+        }
+    }
+
+    /// Lockfile with the hashes of all the modules imported by the
+    /// last call to `resolve`
+    pub fn lockfile(&self) -> Lockfile {
+        self.loaded.borrow().clone()
     }
 
     pub fn resolve(&self, source: &Path) -> Result<ast::Grammar, Error> {
+        *self.loaded.borrow_mut() = Lockfile::default();
+        self.resolving.borrow_mut().clear();
         let mut r = self.resolve_import(source, source)?;
-        let builtins = parser::parse(include_str!("./builtins.peg"))?;
-        for def in builtins.definitions.values() {
-            r.grammar.add_definition(def);
-        }
+        crate::add_builtins(&mut r.grammar)?;
         Ok(r.grammar)
     }
 
@@ -62,35 +225,79 @@ impl<T: ImportLoader> ImportResolver<T> {
         parent_path: &'a Path,
     ) -> Result<ImporterResolverFrame, Error> {
         let mut frame = self.create_frame(import_path, parent_path)?;
-        let imports = frame.grammar.imports.to_owned();
+        let imports = std::mem::take(&mut frame.grammar.imports);
 
-        for import_node in &imports {
+        // a module that imports one of the modules importing it gets
+        // the definitions of that module as they're written.  The
+        // ones it imports get added by the module that's already
+        // resolving them.
+        if self.resolving.borrow().contains(&frame.import_path) {
+            return Ok(frame);
+        }
+        self.resolving.borrow_mut().push(frame.import_path.clone());
+        let r = self.resolve_imports(&mut frame, &imports);
+        self.resolving.borrow_mut().pop();
+        r.map(|_| frame)
+    }
+
+    fn resolve_imports(
+        &self,
+        frame: &mut ImporterResolverFrame,
+        imports: &[ast::Import],
+    ) -> Result<(), Error> {
+        for import_node in imports {
             let import_node_path = Path::new(&import_node.path);
-            let imported_frame = self.resolve_import(import_node_path, &frame.import_path)?;
+            let mut imported_frame = self.resolve_import(import_node_path, &frame.import_path)?;
 
-            for name in &import_node.names {
-                match imported_frame.grammar.definitions.get(name) {
+            // `@import "path"` brings all the rules of the module in,
+            // but the ones the importer writes itself
+            let names = match import_node.names.is_empty() {
+                true => imported_frame
+                    .grammar
+                    .definition_names
+                    .iter()
+                    .filter(|name| !frame.written.contains(name))
+                    .cloned()
+                    .collect(),
+                false => import_node.names.clone(),
+            };
+            for name in &names {
+                let imported_def = match imported_frame.grammar.definitions.get(name) {
+                    Some(def) => def,
                     None => {
                         return Err(Error::NameError(format!(
                             "{} does not provide {}",
                             import_node.path, name,
                         )))
                     }
-                    Some(imported_def) => {
-                        // Add the imported definition to the parent frame's grammar and
-                        // find all definitions that the imported definition depend on
-                        frame.grammar.add_definition(imported_def);
-                        for dep in imported_frame.find_definition_deps(imported_def) {
-                            frame.grammar.add_definition(dep);
-                        }
-                    }
+                };
+                if frame.defines_differently(imported_def) {
+                    return Err(Error::NameError(format!(
+                        "{} from {} is already defined differently",
+                        name, import_node.path,
+                    )));
                 }
             }
-        }
+            imported_frame.rename_colliding_deps(frame, &names, &import_node.path)?;
 
-        frame.grammar.imports = vec![];
-
-        Ok(frame)
+            for name in &names {
+                // Add the imported definition to the parent frame's grammar and
+                // find all definitions that the imported definition depend on
+                let imported_def = &imported_frame.grammar.definitions[name];
+                frame.grammar.add_definition(imported_def);
+                for dep in imported_frame.find_definition_deps(imported_def) {
+                    frame.grammar.add_definition(dep);
+                }
+            }
+            // messages of labels come along with the definitions
+            // that may throw them, unless the importer has its own
+            for label in &imported_frame.grammar.labels {
+                if !frame.grammar.labels.iter().any(|l| l.name == label.name) {
+                    frame.grammar.labels.push(label.clone());
+                }
+            }
+        }
+        Ok(())
     }
 
     fn create_frame<'a>(
@@ -98,11 +305,31 @@ impl<T: ImportLoader> ImportResolver<T> {
         import_path: &'a Path,
         parent_path: &'a Path,
     ) -> Result<ImporterResolverFrame, Error> {
-        let import_path = self.loader.get_path(import_path, parent_path)?;
-        let grammar_str = self.loader.get_content(&import_path)?;
+        // The root grammar is the only one imported from itself
+        let is_root = import_path == parent_path;
+        let registered = match import_path.to_str() {
+            Some(name) if !is_root => self.registry.get(name),
+            _ => None,
+        };
+        let (import_path, grammar_str) = match registered {
+            Some(source) => (import_path.to_path_buf(), source.to_string()),
+            None => {
+                let import_path = self.loader.get_path(import_path, parent_path)?;
+                let grammar_str = self.loader.get_content(&import_path)?;
+                (import_path, grammar_str)
+            }
+        };
+        if !is_root {
+            let key = import_path.display().to_string();
+            if let Some(lockfile) = &self.lockfile {
+                lockfile.verify(&key, &grammar_str)?;
+            }
+            self.loaded.borrow_mut().insert(&key, &grammar_str);
+        }
         let grammar = parser::parse(&grammar_str)?;
         Ok(ImporterResolverFrame {
             import_path,
+            written: grammar.definition_names.clone(),
             grammar,
         })
     }
@@ -111,6 +338,8 @@ impl<T: ImportLoader> ImportResolver<T> {
 struct ImporterResolverFrame {
     import_path: PathBuf,
     grammar: ast::Grammar,
+    // rules written within the module, as opposed to imported
+    written: Vec<String>,
 }
 
 impl ImporterResolverFrame {
@@ -119,6 +348,73 @@ impl ImporterResolverFrame {
         f.visit_definition(def);
         f.deps.into_values().collect()
     }
+
+    /// True if the grammar has a rule named after `def` that isn't
+    /// written the same way
+    fn defines_differently(&self, def: &ast::Definition) -> bool {
+        self.grammar
+            .definitions
+            .get(&def.name)
+            .is_some_and(|d| d.to_string() != def.to_string())
+    }
+
+    /// Rename the dependencies of the rules `names` that other modules
+    /// imported by `importer` define differently, so both can be
+    /// used.  They're prefixed with the name of their module, e.g.:
+    /// `Digit` from "./num.peg" becomes `num_Digit`.  The rules the
+    /// importer writes itself replace the dependencies named after
+    /// them instead.
+    fn rename_colliding_deps(
+        &mut self,
+        importer: &ImporterResolverFrame,
+        names: &[String],
+        module: &str,
+    ) -> Result<(), Error> {
+        let mut colliding = vec![];
+        for name in names {
+            let def = &self.grammar.definitions[name];
+            for dep in self.find_definition_deps(def) {
+                let collides = !names.contains(&dep.name)
+                    && !importer.written.contains(&dep.name)
+                    && importer.defines_differently(dep);
+                if collides && !colliding.contains(&dep.name) {
+                    colliding.push(dep.name.clone());
+                }
+            }
+        }
+        let prefix = module_prefix(module);
+        for old in colliding {
+            let base = format!("{}_{}", prefix, old);
+            let mut new = base.clone();
+            let mut n = 1;
+            while self.grammar.definitions.contains_key(&new)
+                || importer.grammar.definitions.contains_key(&new)
+            {
+                n += 1;
+                new = format!("{}{}", base, n);
+            }
+            ast::rename_rule(&mut self.grammar, &old, &new)
+                .map_err(|e| Error::NameError(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the module at `path` usable within rule names, which is
+/// its file name without the extension
+fn module_prefix(path: &str) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(path);
+    let prefix: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    match prefix.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => prefix,
+        _ => format!("_{}", prefix),
+    }
 }
 
 struct DepFinder<'ast> {
@@ -137,15 +433,18 @@ impl<'ast> DepFinder<'ast> {
 
 impl<'ast> Visitor<'ast> for DepFinder<'ast> {
     fn visit_identifier(&mut self, n: &'ast ast::Identifier) {
-        if self.deps.get(&n.name).is_none() {
-            let def = &self.grammar.definitions[&n.name];
+        if self.deps.contains_key(&n.name) {
+            return;
+        }
+        // rules of modules that import each other may not be there
+        if let Some(def) = self.grammar.definitions.get(&n.name) {
             self.deps.insert(&n.name, def);
             self.visit_definition(def);
         }
     }
 
     fn visit_label(&mut self, n: &'ast ast::Label) {
-        if self.deps.get(&n.label).is_none() {
+        if !self.deps.contains_key(&n.label) {
             if let Some(def) = self.grammar.definitions.get(&n.label) {
                 self.deps.insert(&n.label, def);
                 self.visit_definition(def);
@@ -187,6 +486,121 @@ impl ImportLoader for RelativeImportLoader {
     }
 }
 
+/// FileSystem is where loaders read grammar modules from.  Embedders
+/// can implement it to serve modules from memory, archives or
+/// databases instead of the disk.
+pub trait FileSystem {
+    fn exists(&self, path: &Path) -> bool;
+    fn read_to_string(&self, path: &Path) -> Result<String, Error>;
+}
+
+/// The file system of the operating system
+#[derive(Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String, Error> {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+/// File system made of files kept in memory
+#[derive(Default)]
+pub struct MemoryFileSystem {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryFileSystem {
+    pub fn add_file(&mut self, path: &Path, content: &str) {
+        self.files.insert(path.to_path_buf(), content.to_string());
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<String, Error> {
+        match self.files.get(path) {
+            Some(content) => Ok(content.clone()),
+            None => Err(Error::FileNotFound(format!(
+                "No such file: {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// SearchPathImportLoader resolves imports that start with `./` or
+/// `../` relative to the importing file, and any other import by
+/// looking it up within each one of the search paths, in the order
+/// they were added.
+pub struct SearchPathImportLoader<F: FileSystem> {
+    fs: F,
+    search_paths: Vec<PathBuf>,
+}
+
+impl<F: FileSystem> SearchPathImportLoader<F> {
+    pub fn new(fs: F) -> Self {
+        Self {
+            fs,
+            search_paths: vec![],
+        }
+    }
+
+    pub fn add_search_path(&mut self, dir: &Path) {
+        self.search_paths.push(dir.to_path_buf());
+    }
+
+    /// Add the directories listed in the `LANGLANG_PATH` environment
+    /// variable, separated the same way as in `PATH`
+    pub fn add_env_search_paths(&mut self) {
+        if let Some(paths) = std::env::var_os(LANGLANG_PATH_VAR) {
+            for dir in std::env::split_paths(&paths) {
+                self.search_paths.push(dir);
+            }
+        }
+    }
+}
+
+impl<F: FileSystem> ImportLoader for SearchPathImportLoader<F> {
+    fn get_path(&self, import_path: &Path, parent_path: &Path) -> Result<PathBuf, Error> {
+        if import_path == parent_path {
+            // Root node handling
+            return Ok(import_path.to_path_buf());
+        }
+        if import_path.starts_with(".") || import_path.starts_with("..") {
+            let base_path = parent_path.parent().unwrap_or_else(|| Path::new(""));
+            let relative_path = import_path.strip_prefix(".").unwrap_or(import_path);
+            return Ok(base_path.join(relative_path));
+        }
+        for dir in &self.search_paths {
+            let path = dir.join(import_path);
+            if self.fs.exists(&path) {
+                return Ok(path);
+            }
+        }
+        Err(Error::FileNotFound(format!(
+            "{} not found in search paths: [{}]",
+            import_path.display(),
+            self.search_paths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )))
+    }
+
+    fn get_content(&self, path: &Path) -> Result<String, Error> {
+        self.fs.read_to_string(path)
+    }
+}
+
 #[derive(Default)]
 pub struct InMemoryImportLoader<'a> {
     grammars: HashMap<&'a str, &'a str>,
@@ -0,0 +1,1049 @@
+// interp.rs --- match grammars by walking their syntax tree
+//
+// The interpreter matches input against a grammar straight from its
+// AST, without compiling it into a program for the virtual machine.
+// It's slow, but each expression is matched by a few obvious lines
+// of code, which makes it a reference that the output of the
+// compiler and of the virtual machine can be checked against, and a
+// fallback for where the virtual machine can't be used.  It builds
+// the same values the virtual machine does, recovers from the same
+// labels and takes the same `vm::Config`, so it runs the grammar
+// after the same rewrites the compiler applies to it.  Only text
+// input is supported.
+//
+// Left recursion is handled with bounded left recursion, as
+// described by Medeiros et al. in "Left Recursion in Parsing
+// Expression Grammars": a left recursive call fails at first, then
+// the rule is matched again and again, each time using the previous
+// (longer) match as the result of the left recursive call, until the
+// match stops growing.
+//
+use std::collections::HashMap;
+
+use langlang_syntax::ast::{self, IsSyntactic};
+use langlang_value::source_map::{Position, Span};
+use langlang_value::value::{self, RuleId, Value};
+
+use crate::casefold;
+use crate::compiler::{self, Compiler};
+use crate::consts::WHITE_SPACE_RULE_NAME;
+use crate::coverage::Coverage;
+use crate::properties;
+use crate::vm::{self, Error, RuleEvent};
+
+/// Match grammars without compiling them first
+pub trait MatchInterpreted {
+    /// Match `input` against the first rule with the default options
+    /// of both the compiler and the virtual machine
+    fn match_interpreted(&self, input: &str) -> Result<Option<Value>, crate::Error>;
+}
+
+impl MatchInterpreted for ast::Grammar {
+    fn match_interpreted(&self, input: &str) -> Result<Option<Value>, crate::Error> {
+        let mut interp = Interpreter::new(
+            self,
+            None,
+            &compiler::Config::default(),
+            vm::Config::default(),
+        )?;
+        Ok(interp.run(input)?)
+    }
+}
+
+pub struct Interpreter {
+    // grammar after the rewrites done by the compiler
+    grammar: ast::Grammar,
+    // which rules are left recursive
+    left_rec: HashMap<String, bool>,
+    // rule matching starts from
+    main: String,
+    config: vm::Config,
+    // productions entered and exited by the last run
+    events: Vec<RuleEvent>,
+    // rules and alternatives exercised by all the runs, if enabled
+    coverage: Option<Coverage>,
+}
+
+impl Interpreter {
+    /// Prepare `grammar` to be matched from the rule `main`, or from
+    /// its first rule.  The rewrites set in `compiler_config`, like
+    /// white space handling, are applied to `grammar` the same way
+    /// the compiler would apply them.
+    pub fn new(
+        grammar: &ast::Grammar,
+        main: Option<&str>,
+        compiler_config: &compiler::Config,
+        config: vm::Config,
+    ) -> Result<Self, compiler::Error> {
+        let (grammar, left_rec) = Compiler::new(compiler_config.clone()).prepare(grammar)?;
+        let main = match main {
+            Some(main) if grammar.definitions.contains_key(main) => main.to_string(),
+            Some(main) => {
+                return Err(compiler::Error::NotFound(format!(
+                    "Production {:?} doesnt exist",
+                    main
+                )))
+            }
+            None => match grammar.definition_names.first() {
+                Some(main) => main.clone(),
+                None => return Err(compiler::Error::NotFound("Grammar is empty".to_string())),
+            },
+        };
+        Ok(Self {
+            grammar,
+            left_rec,
+            main,
+            config,
+            events: vec![],
+            coverage: None,
+        })
+    }
+
+    /// Record the rules and alternatives exercised by each run,
+    /// adding up what all of them exercised.  See [`Coverage`].
+    pub fn with_coverage(self) -> Self {
+        Self {
+            coverage: Some(Coverage::default()),
+            ..self
+        }
+    }
+
+    /// Match the text in `input`
+    pub fn run(&mut self, input: &str) -> Result<Option<Value>, Error> {
+        let mut m = Match::new(self, input);
+        let mut captures = vec![];
+        let precedence = usize::from(m.is_left_recursive(&self.main));
+        let result = m.call(&self.main, precedence, &mut captures);
+        let ffp = m.position(m.ffp);
+        let (events, coverage) = (m.events, m.coverage);
+        self.events = events;
+        if let (Some(total), Some(coverage)) = (&mut self.coverage, coverage) {
+            total.merge(coverage);
+        }
+        if result? {
+            Ok(captures.pop())
+        } else {
+            Err(Error::Matching(ffp, "Unexpected input".to_string()))
+        }
+    }
+
+    /// Productions entered and exited by the last run.  Only recorded
+    /// if enabled with `vm::Config::with_rule_events`.
+    pub fn events(&self) -> &[RuleEvent] {
+        &self.events
+    }
+
+    /// Rules and alternatives exercised by the runs so far.  Only
+    /// recorded if enabled with [`Interpreter::with_coverage`].
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+}
+
+// Where a left recursive match ended and the values it captured
+type LeftRecResult = Option<(usize, Vec<Value>)>;
+
+/// State of matching one input
+struct Match<'a> {
+    interp: &'a Interpreter,
+    chars: Vec<char>,
+    // offset of the first char of each line
+    lines: Vec<usize>,
+    cursor: usize,
+    // farther failure position
+    ffp: usize,
+    // how many predicates are being matched
+    predicates: usize,
+    // how many labels were recovered from
+    errors: usize,
+    // names of the rules being matched
+    rules: Vec<String>,
+    events: Vec<RuleEvent>,
+    coverage: Option<Coverage>,
+    // results of left recursive rules keyed by rule name and input
+    // position: where the match ended, what it captured, and the
+    // precedence it was matched with.  A `None` result is a failure.
+    memo: HashMap<(String, usize), (LeftRecResult, usize)>,
+}
+
+impl<'a> Match<'a> {
+    fn new(interp: &'a Interpreter, input: &str) -> Self {
+        let chars: Vec<char> = input.chars().collect();
+        let mut lines = vec![0];
+        for (i, c) in chars.iter().enumerate() {
+            if *c == '\n' {
+                lines.push(i + 1);
+            }
+        }
+        Self {
+            interp,
+            chars,
+            lines,
+            cursor: 0,
+            ffp: 0,
+            predicates: 0,
+            errors: 0,
+            rules: vec![],
+            events: vec![],
+            coverage: interp.coverage.as_ref().map(|_| Coverage::default()),
+            memo: HashMap::new(),
+        }
+    }
+
+    /// Match `expr` at the cursor, appending the values it captures
+    /// to `captures`.  When `expr` doesn't match, the cursor and the
+    /// captures are left wherever the match stopped, and it's up to
+    /// the expressions that backtrack to put them back in place.
+    fn eval(&mut self, expr: &ast::Expression, captures: &mut Vec<Value>) -> Result<bool, Error> {
+        match expr {
+            ast::Expression::Sequence(n) => {
+                for item in &n.items {
+                    if !self.eval(item, captures)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            ast::Expression::Choice(n) => {
+                for (i, item) in n.items.iter().enumerate() {
+                    if self.attempt(item, captures)? {
+                        if let (Some(coverage), Some(rule)) =
+                            (&mut self.coverage, self.rules.last())
+                        {
+                            coverage.alternative(rule, &n.span, i);
+                        }
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            ast::Expression::Lex(n) => self.eval(&n.expr, captures),
+            ast::Expression::And(n) => self.predicate(&n.expr, true),
+            ast::Expression::Not(n) => self.predicate(&n.expr, false),
+            ast::Expression::Optional(n) => {
+                self.attempt(&n.expr, captures)?;
+                Ok(true)
+            }
+            ast::Expression::ZeroOrMore(n) => {
+                self.repeat(&n.expr, captures)?;
+                Ok(true)
+            }
+            ast::Expression::OneOrMore(n) => {
+                if !self.eval(&n.expr, captures)? {
+                    return Ok(false);
+                }
+                self.repeat(&n.expr, captures)?;
+                Ok(true)
+            }
+            ast::Expression::Repeat(n) => {
+                for _ in 0..n.min {
+                    if !self.eval(&n.expr, captures)? {
+                        return Ok(false);
+                    }
+                }
+                match n.max {
+                    None => self.repeat(&n.expr, captures)?,
+                    Some(max) => {
+                        for _ in n.min..max {
+                            if !self.attempt(&n.expr, captures)? {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok(true)
+            }
+            ast::Expression::Precedence(n) => match &*n.expr {
+                ast::Expression::Identifier(id) => self.call(&id.name, n.precedence, captures),
+                _ => Err(Error::InvalidProgram(
+                    self.cursor,
+                    "Precedence only works on Identifiers".to_string(),
+                )),
+            },
+            ast::Expression::Label(n) => {
+                if self.attempt(&n.expr, captures)? {
+                    return Ok(true);
+                }
+                self.throw(&n.label, captures)
+            }
+            ast::Expression::Identifier(n) => {
+                let precedence = usize::from(self.is_left_recursive(&n.name));
+                self.call(&n.name, precedence, captures)
+            }
+            ast::Expression::Literal(n) => self.literal(n, false, captures),
+            ast::Expression::Empty(_) => Ok(true),
+            ast::Expression::List(_) | ast::Expression::Node(_) => Err(Error::InvalidProgram(
+                self.cursor,
+                "Lists and nodes can only match structured input".to_string(),
+            )),
+        }
+    }
+
+    /// Match `expr`, putting the cursor and the captures back where
+    /// they were if it doesn't match
+    fn attempt(
+        &mut self,
+        expr: &ast::Expression,
+        captures: &mut Vec<Value>,
+    ) -> Result<bool, Error> {
+        let (cursor, len) = (self.cursor, captures.len());
+        if self.eval(expr, captures)? {
+            return Ok(true);
+        }
+        self.cursor = cursor;
+        captures.truncate(len);
+        Ok(false)
+    }
+
+    /// Match `expr` as many times as possible
+    fn repeat(&mut self, expr: &ast::Expression, captures: &mut Vec<Value>) -> Result<(), Error> {
+        loop {
+            let cursor = self.cursor;
+            if !self.attempt(expr, captures)? {
+                return Ok(());
+            }
+            // the virtual machine would repeat this iteration forever
+            if self.cursor == cursor {
+                let empty = RuleEvent::EmptyIteration {
+                    rule: self.rules.last().cloned().unwrap_or_default(),
+                    cursor,
+                };
+                return self.interp.config.record(&mut self.events, empty);
+            }
+        }
+    }
+
+    /// Match `expr` without consuming input nor capturing values
+    fn predicate(&mut self, expr: &ast::Expression, expected: bool) -> Result<bool, Error> {
+        let cursor = self.cursor;
+        self.predicates += 1;
+        let matched = self.eval(expr, &mut vec![]);
+        self.predicates -= 1;
+        self.cursor = cursor;
+        Ok(matched? == expected)
+    }
+
+    /// Call the recovery expression of `label`, or stop matching if
+    /// it doesn't have one.  Labels thrown within predicates just
+    /// make the predicate fail.
+    fn throw(&mut self, label: &str, captures: &mut Vec<Value>) -> Result<bool, Error> {
+        if self.predicates > 0 {
+            return Ok(false);
+        }
+        if !self.interp.grammar.definitions.contains_key(label)
+            || !self
+                .interp
+                .config
+                .on_error()
+                .recovers(self.errors, self.position(self.cursor))?
+        {
+            let message = self.label_message(label);
+            return Err(Error::Label(
+                label.to_string(),
+                message,
+                self.position(self.cursor),
+            ));
+        }
+        self.errors += 1;
+        let start = self.cursor;
+        let precedence = usize::from(self.is_left_recursive(label));
+        if !self.call(label, precedence, &mut vec![])? {
+            return Ok(false);
+        }
+        let span = self.span(start);
+        let message = self.label_message(label);
+        captures.push(value::Error::new_val(span, label.to_string(), message));
+        Ok(true)
+    }
+
+    /// Message declared for `label` with `label name = "message"`
+    fn label_message(&self, label: &str) -> Option<String> {
+        let labels = &self.interp.grammar.labels;
+        labels
+            .iter()
+            .find(|l| l.name == label)
+            .map(|l| l.message.clone())
+    }
+
+    /// Match the rule `name`.  Calls with a precedence level greater
+    /// than zero are matched as left recursive.
+    fn call(
+        &mut self,
+        name: &str,
+        precedence: usize,
+        captures: &mut Vec<Value>,
+    ) -> Result<bool, Error> {
+        if precedence == 0 {
+            let start = self.cursor;
+            let mut items = vec![];
+            if !self.rule_body(name, &mut items)? {
+                return Ok(false);
+            }
+            captures.extend(self.node(name, start, items));
+            return Ok(true);
+        }
+
+        let key = (name.to_string(), self.cursor);
+        if let Some((result, level)) = self.memo.get(&key) {
+            return Ok(match result {
+                Some((cursor, values)) if precedence >= *level => {
+                    self.cursor = *cursor;
+                    captures.extend(values.iter().cloned());
+                    true
+                }
+                _ => false,
+            });
+        }
+
+        // grow the seed until the match stops getting longer
+        let start = self.cursor;
+        self.memo.insert(key.clone(), (None, precedence));
+        loop {
+            self.cursor = start;
+            let mut items = vec![];
+            let matched = self.rule_body(name, &mut items)?;
+            let longest = match &self.memo[&key].0 {
+                Some((cursor, _)) => *cursor,
+                None => start,
+            };
+            let grew = self.memo[&key].0.is_none() || self.cursor > longest;
+            if !matched || !grew {
+                break;
+            }
+            let values = self.node(name, start, items);
+            self.memo
+                .insert(key.clone(), (Some((self.cursor, values)), precedence));
+        }
+        Ok(match self.memo.remove(&key) {
+            Some((Some((cursor, values)), _)) => {
+                self.cursor = cursor;
+                captures.extend(values);
+                true
+            }
+            _ => {
+                self.cursor = start;
+                false
+            }
+        })
+    }
+
+    fn rule_body(&mut self, name: &str, items: &mut Vec<Value>) -> Result<bool, Error> {
+        let def = match self.interp.grammar.definitions.get(name) {
+            Some(def) => def,
+            None => {
+                return Err(Error::InvalidProgram(
+                    self.cursor,
+                    format!("Production {:?} doesnt exist", name),
+                ))
+            }
+        };
+        let cursor = self.cursor;
+        let enter = RuleEvent::Enter {
+            rule: name.to_string(),
+            cursor,
+        };
+        self.interp.config.record(&mut self.events, enter)?;
+        self.rules.push(name.to_string());
+        let matched = self.eval(&def.expr, items);
+        self.rules.pop();
+        let matched = matched?;
+        let exit = RuleEvent::Exit {
+            rule: name.to_string(),
+            cursor: self.cursor,
+            matched,
+        };
+        self.interp.config.record(&mut self.events, exit)?;
+        if !matched {
+            return Ok(false);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.rule(name);
+        }
+        match def.capture {
+            ast::CaptureMode::Node if def.is_syntactic() && !items.is_empty() => {
+                let span = Span::new(items[0].span().start, items[items.len() - 1].span().end);
+                let text: String = items.drain(..).map(|i| i.to_string()).collect();
+                items.push(value::String::new_val(span, text));
+            }
+            ast::CaptureMode::Node => {}
+            ast::CaptureMode::String => vm::fold_text(items),
+            ast::CaptureMode::List => vm::fold_list(items, self.position(self.cursor)),
+            ast::CaptureMode::Skip => items.clear(),
+        }
+        Ok(true)
+    }
+
+    /// Values a rule that started matching at `start` and captured
+    /// `items` hands over to its caller.  Rules filtered out of the
+    /// output by the config hand their items over, see
+    /// `vm::Config::with_capture_only`.
+    fn node(&self, name: &str, start: usize, mut items: Vec<Value>) -> Vec<Value> {
+        if items.is_empty() {
+            return vec![];
+        }
+        if let Some(def) = self.interp.grammar.definitions.get(name) {
+            if matches!(
+                def.capture,
+                ast::CaptureMode::String | ast::CaptureMode::List
+            ) {
+                return items;
+            }
+        }
+        if name == WHITE_SPACE_RULE_NAME {
+            return vec![];
+        }
+        if !self.interp.config.captures(name) {
+            let is_leaf = |i: &Value| !matches!(i, Value::Node(_) | Value::Error(_));
+            if !self.rules.is_empty() {
+                if !self.rules.iter().any(|r| self.interp.config.captures(r)) {
+                    items.retain(|i| !is_leaf(i));
+                }
+                return items;
+            }
+            items.retain(|i| !is_leaf(i));
+        }
+        let span = self.span(start);
+        vec![match self.rule_id(name) {
+            Some(id) => value::Node::new_val_with_rule(span, id, name.to_string(), items),
+            None => value::Node::new_val(span, name.to_string(), items),
+        }]
+    }
+
+    /// Match a literal, `ignore_case` is set for the items of a
+    /// case-insensitive class
+    fn literal(
+        &mut self,
+        literal: &ast::Literal,
+        ignore_case: bool,
+        captures: &mut Vec<Value>,
+    ) -> Result<bool, Error> {
+        match literal {
+            ast::Literal::String(s) => {
+                let start = self.cursor;
+                for expected in s.value.chars() {
+                    if !self
+                        .consume(|c| c == expected || (s.ignore_case && casefold::eq(c, expected)))
+                    {
+                        return Ok(false);
+                    }
+                }
+                let span = self.span(start);
+                let value = if s.ignore_case && !self.interp.config.canonical_case() {
+                    self.chars[start..self.cursor].iter().collect()
+                } else {
+                    s.value.clone()
+                };
+                captures.push(value::String::new_val(span, value));
+                Ok(true)
+            }
+            ast::Literal::Class(class) if class.negated => {
+                // any character but the ones listed
+                let (cursor, ffp) = (self.cursor, self.ffp);
+                let listed = class.clone().with_negated(false);
+                let matched = self.literal(&ast::Literal::Class(listed), false, &mut vec![])?;
+                (self.cursor, self.ffp) = (cursor, ffp);
+                if matched {
+                    self.ffp = self.ffp.max(cursor);
+                    return Ok(false);
+                }
+                Ok(self.consume_char(Some, captures))
+            }
+            ast::Literal::Class(class) => {
+                for item in &class.literals {
+                    if self.literal(item, class.ignore_case, captures)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            ast::Literal::Range(r) => Ok(self.consume_char(
+                |c| match c >= r.start && c <= r.end {
+                    true => Some(c),
+                    false if ignore_case => casefold::in_range(c, r.start, r.end),
+                    false => None,
+                },
+                captures,
+            )),
+            ast::Literal::Char(expected) => Ok(self.consume_char(
+                |c| match c == expected.value {
+                    true => Some(c),
+                    false if ignore_case && casefold::eq(c, expected.value) => Some(expected.value),
+                    false => None,
+                },
+                captures,
+            )),
+            ast::Literal::Any(_) => Ok(self.consume_char(Some, captures)),
+            ast::Literal::Property(p) => {
+                // names are checked by the compiler before matching
+                let id = properties::lookup(&p.name).unwrap_or_default();
+                Ok(self.consume_char(
+                    |c| (properties::contains(id, c) != p.negated).then_some(c),
+                    captures,
+                ))
+            }
+            ast::Literal::Int(int) => {
+                let start = self.cursor;
+                let bytes: Vec<u8> = self.chars[start..]
+                    .iter()
+                    .take(10)
+                    .map_while(|c| u8::try_from(*c).ok())
+                    .collect();
+                match int.kind.decode(&bytes) {
+                    Some((value, len)) => {
+                        self.cursor += len;
+                        let span = self.span(start);
+                        captures.push(value::String::new_val(span, value.to_string()));
+                        Ok(true)
+                    }
+                    None => {
+                        self.ffp = self.ffp.max(self.cursor);
+                        Ok(false)
+                    }
+                }
+            }
+            // there's no way to register host functions with the
+            // interpreter
+            ast::Literal::Host(host) => Err(Error::HostNotFound(host.name.clone())),
+            // replaced with the classes they name before matching
+            ast::Literal::NamedClass(class) => Err(Error::InvalidProgram(
+                self.cursor,
+                format!("class `%{}` wasn't resolved", class.name),
+            )),
+            ast::Literal::Take(take) => {
+                let start = self.cursor;
+                let end = vm::captured_count(captures, &take.name).map(|n| start + n);
+                match end {
+                    Some(end) if end <= self.chars.len() => {
+                        self.cursor = end;
+                        if end > start {
+                            let text: String = self.chars[start..end].iter().collect();
+                            captures.push(value::String::new_val(self.span(start), text));
+                        }
+                        Ok(true)
+                    }
+                    _ => {
+                        self.ffp = self.ffp.max(self.cursor);
+                        Ok(false)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Consume one character if `test` returns the character of the
+    /// grammar it matches, capturing it.  The character of the input
+    /// is captured unless case-insensitive literals capture their
+    /// canonical form.
+    fn consume_char<F: Fn(char) -> Option<char>>(
+        &mut self,
+        test: F,
+        captures: &mut Vec<Value>,
+    ) -> bool {
+        let start = self.cursor;
+        let canonical = match self.chars.get(start).and_then(|c| test(*c)) {
+            Some(c) => c,
+            None => {
+                self.ffp = self.ffp.max(self.cursor);
+                return false;
+            }
+        };
+        self.cursor += 1;
+        let span = Span::new(self.position(start), self.position(start + 1));
+        let value = match self.interp.config.canonical_case() {
+            true => canonical,
+            false => self.chars[start],
+        };
+        captures.push(value::Char::new_val(span, value));
+        true
+    }
+
+    fn consume<F: Fn(char) -> bool>(&mut self, test: F) -> bool {
+        match self.chars.get(self.cursor) {
+            Some(c) if test(*c) => {
+                self.cursor += 1;
+                true
+            }
+            _ => {
+                self.ffp = self.ffp.max(self.cursor);
+                false
+            }
+        }
+    }
+
+    fn is_left_recursive(&self, name: &str) -> bool {
+        self.interp.left_rec.get(name).copied().unwrap_or(false)
+    }
+
+    /// Rules are numbered in the order they're defined, the same way
+    /// the virtual machine numbers them
+    fn rule_id(&self, name: &str) -> Option<RuleId> {
+        let names = &self.interp.grammar.definition_names;
+        let i = names.iter().position(|n| n == name)?;
+        Some(RuleId(i as u32))
+    }
+
+    fn span(&self, start: usize) -> Span {
+        Span::new(self.position(start), self.position(self.cursor))
+    }
+
+    /// Position of `offset` with lines counted from zero and columns
+    /// from one, like the positions of the virtual machine
+    fn position(&self, offset: usize) -> Position {
+        let line = self.lines.partition_point(|start| *start <= offset) - 1;
+        Position::new(offset, line, offset - self.lines[line] + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use langlang_syntax::parser;
+    use langlang_value::format;
+
+    use crate::vm::VM;
+    use crate::xorshift::XorShift;
+
+    /// Match each one of the `inputs` against `grammar` with both the
+    /// virtual machine and the interpreter, and require both to
+    /// accept the same inputs and to capture the same values
+    fn assert_same(grammar: &str, inputs: &[&str]) {
+        assert_same_with(grammar, vm::Config::default(), inputs)
+    }
+
+    fn parse(grammar: &str) -> ast::Grammar {
+        let mut grammar = parser::parse(grammar).unwrap();
+        let builtins = parser::parse(crate::consts::BUILTINS).unwrap();
+        for def in builtins.definitions.values() {
+            grammar.add_definition(def);
+        }
+        grammar
+    }
+
+    fn assert_same_with(grammar: &str, config: vm::Config, inputs: &[&str]) {
+        assert_same_compiled_with(grammar, |cc| cc, config, inputs)
+    }
+
+    /// Same as `assert_same_with`, with `options` applied to the
+    /// compiler configurations
+    fn assert_same_compiled_with<F: Fn(compiler::Config) -> compiler::Config>(
+        grammar: &str,
+        options: F,
+        config: vm::Config,
+        inputs: &[&str],
+    ) {
+        let grammar = parse(grammar);
+        for cc in [
+            compiler::Config::default(),
+            compiler::Config::o0(),
+            compiler::Config::default().disable_injecting_whitespace_handling(),
+        ] {
+            let cc = options(cc);
+            let program = Compiler::new(cc.clone()).compile(&grammar, None).unwrap();
+            let mut interp = Interpreter::new(&grammar, None, &cc, config.clone()).unwrap();
+            for input in inputs {
+                let mut m = VM::new_with_config(&program, config.clone());
+                let expected = m.run(input);
+                let actual = interp.run(input);
+                assert_eq!(
+                    expected.is_ok(),
+                    actual.is_ok(),
+                    "accept/reject differ for {:?}: vm: {:?}, interpreter: {:?}",
+                    input,
+                    expected,
+                    actual
+                );
+                let compact =
+                    |r: Result<Option<Value>, Error>| r.ok().flatten().map(|v| format::compact(&v));
+                assert_eq!(
+                    compact(expected),
+                    compact(actual),
+                    "values differ for {:?}",
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn same_as_vm_terminals() {
+        assert_same(
+            "A <- 'ab' [x-z0-9_] . 'c'? 'd'* 'e'+",
+            &["abxqe", "ab1qcdddee", "ab_q", "abx", "", "xx"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_repetition_counts() {
+        assert_same(
+            "A <- [0-9a-f]{4} 'x'{0,2} 'y'{2,} B{1,3}\nB <- 'b' / 'c'",
+            &[
+                "0a1fyy",
+                "0a1fxxyyyb",
+                "0a1fxxxyy",
+                "0a1yy",
+                "0a1fybc",
+                "beefyybcbc",
+            ],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_unicode_properties() {
+        assert_same(
+            "A <- [\\p{Lu}][\\p{Ll}\\p{Nd}]* [^\\p{Greek}\\P{Alphabetic}]",
+            &["Abc1d", "Ab1α", "Ab1", "ΑΒ1x", "É9é", "a1b"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_choices_and_predicates() {
+        assert_same(
+            "A <- B* 'x' / B* / 'c'\nB <- !'bb' 'b' / &'c' 'c'",
+            &["bbbbx", "bbbb", "bcbx", "c", "bb", "d"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_captures_within_predicates() {
+        assert_same("R0 <- &((!('b') / [bc]))", &["bbb", "c", "a", ""]);
+        assert_same("R0 <- &('a' !'b')+", &["aa", "ab", "a", ""]);
+        assert_same("A <- !(B 'b') B\nB <- 'a' B / !'a'", &["ac", "ab", "aab"]);
+    }
+
+    /// Grammars made up of random expressions, with terminals and
+    /// calls within predicates and repetitions nested in each other.
+    /// Grammars `analysis` finds problems with are left out, since
+    /// they can loop forever.
+    fn random_grammars(count: usize) -> Vec<String> {
+        // the same grammars get generated on every run
+        let mut rng = XorShift::new(0x9e37_79b9_7f4a_7c15);
+        fn expr(rng: &mut XorShift, depth: usize, call: bool) -> String {
+            let terminals = ["'a'", "'b'", "'c'", "[bc]", ".", "R1"];
+            let terminal = |rng: &mut XorShift| {
+                let choices = if call { 6 } else { 5 };
+                terminals[rng.below(choices)].to_string()
+            };
+            if depth == 0 {
+                return terminal(rng);
+            }
+            match rng.below(8) {
+                0 => terminal(rng),
+                1 => format!(
+                    "({} {})",
+                    expr(rng, depth - 1, call),
+                    expr(rng, depth - 1, call)
+                ),
+                2 => format!(
+                    "({} / {})",
+                    expr(rng, depth - 1, call),
+                    expr(rng, depth - 1, call)
+                ),
+                3 => format!("&({})", expr(rng, depth - 1, call)),
+                4 => format!("!({})", expr(rng, depth - 1, call)),
+                5 => format!("({})?", expr(rng, depth - 1, call)),
+                6 => format!("({})*", expr(rng, depth - 1, call)),
+                _ => format!("({})+", expr(rng, depth - 1, call)),
+            }
+        }
+        let mut grammars = vec![];
+        while grammars.len() < count {
+            let grammar = format!(
+                "R0 <- {}\nR1 <- 'a' R1 / {}",
+                expr(&mut rng, 3, true),
+                expr(&mut rng, 2, false),
+            );
+            if crate::analysis::analyze(&parse(&grammar)).is_empty() {
+                grammars.push(grammar);
+            }
+        }
+        grammars
+    }
+
+    #[test]
+    fn same_as_vm_random_grammars() {
+        let mut inputs = vec![String::new()];
+        for len in 1..=3 {
+            let shorter: Vec<String> = inputs
+                .iter()
+                .filter(|i| i.len() == len - 1)
+                .cloned()
+                .collect();
+            for input in shorter {
+                for c in ['a', 'b', 'c'] {
+                    inputs.push(format!("{}{}", input, c));
+                }
+            }
+        }
+        let inputs: Vec<&str> = inputs.iter().map(|i| i.as_str()).collect();
+        for grammar in random_grammars(200) {
+            assert_same(&grammar, &inputs);
+        }
+    }
+
+    #[test]
+    fn same_as_vm_rules_and_spacing() {
+        assert_same(
+            "Call <- Name '(' Args? ')'\nArgs <- Arg (',' Arg)*\nArg <- Name / Number\nName <- [a-z]+\nNumber <- [0-9]+",
+            &["f()", "f(x)", "f( x , 12 )", "f(x,", "(x)"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_ignore_case() {
+        let grammar = "A <- 'select'i Name\nName <- [a-z]i+ 'σ'i";
+        let inputs = ["select abς", "SeLeCt AbΣ", "selecT \u{212a}σ", "SELEC aσ"];
+        assert_same(grammar, &inputs);
+        let config = vm::Config::default().with_case_capture(vm::CaseCapture::Canonical);
+        assert_same_with(grammar, config, &inputs);
+    }
+
+    #[test]
+    fn same_as_vm_int_terminals() {
+        let grammar = "A <- %u8 %u16le %u32be %varint";
+        let inputs = [
+            "\x07\x02\x01\x00\x00\x01\x00\u{ac}\x02",
+            "\x07\x02\x01\x00\x00\x01\x00\u{ff}",
+            "\x07\x02\x01\x00\x00\x01\x00\u{ff}\u{ff}\u{ff}\u{ff}\u{ff}\u{ff}\u{ff}\u{ff}\u{ff}\x02",
+            "\x07\u{100}",
+            "\x07",
+        ];
+        let byte_mode = |cc: compiler::Config| cc.byte_mode();
+        assert_same_compiled_with(grammar, byte_mode, vm::Config::default(), &inputs);
+    }
+
+    #[test]
+    fn same_as_vm_take() {
+        assert_same(
+            "A <- Count ':' %take(Count) / 'x'\nCount <- [0-9]+",
+            &["3:abc", "3:ab", "0:", "12:abcdefghijkl!", "x"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_labels() {
+        assert_same(
+            "A <- 'a' B^b 'c'\nB <- 'b'\nb <- (!'c' .)*",
+            &["abc", "axxc", "a"],
+        );
+        assert_same("A <- 'a' 'b'^l", &["ab", "ax"]);
+    }
+
+    #[test]
+    fn same_as_vm_capture_modes() {
+        assert_same(
+            "A <- K (',' K)* L S\n@capture(string) K <- Id ':' Id\nId <- [a-z]+\n\
+             @capture(list) L <- '[' Id* ']'\n@capture(skip) S <- ';' Id",
+            &["a:b,c:d[x y];z", "a:b[];z", "a:b[x]", "a:b,[];z"],
+        );
+        assert_same("@capture(string) A <- B^l\nB <- 'b'\nl <- .", &["b", "x"]);
+    }
+
+    #[test]
+    fn same_as_vm_left_recursion() {
+        assert_same("E <- E '+n' / 'n'", &["n", "n+n", "n+n+n", "+n"]);
+        assert_same(
+            "E <- M '+' E / M\nM <- M '-n' / 'n'",
+            &["n", "n-n", "n-n-n", "n+n-n+n"],
+        );
+        assert_same(
+            "E <- E '+' E / E '*' E / 'n'",
+            &["n", "n+n", "n+n*n", "n*n+n"],
+        );
+    }
+
+    #[test]
+    fn same_as_vm_options() {
+        let grammar = "Call <- Name '(' Args ')'\nArgs <- Arg (',' Arg)*\nArg <- Name / Number\nName <- [a-z]+\nNumber <- Digit+\nDigit <- [0-9]";
+        let inputs = &["f(x, 12)", "f(1)"];
+        assert_same_with(
+            grammar,
+            vm::Config::default().with_capture_only(&["Name", "Number"]),
+            inputs,
+        );
+        assert_same_with(
+            grammar,
+            vm::Config::default().with_capture_except(&["Arg", "Digit"]),
+            inputs,
+        );
+
+        let grammar = "A <- ('a' B^b)+\nB <- 'b'\nb <- (!'a' .)*";
+        let config = vm::Config::default().with_max_errors(1);
+        assert_same_with(grammar, config, &["ab", "axab", "axax"]);
+        let config = vm::Config::default().with_on_error(vm::OnError::FailFast);
+        assert_same_with(grammar, config, &["ab", "axab"]);
+    }
+
+    #[test]
+    fn interpreter_records_rule_events() {
+        let config = vm::Config::default().with_rule_events();
+        let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+        let grammar = parse("A <- B C\nB <- 'b'\nC <- 'c' / 'x'");
+        let mut interp = Interpreter::new(&grammar, None, &cc, config.clone()).unwrap();
+        assert!(interp.run("bd").is_err());
+        let names: Vec<String> = interp
+            .events()
+            .iter()
+            .map(|e| match e {
+                RuleEvent::Enter { rule, .. } => format!("+{}", rule),
+                RuleEvent::Exit { rule, matched, .. } => format!("-{}:{}", rule, matched),
+                RuleEvent::EmptyIteration { rule, .. } => format!("!{}", rule),
+            })
+            .collect();
+        assert_eq!(
+            vec!["+A", "+B", "-B:true", "+C", "-C:false", "-A:false"],
+            names
+        );
+
+        // repetitions that match without consuming input get reported
+        let grammar = parse("A <- B* 'x'\nB <- 'b'?");
+        let mut interp = Interpreter::new(&grammar, None, &cc, config).unwrap();
+        assert!(interp.run("bbx").is_ok());
+        assert!(interp.events().contains(&RuleEvent::EmptyIteration {
+            rule: "A".to_string(),
+            cursor: 2,
+        }));
+    }
+
+    #[test]
+    fn interpreter_records_coverage() {
+        let source = "V <- N / S / B\nN <- [0-9]+\nS <- '\"' (E / [a-z])* '\"'\nE <- '\\\\n'\nB <- 'true' / 'false'";
+        let grammar = parse(source);
+        let cc = compiler::Config::default();
+        let mut interp = Interpreter::new(&grammar, None, &cc, vm::Config::default())
+            .unwrap()
+            .with_coverage();
+        for input in ["12", "\"ab\"", "true", "x"] {
+            let _ = interp.run(input);
+        }
+        let report = interp
+            .coverage()
+            .unwrap()
+            .report(&parser::parse(source).unwrap());
+        let gaps: Vec<String> = report.gaps.iter().map(|g| g.to_string()).collect();
+        assert_eq!(
+            vec![
+                "alternative 1 of `S` never matched: `E`",
+                "rule `E` never matched",
+                "alternative 2 of `B` never matched: `\"false\"`",
+            ],
+            gaps
+        );
+        assert_eq!((4, 5), report.rules);
+        assert_eq!((5, 7), report.alternatives);
+    }
+
+    #[test]
+    fn match_interpreted_uses_the_first_rule() {
+        let grammar = parse("A <- B+\nB <- 'b'");
+        let value = grammar.match_interpreted("bb").unwrap().unwrap();
+        assert_eq!("A[B[b]B[b]]", format::compact(&value));
+
+        let r = Interpreter::new(
+            &grammar,
+            Some("C"),
+            &compiler::Config::default(),
+            vm::Config::default(),
+        );
+        assert!(matches!(r, Err(compiler::Error::NotFound(_))));
+    }
+}
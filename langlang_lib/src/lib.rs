@@ -1,34 +1,216 @@
+use langlang_syntax::ast;
 pub use langlang_syntax::parser;
+pub use langlang_value::format;
+use langlang_value::source_map::{Position, Span};
+pub use langlang_value::value::Value;
 
+#[cfg(feature = "alloc-stats")]
+pub mod alloc_stats;
+pub mod analysis;
+pub mod bytecode;
 pub mod compiler;
+#[cfg(feature = "interp")]
+pub mod coverage;
+pub mod diagnostic;
+#[cfg(feature = "import")]
 pub mod import;
+#[cfg(feature = "interp")]
+pub mod interp;
+#[cfg(feature = "pipeline")]
+pub mod pipeline;
+#[cfg(feature = "reduce")]
+pub mod reduce;
+#[cfg(feature = "unparse")]
+pub mod unparse;
 pub mod vm;
 
+#[cfg(feature = "unicode")]
+mod casefold;
+#[cfg(not(feature = "unicode"))]
+#[path = "casefold_ascii.rs"]
+mod casefold;
 mod consts;
+mod lint;
+mod properties;
+#[cfg(feature = "import")]
+mod sha256;
+// generated by extra/ucd.py, which lays out the tables one range per line
+#[cfg(feature = "unicode")]
+#[rustfmt::skip]
+mod ucd;
 mod wsrewrite;
+#[cfg(test)]
+mod xorshift;
+
+/// Compile the text of `grammar` into a program that matches input
+/// from its first rule, with the default options of the compiler.
+/// The builtin rules are added to the grammar, but `@import`
+/// directives aren't resolved; use `import::ImportResolver` and
+/// `compiler::Compiler` for grammars split across files.
+///
+/// ```
+/// let program = langlang_lib::compile("Sum <- Num '+' Num\nNum <- [0-9]+")?;
+/// let value = program.parse_value("1 + 2")?;
+/// assert_eq!(
+///     Some("Sum[Num[1]+Num[2]]".to_string()),
+///     value.map(|v| langlang_lib::format::compact(&v)),
+/// );
+/// # Ok::<(), langlang_lib::Error>(())
+/// ```
+pub fn compile(grammar: &str) -> Result<vm::Program, Error> {
+    let mut grammar = parser::parse(grammar)?;
+    add_builtins(&mut grammar)?;
+    let program = compiler::Compiler::new(compiler::Config::default()).compile(&grammar, None)?;
+    Ok(program)
+}
+
+/// Add the rules every grammar can call without defining them: the
+/// white space handling generated from `@comments`, unless the
+/// grammar writes its own, and then the builtins
+pub(crate) fn add_builtins(grammar: &mut ast::Grammar) -> Result<(), parser::Error> {
+    if !grammar
+        .definitions
+        .contains_key(consts::WHITE_SPACE_RULE_NAME)
+    {
+        for def in wsrewrite::comment_definitions(&grammar.comments) {
+            grammar.add_definition(&def);
+        }
+    }
+    // in the order they're written, so compiling a grammar always
+    // lays its program out the same way
+    let builtins = parser::parse(consts::BUILTINS)?;
+    for name in &builtins.definition_names {
+        grammar.add_definition(&builtins.definitions[name]);
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
+    BytecodeError(bytecode::Error),
     CompilerError(compiler::Error),
     ParserError(parser::Error),
+    #[cfg(feature = "import")]
     ImportError(import::Error),
     RuntimeError(vm::Error),
+    #[cfg(feature = "unparse")]
+    UnparseError(unparse::Error),
     IOError(std::io::Error),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::ParserError(e) => write!(f, "Parsing Error: {:#?}", e),
-            Error::CompilerError(e) => write!(f, "Compiler Error: {:#?}", e),
-            Error::ImportError(e) => write!(f, "Import Error: {:#?}", e),
-            Error::RuntimeError(e) => write!(f, "Runtime Error: {:#?}", e),
-            Error::IOError(e) => write!(f, "Input/Output Error: {:#?}", e),
+            Error::BytecodeError(e) => write!(f, "{}", e),
+            Error::ParserError(e) => write!(f, "{}", e),
+            Error::CompilerError(e) => write!(f, "{}", e),
+            #[cfg(feature = "import")]
+            Error::ImportError(e) => write!(f, "{}", e),
+            Error::RuntimeError(e) => write!(f, "{}", e),
+            #[cfg(feature = "unparse")]
+            Error::UnparseError(e) => write!(f, "{}", e),
+            Error::IOError(e) => write!(f, "Input/Output Error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::BytecodeError(e) => Some(e),
+            Error::ParserError(e) => Some(e),
+            Error::CompilerError(e) => Some(e),
+            #[cfg(feature = "import")]
+            Error::ImportError(e) => Some(e),
+            Error::RuntimeError(e) => Some(e),
+            #[cfg(feature = "unparse")]
+            Error::UnparseError(e) => Some(e),
+            Error::IOError(e) => Some(e),
         }
     }
 }
 
-impl std::error::Error for Error {}
+/// Stage of the work an [`Error`] happened in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    // The grammar isn't valid syntax
+    Syntax,
+    // The grammar can't be compiled
+    Compile,
+    // A compiled program can't be loaded
+    Bytecode,
+    // The grammar's imports can't be resolved
+    Import,
+    // The input doesn't match the grammar
+    Runtime,
+    // A value can't be turned back into text
+    Unparse,
+    // Reading or writing files failed
+    IO,
+}
+
+impl Error {
+    /// Stage of the work the error happened in
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::ParserError(_) => ErrorKind::Syntax,
+            Error::CompilerError(_) => ErrorKind::Compile,
+            Error::BytecodeError(_) => ErrorKind::Bytecode,
+            #[cfg(feature = "import")]
+            Error::ImportError(_) => ErrorKind::Import,
+            Error::RuntimeError(_) => ErrorKind::Runtime,
+            #[cfg(feature = "unparse")]
+            Error::UnparseError(_) => ErrorKind::Unparse,
+            Error::IOError(_) => ErrorKind::IO,
+        }
+    }
+
+    /// Location within the grammar, or within the input for runtime
+    /// errors, the error refers to, for the errors that know it
+    pub fn span(&self) -> Option<Span> {
+        self.to_diagnostic().span
+    }
+
+    /// Line, column and offset the error starts at, for the errors
+    /// that know it
+    pub fn position(&self) -> Option<Position> {
+        self.span().map(|span| span.start)
+    }
+
+    /// Line of `source` the error happened at, with a caret under
+    /// the column it starts at.  `source` is the grammar for syntax
+    /// errors and the input for runtime errors.
+    pub fn snippet(&self, source: &str) -> Option<String> {
+        self.position().map(|p| diagnostic::snippet(source, &p))
+    }
+
+    /// Offset within the grammar source for syntax errors, or within
+    /// the input for runtime errors
+    pub fn offset(&self) -> Option<usize> {
+        self.to_diagnostic().offset
+    }
+
+    /// Name of the rule the error refers to, for the errors that
+    /// know it
+    pub fn rule(&self) -> Option<&str> {
+        match self {
+            Error::CompilerError(compiler::Error::Expand(rule, _))
+            | Error::CompilerError(compiler::Error::EmptyRange(rule, _))
+            | Error::CompilerError(compiler::Error::RepeatTooLarge(rule, _)) => Some(rule),
+            Error::CompilerError(compiler::Error::UnresolvedReference(references)) => {
+                references.first().map(|r| r.referenced_from.as_str())
+            }
+            Error::CompilerError(compiler::Error::Analysis(problems)) => match problems.first()? {
+                analysis::Problem::UnresolvedReference(r) => Some(&r.referenced_from),
+                analysis::Problem::UnguardedLeftRecursion(rule, _)
+                | analysis::Problem::EmptyLoop(rule, ..) => Some(rule),
+            },
+            _ => None,
+        }
+    }
+}
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
@@ -36,6 +218,12 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<bytecode::Error> for Error {
+    fn from(e: bytecode::Error) -> Self {
+        Error::BytecodeError(e)
+    }
+}
+
 impl From<compiler::Error> for Error {
     fn from(e: compiler::Error) -> Self {
         Error::CompilerError(e)
@@ -48,6 +236,7 @@ impl From<parser::Error> for Error {
     }
 }
 
+#[cfg(feature = "import")]
 impl From<import::Error> for Error {
     fn from(e: import::Error) -> Self {
         Error::ImportError(e)
@@ -59,3 +248,10 @@ impl From<vm::Error> for Error {
         Error::RuntimeError(e)
     }
 }
+
+#[cfg(feature = "unparse")]
+impl From<unparse::Error> for Error {
+    fn from(e: unparse::Error) -> Self {
+        Error::UnparseError(e)
+    }
+}
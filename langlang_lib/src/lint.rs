@@ -0,0 +1,260 @@
+// lint.rs --- checks that don't stop compilation
+//
+// Each check walks the grammar looking for constructions that are
+// valid but most likely mistakes, and reports them as warnings
+// attached to the span of the offending expression.  Rules that come
+// from the builtins are never reported.
+//
+use std::collections::{HashMap, HashSet};
+
+use langlang_syntax::ast;
+use langlang_syntax::stack;
+use langlang_syntax::visitor::{self, Visitor};
+
+use crate::compiler::{Error, Warning, WarningCode};
+use crate::consts::{BUILTINS, WHITE_SPACE_RULE_NAME};
+
+pub(crate) fn run(grammar: &ast::Grammar, main: &str) -> Result<Vec<Warning>, Error> {
+    let builtins = builtin_names();
+    let infallible_rules = fixpoint(grammar, infallible);
+    let reachable = reachable(grammar, main);
+    let mut warnings = vec![];
+
+    for name in &grammar.definition_names {
+        if builtins.contains(name) {
+            continue;
+        }
+        let def = &grammar.definitions[name];
+        let mut def_warnings = vec![];
+        if !reachable.contains(name.as_str()) {
+            def_warnings.push(Warning::new(
+                WarningCode::UnusedRule,
+                def.span.clone(),
+                format!("rule `{}` is never used", name),
+            ));
+        }
+        walk(&def.expr, &mut |expr| {
+            if let ast::Expression::Choice(choice) = expr {
+                let last = choice.items.len() - 1;
+                for (i, item) in choice.items.iter().enumerate() {
+                    if let Some(prev) = choice.items[..i].iter().find(|p| shadows(p, item)) {
+                        def_warnings.push(Warning::new(
+                            WarningCode::ShadowedAlternative,
+                            item.span(),
+                            format!(
+                                "`{}` is never matched because `{}` matches its start first; \
+                                 try `{}` before `{}`",
+                                item, prev, item, prev
+                            ),
+                        ));
+                    }
+                    if i < last && infallible(item, &infallible_rules) {
+                        def_warnings.push(Warning::new(
+                            WarningCode::UnreachableAlternative,
+                            choice.items[i + 1].span(),
+                            format!("alternative is never tried because `{}` can't fail", item),
+                        ));
+                        break;
+                    }
+                }
+            }
+        });
+
+        // drop what the definition allows with `@allow(...)`
+        let allowed = def
+            .allow
+            .iter()
+            .map(|code| code.parse())
+            .collect::<Result<Vec<WarningCode>, Error>>()?;
+        warnings.extend(
+            def_warnings
+                .into_iter()
+                .filter(|w| !allowed.contains(&w.code)),
+        );
+    }
+    Ok(warnings)
+}
+
+/// Names of the rules that are added to every grammar by the import
+/// resolver.  They're never reported as unused.
+pub(crate) fn builtin_names() -> HashSet<String> {
+    match langlang_syntax::parser::parse(BUILTINS) {
+        Ok(g) => g.definition_names.into_iter().collect(),
+        Err(_) => HashSet::new(),
+    }
+}
+
+/// Names of the rules that can be called when matching starts from
+/// `main`.  Labels count as calls to their recovery expression, and
+/// the white space rule counts as used because the compiler injects
+/// calls to it.
+pub(crate) fn reachable<'a>(grammar: &'a ast::Grammar, main: &'a str) -> HashSet<&'a str> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![main, WHITE_SPACE_RULE_NAME];
+    while let Some(name) = stack.pop() {
+        if !seen.insert(name) {
+            continue;
+        }
+        if let Some(def) = grammar.definitions.get(name) {
+            walk(&def.expr, &mut |expr| match expr {
+                ast::Expression::Identifier(n) => stack.push(&n.name),
+                ast::Expression::Label(n) => stack.push(&n.label),
+                _ => {}
+            });
+        }
+    }
+    seen
+}
+
+/// Compute a property of every rule by applying `f` to the rules'
+/// expressions until no value changes.  Every rule starts as `false`,
+/// so recursive rules only get `true` if some path doesn't recurse.
+pub(crate) fn fixpoint<F>(grammar: &ast::Grammar, f: F) -> HashMap<String, bool>
+where
+    F: Fn(&ast::Expression, &HashMap<String, bool>) -> bool,
+{
+    let mut values: HashMap<String, bool> = grammar
+        .definition_names
+        .iter()
+        .map(|n| (n.clone(), false))
+        .collect();
+    loop {
+        let mut changed = false;
+        for name in &grammar.definition_names {
+            if !values[name] && f(&grammar.definitions[name].expr, &values) {
+                values.insert(name.clone(), true);
+                changed = true;
+            }
+        }
+        if !changed {
+            return values;
+        }
+    }
+}
+
+/// True if the expression can succeed without consuming input
+pub(crate) fn nullable(expr: &ast::Expression, rules: &HashMap<String, bool>) -> bool {
+    stack::guard(|| match expr {
+        ast::Expression::Sequence(n) => n.items.iter().all(|e| nullable(e, rules)),
+        ast::Expression::Choice(n) => n.items.iter().any(|e| nullable(e, rules)),
+        ast::Expression::Lex(n) => nullable(&n.expr, rules),
+        ast::Expression::And(_) | ast::Expression::Not(_) => true,
+        ast::Expression::Optional(_) | ast::Expression::ZeroOrMore(_) => true,
+        ast::Expression::OneOrMore(n) => nullable(&n.expr, rules),
+        ast::Expression::Repeat(n) => n.min == 0 || nullable(&n.expr, rules),
+        ast::Expression::Precedence(n) => nullable(&n.expr, rules),
+        ast::Expression::Label(n) => nullable(&n.expr, rules),
+        ast::Expression::List(_) | ast::Expression::Node(_) => false,
+        ast::Expression::Identifier(n) => rules.get(&n.name).copied().unwrap_or(false),
+        ast::Expression::Literal(ast::Literal::String(s)) => s.value.is_empty(),
+        // a count of zero takes nothing, and host functions never
+        // consume input
+        ast::Expression::Literal(ast::Literal::Take(_) | ast::Literal::Host(_)) => true,
+        ast::Expression::Literal(_) => false,
+        ast::Expression::Empty(_) => true,
+    })
+}
+
+/// True if the expression succeeds on any input
+pub(crate) fn infallible(expr: &ast::Expression, rules: &HashMap<String, bool>) -> bool {
+    stack::guard(|| match expr {
+        ast::Expression::Sequence(n) => n.items.iter().all(|e| infallible(e, rules)),
+        ast::Expression::Choice(n) => n.items.iter().any(|e| infallible(e, rules)),
+        ast::Expression::Lex(n) => infallible(&n.expr, rules),
+        ast::Expression::And(n) => infallible(&n.expr, rules),
+        ast::Expression::Not(_) => false,
+        ast::Expression::Optional(_) | ast::Expression::ZeroOrMore(_) => true,
+        ast::Expression::OneOrMore(n) => infallible(&n.expr, rules),
+        ast::Expression::Repeat(n) => n.min == 0 || infallible(&n.expr, rules),
+        ast::Expression::Precedence(n) => infallible(&n.expr, rules),
+        ast::Expression::Label(n) => infallible(&n.expr, rules),
+        ast::Expression::List(_) | ast::Expression::Node(_) => false,
+        ast::Expression::Identifier(n) => rules.get(&n.name).copied().unwrap_or(false),
+        ast::Expression::Literal(ast::Literal::String(s)) => s.value.is_empty(),
+        ast::Expression::Literal(_) => false,
+        ast::Expression::Empty(_) => true,
+    })
+}
+
+/// True if `second` only matches input that starts with something
+/// `first` matches, which makes `first / second` never try `second`.
+/// That's the case when `first` is written as the beginning of
+/// `second`, e.g.: `'<' / '<='` or `A B / A B C`.
+fn shadows(first: &ast::Expression, second: &ast::Expression) -> bool {
+    let (first, second) = (items(first), items(second));
+    let Some((last, init)) = first.split_last() else {
+        return false;
+    };
+    if first.len() > second.len() || !init.iter().zip(second.iter()).all(|(a, b)| same(a, b)) {
+        return false;
+    }
+    let other = &second[init.len()];
+    match (last, other) {
+        (
+            ast::Expression::Literal(ast::Literal::String(a)),
+            ast::Expression::Literal(ast::Literal::String(b)),
+        ) if a.ignore_case == b.ignore_case && a.bytes == b.bytes => {
+            // empty strings never fail, which is reported as an
+            // unreachable alternative instead
+            let fold = |s: &str| match a.ignore_case {
+                true => s.to_lowercase(),
+                false => s.to_string(),
+            };
+            !a.value.is_empty() && fold(&b.value).starts_with(&fold(&a.value))
+        }
+        _ => same(last, other),
+    }
+}
+
+/// True if both expressions are written the same way, regardless of
+/// where in the grammar they are
+fn same(a: &ast::Expression, b: &ast::Expression) -> bool {
+    use ast::Expression as E;
+    let all = |a: &[E], b: &[E]| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b));
+    stack::guard(|| match (a, b) {
+        (E::Sequence(a), E::Sequence(b)) => all(&a.items, &b.items),
+        (E::Choice(a), E::Choice(b)) => all(&a.items, &b.items),
+        (E::List(a), E::List(b)) => all(&a.items, &b.items),
+        (E::Lex(a), E::Lex(b)) => same(&a.expr, &b.expr),
+        (E::And(a), E::And(b)) => same(&a.expr, &b.expr),
+        (E::Not(a), E::Not(b)) => same(&a.expr, &b.expr),
+        (E::Optional(a), E::Optional(b)) => same(&a.expr, &b.expr),
+        (E::ZeroOrMore(a), E::ZeroOrMore(b)) => same(&a.expr, &b.expr),
+        (E::OneOrMore(a), E::OneOrMore(b)) => same(&a.expr, &b.expr),
+        (E::Repeat(a), E::Repeat(b)) => (a.min, a.max) == (b.min, b.max) && same(&a.expr, &b.expr),
+        (E::Precedence(a), E::Precedence(b)) => {
+            a.precedence == b.precedence && same(&a.expr, &b.expr)
+        }
+        (E::Label(a), E::Label(b)) => a.label == b.label && same(&a.expr, &b.expr),
+        (E::Node(a), E::Node(b)) => a.name == b.name && same(&a.expr, &b.expr),
+        (E::Identifier(a), E::Identifier(b)) => a.name == b.name,
+        // literals don't nest, so their text is cheap to compare
+        (E::Literal(a), E::Literal(b)) => a.to_string() == b.to_string(),
+        (E::Empty(_), E::Empty(_)) => true,
+        _ => false,
+    })
+}
+
+// items of a sequence, or the expression itself
+fn items(expr: &ast::Expression) -> &[ast::Expression] {
+    match expr {
+        ast::Expression::Sequence(n) => &n.items,
+        _ => std::slice::from_ref(expr),
+    }
+}
+
+/// Call `f` on `expr` and on each one of its sub-expressions
+pub(crate) fn walk<'a, F: FnMut(&'a ast::Expression)>(expr: &'a ast::Expression, f: &mut F) {
+    Walker { f }.visit_expression(expr);
+}
+
+struct Walker<'f, F> {
+    f: &'f mut F,
+}
+
+impl<'a, F: FnMut(&'a ast::Expression)> Visitor<'a> for Walker<'_, F> {
+    fn visit_expression(&mut self, n: &'a ast::Expression) {
+        (self.f)(n);
+        visitor::walk_expression(self, n);
+    }
+}
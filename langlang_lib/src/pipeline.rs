@@ -0,0 +1,200 @@
+// pipeline.rs --- scanner and parser compiled from a single grammar
+//
+// A grammar can be split into two tiers by declaring a rule named
+// `Token` as an ordered choice of the rules that produce tokens:
+//
+//     Expr   <- Number (Plus Number)*
+//     Token  <- Number / Plus
+//     Number <- [0-9]+
+//     Plus   <- '+'
+//
+// The lexical tier gets compiled into a scanner that skips `Spacing`
+// between tokens and turns the whole input into a token stream.  The
+// syntactic tier gets compiled into a parser over that stream, where
+// token rules match tokens of their kind.  Neither tier
+// needs white space handling injected within its rules.
+//
+// Both tiers are compiled once by `Pipeline::new`, and inputs are
+// matched with `Pipeline::match_str`, which runs the scanner and then
+// the parser over its tokens.  There's no `match_str` on the grammar
+// itself: `ast::Grammar` comes from `langlang_syntax`, which can't
+// depend on the compiler, and matching from the grammar would compile
+// both tiers again for each input.
+//
+use std::collections::HashMap;
+
+use langlang_syntax::ast;
+use langlang_value::source_map::Span;
+use langlang_value::value::Value;
+
+use crate::compiler::{self, Compiler};
+use crate::consts::{TOKEN_RULE_NAME, WHITE_SPACE_RULE_NAME};
+use crate::vm::{Match, Program, Token, VM};
+use crate::Error;
+
+// Name of the rule synthesized as the entry point of the scanner
+const SCANNER_RULE_NAME: &str = "__tokens";
+
+pub struct Pipeline {
+    scanner: Program,
+    parser: Program,
+}
+
+impl Pipeline {
+    /// Compile both tiers of `grammar`.  The grammar must be already
+    /// resolved, so the `Spacing` rule is available to the scanner.
+    /// The `start` rule, or the first one, is the parser's entry point.
+    pub fn new(
+        grammar: &ast::Grammar,
+        start: Option<&str>,
+        config: &compiler::Config,
+    ) -> Result<Self, Error> {
+        let config = config.disable_injecting_whitespace_handling();
+        let kinds = token_kinds(grammar)?;
+
+        let mut scanner = Compiler::new(config.clone());
+        let scanner = scanner.compile(&scanner_grammar(grammar), Some(SCANNER_RULE_NAME))?;
+
+        let mut parser = Compiler::new(config);
+        let parser = parser.compile(&parser_grammar(grammar, &kinds), start)?;
+
+        Ok(Self { scanner, parser })
+    }
+
+    /// Break `input` down into tokens with the scanner
+    pub fn scan(&self, input: &str) -> Result<Vec<Token>, Error> {
+        let mut tokens = vec![];
+        if let Some(Value::Node(n)) = VM::new(&self.scanner).run(input)? {
+            for item in n.items {
+                // the `Token` rule wraps the node of the token rule
+                let node = match item {
+                    Value::Node(mut t) if t.items.len() == 1 => t.items.remove(0),
+                    _ => continue,
+                };
+                if let Value::Node(t) = node {
+                    let text: String = t.items.iter().map(|v| v.to_string()).collect();
+                    let span = match (t.items.first(), t.items.last()) {
+                        (Some(first), Some(last)) => Span::new(first.span().start, last.span().end),
+                        _ => t.span.clone(),
+                    };
+                    tokens.push(Token::new(&t.name, &text, span));
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Scan `input` and run the parser over the resulting tokens.
+    /// Errors reported by the parser point at the position of the
+    /// token they happened at, and the span and the farthest failure
+    /// position of the match point at offsets within the input rather
+    /// than at indexes in the token stream.
+    pub fn match_str(&self, input: &str) -> Result<Match, Error> {
+        let tokens = self.scan(input)?;
+        let spans: Vec<Span> = tokens.iter().map(|t| t.span.clone()).collect();
+        let end = input.chars().count();
+        let offset = |index: usize| match spans.get(index) {
+            Some(span) => span.start.offset,
+            None => end,
+        };
+        let m = VM::new(&self.parser).match_tokens(tokens)?;
+        Ok(Match {
+            ffp: offset(m.ffp),
+            ..m
+        })
+    }
+}
+
+/// Names of the rules listed as alternatives of the `Token` rule
+fn token_kinds(grammar: &ast::Grammar) -> Result<Vec<String>, Error> {
+    let def = match grammar.definitions.get(TOKEN_RULE_NAME) {
+        Some(def) => def,
+        None => return Err(compiler::Error::NotFound(TOKEN_RULE_NAME.to_string()).into()),
+    };
+    let alternatives = match &def.expr {
+        ast::Expression::Choice(c) => c.items.iter().collect(),
+        expr => vec![expr],
+    };
+    alternatives
+        .into_iter()
+        .map(|expr| match expr {
+            ast::Expression::Identifier(id) => Ok(id.name.clone()),
+            ast::Expression::Sequence(seq) if seq.items.len() == 1 => match &seq.items[0] {
+                ast::Expression::Identifier(id) => Ok(id.name.clone()),
+                expr => Err(not_a_rule_name(expr)),
+            },
+            expr => Err(not_a_rule_name(expr)),
+        })
+        .collect()
+}
+
+fn not_a_rule_name(expr: &ast::Expression) -> Error {
+    compiler::Error::Semantic(format!(
+        "`{}` alternatives must be rule names, not `{}`",
+        TOKEN_RULE_NAME, expr
+    ))
+    .into()
+}
+
+/// Add the scanner entry point to the grammar:
+/// `__tokens <- (Spacing Token)* Spacing !.`
+fn scanner_grammar(grammar: &ast::Grammar) -> ast::Grammar {
+    let span = Span::default();
+    let ident = |name: &str| ast::Identifier::new_expr(span.clone(), name.to_string());
+    let expr = ast::Sequence::new_expr(
+        span.clone(),
+        vec![
+            ast::ZeroOrMore::new_expr(
+                span.clone(),
+                Box::new(ast::Sequence::new_expr(
+                    span.clone(),
+                    vec![ident(WHITE_SPACE_RULE_NAME), ident(TOKEN_RULE_NAME)],
+                )),
+            ),
+            ident(WHITE_SPACE_RULE_NAME),
+            ast::Not::new_expr(span.clone(), Box::new(ast::Any::new_expr(span.clone()))),
+        ],
+    );
+    let mut definitions = grammar.definitions.clone();
+    let mut definition_names = grammar.definition_names.clone();
+    definitions.insert(
+        SCANNER_RULE_NAME.to_string(),
+        ast::Definition::new(span.clone(), SCANNER_RULE_NAME.to_string(), expr),
+    );
+    definition_names.push(SCANNER_RULE_NAME.to_string());
+    ast::Grammar::new(span, grammar.imports.clone(), definition_names, definitions)
+        .with_version(grammar.version.clone())
+        .with_labels(grammar.labels.clone())
+}
+
+/// Replace the body of each token rule with a literal, which matches
+/// the kind of the token under the cursor.  Rules that reference
+/// tokens still get a node named after the token rule around its text.
+fn parser_grammar(grammar: &ast::Grammar, kinds: &[String]) -> ast::Grammar {
+    let mut definitions = HashMap::new();
+    let mut definition_names = vec![];
+    for name in &grammar.definition_names {
+        if name == TOKEN_RULE_NAME {
+            continue;
+        }
+        let def = &grammar.definitions[name];
+        let expr = if kinds.contains(name) {
+            ast::String::new_expr(def.span.clone(), name.clone())
+        } else {
+            def.expr.clone()
+        };
+        definitions.insert(
+            name.clone(),
+            ast::Definition::new(def.span.clone(), name.clone(), expr),
+        );
+        definition_names.push(name.clone());
+    }
+    ast::Grammar::new(
+        grammar.span.clone(),
+        grammar.imports.clone(),
+        definition_names,
+        definitions,
+    )
+    .with_version(grammar.version.clone())
+    .with_labels(grammar.labels.clone())
+}
@@ -0,0 +1,126 @@
+// properties.rs --- Unicode properties matched by `\p{..}` classes
+//
+// Each property has an id, which is what `Instruction::Property`
+// holds: the binary properties answered by the standard library come
+// first, then the general categories and the scripts of the tables
+// generated by extra/ucd.py.  Only the binary properties are known
+// when the `unicode` feature is disabled.
+//
+
+#[cfg(feature = "unicode")]
+use crate::ucd::{CATEGORIES, SCRIPTS};
+
+#[cfg(not(feature = "unicode"))]
+const CATEGORIES: &[(&str, &str, Ranges)] = &[];
+#[cfg(not(feature = "unicode"))]
+const SCRIPTS: &[(&str, Ranges)] = &[];
+
+/// Sorted ranges of the characters with a property
+pub(crate) type Ranges = &'static [(char, char)];
+
+type Test = fn(char) -> bool;
+
+/// Name and test of the properties that don't need tables
+const BINARY: &[(&str, Test)] = &[
+    ("Any", |_| true),
+    ("ASCII", |c| c.is_ascii()),
+    ("Alphabetic", char::is_alphabetic),
+    ("Lowercase", char::is_lowercase),
+    ("Uppercase", char::is_uppercase),
+    ("White_Space", char::is_whitespace),
+];
+
+/// Number of properties, which is one past the last id
+pub(crate) fn len() -> usize {
+    BINARY.len() + CATEGORIES.len() + SCRIPTS.len()
+}
+
+/// Id of the property `name`, e.g.: `L`, `Letter`, `Greek` or
+/// `Alphabetic`.  Names are compared ignoring case, spaces, `_` and
+/// `-`, and may be prefixed by `gc=` or `General_Category=` to only
+/// look for general categories, or by `sc=` or `Script=` to only
+/// look for scripts.
+pub(crate) fn lookup(name: &str) -> Option<usize> {
+    let name = loose(name);
+    let (key, value) = match name.split_once('=') {
+        Some((key, value)) => (Some(key), value),
+        None => (None, name.as_str()),
+    };
+    let categories = BINARY.len();
+    let scripts = categories + CATEGORIES.len();
+    match key {
+        None => BINARY
+            .iter()
+            .position(|(n, _)| loose(n) == value)
+            .or_else(|| category(value).map(|id| categories + id))
+            .or_else(|| script(value).map(|id| scripts + id)),
+        Some("gc" | "generalcategory") => category(value).map(|id| categories + id),
+        Some("sc" | "script") => script(value).map(|id| scripts + id),
+        Some(_) => None,
+    }
+}
+
+/// Name the property `id` is displayed with
+pub(crate) fn name(id: usize) -> &'static str {
+    match locate(id) {
+        Property::Binary(name, _) => name,
+        Property::Ranges(name, _) => name,
+    }
+}
+
+/// Whether `c` has the property `id`
+pub(crate) fn contains(id: usize, c: char) -> bool {
+    match locate(id) {
+        Property::Binary(_, test) => test(c),
+        Property::Ranges(_, ranges) => ranges
+            .binary_search_by(|(start, end)| match (*start > c, *end < c) {
+                (true, _) => std::cmp::Ordering::Greater,
+                (_, true) => std::cmp::Ordering::Less,
+                _ => std::cmp::Ordering::Equal,
+            })
+            .is_ok(),
+    }
+}
+
+enum Property {
+    Binary(&'static str, Test),
+    Ranges(&'static str, Ranges),
+}
+
+// `id` must be lower than `len()`, which programs are validated for
+fn locate(id: usize) -> Property {
+    if let Some((name, test)) = BINARY.get(id) {
+        return Property::Binary(name, *test);
+    }
+    let id = id - BINARY.len();
+    if let Some((short, _, ranges)) = CATEGORIES.get(id) {
+        return Property::Ranges(short, ranges);
+    }
+    let (name, ranges) = SCRIPTS[id - CATEGORIES.len()];
+    Property::Ranges(name, ranges)
+}
+
+fn category(name: &str) -> Option<usize> {
+    CATEGORIES
+        .iter()
+        .position(|(short, long, _)| loose(short) == name || loose(long) == name)
+        // `Combining_Mark` and `punct` are aliases of `M` and `P`
+        .or_else(|| match name {
+            "combiningmark" => category("m"),
+            "punct" => category("p"),
+            _ => None,
+        })
+}
+
+fn script(name: &str) -> Option<usize> {
+    SCRIPTS.iter().position(|(n, _)| loose(n) == name)
+}
+
+// lowercase `name` without spaces, `_` and `-`, as the loose
+// matching of UAX #44 does
+fn loose(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '-'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
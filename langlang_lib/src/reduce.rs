@@ -0,0 +1,405 @@
+// reduce.rs --- shrink grammars and inputs that crash the engine
+//
+// A grammar and an input that crash or hang the engine are usually
+// found on large grammars, and most of what's in them has nothing to
+// do with the bug.  `shrink` takes them apart one piece at a time,
+// keeping each removal that preserves the failure, until no single
+// removal does.  What's left is a reproducer small enough to read.
+//
+// The grammar is shrunk by removing whole rules, then alternatives of
+// choices and items of sequences, then by replacing operators with
+// the expression they apply to.  The input is shrunk by removing
+// chunks of characters, halving their size down to single ones.
+// Both are shrunk in turns until neither gets any smaller, as
+// removing rules often allows removing more input, and vice versa.
+//
+// Grammars are handled as text, so what's being checked is exactly
+// what's reported.  Once the grammar gets parsed, it's printed back
+// from its tree, so comments within it are lost.
+//
+use std::panic::{self, AssertUnwindSafe};
+
+use langlang_syntax::{ast, parser};
+
+use crate::vm;
+
+/// How matching an input with a grammar broke the engine
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure {
+    /// The engine panicked, with the message of the panic
+    Panic(String),
+    /// Matching ran more instructions than `Reducer::with_max_steps`
+    /// allows, which is how hangs show up
+    Hang,
+    /// The virtual machine reached a state a program should never
+    /// put it in, e.g.: popping from an empty stack
+    Internal(String),
+}
+
+impl Failure {
+    /// Whether both failures are of the same kind, regardless of
+    /// the messages, which tend to change as the input shrinks
+    pub fn same_kind(&self, other: &Failure) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Panic(message) => write!(f, "panic: {}", message),
+            Failure::Hang => write!(f, "hang: ran out of steps"),
+            Failure::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+/// Grammar and input left once no piece of them could be removed
+/// without the failure going away
+#[derive(Clone, Debug)]
+pub struct Reduced {
+    pub grammar: String,
+    pub input: String,
+    /// Failure of the reduced grammar and input
+    pub failure: Failure,
+    /// Grammars and inputs checked while reducing
+    pub attempts: usize,
+}
+
+/// Finds and reduces grammars and inputs that crash or hang the
+/// engine.  Panics are caught, but still reported by the panic hook,
+/// so programs checking many inputs might want to silence it.
+#[derive(Clone, Debug)]
+pub struct Reducer {
+    max_steps: usize,
+}
+
+impl Default for Reducer {
+    fn default() -> Self {
+        Self {
+            max_steps: 10_000_000,
+        }
+    }
+}
+
+impl Reducer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instructions a match can run before it's considered a hang
+    pub fn with_max_steps(self, max_steps: usize) -> Self {
+        Self { max_steps }
+    }
+
+    /// Compile `grammar` and match `input` with it, returning how it
+    /// broke the engine.  Grammars that don't compile and inputs that
+    /// don't match aren't failures of the engine.
+    pub fn check(&self, grammar: &str, input: &str) -> Option<Failure> {
+        let run = || {
+            let program = crate::compile(grammar).ok()?;
+            let config = vm::Config::default().with_max_steps(self.max_steps);
+            match vm::VM::new_with_config(&program, config).run(input) {
+                Err(vm::Error::ResourceLimit(vm::Limit::Steps, ..)) => Some(Failure::Hang),
+                Err(
+                    e @ (vm::Error::Index | vm::Error::LeftRec | vm::Error::InvalidProgram(..)),
+                ) => Some(Failure::Internal(e.to_string())),
+                _ => None,
+            }
+        };
+        match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(failure) => failure,
+            Err(payload) => Some(Failure::Panic(panic_message(payload))),
+        }
+    }
+
+    /// Shrink `grammar` and `input` while they keep breaking the
+    /// engine the same way.  Returns `None` if they don't break it.
+    pub fn reduce(&self, grammar: &str, input: &str) -> Option<Reduced> {
+        let failure = self.check(grammar, input)?;
+        let mut attempts = 0;
+        let (grammar, input) = shrink(grammar, input, |g, i| {
+            attempts += 1;
+            self.check(g, i).is_some_and(|f| f.same_kind(&failure))
+        });
+        let failure = self.check(&grammar, &input).unwrap_or(failure);
+        Some(Reduced {
+            grammar,
+            input,
+            failure,
+            attempts,
+        })
+    }
+}
+
+/// Shrink `grammar` and `input` for as long as `still_fails` returns
+/// true for them, e.g.: to reduce failures other than the ones
+/// `Reducer` looks for.  `still_fails` is expected to return true for
+/// the original grammar and input.
+pub fn shrink(
+    grammar: &str,
+    input: &str,
+    mut still_fails: impl FnMut(&str, &str) -> bool,
+) -> (String, String) {
+    let mut grammar = grammar.to_string();
+    let mut input: Vec<char> = input.chars().collect();
+    // the grammar is only taken apart if printing it back from its
+    // tree doesn't change how it fails
+    let mut shrink_grammar = match parser::parse(&grammar) {
+        Ok(tree) if still_fails(&tree.to_string(), &text(&input)) => {
+            grammar = tree.to_string();
+            true
+        }
+        _ => false,
+    };
+    loop {
+        let mut changed = false;
+        if shrink_grammar {
+            match shrink_grammar_once(&grammar, |g| still_fails(g, &text(&input))) {
+                Some(smaller) => {
+                    grammar = smaller;
+                    changed = true;
+                }
+                None => shrink_grammar = false,
+            }
+        }
+        if shrink_input(&mut input, |i| still_fails(&grammar, i)) {
+            // what's left of the grammar might go now
+            shrink_grammar = shrink_grammar || parser::parse(&grammar).is_ok();
+            changed = true;
+        }
+        if !changed {
+            return (grammar, text(&input));
+        }
+    }
+}
+
+fn text(chars: &[char]) -> String {
+    chars.iter().collect()
+}
+
+// remove chunks of the input, halving their size down to a single
+// char, returning whether anything got removed
+fn shrink_input(input: &mut Vec<char>, mut still_fails: impl FnMut(&str) -> bool) -> bool {
+    let mut changed = false;
+    let mut size = input.len().div_ceil(2);
+    while size > 0 {
+        let mut start = 0;
+        while start < input.len() {
+            let end = (start + size).min(input.len());
+            let candidate: String = input[..start].iter().chain(&input[end..]).collect();
+            if still_fails(&candidate) {
+                input.drain(start..end);
+                changed = true;
+            } else {
+                start = end;
+            }
+        }
+        size /= 2;
+    }
+    changed
+}
+
+// go through the grammars one removal away from `grammar` until one
+// still fails, resuming from where the last one was found so each
+// pass doesn't check the removals that already didn't work again
+fn shrink_grammar_once(grammar: &str, mut still_fails: impl FnMut(&str) -> bool) -> Option<String> {
+    let mut current = grammar.to_string();
+    let mut changed = false;
+    let mut next = 0;
+    'outer: loop {
+        let tree = parser::parse(&current).ok()?;
+        let candidates = candidates(&tree);
+        for (i, candidate) in candidates.iter().enumerate().skip(next) {
+            if still_fails(candidate) {
+                current = candidate.clone();
+                changed = true;
+                next = i;
+                continue 'outer;
+            }
+        }
+        return changed.then_some(current);
+    }
+}
+
+// grammars with a single piece of `grammar` removed, from the largest
+// pieces to the smallest ones
+fn candidates(grammar: &ast::Grammar) -> Vec<String> {
+    let mut out = vec![];
+    for name in &grammar.definition_names {
+        out.push(print(grammar, |n, d| (n != name).then(|| d.clone())));
+    }
+    for name in &grammar.definition_names {
+        let def = &grammar.definitions[name];
+        for expr in smaller(&def.expr) {
+            out.push(print(grammar, |n, d| {
+                Some(match n == name {
+                    true => ast::Definition {
+                        expr: expr.clone(),
+                        ..d.clone()
+                    },
+                    false => d.clone(),
+                })
+            }));
+        }
+    }
+    out
+}
+
+// print `grammar` with each definition replaced by what `f` returns
+// for it, leaving out the ones it returns `None` for
+fn print(
+    grammar: &ast::Grammar,
+    f: impl Fn(&String, &ast::Definition) -> Option<ast::Definition>,
+) -> String {
+    let mut names = vec![];
+    let mut definitions = std::collections::HashMap::new();
+    for name in &grammar.definition_names {
+        if let Some(def) = f(name, &grammar.definitions[name]) {
+            names.push(name.clone());
+            definitions.insert(name.clone(), def);
+        }
+    }
+    ast::Grammar::new(
+        grammar.span.clone(),
+        grammar.imports.clone(),
+        names,
+        definitions,
+    )
+    .with_version(grammar.version.clone())
+    .with_comments(grammar.comments.clone())
+    .with_labels(grammar.labels.clone())
+    .to_string()
+}
+
+// expressions one step smaller than `expr`: without one of its items,
+// or with one of its operators replaced by what it applies to
+fn smaller(expr: &ast::Expression) -> Vec<ast::Expression> {
+    use ast::Expression as E;
+    let mut out = vec![];
+    match expr {
+        E::Sequence(ast::Sequence { items, .. })
+        | E::Choice(ast::Choice { items, .. })
+        | E::List(ast::List { items, .. }) => {
+            for i in 0..items.len() {
+                let mut fewer = items.clone();
+                fewer.remove(i);
+                match (expr, fewer.len()) {
+                    (E::List(_), _) | (_, 2..) => out.push(with_items(expr, fewer)),
+                    (_, 1) => out.push(fewer.remove(0)),
+                    _ => {}
+                }
+            }
+            for (i, item) in items.iter().enumerate() {
+                for s in smaller(item) {
+                    let mut items = items.clone();
+                    items[i] = s;
+                    out.push(with_items(expr, items));
+                }
+            }
+        }
+        E::Lex(ast::Lex { expr: inner, .. })
+        | E::And(ast::And { expr: inner, .. })
+        | E::Not(ast::Not { expr: inner, .. })
+        | E::Optional(ast::Optional { expr: inner, .. })
+        | E::ZeroOrMore(ast::ZeroOrMore { expr: inner, .. })
+        | E::OneOrMore(ast::OneOrMore { expr: inner, .. })
+        | E::Repeat(ast::Repeat { expr: inner, .. })
+        | E::Precedence(ast::Precedence { expr: inner, .. })
+        | E::Label(ast::Label { expr: inner, .. })
+        | E::Node(ast::Node { expr: inner, .. }) => {
+            out.push(inner.as_ref().clone());
+            for s in smaller(inner) {
+                out.push(with_inner(expr, s));
+            }
+        }
+        E::Identifier(_) | E::Literal(_) | E::Empty(_) => {}
+    }
+    out
+}
+
+fn with_items(expr: &ast::Expression, items: Vec<ast::Expression>) -> ast::Expression {
+    let mut expr = expr.clone();
+    match &mut expr {
+        ast::Expression::Sequence(v) => v.items = items,
+        ast::Expression::Choice(v) => v.items = items,
+        ast::Expression::List(v) => v.items = items,
+        _ => unreachable!("only sequences, choices and lists have items"),
+    }
+    expr
+}
+
+fn with_inner(expr: &ast::Expression, inner: ast::Expression) -> ast::Expression {
+    let mut expr = expr.clone();
+    let slot = match &mut expr {
+        ast::Expression::Lex(v) => &mut v.expr,
+        ast::Expression::And(v) => &mut v.expr,
+        ast::Expression::Not(v) => &mut v.expr,
+        ast::Expression::Optional(v) => &mut v.expr,
+        ast::Expression::ZeroOrMore(v) => &mut v.expr,
+        ast::Expression::OneOrMore(v) => &mut v.expr,
+        ast::Expression::Repeat(v) => &mut v.expr,
+        ast::Expression::Precedence(v) => &mut v.expr,
+        ast::Expression::Label(v) => &mut v.expr,
+        ast::Expression::Node(v) => &mut v.expr,
+        _ => unreachable!("only operators wrap a single expression"),
+    };
+    **slot = inner;
+    expr
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrink_keeps_what_the_failure_needs() {
+        let grammar = "
+            Doc   <- Item+ Tail
+            Item  <- Word / Num / Bang
+            Word  <- [a-z]+
+            Num   <- [0-9]+
+            Bang  <- '!'
+            Tail  <- !.
+        ";
+        let still_fails = |g: &str, i: &str| g.contains("\"!\"") && i.contains('!');
+        let (grammar, input) = shrink(grammar, "abc 12 ! def", still_fails);
+        assert_eq!("Bang <- \"!\"\n", grammar);
+        assert_eq!("!", input);
+    }
+
+    #[test]
+    fn reduce_hangs() {
+        // each `T` is matched again by every alternative of `E`,
+        // which is exponential on how deep the input nests
+        let grammar = "
+            E      <- T '+' E / T '-' E / T
+            T      <- '(' E ')' / 'n'
+            Unused <- 'u'
+        ";
+        let input = format!("{}n{}", "(".repeat(8), ")".repeat(8));
+        let reducer = Reducer::new().with_max_steps(20_000);
+        assert_eq!(None, reducer.check(grammar, "n"));
+        assert_eq!(Some(Failure::Hang), reducer.check(grammar, &input));
+
+        let reduced = reducer.reduce(grammar, &input).unwrap();
+        assert_eq!(Failure::Hang, reduced.failure);
+        assert!(!reduced.grammar.contains("Unused"));
+        assert!(reduced.input.len() < input.len());
+        assert!(reduced.attempts > 0);
+        assert_eq!(
+            Some(Failure::Hang),
+            reducer.check(&reduced.grammar, &reduced.input)
+        );
+    }
+}
@@ -0,0 +1,400 @@
+// unparse.rs --- turn values back into text
+//
+// The unparser is the inverse of running a program: it takes a value
+// shaped like the output of a grammar and walks the grammar's
+// expressions alongside it to reproduce concrete text.  Literal
+// terminals are taken from the grammar, characters matched by classes
+// and the any (`.`) operator are taken from the value, and white
+// space is produced by a configurable separator wherever the compiler
+// would have injected a call to the `Spacing` rule.
+//
+use langlang_syntax::ast::{self, IsSyntactic};
+use langlang_value::value::{self, Value};
+
+use crate::consts::WHITE_SPACE_RULE_NAME;
+use crate::properties;
+use crate::vm;
+
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    // The value refers to a rule that isn't defined in the grammar
+    NotFound(String),
+    // The value doesn't have the shape of the output of its rule
+    Mismatch(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Unparse Error")?;
+        match self {
+            Error::NotFound(msg) => write!(f, "[NotFound]: {}", msg),
+            Error::Mismatch(msg) => write!(f, "[Mismatch]: {}", msg),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    // text emitted wherever the grammar would consume white space
+    separator: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            separator: " ".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Replace the text emitted between tokens of non-lexical
+    /// sequences.  An empty separator glues all tokens together.
+    pub fn with_separator(&self, separator: &str) -> Self {
+        Self {
+            separator: separator.to_string(),
+        }
+    }
+}
+
+/// Unparse allows calling `grammar.unparse(&value)` with the default
+/// configuration.
+pub trait Unparse {
+    fn unparse(&self, value: &Value) -> Result<String, Error>;
+}
+
+impl Unparse for ast::Grammar {
+    fn unparse(&self, value: &Value) -> Result<String, Error> {
+        Unparser::new(self, Config::default()).unparse(value)
+    }
+}
+
+// Item is what the expressions of a rule are matched against.  Text
+// is broken down into chars because syntactic rules join all their
+// captures into a single string.
+enum Item<'v> {
+    Char(char),
+    Value(&'v Value),
+}
+
+pub struct Unparser<'a> {
+    grammar: &'a ast::Grammar,
+    config: Config,
+}
+
+impl<'a> Unparser<'a> {
+    pub fn new(grammar: &'a ast::Grammar, config: Config) -> Self {
+        Self { grammar, config }
+    }
+
+    /// Produce the text that, once matched by the grammar, would
+    /// output `value`.  White space that was consumed by the grammar
+    /// isn't part of the value, so it's replaced by the separator.
+    pub fn unparse(&self, value: &Value) -> Result<String, Error> {
+        match value {
+            Value::Char(v) => Ok(v.value.to_string()),
+            Value::String(v) => Ok(v.value.to_string()),
+            Value::List(v) => {
+                let mut output = String::new();
+                for item in &v.values {
+                    output.push_str(&self.unparse(item)?);
+                }
+                Ok(output)
+            }
+            Value::Node(n) => self.unparse_node(n),
+            Value::Error(e) => Err(Error::Mismatch(format!(
+                "can't unparse error value `{}`",
+                e.label
+            ))),
+        }
+    }
+
+    fn unparse_node(&self, node: &value::Node) -> Result<String, Error> {
+        let def = match self.grammar.definitions.get(&node.name) {
+            Some(def) => def,
+            None => return Err(Error::NotFound(node.name.clone())),
+        };
+        let items = flatten(&node.items);
+        let (mut pos, mut output) = (0, String::new());
+        let lex = def.is_syntactic();
+        if self.emit(&def.expr, &items, &mut pos, &mut output, lex)? && pos == items.len() {
+            return Ok(output);
+        }
+        Err(Error::Mismatch(format!(
+            "value doesn't match rule `{}`",
+            node.name
+        )))
+    }
+
+    /// Match `expr` against the items starting at `pos`, writing
+    /// text into `out`.  Returns `Ok(false)` and leaves both `pos`
+    /// and `out` untouched if the items don't match the expression.
+    fn emit(
+        &self,
+        expr: &ast::Expression,
+        items: &[Item],
+        pos: &mut usize,
+        out: &mut String,
+        lex: bool,
+    ) -> Result<bool, Error> {
+        let (start_pos, start_len) = (*pos, out.len());
+        let matched = match expr {
+            ast::Expression::Sequence(n) => {
+                let spaced = !lex && !n.is_lexical();
+                let mut matched = true;
+                for (i, item) in n.items.iter().enumerate() {
+                    let before = out.len();
+                    if !self.emit(item, items, pos, out, lex)? {
+                        matched = false;
+                        break;
+                    }
+                    let is_lex = matches!(item, ast::Expression::Lex(_));
+                    if spaced && i > 0 && !is_lex && before > start_len && out.len() > before {
+                        out.insert_str(before, &self.config.separator);
+                    }
+                }
+                matched
+            }
+            ast::Expression::Choice(n) => {
+                let mut matched = false;
+                for item in &n.items {
+                    if self.emit(item, items, pos, out, lex)? {
+                        matched = true;
+                        break;
+                    }
+                }
+                matched
+            }
+            ast::Expression::Lex(n) => self.emit(&n.expr, items, pos, out, true)?,
+            ast::Expression::Optional(n) => {
+                self.emit(&n.expr, items, pos, out, lex)?;
+                true
+            }
+            ast::Expression::ZeroOrMore(n) => {
+                self.emit_many(&n.expr, items, pos, out, lex)?;
+                true
+            }
+            ast::Expression::OneOrMore(n) => {
+                self.emit(&n.expr, items, pos, out, lex)?
+                    && self.emit_many(&n.expr, items, pos, out, lex)?
+            }
+            ast::Expression::Repeat(n) => {
+                let mut matched = true;
+                for _ in 0..n.min {
+                    if !self.emit(&n.expr, items, pos, out, lex)? {
+                        matched = false;
+                        break;
+                    }
+                }
+                match n.max {
+                    _ if !matched => {}
+                    None => {
+                        self.emit_many(&n.expr, items, pos, out, lex)?;
+                    }
+                    Some(max) => {
+                        for _ in n.min..max {
+                            if !self.emit(&n.expr, items, pos, out, lex)? {
+                                break;
+                            }
+                        }
+                    }
+                }
+                matched
+            }
+            // predicates don't produce any values
+            ast::Expression::And(_) | ast::Expression::Not(_) | ast::Expression::Empty(_) => true,
+            ast::Expression::Precedence(n) => self.emit(&n.expr, items, pos, out, lex)?,
+            ast::Expression::Label(n) => self.emit(&n.expr, items, pos, out, lex)?,
+            ast::Expression::Identifier(n) if n.name == WHITE_SPACE_RULE_NAME => true,
+            ast::Expression::Identifier(n) => match items.get(*pos) {
+                Some(Item::Value(Value::Node(node))) if node.name == n.name => {
+                    out.push_str(&self.unparse_node(node)?);
+                    *pos += 1;
+                    true
+                }
+                _ => false,
+            },
+            ast::Expression::Node(n) => match items.get(*pos) {
+                Some(Item::Value(Value::Node(node))) if node.name == n.name => {
+                    let inner = flatten(&node.items);
+                    let mut inner_pos = 0;
+                    let matched = self.emit(&n.expr, &inner, &mut inner_pos, out, lex)?
+                        && inner_pos == inner.len();
+                    *pos += 1;
+                    matched
+                }
+                _ => false,
+            },
+            ast::Expression::List(n) => match items.get(*pos) {
+                Some(Item::Value(Value::List(list))) => {
+                    let inner = flatten(&list.values);
+                    let mut inner_pos = 0;
+                    let mut matched = true;
+                    for item in &n.items {
+                        if !self.emit(item, &inner, &mut inner_pos, out, lex)? {
+                            matched = false;
+                            break;
+                        }
+                    }
+                    *pos += 1;
+                    matched && inner_pos == inner.len()
+                }
+                _ => false,
+            },
+            ast::Expression::Literal(n) => self.emit_literal(n, items, pos, out),
+        };
+        if !matched {
+            *pos = start_pos;
+            out.truncate(start_len);
+        }
+        Ok(matched)
+    }
+
+    fn emit_many(
+        &self,
+        expr: &ast::Expression,
+        items: &[Item],
+        pos: &mut usize,
+        out: &mut String,
+        lex: bool,
+    ) -> Result<bool, Error> {
+        loop {
+            let before = *pos;
+            // stop on expressions that match without consuming items
+            if !self.emit(expr, items, pos, out, lex)? || *pos == before {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn emit_literal(
+        &self,
+        literal: &ast::Literal,
+        items: &[Item],
+        pos: &mut usize,
+        out: &mut String,
+    ) -> bool {
+        match literal {
+            ast::Literal::String(s) => {
+                let mut cursor = *pos;
+                for expected in s.value.chars() {
+                    match items.get(cursor) {
+                        Some(Item::Char(c)) if *c == expected => cursor += 1,
+                        _ => return false,
+                    }
+                }
+                *pos = cursor;
+                out.push_str(&s.value);
+                true
+            }
+            ast::Literal::Class(class) if class.negated => {
+                let listed = class.clone().with_negated(false);
+                let (mut cursor, mut text) = (*pos, String::new());
+                if self.emit_literal(&ast::Literal::Class(listed), items, &mut cursor, &mut text) {
+                    return false;
+                }
+                self.emit_char(items, pos, out, |_| true)
+            }
+            ast::Literal::Class(class) => class
+                .literals
+                .iter()
+                .any(|l| self.emit_literal(l, items, pos, out)),
+            ast::Literal::Range(r) => {
+                self.emit_char(items, pos, out, |c| c >= r.start && c <= r.end)
+            }
+            ast::Literal::Char(expected) => {
+                self.emit_char(items, pos, out, |c| c == expected.value)
+            }
+            ast::Literal::Any(_) => self.emit_char(items, pos, out, |_| true),
+            ast::Literal::Property(p) => match properties::lookup(&p.name) {
+                Some(id) => self.emit_char(items, pos, out, |c| {
+                    properties::contains(id, c) != p.negated
+                }),
+                None => false,
+            },
+            // the value has the integer in decimal, and the output
+            // gets the bytes it's encoded with.  The digits of
+            // integers captured next to each other get joined, so
+            // they need rules of their own to be told apart
+            ast::Literal::Int(int) => {
+                let digits: String = items[*pos..]
+                    .iter()
+                    .map_while(|i| match i {
+                        Item::Char(c) if c.is_ascii_digit() => Some(*c),
+                        _ => None,
+                    })
+                    .collect();
+                match digits.parse().ok().and_then(|v| int.kind.encode(v)) {
+                    Some(bytes) => {
+                        *pos += digits.len();
+                        out.extend(bytes.into_iter().map(char::from));
+                        true
+                    }
+                    None => false,
+                }
+            }
+            ast::Literal::Host(_) => true,
+            // the ranges of named classes are only known by the
+            // providers registered with the compiler
+            ast::Literal::NamedClass(_) => false,
+            // the count comes from the node captured before it
+            ast::Literal::Take(take) => {
+                let count = items[..*pos].iter().rev().find_map(|i| match i {
+                    Item::Value(v) => vm::captured_count(std::slice::from_ref(*v), &take.name),
+                    Item::Char(_) => None,
+                });
+                let text = count
+                    .and_then(|count| items.get(*pos..*pos + count))
+                    .and_then(|taken| {
+                        taken
+                            .iter()
+                            .map(|i| match i {
+                                Item::Char(c) => Some(*c),
+                                Item::Value(_) => None,
+                            })
+                            .collect::<Option<String>>()
+                    });
+                match text {
+                    Some(text) => {
+                        *pos += text.chars().count();
+                        out.push_str(&text);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    fn emit_char<F: Fn(char) -> bool>(
+        &self,
+        items: &[Item],
+        pos: &mut usize,
+        out: &mut String,
+        accept: F,
+    ) -> bool {
+        match items.get(*pos) {
+            Some(Item::Char(c)) if accept(*c) => {
+                out.push(*c);
+                *pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn flatten(values: &[Value]) -> Vec<Item<'_>> {
+    let mut items = vec![];
+    for v in values {
+        match v {
+            Value::Char(c) => items.push(Item::Char(c.value)),
+            Value::String(s) => items.extend(s.value.chars().map(Item::Char)),
+            _ => items.push(Item::Value(v)),
+        }
+    }
+    items
+}
@@ -6,14 +6,19 @@
 // machine.  This module has nothing to do with how patterns get
 // compiled to programs, but how programs get executted as patterns.
 //
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::sync::Arc;
 
+use crate::bytecode;
+use crate::casefold;
 use crate::consts::WHITE_SPACE_RULE_NAME;
+use crate::properties;
 
-#[cfg(debug_assertions)]
-use langlang_value::format;
 use langlang_value::source_map::{Position, Span};
-use langlang_value::value::{self, Value};
+use langlang_value::value::{self, RuleId, Value};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum ContainerType {
@@ -21,6 +26,13 @@ pub enum ContainerType {
     Node,
 }
 
+/// Byte order of the integers matched by `Instruction::Int`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
 #[derive(Clone, Debug)]
 pub enum Instruction {
     Halt,
@@ -30,6 +42,27 @@ pub enum Instruction {
     Char(char),
     Span(char, char),
     String(usize),
+    // any character within the ranges of the class found in the
+    // classes table at the given index
+    Class(usize),
+    // any character with the Unicode property of the given id, e.g.:
+    // a general category or a script
+    Property(usize),
+    // same as the above, but compare characters once case folded
+    CharCI(char),
+    SpanCI(char, char),
+    StringCI(usize),
+    // unsigned integers encoded in the bytes of the input, captured
+    // in decimal.  `Int` has the number of bytes and their order,
+    // `Varint` reads unsigned LEB128
+    Int(usize, Endian),
+    Varint,
+    // as many characters as the decimal value of the last node named
+    // after the string captured by the current production
+    Take(usize),
+    // call the host function named after the string, which doesn't
+    // consume input and fails if the function returns false
+    Host(usize),
 
     // control flow
     Choice(usize),
@@ -40,12 +73,19 @@ pub enum Instruction {
     FailTwice,
     PartialCommit(usize),
     BackCommit(usize),
-    // TestChar,
-    // TestAny,
+    // jump forward by the offset unless the character under the
+    // cursor is the one given, without consuming it
+    TestChar(char, usize),
+    // jump forward by the offset at the end of the input
+    TestAny(usize),
     Jump(usize),
+    JumpB(usize),
     Call(usize, usize),
     CallB(usize, usize),
     Return,
+    // same as `Return`, but the values captured by the production
+    // are handed to the caller as they are, instead of within a node
+    ReturnValues,
     Throw(usize),
 
     // container (list, map, node, etc)
@@ -57,40 +97,596 @@ pub enum Instruction {
     CapPop,
     CapCommit,
     CapJoin,
+    // replace the values captured by the current production with the
+    // text they matched, keeping the errors found within them
+    CapText,
+    // collect the values captured by the current production in a list
+    CapList,
+    // drop the values captured by the current production
+    CapDrop,
 }
 
 impl std::fmt::Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.metadata().mnemonic)?;
+        for operand in self.operands() {
+            match operand {
+                Operand::Char(c) => write!(f, " {:?}", c)?,
+                Operand::Index(n) => write!(f, " {}", n)?,
+                Operand::Endian(e) => write!(f, " {:?}", e)?,
+                Operand::Container(t) => write!(f, " {:?}", t)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Kind of value an operand of an instruction holds, which says how
+/// tools should display it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    // a character of the input
+    Char,
+    // index within the strings table
+    String,
+    // index within the classes table
+    Class,
+    // id of a Unicode property
+    Property,
+    // index of the label's name within the strings table
+    Label,
+    // distance to an address after the instruction
+    Offset,
+    // distance to an address before the instruction
+    BackOffset,
+    // distance to the production called, after the instruction
+    Production,
+    // distance to the production called, before the instruction
+    BackProduction,
+    // precedence level of left recursive calls, 0 when the call isn't
+    // left recursive
+    Precedence,
+    // number of bytes
+    Width,
+    // byte order
+    Endian,
+    // type of the container built
+    Container,
+}
+
+/// Description of an operand of an instruction
+#[derive(Debug)]
+pub struct OperandInfo {
+    pub name: &'static str,
+    pub kind: OperandKind,
+    pub description: &'static str,
+}
+
+/// Value of an operand, as held by an instruction
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operand {
+    Char(char),
+    Index(usize),
+    Endian(Endian),
+    Container(ContainerType),
+}
+
+/// Description of an instruction, used by the tools that display
+/// programs and to generate the reference of the instruction set, so
+/// neither drift apart from the instructions the VM actually runs
+#[derive(Debug)]
+pub struct Metadata {
+    pub mnemonic: &'static str,
+    // section of the reference the instruction is listed in
+    pub group: &'static str,
+    pub operands: &'static [OperandInfo],
+    pub description: &'static str,
+    // effect on the backtrack/call stack
+    pub stack: &'static str,
+    // effect on the capture stack
+    pub captures: &'static str,
+}
+
+macro_rules! operand {
+    ($name:literal, $kind:ident, $description:literal) => {
+        OperandInfo {
+            name: $name,
+            kind: OperandKind::$kind,
+            description: $description,
+        }
+    };
+}
+
+const NO_EFFECT: &str = "none";
+const CAPTURES_MATCH: &str =
+    "pushes the matched input onto the top frame, unless within a predicate";
+
+/// Metadata of every instruction, in the same order as their opcodes
+pub static INSTRUCTION_SET: [Metadata; 40] = [
+    Metadata {
+        mnemonic: "halt",
+        group: "Control flow",
+        operands: &[],
+        description: "Stops the VM, successfully.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "any",
+        group: "Lexical",
+        operands: &[],
+        description: "Matches any character, failing at the end of the input.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "char",
+        group: "Lexical",
+        operands: &[operand!("c", Char, "character to match")],
+        description: "Matches the character `c`.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "span",
+        group: "Lexical",
+        operands: &[
+            operand!("start", Char, "first character of the range"),
+            operand!("end", Char, "last character of the range"),
+        ],
+        description: "Matches a character between `start` and `end`, inclusive.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "string",
+        group: "Lexical",
+        operands: &[operand!("id", String, "string to match")],
+        description: "Matches the string `id`.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "class",
+        group: "Lexical",
+        operands: &[operand!("id", Class, "ranges of the class")],
+        description: "Matches a character within any of the ranges of the class `id`.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "charci",
+        group: "Lexical",
+        operands: &[operand!("c", Char, "character to match")],
+        description: "Same as `char`, but compares characters once case folded.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "spanci",
+        group: "Lexical",
+        operands: &[
+            operand!("start", Char, "first character of the range"),
+            operand!("end", Char, "last character of the range"),
+        ],
+        description: "Same as `span`, but compares characters once case folded.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "stringci",
+        group: "Lexical",
+        operands: &[operand!("id", String, "string to match")],
+        description: "Same as `string`, but compares characters once case folded.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "int",
+        group: "Lexical",
+        operands: &[
+            operand!("width", Width, "number of bytes of the integer"),
+            operand!("endian", Endian, "order of the bytes"),
+        ],
+        description: "Matches an unsigned integer encoded in `width` bytes of the input.",
+        stack: NO_EFFECT,
+        captures: "pushes the integer, in decimal, onto the top frame, unless within a predicate",
+    },
+    Metadata {
+        mnemonic: "varint",
+        group: "Lexical",
+        operands: &[],
+        description: "Matches an unsigned integer encoded as LEB128.",
+        stack: NO_EFFECT,
+        captures: "pushes the integer, in decimal, onto the top frame, unless within a predicate",
+    },
+    Metadata {
+        mnemonic: "take",
+        group: "Lexical",
+        operands: &[operand!("name", String, "name of the node holding the length")],
+        description: "Matches as many characters as the decimal value of the last node named `name` captured by the current production.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "host",
+        group: "Lexical",
+        operands: &[operand!("name", String, "name of the host function")],
+        description: "Calls the host function `name`, failing if it returns false.  Doesn't consume input.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "choice",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to the alternative")],
+        description: "Saves the state of the VM, so a failure resumes matching from the alternative.",
+        stack: "pushes a backtrack frame",
+        captures: "commits the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "choicep",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to the alternative")],
+        description: "Same as `choice`, but for predicates: values aren't captured until the frame is popped.",
+        stack: "pushes a backtrack frame",
+        captures: "commits the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "commit",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to jump forward")],
+        description: "Discards the last saved state and jumps forward.",
+        stack: "pops a backtrack frame",
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "commitb",
+        group: "Control flow",
+        operands: &[operand!("offset", BackOffset, "distance to jump backwards")],
+        description: "Same as `commit`, but jumps backwards.",
+        stack: "pops a backtrack frame",
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "fail",
+        group: "Control flow",
+        operands: &[],
+        description: "Fails, restoring the last saved state.",
+        stack: "pops frames up to and including the last backtrack frame",
+        captures: "drops the values captured since the state was saved",
+    },
+    Metadata {
+        mnemonic: "failtwice",
+        group: "Control flow",
+        operands: &[],
+        description: "Discards the last saved state, then fails.",
+        stack: "pops a backtrack frame, then the same as `fail`",
+        captures: "drops the values captured since the state was saved",
+    },
+    Metadata {
+        mnemonic: "partialcommit",
+        group: "Control flow",
+        operands: &[operand!("offset", BackOffset, "distance to jump backwards")],
+        description: "Updates the last saved state to the current one and jumps backwards.",
+        stack: "updates the top backtrack frame",
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "backcommit",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to jump forward")],
+        description: "Discards the last saved state, restoring its cursor, and jumps forward.",
+        stack: "pops a backtrack frame",
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "jump",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to jump forward")],
+        description: "Jumps forward.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "jumpb",
+        group: "Control flow",
+        operands: &[operand!("offset", BackOffset, "distance to jump backwards")],
+        description: "Jumps backwards.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "call",
+        group: "Control flow",
+        operands: &[
+            operand!("offset", Production, "distance to the production"),
+            operand!("precedence", Precedence, "precedence level of the call"),
+        ],
+        description: "Calls the production after the instruction.",
+        stack: "pushes a call frame",
+        captures: "pushes a frame",
+    },
+    Metadata {
+        mnemonic: "callb",
+        group: "Control flow",
+        operands: &[
+            operand!("offset", BackProduction, "distance to the production"),
+            operand!("precedence", Precedence, "precedence level of the call"),
+        ],
+        description: "Calls the production before the instruction.",
+        stack: "pushes a call frame",
+        captures: "pushes a frame",
+    },
+    Metadata {
+        mnemonic: "return",
+        group: "Control flow",
+        operands: &[],
+        description: "Returns from the current production.",
+        stack: "pops a call frame",
+        captures: "pops a frame, pushing a node with its values onto the frame below",
+    },
+    Metadata {
+        mnemonic: "returnvalues",
+        group: "Control flow",
+        operands: &[],
+        description: "Same as `return`, but hands the values to the caller as they are.",
+        stack: "pops a call frame",
+        captures: "pops a frame, pushing its values onto the frame below",
+    },
+    Metadata {
+        mnemonic: "throw",
+        group: "Control flow",
+        operands: &[operand!("label", Label, "label thrown")],
+        description: "Throws `label`, calling its recovery expression if there's one.  Fails instead within predicates.",
+        stack: "pushes a call frame when recovering",
+        captures: "pushes a frame when recovering",
+    },
+    Metadata {
+        mnemonic: "open",
+        group: "Containers",
+        operands: &[],
+        description: "Matches the items of the list or node at the cursor.",
+        stack: "pushes a list frame",
+        captures: "pushes a frame",
+    },
+    Metadata {
+        mnemonic: "close",
+        group: "Containers",
+        operands: &[operand!("type", Container, "type of container built")],
+        description: "Goes back to matching the input around the container opened last.",
+        stack: "pops a list frame",
+        captures: "pops a frame, pushing a container with its values onto the frame below",
+    },
+    Metadata {
+        mnemonic: "cappush",
+        group: "Captures",
+        operands: &[],
+        description: "Starts a new frame of values, unless within a predicate.",
+        stack: NO_EFFECT,
+        captures: "pushes a frame",
+    },
+    Metadata {
+        mnemonic: "cappop",
+        group: "Captures",
+        operands: &[],
+        description: "Moves the values of the top frame onto the one below, unless within a predicate.",
+        stack: NO_EFFECT,
+        captures: "pops a frame",
+    },
+    Metadata {
+        mnemonic: "capcommit",
+        group: "Captures",
+        operands: &[],
+        description: "Marks the values of the top frame as committed, unless within a predicate.",
+        stack: NO_EFFECT,
+        captures: "commits the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "capjoin",
+        group: "Captures",
+        operands: &[],
+        description: "Joins the values of the top frame into a single string.",
+        stack: NO_EFFECT,
+        captures: "replaces the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "captext",
+        group: "Captures",
+        operands: &[],
+        description: "Replaces the values of the top frame with the text they matched, followed by the errors found within them.",
+        stack: NO_EFFECT,
+        captures: "replaces the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "caplist",
+        group: "Captures",
+        operands: &[],
+        description: "Collects the values of the top frame in a list.",
+        stack: NO_EFFECT,
+        captures: "replaces the values of the top frame",
+    },
+    Metadata {
+        mnemonic: "capdrop",
+        group: "Captures",
+        operands: &[],
+        description: "Drops the values of the top frame.",
+        stack: NO_EFFECT,
+        captures: "clears the top frame",
+    },
+    Metadata {
+        mnemonic: "property",
+        group: "Lexical",
+        operands: &[operand!("id", Property, "Unicode property")],
+        description: "Matches a character with the Unicode property `id`.",
+        stack: NO_EFFECT,
+        captures: CAPTURES_MATCH,
+    },
+    Metadata {
+        mnemonic: "testchar",
+        group: "Control flow",
+        operands: &[
+            operand!("c", Char, "character expected under the cursor"),
+            operand!("offset", Offset, "distance to jump forward"),
+        ],
+        description: "Jumps forward unless the character under the cursor is `c`, which isn't consumed.  Skips the alternatives that can't match without saving the state of the VM.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+    Metadata {
+        mnemonic: "testany",
+        group: "Control flow",
+        operands: &[operand!("offset", Offset, "distance to jump forward")],
+        description: "Jumps forward at the end of the input, without consuming anything otherwise.",
+        stack: NO_EFFECT,
+        captures: NO_EFFECT,
+    },
+];
+
+impl Instruction {
+    /// Position of the instruction within `INSTRUCTION_SET`, which
+    /// is also its opcode
+    fn opcode(&self) -> usize {
+        match self {
+            Instruction::Halt => 0,
+            Instruction::Any => 1,
+            Instruction::Char(_) => 2,
+            Instruction::Span(..) => 3,
+            Instruction::String(_) => 4,
+            Instruction::Class(_) => 5,
+            Instruction::CharCI(_) => 6,
+            Instruction::SpanCI(..) => 7,
+            Instruction::StringCI(_) => 8,
+            Instruction::Int(..) => 9,
+            Instruction::Varint => 10,
+            Instruction::Take(_) => 11,
+            Instruction::Host(_) => 12,
+            Instruction::Choice(_) => 13,
+            Instruction::ChoiceP(_) => 14,
+            Instruction::Commit(_) => 15,
+            Instruction::CommitB(_) => 16,
+            Instruction::Fail => 17,
+            Instruction::FailTwice => 18,
+            Instruction::PartialCommit(_) => 19,
+            Instruction::BackCommit(_) => 20,
+            Instruction::Jump(_) => 21,
+            Instruction::JumpB(_) => 22,
+            Instruction::Call(..) => 23,
+            Instruction::CallB(..) => 24,
+            Instruction::Return => 25,
+            Instruction::ReturnValues => 26,
+            Instruction::Throw(_) => 27,
+            Instruction::Open => 28,
+            Instruction::Close(_) => 29,
+            Instruction::CapPush => 30,
+            Instruction::CapPop => 31,
+            Instruction::CapCommit => 32,
+            Instruction::CapJoin => 33,
+            Instruction::CapText => 34,
+            Instruction::CapList => 35,
+            Instruction::CapDrop => 36,
+            Instruction::Property(_) => 37,
+            Instruction::TestChar(..) => 38,
+            Instruction::TestAny(_) => 39,
+        }
+    }
+
+    /// Mnemonic, operands and effects of the instruction
+    pub fn metadata(&self) -> &'static Metadata {
+        &INSTRUCTION_SET[self.opcode()]
+    }
+
+    /// Values of the operands, in the same order as they're described
+    /// in the metadata
+    pub fn operands(&self) -> Vec<Operand> {
         match self {
-            Instruction::Halt => write!(f, "halt"),
-            Instruction::Any => write!(f, "any"),
-            Instruction::Fail => write!(f, "fail"),
-            Instruction::FailTwice => write!(f, "failtwice"),
-            Instruction::Return => write!(f, "return"),
-            Instruction::Char(c) => write!(f, "char {:?}", c),
-            Instruction::String(i) => write!(f, "string {:?}", i),
-            Instruction::Span(a, b) => write!(f, "span {:?} {:?}", a, b),
-            Instruction::Choice(o) => write!(f, "choice {:?}", o),
-            Instruction::ChoiceP(o) => write!(f, "choicep {:?}", o),
-            Instruction::Commit(o) => write!(f, "commit {:?}", o),
-            Instruction::CommitB(o) => write!(f, "commitb {:?}", o),
-            Instruction::PartialCommit(u) => write!(f, "partialcommit {:?}", u),
-            Instruction::BackCommit(u) => write!(f, "backcommit {:?}", u),
-            Instruction::Jump(addr) => write!(f, "jump {:?}", addr),
-            Instruction::Throw(label) => write!(f, "throw {:?}", label),
-            Instruction::Call(addr, k) => write!(f, "call {:?} {:?}", addr, k),
-            Instruction::CallB(addr, k) => write!(f, "callb {:?} {:?}", addr, k),
-            Instruction::Open => write!(f, "open"),
-            Instruction::Close(t) => write!(f, "close({:?})", t),
-            Instruction::CapPush => write!(f, "cappush"),
-            Instruction::CapPop => write!(f, "cappop"),
-            Instruction::CapCommit => write!(f, "capcommit"),
-            Instruction::CapJoin => write!(f, "capjoin"),
+            Instruction::Char(c) | Instruction::CharCI(c) => vec![Operand::Char(*c)],
+            Instruction::Span(a, b) | Instruction::SpanCI(a, b) => {
+                vec![Operand::Char(*a), Operand::Char(*b)]
+            }
+            Instruction::String(n)
+            | Instruction::Class(n)
+            | Instruction::Property(n)
+            | Instruction::StringCI(n)
+            | Instruction::Take(n)
+            | Instruction::Host(n)
+            | Instruction::Choice(n)
+            | Instruction::ChoiceP(n)
+            | Instruction::Commit(n)
+            | Instruction::CommitB(n)
+            | Instruction::PartialCommit(n)
+            | Instruction::BackCommit(n)
+            | Instruction::TestAny(n)
+            | Instruction::Jump(n)
+            | Instruction::JumpB(n)
+            | Instruction::Throw(n) => vec![Operand::Index(*n)],
+            Instruction::Int(width, endian) => {
+                vec![Operand::Index(*width), Operand::Endian(*endian)]
+            }
+            Instruction::Call(offset, k) | Instruction::CallB(offset, k) => {
+                vec![Operand::Index(*offset), Operand::Index(*k)]
+            }
+            Instruction::TestChar(c, offset) => vec![Operand::Char(*c), Operand::Index(*offset)],
+            Instruction::Close(t) => vec![Operand::Container(t.clone())],
+            Instruction::Halt
+            | Instruction::Any
+            | Instruction::Varint
+            | Instruction::Fail
+            | Instruction::FailTwice
+            | Instruction::Return
+            | Instruction::ReturnValues
+            | Instruction::Open
+            | Instruction::CapPush
+            | Instruction::CapPop
+            | Instruction::CapCommit
+            | Instruction::CapJoin
+            | Instruction::CapText
+            | Instruction::CapList
+            | Instruction::CapDrop => vec![],
+        }
+    }
+}
+
+/// Reference of the instruction set, in markdown, generated from the
+/// metadata of each instruction
+pub fn instruction_reference() -> String {
+    let mut groups: Vec<&str> = vec![];
+    for m in &INSTRUCTION_SET {
+        if !groups.contains(&m.group) {
+            groups.push(m.group);
+        }
+    }
+    let mut out = String::from("# Instruction set\n");
+    for group in groups {
+        out.push_str(&format!("\n## {}\n", group));
+        for (opcode, m) in INSTRUCTION_SET.iter().enumerate() {
+            if m.group != group {
+                continue;
+            }
+            let signature = m
+                .operands
+                .iter()
+                .fold(m.mnemonic.to_string(), |s, o| format!("{} {}", s, o.name));
+            out.push_str(&format!("\n### `{}`\n\n", signature));
+            out.push_str(&format!("Opcode {}.  {}\n\n", opcode, m.description));
+            for o in m.operands {
+                out.push_str(&format!(
+                    "- `{}` ({:?}): {}\n",
+                    o.name, o.kind, o.description
+                ));
+            }
+            if !m.operands.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("- stack: {}\n", m.stack));
+            out.push_str(&format!("- captures: {}\n", m.captures));
         }
     }
+    out
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
     // Backtracking
     Fail,
@@ -99,9 +695,161 @@ pub enum Error {
     // Something was incorrectly indexed
     Index,
     // Error matching the input (ffp, expected)
-    Matching(usize, String),
+    Matching(Position, String),
+    // Label thrown without being recovered from, along with the
+    // message declared for it (label, message, cursor)
+    Label(String, Option<String>, Position),
     // End of file
     EOF,
+    // Program didn't pass validation (address, reason)
+    InvalidProgram(usize, String),
+    // More labels were recovered from than allowed (limit, cursor)
+    TooManyErrors(usize, Position),
+    // Matching stopped at the requested rule event (index)
+    Stopped(usize),
+    // The progress callback asked matching to stop (cursor)
+    Cancelled(Position),
+    // The program calls a host function that wasn't registered (name)
+    HostNotFound(String),
+    // Matching was asked to start from a production the program
+    // doesn't have (name)
+    RuleNotFound(String),
+    // Reading the input from a reader failed (reason)
+    Read(String),
+    // Matching went over one of the limits set with `Config` (limit,
+    // its value, production being matched, cursor)
+    ResourceLimit(Limit, usize, String, Position),
+    // Tree matched in lossless mode doesn't cover each char of the
+    // input it consumed exactly once (first char that it doesn't)
+    Lossless(Position),
+}
+
+/// Limits on the resources a single run of the virtual machine can
+/// use, see `Config::with_max_stack_depth` and `Config::with_max_steps`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    /// Frames held by the stack at once
+    StackDepth,
+    /// Instructions executed
+    Steps,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Limit::StackDepth => write!(f, "max_stack_depth"),
+            Limit::Steps => write!(f, "max_steps"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Runtime Error")?;
+        match self {
+            Error::Fail => write!(f, "[Fail]"),
+            Error::LeftRec => write!(f, "[LeftRec]"),
+            Error::Index => write!(f, "[Index]"),
+            Error::Matching(ffp, msg) => write!(f, "[Matching]: {}: {}", ffp, msg),
+            Error::Label(label, message, cursor) => {
+                write!(f, "[Label]: {}: {}", cursor, label)?;
+                match message {
+                    Some(message) => write!(f, ": {}", message),
+                    None => Ok(()),
+                }
+            }
+            Error::EOF => write!(f, "[EOF]: unexpected end of input"),
+            Error::InvalidProgram(addr, msg) => write!(f, "[InvalidProgram]: {}: {}", addr, msg),
+            Error::TooManyErrors(max, cursor) => write!(
+                f,
+                "[TooManyErrors]: gave up at {} after recovering from {} errors",
+                cursor, max
+            ),
+            Error::Stopped(event) => write!(f, "[Stopped]: at event {}", event),
+            Error::Cancelled(cursor) => write!(f, "[Cancelled]: at {}", cursor),
+            Error::HostNotFound(name) => write!(f, "[HostNotFound]: {}", name),
+            Error::RuleNotFound(name) => write!(f, "[RuleNotFound]: {}", name),
+            Error::Read(reason) => write!(f, "[Read]: {}", reason),
+            Error::ResourceLimit(limit, max, rule, cursor) => write!(
+                f,
+                "[ResourceLimit]: {} of {} reached within {} at {}",
+                limit, max, rule, cursor
+            ),
+            Error::Lossless(at) => {
+                write!(f, "[Lossless]: tree doesn't cover the input at {} once", at)
+            }
+        }
+    }
+}
+
+/// Decides what the virtual machine does with values captured by a
+/// production whose address isn't found in the table of identifiers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnnamedProductions {
+    /// Wrap the captured values in a node named `<anon@addr>`, where
+    /// `addr` is the address of the first instruction of the
+    /// production
+    #[default]
+    SyntheticName,
+    /// Don't wrap the captured values in a node, and hand them over
+    /// to the calling production as if they were captured by it
+    Unwrap,
+}
+
+/// Instructions of a program.  Programs loaded with
+/// `Program::from_mapped` read their instructions from the bytes they
+/// were saved to, as they get executed, instead of holding a copy.
+#[derive(Clone)]
+pub(crate) enum Code {
+    Owned(Vec<Instruction>),
+    Mapped(bytecode::Mapped),
+}
+
+impl Code {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Code::Owned(code) => code.len(),
+            Code::Mapped(code) => code.len(),
+        }
+    }
+
+    /// Instruction at `pc`, which must be within the code
+    pub(crate) fn at(&self, pc: usize) -> Instruction {
+        match self {
+            Code::Owned(code) => code[pc].clone(),
+            Code::Mapped(code) => code.instruction(pc),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Instruction> + '_ {
+        (0..self.len()).map(|pc| self.at(pc))
+    }
+
+    /// Instructions that can be changed, copied out of the bytes
+    /// they're read from first if they're mapped
+    pub(crate) fn to_mut(&mut self) -> &mut Vec<Instruction> {
+        if let Code::Mapped(code) = self {
+            *self = Code::Owned((0..code.len()).map(|pc| code.instruction(pc)).collect());
+        }
+        match self {
+            Code::Owned(code) => code,
+            Code::Mapped(_) => unreachable!(),
+        }
+    }
+}
+
+impl From<Vec<Instruction>> for Code {
+    fn from(code: Vec<Instruction>) -> Self {
+        Code::Owned(code)
+    }
+}
+
+impl std::fmt::Debug for Code {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -109,19 +857,33 @@ pub struct Program {
     // Map with keys as the position of the first instruction of each
     // production in the source code, and values as the index in the
     // strings table where the name of the production can be found.
-    identifiers: HashMap<usize, usize>,
+    pub(crate) identifiers: HashMap<usize, usize>,
     // Map with IDs of labels as keys and the ID of the messages
     // associated with the labels as values
-    labels: HashMap<usize, usize>,
+    pub(crate) labels: HashMap<usize, usize>,
     // Map from label IDs to tuples with two things: address of the
     // recovery expression and its precedence level
-    recovery: HashMap<usize, (usize, usize)>,
+    pub(crate) recovery: HashMap<usize, (usize, usize)>,
     // Table with strings that refer to either error labels or
     // production identifiers.  IDs are assigned in the order they are
     // requested.
-    strings: Vec<String>,
+    pub(crate) strings: Vec<String>,
     // Array of instructions that get executed by the virtual machine
-    code: Vec<Instruction>,
+    pub(crate) code: Code,
+    // What to do with values captured by productions without a name
+    pub(crate) unnamed: UnnamedProductions,
+    // Map with the addresses of calls to productions that aren't
+    // defined within the program as keys, and the index in the
+    // strings table where the name of the production can be found as
+    // values.  These calls get resolved when programs are linked.
+    pub(crate) externals: HashMap<usize, usize>,
+    // Table with the ranges of the characters matched by each
+    // `Class` instruction, sorted and without overlaps, so a
+    // character can be looked up with a binary search
+    pub(crate) classes: Vec<Vec<(char, char)>>,
+    // Version declared by the grammar the program was compiled from
+    // with `@version`, if any
+    pub(crate) version: Option<String>,
 }
 
 impl Program {
@@ -131,14 +893,226 @@ impl Program {
         recovery: HashMap<usize, (usize, usize)>,
         strings: Vec<String>,
         code: Vec<Instruction>,
+        unnamed: UnnamedProductions,
     ) -> Self {
         Program {
             identifiers,
             labels,
             recovery,
             strings,
-            code,
+            code: Code::Owned(code),
+            unnamed,
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+        }
+    }
+
+    /// Set the table of ranges the `Class` instructions of the
+    /// program point into
+    pub(crate) fn with_classes(self, classes: Vec<Vec<(char, char)>>) -> Self {
+        Program { classes, ..self }
+    }
+
+    /// Set the version declared by the grammar the program was
+    /// compiled from
+    pub(crate) fn with_version(self, version: Option<String>) -> Self {
+        Program { version, ..self }
+    }
+
+    /// Match `input` with the default options of the virtual
+    /// machine and return the values captured by the rule matching
+    /// starts from.  `None` means the input matched without anything
+    /// being captured.  Use [`VM`] for more options.
+    pub fn parse_value(&self, input: &str) -> Result<Option<Value>, Error> {
+        VM::new(self).run(input)
+    }
+
+    /// Save the program in the format described in [`bytecode`], so
+    /// it can be loaded with `Program::from_bytes` without compiling
+    /// the grammar again
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bytecode::encode(self)
+    }
+
+    /// Load a program saved with `Program::to_bytes`.  Its code is
+    /// checked like `Program::validate` checks it, but calls to
+    /// productions of other programs don't have to be linked yet.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, bytecode::Error> {
+        bytecode::decode(bytes)
+    }
+
+    /// Load a program saved with `Program::to_bytes` without copying
+    /// its code, which gets executed from `bytes` as they are.  The
+    /// code is checked like `Program::from_bytes` checks it.
+    pub fn from_mapped(
+        bytes: impl AsRef<[u8]> + Send + Sync + 'static,
+    ) -> Result<Program, bytecode::Error> {
+        bytecode::load(Arc::new(bytes))
+    }
+
+    /// Map the program saved at `path` into memory and check it with
+    /// `Program::validate`.  Loading doesn't get slower as programs
+    /// grow, since their code is executed from the mapped file.  The
+    /// file must not change while the program is in use.
+    #[cfg(feature = "mmap")]
+    pub fn map_file(path: impl AsRef<std::path::Path>) -> Result<Program, crate::Error> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the mapping is only ever read, and the contract of
+        // this function is that the file isn't changed while it's
+        // mapped
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let program = Program::from_mapped(map)?;
+        program.validate()?;
+        Ok(program)
+    }
+
+    /// Replace the code of the program
+    pub(crate) fn with_code(self, code: Code) -> Self {
+        Program { code, ..self }
+    }
+
+    /// Record calls to productions defined in other programs, which
+    /// must be linked to this one with `Program::concat` before it
+    /// can be executed
+    pub(crate) fn with_externals(self, externals: HashMap<usize, usize>) -> Self {
+        Program { externals, ..self }
+    }
+
+    /// Link `programs` into a single one that starts where the first
+    /// program starts.  The code of each program is appended after
+    /// the code of the previous one as it is, since instructions only
+    /// hold offsets relative to their own address, and the tables of
+    /// strings, identifiers, labels and recovery expressions merged.
+    /// The addresses within the tables are the only absolute ones,
+    /// and get rebased.  Calls to productions defined in
+    /// other programs are then resolved by name.  When more than one
+    /// program defines a production, the first one wins.  Calls that
+    /// can't be resolved stay unresolved within the output, so it can
+    /// be linked again with more programs.
+    pub fn concat(programs: &[&Program]) -> Result<Program, Error> {
+        let first = programs
+            .first()
+            .ok_or_else(|| invalid(0, "no programs to link"))?;
+        let mut linked = Program::new(
+            HashMap::new(),
+            HashMap::new(),
+            HashMap::new(),
+            vec![],
+            vec![],
+            first.unnamed,
+        )
+        .with_version(first.version.clone());
+        let mut strings_map: HashMap<String, usize> = HashMap::new();
+        // name of each production mapped to its address and the
+        // precedence level the calls to it must use
+        let mut rules: HashMap<usize, (usize, usize)> = HashMap::new();
+
+        for program in programs {
+            let base = linked.code.len();
+            let class_base = linked.classes.len();
+            linked.classes.extend(program.classes.iter().cloned());
+            let ids: Vec<usize> = program
+                .strings
+                .iter()
+                .map(|s| {
+                    *strings_map.entry(s.clone()).or_insert_with(|| {
+                        linked.strings.push(s.clone());
+                        linked.strings.len() - 1
+                    })
+                })
+                .collect();
+            linked
+                .code
+                .to_mut()
+                .extend(program.code.iter().map(|instruction| match instruction {
+                    Instruction::String(id) => Instruction::String(ids[id]),
+                    Instruction::StringCI(id) => Instruction::StringCI(ids[id]),
+                    Instruction::Take(id) => Instruction::Take(ids[id]),
+                    Instruction::Host(id) => Instruction::Host(ids[id]),
+                    Instruction::Throw(id) => Instruction::Throw(ids[id]),
+                    Instruction::Class(id) => Instruction::Class(id + class_base),
+                    instruction => instruction,
+                }));
+            for (addr, id) in &program.identifiers {
+                linked.identifiers.insert(addr + base, ids[*id]);
+                rules
+                    .entry(ids[*id])
+                    .or_insert_with(|| (addr + base, program.precedence(*addr)));
+            }
+            for (label, message) in &program.labels {
+                linked.labels.entry(ids[*label]).or_insert(ids[*message]);
+            }
+            for (label, (addr, precedence)) in &program.recovery {
+                linked
+                    .recovery
+                    .entry(ids[*label])
+                    .or_insert((addr + base, *precedence));
+            }
+            for (addr, id) in &program.externals {
+                linked.externals.insert(addr + base, ids[*id]);
+            }
+        }
+
+        // point the calls to other programs to where the production
+        // ended up within the linked code
+        let externals = std::mem::take(&mut linked.externals);
+        for (site, id) in externals {
+            match rules.get(&id) {
+                Some((addr, precedence)) => {
+                    linked.code.to_mut()[site] = if *addr > site {
+                        Instruction::Call(addr - site, *precedence)
+                    } else {
+                        Instruction::CallB(site - addr, *precedence)
+                    };
+                }
+                None => {
+                    linked.externals.insert(site, id);
+                }
+            }
+        }
+
+        // labels thrown by one program can be recovered from by a
+        // production defined in another one
+        for instruction in linked.code.iter() {
+            if let Instruction::Throw(label) = instruction {
+                if let Some(target) = rules.get(&label) {
+                    linked.recovery.entry(label).or_insert(*target);
+                }
+            }
         }
+        Ok(linked)
+    }
+
+    /// Precedence level used by the calls to the production at
+    /// `address`, which is only greater than zero for left recursive
+    /// productions.  A left recursive production always has at least
+    /// one call to it within the program it's defined in.
+    fn precedence(&self, address: usize) -> usize {
+        let calls = self.code.iter().enumerate().filter_map(|(pc, i)| match i {
+            Instruction::Call(offset, k) if pc + offset == address => Some(k),
+            Instruction::CallB(offset, k) if pc.checked_sub(offset) == Some(address) => Some(k),
+            _ => None,
+        });
+        let recovery = self
+            .recovery
+            .values()
+            .filter(|(addr, _)| *addr == address)
+            .map(|(_, k)| *k);
+        calls.chain(recovery).max().unwrap_or(0)
+    }
+
+    /// Names of the productions this program calls but doesn't
+    /// define, sorted and without duplicates
+    pub fn unresolved(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self
+            .externals
+            .values()
+            .map(|id| &self.strings[*id])
+            .collect();
+        names.sort();
+        names.dedup();
+        names
     }
 
     pub fn label(&self, id: usize) -> String {
@@ -152,25 +1126,422 @@ impl Program {
         None
     }
 
+    /// Name of the production starting at `address`.  If there's no
+    /// production registered at `address`, a synthetic name that
+    /// contains the address is returned instead.
     pub fn identifier(&self, address: usize) -> String {
         match self.identifiers.get(&address) {
-            None => "?".to_string(),
+            None => format!("<anon@{}>", address),
             Some(id) => self.strings[*id].clone(),
         }
     }
 
+    /// Name of the production starting at `address` if there's one
+    /// registered within the table of identifiers
+    pub fn identifier_at(&self, address: usize) -> Option<&String> {
+        self.identifiers.get(&address).map(|id| &self.strings[*id])
+    }
+
+    /// ID of the production starting at `address`.  IDs are dense
+    /// and follow the order in which productions were emitted, which
+    /// is the order in which they're defined in the grammar.
+    pub fn rule_id(&self, address: usize) -> Option<RuleId> {
+        self.rule_addresses()
+            .binary_search(&address)
+            .ok()
+            .map(|i| RuleId(i as u32))
+    }
+
+    /// Name of the production identified by `id`
+    pub fn rule_name(&self, id: RuleId) -> Option<&String> {
+        let address = *self.rule_addresses().get(id.0 as usize)?;
+        self.identifier_at(address)
+    }
+
+    /// Names of the productions matching can start from with
+    /// `VM::run_from`, in the order they're defined in the grammar.
+    /// That's every named production, not only the first one, which
+    /// is where `VM::run` starts from.
+    pub fn entry_points(&self) -> Vec<&String> {
+        self.rule_addresses()
+            .into_iter()
+            .filter_map(|address| self.identifier_at(address))
+            .collect()
+    }
+
+    /// Address and precedence level of the call that starts matching
+    /// from the production named `rule`
+    fn entry_point(&self, rule: &str) -> Option<(usize, usize)> {
+        let (address, _) = self
+            .identifiers
+            .iter()
+            .find(|(_, id)| self.strings[**id] == rule)?;
+        Some((*address, self.precedence(*address)))
+    }
+
+    /// Addresses of all productions sorted, so the index of each
+    /// address is the ID of the production
+    fn rule_addresses(&self) -> Vec<usize> {
+        let mut addresses: Vec<usize> = self.identifiers.keys().copied().collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// What the virtual machine does with values captured by
+    /// productions that don't have a name
+    pub fn unnamed_productions(&self) -> UnnamedProductions {
+        self.unnamed
+    }
+
+    /// Version declared with `@version` by the grammar the program
+    /// was compiled from.  Values produced by programs of older
+    /// versions can be brought up to date with
+    /// [`langlang_value::migrate::Migrations`].
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
     pub fn string_at(&self, id: usize) -> &String {
         &self.strings[id]
     }
+
+    /// Ranges of the characters matched by the class at `id` within
+    /// the classes table
+    pub fn class_at(&self, id: usize) -> &[(char, char)] {
+        &self.classes[id]
+    }
+
+    /// Check that the program can be executed without the virtual
+    /// machine indexing anything out of bounds.  All jump and call
+    /// targets must point within the code vector, all string and
+    /// label references must exist within the strings table, every
+    /// instruction that pops a backtrack frame must have a matching
+    /// `Choice` on every path that reaches it, and no path can run
+    /// past the end of the program without hitting `Halt`, `Return`
+    /// or a failure.  Calls to productions of other programs must
+    /// have been linked with `Program::concat`.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_code()?;
+        if let Some(addr) = self.externals.keys().min() {
+            let name = &self.strings[self.externals[addr]];
+            return Err(Error::InvalidProgram(
+                *addr,
+                format!("call to production {:?} that wasn't linked", name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks of `Program::validate` that don't need the program to
+    /// be linked, which loaded programs go through, so a program
+    /// saved before it got linked can still be loaded
+    pub(crate) fn validate_code(&self) -> Result<(), Error> {
+        let len = self.code.len();
+        if len == 0 {
+            return Err(Error::InvalidProgram(0, "empty program".to_string()));
+        }
+        for addr in self.identifiers.keys() {
+            if *addr >= len {
+                return Err(invalid(*addr, "production address out of bounds"));
+            }
+        }
+        for addr in self.externals.keys() {
+            if *addr >= len {
+                return Err(invalid(*addr, "call address out of bounds"));
+            }
+        }
+        for (label, message) in &self.labels {
+            if *label >= self.strings.len() || *message >= self.strings.len() {
+                return Err(invalid(*label, "label not found in strings table"));
+            }
+        }
+
+        // Each function is walked from its entry point with an empty
+        // backtrack stack.  The depth of the stack seen at each
+        // address must be the same regardless of the path taken to
+        // get there.
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        let mut entries = vec![0];
+        let mut seen_entries = HashSet::new();
+        for (addr, _) in self.recovery.values() {
+            entries.push(*addr);
+        }
+        // productions can be matched from with `VM::run_from`, even
+        // if no call reaches them
+        entries.extend(self.identifiers.keys());
+        while let Some(entry) = entries.pop() {
+            if !seen_entries.insert(entry) {
+                continue;
+            }
+            let mut work = vec![(entry, 0)];
+            while let Some((pc, depth)) = work.pop() {
+                if pc >= len {
+                    return Err(invalid(pc, "execution runs past the end of the program"));
+                }
+                match depths[pc] {
+                    Some(d) if d == depth => continue,
+                    Some(_) => return Err(invalid(pc, "unbalanced backtrack stack")),
+                    None => depths[pc] = Some(depth),
+                }
+                let pop = |depth: usize| {
+                    depth
+                        .checked_sub(1)
+                        .ok_or_else(|| invalid(pc, "commit without a matching choice"))
+                };
+                let fwd = |offset: usize| {
+                    pc.checked_add(offset)
+                        .filter(|t| *t < len)
+                        .ok_or_else(|| invalid(pc, "target address out of bounds"))
+                };
+                let back = |offset: usize| {
+                    pc.checked_sub(offset)
+                        .ok_or_else(|| invalid(pc, "target address out of bounds"))
+                };
+                match self.code.at(pc) {
+                    Instruction::Halt | Instruction::Fail => {}
+                    Instruction::Return | Instruction::ReturnValues => {
+                        if depth != 0 {
+                            return Err(invalid(pc, "return with pending backtrack frames"));
+                        }
+                    }
+                    Instruction::FailTwice => {
+                        pop(depth)?;
+                    }
+                    Instruction::String(id)
+                    | Instruction::StringCI(id)
+                    | Instruction::Take(id)
+                    | Instruction::Host(id)
+                    | Instruction::Throw(id) => {
+                        if id >= self.strings.len() {
+                            return Err(invalid(pc, "string not found in strings table"));
+                        }
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::Class(id) => {
+                        if id >= self.classes.len() {
+                            return Err(invalid(pc, "class not found in classes table"));
+                        }
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::Property(id) => {
+                        if id >= properties::len() {
+                            return Err(invalid(pc, "unknown Unicode property"));
+                        }
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::Choice(offset) | Instruction::ChoiceP(offset) => {
+                        work.push((fwd(offset)?, depth));
+                        work.push((pc + 1, depth + 1));
+                    }
+                    Instruction::Commit(offset) | Instruction::BackCommit(offset) => {
+                        work.push((fwd(offset)?, pop(depth)?));
+                    }
+                    Instruction::CommitB(offset) => {
+                        work.push((back(offset)?, pop(depth)?));
+                    }
+                    Instruction::PartialCommit(offset) => {
+                        pop(depth)?;
+                        work.push((back(offset)?, depth));
+                    }
+                    Instruction::TestChar(_, offset) | Instruction::TestAny(offset) => {
+                        work.push((fwd(offset)?, depth));
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::Jump(offset) => work.push((fwd(offset)?, depth)),
+                    Instruction::JumpB(offset) => work.push((back(offset)?, depth)),
+                    Instruction::Call(offset, _) => {
+                        entries.push(fwd(offset)?);
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::CallB(offset, _) => {
+                        entries.push(back(offset)?);
+                        work.push((pc + 1, depth));
+                    }
+                    Instruction::Open => work.push((pc + 1, depth + 1)),
+                    Instruction::Close(_) => work.push((pc + 1, pop(depth)?)),
+                    Instruction::Any
+                    | Instruction::Char(_)
+                    | Instruction::Span(..)
+                    | Instruction::CharCI(_)
+                    | Instruction::SpanCI(..)
+                    | Instruction::Int(..)
+                    | Instruction::Varint
+                    | Instruction::CapPush
+                    | Instruction::CapPop
+                    | Instruction::CapCommit
+                    | Instruction::CapJoin
+                    | Instruction::CapText
+                    | Instruction::CapList
+                    | Instruction::CapDrop => work.push((pc + 1, depth)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(pc: usize, reason: &str) -> Error {
+    Error::InvalidProgram(pc, reason.to_string())
 }
 
 fn instruction_to_string(p: &Program, instruction: &Instruction, pc: usize) -> String {
+    if let Some(id) = p.externals.get(&pc) {
+        return format!("call {:?} (unresolved)", p.strings[*id]);
+    }
+    let metadata = instruction.metadata();
+    let operands = metadata.operands.iter().zip(instruction.operands());
+    operands.fold(metadata.mnemonic.to_string(), |s, (info, operand)| {
+        format!("{} {}", s, operand_to_string(p, info.kind, &operand, pc))
+    })
+}
+
+/// Operand of the instruction at `pc` with the entries of the
+/// tables it points to, instead of their indexes
+fn operand_to_string(p: &Program, kind: OperandKind, operand: &Operand, pc: usize) -> String {
+    match (kind, operand) {
+        (OperandKind::String | OperandKind::Label, Operand::Index(i)) => {
+            format!("{:?}", p.strings[*i])
+        }
+        (OperandKind::Class, Operand::Index(i)) => class_to_string(&p.classes[*i]),
+        (OperandKind::Property, Operand::Index(i)) => format!("\\p{{{}}}", properties::name(*i)),
+        (OperandKind::Production, Operand::Index(i)) => format!("{:?}", p.identifier(pc + i)),
+        (OperandKind::BackProduction, Operand::Index(i)) => {
+            format!("{:?}", p.identifier(pc - i))
+        }
+        (_, Operand::Char(c)) => format!("{:?}", c),
+        (_, Operand::Index(n)) => n.to_string(),
+        (_, Operand::Endian(e)) => format!("{:?}", e),
+        (_, Operand::Container(t)) => format!("{:?}", t),
+    }
+}
+
+/// Ranges of a class written the way they're written in grammars,
+/// e.g.: `[a-z_]`
+fn class_to_string(ranges: &[(char, char)]) -> String {
+    let escape = |c: char| match c {
+        '[' | ']' | '\\' | '-' | '^' => format!("\\{}", c),
+        c => c.escape_debug().to_string(),
+    };
+    let items = ranges.iter().map(|(start, end)| match start == end {
+        true => escape(*start),
+        false => format!("{}-{}", escape(*start), escape(*end)),
+    });
+    format!("[{}]", items.collect::<String>())
+}
+
+/// Replace `values` with a string holding the text they matched,
+/// followed by the errors found within them, for `@capture(string)`
+pub(crate) fn fold_text(values: &mut Vec<Value>) {
+    fn errors(value: &Value, found: &mut Vec<Value>) {
+        match value {
+            Value::Error(_) => found.push(value.clone()),
+            Value::List(v) => v.values.iter().for_each(|i| errors(i, found)),
+            Value::Node(v) => v.items.iter().for_each(|i| errors(i, found)),
+            Value::Char(_) | Value::String(_) => {}
+        }
+    }
+    if let (Some(first), Some(last)) = (values.first(), values.last()) {
+        let span = Span::new(first.span().start, last.span().end);
+        let text = values.iter().map(Value::to_text).collect::<String>();
+        let mut found = vec![];
+        values.iter().for_each(|v| errors(v, &mut found));
+        *values = vec![value::String::new_val(span, text)];
+        values.append(&mut found);
+    }
+}
+
+/// Replace `values` with a list holding them, for
+/// `@capture(list)`.  Empty lists are placed at `pos`
+pub(crate) fn fold_list(values: &mut Vec<Value>, pos: Position) {
+    let span = match (values.first(), values.last()) {
+        (Some(first), Some(last)) => Span::new(first.span().start, last.span().end),
+        _ => Span::new(pos.clone(), pos),
+    };
+    let items = std::mem::take(values);
+    values.push(value::List::new_val(span, items));
+}
+
+/// Offset of the first char of the input up to `end` that the leaves
+/// of `value` don't cover exactly once, if any, for
+/// `Config::with_lossless`.  Errors cover the input skipped by their
+/// recovery expression, unless they're within text already covered,
+/// like the errors `@capture(string)` places after the text.
+fn uncovered(value: Option<&Value>, end: usize) -> Option<usize> {
+    fn walk(value: &Value, next: &mut usize) -> Result<(), usize> {
+        let span = match value {
+            Value::List(l) => return l.values.iter().try_for_each(|v| walk(v, next)),
+            Value::Node(n) => return n.items.iter().try_for_each(|v| walk(v, next)),
+            Value::Error(e) if e.span.end.offset <= *next => return Ok(()),
+            leaf => leaf.span(),
+        };
+        if span.start.offset != *next {
+            return Err(span.start.offset.min(*next));
+        }
+        *next = span.end.offset;
+        Ok(())
+    }
+    let mut next = 0;
+    if let Some(value) = value {
+        if let Err(offset) = walk(value, &mut next) {
+            return Some(offset);
+        }
+    }
+    (next != end).then_some(next.min(end))
+}
+
+/// Read the integer `instruction` matches from the start of `bytes`,
+/// returning its value and how many bytes it took
+fn read_int(instruction: &Instruction, bytes: &[u8]) -> Option<(u64, usize)> {
+    match instruction {
+        Instruction::Int(width, endian) => {
+            let bytes = bytes.get(..*width)?;
+            let fold = |acc: u64, b: &u8| acc << 8 | u64::from(*b);
+            let value = match endian {
+                Endian::Big => bytes.iter().fold(0, fold),
+                Endian::Little => bytes.iter().rev().fold(0, fold),
+            };
+            Some((value, *width))
+        }
+        _ => {
+            let mut value = 0u64;
+            for (i, b) in bytes.iter().enumerate() {
+                let bits = u64::from(b & 0x7f);
+                // the tenth byte only has room for the 64th bit
+                if i == 9 && bits > 1 {
+                    return None;
+                }
+                value |= bits << (7 * i);
+                if b & 0x80 == 0 {
+                    return Some((value, i + 1));
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Count `%take(name)` consumes: the decimal text of the last node
+/// called `name` within `values`
+pub(crate) fn captured_count(values: &[Value], name: &str) -> Option<usize> {
+    values.iter().rev().find_map(|v| match v {
+        Value::Node(n) if n.name == name => n
+            .items
+            .iter()
+            .map(Value::to_text)
+            .collect::<String>()
+            .parse()
+            .ok(),
+        _ => None,
+    })
+}
+
+/// Name of the grammar terminal an integer instruction comes from
+fn int_name(instruction: &Instruction) -> String {
     match instruction {
-        Instruction::String(i) => format!("str {:?}", p.strings[*i]),
-        Instruction::Call(addr, k) => format!("call {:?} {}", p.identifier(pc + addr), k),
-        Instruction::CallB(addr, k) => format!("callb {:?} {}", p.identifier(pc - addr), k),
-        Instruction::Throw(label) => format!("throw {:?}", p.strings[*label]),
-        instruction => format!("{}", instruction),
+        Instruction::Int(1, _) => "%u8".to_string(),
+        Instruction::Int(width, Endian::Little) => format!("%u{}le", width * 8),
+        Instruction::Int(width, Endian::Big) => format!("%u{}be", width * 8),
+        _ => "%varint".to_string(),
     }
 }
 
@@ -192,7 +1563,7 @@ impl std::fmt::Display for Program {
                 writeln!(f, " {}:", self.string_at(*id))?;
             }
             write!(f, "  {:#04} ", i)?;
-            writeln!(f, "{}", instruction_to_string(self, instruction, i))?;
+            writeln!(f, "{}", instruction_to_string(self, &instruction, i))?;
         }
         write!(f, "")
     }
@@ -217,7 +1588,22 @@ struct StackFrame {
     precedence: usize,            // k
     predicate: bool,
     recovery_label: Option<usize>,
-    list: Option<Vec<Value>>,
+    list: Option<Input>,
+    // number of values on the top of the capture stack when a
+    // backtrack frame was pushed, which are the ones kept on failure.
+    // For left recursive calls, number of values holding the longest
+    // match found so far
+    captures: usize,
+    // number of inputs pushed by host functions when a backtrack
+    // frame was pushed, the ones pushed after it are dropped on
+    // failure
+    inputs: usize,
+    // number of trivia entries kept when the frame was pushed, or by
+    // the longest match of a left recursive call, see `captures`
+    trivia: usize,
+    // times a left recursive call matched more input, each one
+    // nesting the node it captures one level deeper
+    bound: usize,
 }
 
 impl StackFrame {
@@ -227,6 +1613,8 @@ impl StackFrame {
         column: usize,
         pc: usize,
         predicate: bool,
+        captures: usize,
+        inputs: usize,
     ) -> Self {
         StackFrame {
             ftype: StackFrameType::Backtrack,
@@ -235,6 +1623,10 @@ impl StackFrame {
             line,
             column,
             predicate,
+            captures,
+            inputs,
+            trivia: 0,
+            bound: 0,
             // fields not used for backtrack frames
             recovery_label: None,
             address: 0,
@@ -245,6 +1637,9 @@ impl StackFrame {
     }
 
     fn new_call(
+        cursor: usize,
+        line: usize,
+        column: usize,
         pc: usize,
         address: usize,
         precedence: usize,
@@ -253,12 +1648,16 @@ impl StackFrame {
         StackFrame {
             ftype: StackFrameType::Call,
             program_counter: pc,
-            cursor: 0,
-            line: 0,
-            column: 0,
+            cursor,
+            line,
+            column,
             result: Err(Error::Fail),
             predicate: false,
             list: None,
+            captures: 0,
+            inputs: 0,
+            trivia: 0,
+            bound: 0,
             address,
             precedence,
             recovery_label,
@@ -267,6 +1666,8 @@ impl StackFrame {
 
     fn new_lrcall(
         cursor: usize,
+        line: usize,
+        column: usize,
         pc: usize,
         address: usize,
         precedence: usize,
@@ -278,16 +1679,20 @@ impl StackFrame {
             result: Err(Error::LeftRec),
             predicate: false,
             list: None,
+            captures: 0,
+            inputs: 0,
+            trivia: 0,
+            bound: 0,
             cursor,
-            line: 0,
-            column: 0,
+            line,
+            column,
             address,
             precedence,
             recovery_label,
         }
     }
 
-    fn new_list(cursor: usize, pc: usize, list: Vec<Value>) -> Self {
+    fn new_list(cursor: usize, pc: usize, list: Input) -> Self {
         StackFrame {
             ftype: StackFrameType::List,
             program_counter: pc,
@@ -301,6 +1706,10 @@ impl StackFrame {
             result: Ok(0),
             line: 0,
             column: 0,
+            captures: 0,
+            inputs: 0,
+            trivia: 0,
+            bound: 0,
         }
     }
 }
@@ -321,6 +1730,17 @@ struct CapStackFrame {
 //    s: subject, cursor index
 type LeftRecTableKey = (usize, usize);
 
+// outcome of a call kept by `Config::with_packrat`, keyed by the
+// address of the production and the cursor it was called at
+#[derive(Debug)]
+enum PackratEntry {
+    // the production didn't match, with the error it failed with
+    Failed(Error),
+    // the production matched up to `end`, adding `values` to the
+    // captures of its caller
+    Matched { end: usize, values: Vec<Value> },
+}
+
 #[derive(Debug)]
 struct LeftRecTableEntry {
     // cursor (s'): subject in left recursive call
@@ -345,13 +1765,1037 @@ impl LeftRecTableEntry {
     }
 }
 
+/// Token is the unit of input produced by an external lexer.  When
+/// matching over tokens, string literals in the grammar match the
+/// `kind` of the token under the cursor and the any (`.`) operator
+/// matches any token.  Matched tokens are captured as their `text`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: String,
+    pub text: String,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn new(kind: &str, text: &str, span: Span) -> Self {
+        Self {
+            kind: kind.to_string(),
+            text: text.to_string(),
+            span,
+        }
+    }
+}
+
+/// Input is what the virtual machine matches patterns against.  Text
+/// is kept as an index-only representation: the value of each char
+/// and its position within the input are only materialized when they
+/// get captured.
 #[derive(Debug)]
-pub struct VM<'a> {
-    // Cursor position at the input
-    cursor: usize,
+enum Input {
+    Chars {
+        chars: Vec<char>,
+        // offset of the first char of each line
+        lines: Vec<usize>,
+        // text spliced into `chars` by host functions, in the order
+        // it was pushed
+        inclusions: Vec<Inclusion>,
+    },
+    Values(Vec<Value>),
+    Tokens(Vec<Token>),
+    // text pulled from a reader as it gets matched, see `Stream`
+    Stream(RefCell<Stream>),
+}
+
+impl Input {
+    fn from_str(input: &str) -> Self {
+        Self::from_chars(input.chars().collect())
+    }
+
+    /// Each byte becomes the char with the same code point, from
+    /// `U+0000` to `U+00FF`, which is what `\xHH` escapes compile to
+    fn from_bytes(input: &[u8]) -> Self {
+        Self::from_chars(input.iter().copied().map(char::from).collect())
+    }
+
+    fn from_chars(chars: Vec<char>) -> Self {
+        Input::Chars {
+            lines: line_starts(&chars),
+            chars,
+            inclusions: vec![],
+        }
+    }
+
+    fn from_reader<R: Read + 'static>(reader: R) -> Self {
+        Input::Stream(RefCell::new(Stream::new(Box::new(reader))))
+    }
+
+    fn inclusions(&self) -> &[Inclusion] {
+        match self {
+            Input::Chars { inclusions, .. } => inclusions,
+            Input::Values(_) | Input::Tokens(_) | Input::Stream(_) => &[],
+        }
+    }
+
+    /// Splice `text` into the input at `cursor`.  Only text input
+    /// that is fully loaded can have other inputs pushed into it.
+    fn include(&mut self, cursor: usize, name: String, text: &str) {
+        let (chars, inclusions) = match self {
+            Input::Chars {
+                chars, inclusions, ..
+            } => (chars, inclusions),
+            Input::Values(_) | Input::Tokens(_) | Input::Stream(_) => return,
+        };
+        let added: Vec<char> = text.chars().collect();
+        let len = added.len();
+        for i in inclusions.iter_mut() {
+            if i.start >= cursor {
+                i.start += len;
+                i.end += len;
+            } else if cursor < i.end {
+                i.end += len;
+            }
+        }
+        inclusions.push(Inclusion {
+            name,
+            start: cursor,
+            end: cursor + len,
+            lines: line_starts(&added),
+        });
+        chars.splice(cursor..cursor, added);
+    }
+
+    /// Take out the text of the inclusions after the first `count`
+    fn exclude(&mut self, count: usize) {
+        let (chars, inclusions) = match self {
+            Input::Chars {
+                chars, inclusions, ..
+            } => (chars, inclusions),
+            Input::Values(_) | Input::Tokens(_) | Input::Stream(_) => return,
+        };
+        while inclusions.len() > count {
+            let Some(removed) = inclusions.pop() else {
+                break;
+            };
+            let len = removed.end - removed.start;
+            chars.drain(removed.start..removed.end);
+            for i in inclusions.iter_mut() {
+                if i.start >= removed.end {
+                    i.start -= len;
+                    i.end -= len;
+                } else if i.start < removed.start && removed.end <= i.end {
+                    i.end -= len;
+                }
+            }
+        }
+    }
+
+    /// Number of items within the input.  For streams, that's the
+    /// number of chars read so far.
+    fn len(&self) -> usize {
+        match self {
+            Input::Chars { chars, .. } => chars.len(),
+            Input::Values(values) => values.len(),
+            Input::Tokens(tokens) => tokens.len(),
+            Input::Stream(stream) => stream.borrow().len(),
+        }
+    }
+
+    /// True if there's nothing left to match at `cursor`
+    fn at_end(&self, cursor: usize) -> bool {
+        match self {
+            Input::Stream(stream) => !stream.borrow_mut().fill(cursor),
+            _ => cursor >= self.len(),
+        }
+    }
+
+    /// Character under `cursor` if there's one
+    fn char_at(&self, cursor: usize) -> Option<char> {
+        match self {
+            Input::Chars { chars, .. } => chars.get(cursor).copied(),
+            Input::Values(values) => match values.get(cursor) {
+                Some(Value::Char(c)) => Some(c.value),
+                _ => None,
+            },
+            Input::Tokens(_) => None,
+            Input::Stream(stream) => stream.borrow_mut().char_at(cursor),
+        }
+    }
+
+    /// Value under `cursor`.  Panics if `cursor` is out of bounds.
+    fn value_at(&self, cursor: usize) -> Value {
+        match self {
+            Input::Chars { chars, .. } => value::Char::new_val(self.span_at(cursor), chars[cursor]),
+            Input::Values(values) => values[cursor].clone(),
+            Input::Tokens(tokens) => {
+                value::String::new_val(tokens[cursor].span.clone(), tokens[cursor].text.clone())
+            }
+            Input::Stream(stream) => {
+                let c = stream
+                    .borrow_mut()
+                    .char_at(cursor)
+                    .expect("cursor within input");
+                value::Char::new_val(self.span_at(cursor), c)
+            }
+        }
+    }
+
+    /// Span of the value under `cursor`.  Lines are counted from
+    /// zero and columns from one.
+    fn span_at(&self, cursor: usize) -> Span {
+        match self {
+            Input::Chars { .. } | Input::Stream(_) => {
+                let start = self.char_position(cursor);
+                let end = Position::new(cursor + 1, start.line, start.column + 1);
+                Span::new(start, end)
+            }
+            Input::Values(values) => values[cursor].span(),
+            Input::Tokens(tokens) => tokens[cursor].span.clone(),
+        }
+    }
+
+    /// Position of `cursor` within the input, which can also be the
+    /// end of the input
+    fn position_at(&self, cursor: usize) -> Position {
+        if cursor < self.len() {
+            return self.span_at(cursor).start;
+        }
+        match self {
+            Input::Chars { .. } | Input::Stream(_) => self.char_position(cursor),
+            _ if cursor == 0 => Position::default(),
+            _ => self.span_at(self.len() - 1).end,
+        }
+    }
+
+    /// Error that stopped a stream from being read, if any
+    fn read_error(&self) -> Option<Error> {
+        match self {
+            Input::Stream(stream) => stream.borrow_mut().error.take().map(Error::Read),
+            _ => None,
+        }
+    }
+
+    /// Position of `cursor` within text input.  The line and column
+    /// are counted within the input the char comes from, which is
+    /// the innermost inclusion around `cursor` if there's one.
+    fn char_position(&self, cursor: usize) -> Position {
+        let (lines, inclusions) = match self {
+            Input::Chars {
+                lines, inclusions, ..
+            } => (lines, inclusions),
+            Input::Stream(stream) => return stream.borrow().position(cursor),
+            Input::Values(_) | Input::Tokens(_) => return Position::default(),
+        };
+        // inclusions are nested within the ones they were pushed
+        // into, so the innermost one starts last
+        let within = |outer: &Inclusion, inner: &Inclusion| {
+            outer.start < inner.start && inner.end <= outer.end
+        };
+        let source = inclusions
+            .iter()
+            .filter(|i| i.start <= cursor && cursor < i.end)
+            .max_by_key(|i| i.start);
+        let (start, lines) = match source {
+            Some(i) => (i.start, &i.lines),
+            None => (0, lines),
+        };
+        let in_source = |i: &Inclusion| match source {
+            Some(s) => within(s, i),
+            None => true,
+        };
+        // the text of the inclusions pushed right into the source
+        // before the cursor doesn't count
+        let nested: usize = inclusions
+            .iter()
+            .filter(|i| in_source(i) && i.end <= cursor)
+            .filter(|i| !inclusions.iter().any(|o| in_source(o) && within(o, i)))
+            .map(|i| i.end - i.start)
+            .sum();
+        let offset = cursor - start - nested;
+        let line = lines.partition_point(|start| *start <= offset) - 1;
+        Position::new(cursor, line, offset - lines[line] + 1)
+    }
+}
+
+/// Number of bytes read from a stream at a time
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Text read incrementally from a reader.  Only the chars from the
+/// position the machine can still backtrack to onward are kept
+/// within the buffer, the ones before it are discarded as matching
+/// moves forward.
+struct Stream {
+    reader: Box<dyn Read>,
+    // chars read and not discarded yet, `chars[0]` is at `base`
+    chars: Vec<char>,
+    base: usize,
+    // bytes of a char split between two chunks
+    pending: Vec<u8>,
+    // where chunks are read into
+    chunk: Vec<u8>,
+    // offset of the first char of each line that wasn't discarded
+    // yet, starting with the line `base` is in
+    lines: Vec<usize>,
+    // number of the line `lines[0]` starts
+    first_line: usize,
+    // number of chars buffered before the ones that can't be
+    // backtracked to are discarded
+    limit: usize,
+    // reading stops at the end of the input or at the first error
+    done: bool,
+    error: Option<String>,
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Stream")
+            .field("base", &self.base)
+            .field("buffered", &self.chars.len())
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl Stream {
+    fn new(reader: Box<dyn Read>) -> Self {
+        Self {
+            reader,
+            chars: vec![],
+            base: 0,
+            pending: vec![],
+            chunk: vec![0; STREAM_CHUNK_SIZE],
+            lines: vec![0],
+            first_line: 0,
+            limit: STREAM_CHUNK_SIZE,
+            done: false,
+            error: None,
+        }
+    }
+
+    /// Number of chars read so far, including the discarded ones
+    fn len(&self) -> usize {
+        self.base + self.chars.len()
+    }
+
+    fn char_at(&mut self, cursor: usize) -> Option<char> {
+        if !self.fill(cursor) || cursor < self.base {
+            return None;
+        }
+        Some(self.chars[cursor - self.base])
+    }
+
+    /// Read chunks until the char at `cursor` is buffered.  Returns
+    /// false if the input ends before it.
+    fn fill(&mut self, cursor: usize) -> bool {
+        while cursor >= self.len() && !self.done {
+            self.read_chunk();
+        }
+        cursor < self.len()
+    }
+
+    fn read_chunk(&mut self) {
+        let size = match self.reader.read(&mut self.chunk) {
+            Ok(size) => size,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return,
+            Err(e) => return self.stop(Some(e.to_string())),
+        };
+        if size == 0 {
+            let error = (!self.pending.is_empty())
+                .then(|| "stream ended within a UTF-8 sequence".to_string());
+            return self.stop(error);
+        }
+        self.pending.extend_from_slice(&self.chunk[..size]);
+        let (valid, invalid) = match std::str::from_utf8(&self.pending) {
+            Ok(text) => (text, false),
+            Err(e) => {
+                // a char split at the end of the chunk is completed
+                // by the next one
+                let valid = std::str::from_utf8(&self.pending[..e.valid_up_to()]).unwrap();
+                (valid, e.error_len().is_some())
+            }
+        };
+        let offset = self.len();
+        for (i, c) in valid.chars().enumerate() {
+            if c == '\n' {
+                self.lines.push(offset + i + 1);
+            }
+            self.chars.push(c);
+        }
+        let consumed = valid.len();
+        self.pending.drain(..consumed);
+        if invalid {
+            self.stop(Some(format!(
+                "stream did not contain valid UTF-8 at offset {}",
+                self.len()
+            )));
+        }
+    }
+
+    fn stop(&mut self, error: Option<String>) {
+        self.done = true;
+        self.error = error;
+    }
+
+    /// True once more chars than the limit are buffered
+    fn should_discard(&self) -> bool {
+        self.chars.len() > self.limit
+    }
+
+    /// Drop the chars before `floor`, which the machine can't
+    /// backtrack to anymore.  If most of the buffer is still needed,
+    /// the limit grows so it isn't scanned again right away.
+    fn discard(&mut self, floor: usize) {
+        let count = floor.saturating_sub(self.base).min(self.chars.len());
+        self.chars.drain(..count);
+        self.base += count;
+        let line = self.lines.partition_point(|start| *start <= self.base) - 1;
+        self.lines.drain(..line);
+        self.first_line += line;
+        if self.chars.len() > self.limit / 2 {
+            self.limit *= 2;
+        }
+    }
+
+    /// Position of `cursor`.  Lines of chars that were discarded
+    /// aren't known anymore, so their position only has the offset.
+    fn position(&self, cursor: usize) -> Position {
+        if cursor < self.lines[0] {
+            return Position::new(cursor, 0, 0);
+        }
+        let line = self.lines.partition_point(|start| *start <= cursor) - 1;
+        Position::new(
+            cursor,
+            self.first_line + line,
+            cursor - self.lines[line] + 1,
+        )
+    }
+}
+
+/// offset of the first char of each line of `chars`
+fn line_starts(chars: &[char]) -> Vec<usize> {
+    let mut lines = vec![0];
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '\n' {
+            lines.push(i + 1);
+        }
+    }
+    lines
+}
+
+/// Text pushed into the input by a host function with
+/// [`HostCall::push_input`].  Its chars are matched right where it
+/// was pushed, before the rest of the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inclusion {
+    // name the text was pushed with, e.g.: the path of a file
+    pub name: String,
+    // where the text starts and ends within the input, which
+    // includes the text of all the inclusions
+    pub start: usize,
+    pub end: usize,
+    // offset of the first char of each line of the text
+    lines: Vec<usize>,
+}
+
+/// Decides what gets captured when a literal marked with `i` matches
+/// input that is only the same as the literal once case folded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaseCapture {
+    /// The text as it's written in the input, e.g.: `SeLeCt`
+    #[default]
+    Original,
+    /// The literal as it's written in the grammar, e.g.: `select`
+    Canonical,
+}
+
+/// What matching does when a label is thrown outside of predicates.
+/// Labels without a recovery expression always stop matching with
+/// `Error::Label`, the policy decides what happens to the ones
+/// that have one, either written as a rule named after the label or
+/// added for a rule that sets `@sync(...)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop at the first label thrown with `Error::Label`, as if
+    /// no label had a recovery expression
+    FailFast,
+    /// Match the recovery expression of the label, capture an error
+    /// value for it and carry on from where the expression stopped
+    #[default]
+    Recover,
+    /// Same as `Recover`, but stop with `Error::TooManyErrors` when a
+    /// label is thrown after `n` errors were already recovered from.
+    /// Badly broken input can otherwise produce an error per token.
+    RecoverWithLimit(usize),
+}
+
+impl OnError {
+    /// Whether a label with a recovery expression thrown at `cursor`
+    /// after `errors` were recovered from gets recovered from too.
+    /// `false` means the label stops matching like one without a
+    /// recovery expression.
+    pub(crate) fn recovers(&self, errors: usize, cursor: Position) -> Result<bool, Error> {
+        match self {
+            OnError::FailFast => Ok(false),
+            OnError::Recover => Ok(true),
+            OnError::RecoverWithLimit(max) if errors >= *max => {
+                Err(Error::TooManyErrors(*max, cursor))
+            }
+            OnError::RecoverWithLimit(_) => Ok(true),
+        }
+    }
+}
+
+/// Length in bytes of the longest string shared by the leaves that
+/// have the same text, see `Config::with_interned_leaves`
+pub const MAX_INTERNED_LEN: usize = 64;
+
+/// Options that change how the virtual machine runs programs
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    // what thrown labels do
+    on_error: OnError,
+    // record when productions are entered and exited
+    rule_events: bool,
+    // number of rule events after which matching stops
+    stop_at_event: Option<usize>,
+    // productions that get captured as nodes
+    captures: CaptureFilter,
+    // report the counters of the run within the match
+    stats: bool,
+    // what literals that ignore case capture
+    case_capture: CaseCapture,
+    // leaves with the same text share their storage
+    intern_leaves: bool,
+    // memoize the outcome of each call at each position
+    packrat: bool,
+    // keep what `Spacing` matches aside from the tree
+    trivia: bool,
+    // every char consumed shows up in the tree
+    lossless: bool,
+    // most frames the stack can hold at once
+    max_stack_depth: Option<usize>,
+    // most instructions a run can execute
+    max_steps: Option<usize>,
+}
+
+impl Config {
+    /// Handle thrown labels according to `on_error`, see [`OnError`]
+    pub fn with_on_error(&self, on_error: OnError) -> Self {
+        Self {
+            on_error,
+            ..self.clone()
+        }
+    }
+
+    /// Same as `with_on_error(OnError::RecoverWithLimit(max))`
+    pub fn with_max_errors(&self, max: usize) -> Self {
+        self.with_on_error(OnError::RecoverWithLimit(max))
+    }
+
+    /// Report [`Stats`] about the work the virtual machine did within
+    /// the [`Match`] returned by `VM::match_str` and friends
+    pub fn with_stats(&self) -> Self {
+        Self {
+            stats: true,
+            ..self.clone()
+        }
+    }
+
+    /// Choose between capturing the input or the literal of the
+    /// grammar when case-insensitive literals match.  Keywords can be
+    /// captured in their canonical form, e.g. `'select'i` captures
+    /// `select` from `SELECT`, while other rules still capture what
+    /// was written.
+    pub fn with_case_capture(&self, case_capture: CaseCapture) -> Self {
+        Self {
+            case_capture,
+            ..self.clone()
+        }
+    }
+
+    /// Make the strings captured within a match that have the same
+    /// text share their storage, instead of allocating each of them.
+    /// Only strings up to [`MAX_INTERNED_LEN`] bytes long are
+    /// interned, which covers the tokens that repeat the most, like
+    /// punctuation, keywords and small numbers.
+    pub fn with_interned_leaves(&self) -> Self {
+        Self {
+            intern_leaves: true,
+            ..self.clone()
+        }
+    }
+
+    /// Memoize whether each production matched at each position of
+    /// the input, along with what it captured, so calling it again at
+    /// the same position reuses the outcome instead of matching it
+    /// again.  Grammars that backtrack over the same productions many
+    /// times get matched in linear time, at the cost of the memory
+    /// taken by the table.  Left recursive calls, calls made while a
+    /// left recursive one is being grown and calls within predicates
+    /// aren't memoized, neither is anything when matching structured
+    /// input or when only some productions are captured.  Host
+    /// functions called by a memoized production don't get called
+    /// again when it's reused, and syntax errors, while reported at
+    /// the same position, may list fewer of the terminals expected
+    /// there, since the ones within reused productions aren't tried
+    /// again.
+    pub fn with_packrat(&self) -> Self {
+        Self {
+            packrat: true,
+            ..self.clone()
+        }
+    }
+
+    /// Keep the whitespace and comments matched by the `Spacing`
+    /// production, which are left out of the tree, as [`Trivia`]
+    /// returned along with it within [`Match::trivia`], so tools that
+    /// rewrite the input can put them back.  Packrat mode is off
+    /// while trivia is kept.
+    pub fn with_trivia(&self) -> Self {
+        Self {
+            trivia: true,
+            ..self.clone()
+        }
+    }
+
+    /// Build trees that hold every char of the input they consumed,
+    /// exactly once and in order, so the input can be rebuilt out of
+    /// their leaves, e.g.: by formatters that only change some of it.
+    /// What the `Spacing` production matches becomes a node named
+    /// after it, and productions are captured even if left out with
+    /// `with_capture_only` or `with_capture_except`.  Once matching is
+    /// done, the spans of the leaves, and of the errors recovered from,
+    /// are checked to cover the input, which fails with
+    /// `Error::Lossless` otherwise, e.g.: if a production that consumes
+    /// input is declared with `@capture(skip)`.  Only text input is
+    /// checked.
+    pub fn with_lossless(&self) -> Self {
+        Self {
+            lossless: true,
+            ..self.clone()
+        }
+    }
+
+    /// Stop matching with `Error::ResourceLimit` when the stack holds
+    /// more than `depth` frames, each one being a call to a production
+    /// or a choice that can still be backtracked to.  Grammars that
+    /// recurse for each nested construct of the input otherwise grow
+    /// the stack as deep as the input nests.  Each time a left
+    /// recursive call matches more input counts as one more frame, as
+    /// the node it captures nests one level deeper.
+    pub fn with_max_stack_depth(&self, depth: usize) -> Self {
+        Self {
+            max_stack_depth: Some(depth),
+            ..self.clone()
+        }
+    }
+
+    /// Stop matching with `Error::ResourceLimit` after `steps`
+    /// instructions are executed, which bounds the time a run takes
+    /// regardless of how much input it consumes
+    pub fn with_max_steps(&self, steps: usize) -> Self {
+        Self {
+            max_steps: Some(steps),
+            ..self.clone()
+        }
+    }
+
+    /// True if case-insensitive literals capture the literal of the
+    /// grammar rather than the input
+    pub(crate) fn canonical_case(&self) -> bool {
+        self.case_capture == CaseCapture::Canonical
+    }
+
+    /// Record a [`RuleEvent`] each time a production is entered or
+    /// exited.  Recorded events are available through `VM::events`.
+    pub fn with_rule_events(&self) -> Self {
+        Self {
+            rule_events: true,
+            ..self.clone()
+        }
+    }
+
+    /// Record rule events and stop matching with `Error::Stopped`
+    /// right after the event at position `event` is recorded.  Runs
+    /// are deterministic, so going back to an earlier event is done
+    /// by running the same program over the same input again with a
+    /// smaller `event`, then inspecting the state of the VM.
+    pub fn with_stop_at_event(&self, event: usize) -> Self {
+        Self {
+            rule_events: true,
+            stop_at_event: Some(event),
+            ..self.clone()
+        }
+    }
+
+    #[cfg(feature = "interp")]
+    pub(crate) fn on_error(&self) -> OnError {
+        self.on_error
+    }
+
+    /// Whether the production `rule` gets captured as a node
+    pub(crate) fn captures(&self, rule: &str) -> bool {
+        self.captures.keeps(rule)
+    }
+
+    /// Append `event` to `events` if rule events are enabled, failing
+    /// with `Error::Stopped` past the event matching should stop at
+    pub(crate) fn record(
+        &self,
+        events: &mut Vec<RuleEvent>,
+        event: RuleEvent,
+    ) -> Result<(), Error> {
+        if !self.rule_events {
+            return Ok(());
+        }
+        events.push(event);
+        match self.stop_at_event {
+            Some(n) if n < events.len() => Err(Error::Stopped(n)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Only build nodes for the productions in `rules`.  The nodes
+    /// captured within other productions are handed over to the
+    /// closest enclosing production, and so is their text if that
+    /// production is captured.  Text that no captured production
+    /// encloses is dropped without ever being allocated.
+    pub fn with_capture_only(&self, rules: &[&str]) -> Self {
+        Self {
+            captures: CaptureFilter::Only(rules.iter().map(|r| r.to_string()).collect()),
+            ..self.clone()
+        }
+    }
+
+    /// Build nodes for all productions but the ones in `rules`, which
+    /// are handled as described in `Config::with_capture_only`
+    pub fn with_capture_except(&self, rules: &[&str]) -> Self {
+        Self {
+            captures: CaptureFilter::Except(rules.iter().map(|r| r.to_string()).collect()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Productions that get captured as nodes
+#[derive(Clone, Debug, Default)]
+enum CaptureFilter {
+    #[default]
+    All,
+    Only(HashSet<String>),
+    Except(HashSet<String>),
+}
+
+impl CaptureFilter {
+    fn keeps(&self, rule: &str) -> bool {
+        match self {
+            CaptureFilter::All => true,
+            CaptureFilter::Only(rules) => rules.contains(rule),
+            CaptureFilter::Except(rules) => !rules.contains(rule),
+        }
+    }
+}
+
+/// RuleEvent marks a production being entered or exited, along with
+/// where the cursor was at that moment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleEvent {
+    Enter {
+        rule: String,
+        cursor: usize,
+    },
+    Exit {
+        rule: String,
+        cursor: usize,
+        // false if the production failed
+        matched: bool,
+    },
+    // A repetition within the production matched an iteration
+    // without consuming input, so it repeats it forever.  Recorded
+    // once, even though the iteration keeps being matched.
+    EmptyIteration {
+        rule: String,
+        cursor: usize,
+    },
+}
+
+/// Match is what matching a program against an input produces when
+/// it succeeds
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match {
+    /// Value captured by the program
+    pub value: Option<Value>,
+    /// Range of the input consumed, from its start to where the
+    /// cursor stopped
+    pub span: Span,
+    /// Farthest position matching got to within the input.  When it's
+    /// past the end of `span`, the input that was left behind almost
+    /// matched, which is worth a warning about trailing garbage.
+    pub ffp: usize,
+    /// Counters collected while matching, if enabled with
+    /// `Config::with_stats`
+    pub stats: Option<Stats>,
+    /// Whitespace and comments left out of `value`, in the order they
+    /// appear in the input, if enabled with `Config::with_trivia`
+    pub trivia: Vec<Trivia>,
+}
+
+/// Trivia is a stretch of the input matched by the `Spacing`
+/// production, like whitespace and comments, which isn't part of the
+/// tree.  What `Spacing` matches after a token is split after its
+/// first line break: the part up to the break trails the token
+/// before it, and the rest leads the token that follows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia {
+    /// Span of the input covered by `items`
+    pub span: Span,
+    /// Values captured by `Spacing`, e.g.: a `Comment` node for each
+    /// comment, in the order they were matched
+    pub items: Vec<Value>,
+    /// Token the trivia belongs to
+    pub attachment: Attachment,
+}
+
+impl Trivia {
+    /// Text of the items, see `Value::to_text`
+    pub fn text(&self) -> String {
+        self.items.iter().map(Value::to_text).collect()
+    }
+}
+
+/// Attachment tells which token a [`Trivia`] belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attachment {
+    /// Token that follows the trivia
+    Leading,
+    /// Token that precedes the trivia
+    Trailing,
+}
+
+impl Match {
+    /// True if matching failed somewhere after the consumed input
+    pub fn failed_after_end(&self) -> bool {
+        self.ffp > self.span.end.offset
+    }
+}
+
+/// Stats counts the work the virtual machine did while matching
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Instructions executed
+    pub instructions: usize,
+    /// Times a failure backtracked to a previous choice
+    pub backtracks: usize,
+    /// Largest number of frames the stack held at once
+    pub max_stack_depth: usize,
+    /// Calls matched from the table of `Config::with_packrat`
+    pub memo_hits: usize,
+    /// Calls that could've been matched from the table of
+    /// `Config::with_packrat`, but weren't in it yet
+    pub memo_misses: usize,
+    /// Outcomes the table of `Config::with_packrat` held at the end
+    pub memo_entries: usize,
+    /// Allocations made by the match, which are only counted when
+    /// `alloc_stats::CountingAllocator` is the global allocator
+    #[cfg(feature = "alloc-stats")]
+    pub allocations: crate::alloc_stats::Allocations,
+}
+
+/// Progress reports how far matching got, see `VM::on_progress`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Progress {
+    pub cursor: usize,
+    // farther failure position
+    pub ffp: usize,
+    // number of frames in the stack of the virtual machine
+    pub stack_depth: usize,
+}
+
+struct ProgressCallback {
+    every: usize,
+    // cursor position of the next report
+    next: usize,
+    callback: Box<dyn FnMut(&Progress) -> bool>,
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "ProgressCallback(every: {}, next: {})",
+            self.every, self.next
+        )
+    }
+}
+
+/// Step is where matching is when a [`Tracer`] gets called
+#[derive(Clone, Copy, Debug)]
+pub struct Step<'a> {
+    pub program: &'a Program,
+    pub program_counter: usize,
+    pub cursor: usize,
+    // number of productions being matched, including the one being
+    // entered or exited
+    pub depth: usize,
+}
+
+impl Step<'_> {
+    /// Instruction at the program counter
+    pub fn instruction(&self) -> Instruction {
+        self.program.code.at(self.program_counter)
+    }
+
+    /// Text of the instruction at the program counter, with the names
+    /// of the productions and strings it refers to
+    pub fn describe(&self) -> String {
+        instruction_to_string(self.program, &self.instruction(), self.program_counter)
+    }
+}
+
+/// Tracer gets told about each step matching takes, for debugging
+/// grammars.  Methods do nothing unless implemented, so tracers only
+/// implement the ones they're interested in.  See `VM::set_tracer`
+pub trait Tracer {
+    /// Called right before the instruction at the program counter runs
+    fn on_instruction(&mut self, _step: &Step) {}
+
+    /// Called when the instruction that just ran failed, before
+    /// backtracking
+    fn on_fail(&mut self, _step: &Step) {}
+
+    /// Called right after entering the production `rule`
+    fn on_call(&mut self, _step: &Step, _rule: &str) {}
+
+    /// Called when exiting the production `rule`, which `matched` or
+    /// failed
+    fn on_return(&mut self, _step: &Step, _rule: &str, _matched: bool) {}
+
+    /// Called when a repetition within the production `rule` matches
+    /// an iteration without consuming input, which makes it loop
+    /// forever.  Called once for each repetition and position.
+    fn on_empty_iteration(&mut self, _step: &Step, _rule: &str) {}
+}
+
+struct BoxedTracer(Box<dyn Tracer>);
+
+impl std::fmt::Debug for BoxedTracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+/// PrintTracer writes a line for each step matching takes, with the
+/// program counter, the cursor and the depth in aligned columns,
+/// followed by the instruction indented by the depth.  Productions
+/// entered and exited get their own lines, marked with `>` and `<`.
+pub struct PrintTracer<W> {
+    out: W,
+}
+
+impl PrintTracer<std::io::Stderr> {
+    /// Tracer that writes to stderr
+    pub fn stderr() -> Self {
+        Self::new(std::io::stderr())
+    }
+}
+
+impl<W: Write> PrintTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // errors writing the trace out shouldn't stop matching
+    fn line(&mut self, step: &Step, depth: usize, text: &str) {
+        let _ = writeln!(
+            self.out,
+            "{:>6} {:>8} {:>5}  {}{}",
+            step.program_counter,
+            step.cursor,
+            step.depth,
+            "  ".repeat(depth),
+            text
+        );
+    }
+}
+
+impl<W: Write> Tracer for PrintTracer<W> {
+    fn on_instruction(&mut self, step: &Step) {
+        self.line(step, step.depth, &step.describe());
+    }
+
+    fn on_fail(&mut self, step: &Step) {
+        self.line(step, step.depth, "fail");
+    }
+
+    fn on_call(&mut self, step: &Step, rule: &str) {
+        self.line(step, step.depth.saturating_sub(1), &format!("> {}", rule));
+    }
+
+    fn on_return(&mut self, step: &Step, rule: &str, matched: bool) {
+        let text = match matched {
+            true => format!("< {}", rule),
+            false => format!("< {} (failed)", rule),
+        };
+        self.line(step, step.depth.saturating_sub(1), &text);
+    }
+
+    fn on_empty_iteration(&mut self, step: &Step, rule: &str) {
+        let text = format!("! {}: repetition matched without consuming input", rule);
+        self.line(step, step.depth, &text);
+    }
+}
+
+/// What the functions registered with `VM::register_host` get to
+/// see when the grammar calls them with `%host(name)`
+pub struct HostCall<'a> {
+    // position of the cursor within the input
+    pub cursor: usize,
+    // values captured so far by the production the call is within
+    pub captures: &'a [Value],
+    ctx: Option<&'a mut dyn Any>,
+    // inputs to push once the function returns
+    inputs: Vec<(String, String)>,
+}
+
+impl HostCall<'_> {
+    /// The context passed to `VM::run_with_ctx`, if there's one and
+    /// it's a `C`
+    pub fn ctx<C: Any>(&mut self) -> Option<&mut C> {
+        self.ctx.as_deref_mut()?.downcast_mut()
+    }
+
+    /// Match `text` before the rest of the input, e.g.: the contents
+    /// of a file named by an `#include` directive.  The positions of
+    /// its chars have lines and columns counted within `text`, see
+    /// [`VM::source_of`].  If the function returns `false`, or the
+    /// matching backtracks to before the call, the text is dropped.
+    /// Only text input can have other inputs pushed into it.
+    pub fn push_input(&mut self, name: &str, text: &str) {
+        self.inputs.push((name.to_string(), text.to_string()));
+    }
+}
+
+type HostFn = Box<dyn FnMut(&mut HostCall) -> bool>;
+
+#[derive(Default)]
+struct HostFunctions(HashMap<String, HostFn>);
+
+impl std::fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct VM<'a> {
+    // Cursor position at the input
+    cursor: usize,
     line: usize,
     column: usize,
-    source: Vec<Value>,
+    source: Input,
     // Farther Failure Position
     ffp: usize,
     // Vector of instructions and tables with literal values
@@ -362,48 +2806,172 @@ pub struct VM<'a> {
     stack: Vec<StackFrame>,
     // last call frame
     call_frames: Vec<usize>,
+    // production the next run starts from instead of the first one,
+    // along with its precedence level, see `VM::run_from`
+    entry: Option<(usize, usize)>,
+    // repetition and cursor of the last iteration reported to match
+    // without consuming input, see `VM::check_iteration`
+    empty_iteration: Option<(usize, usize)>,
     // Memoized position of left recursive results
     lrmemo: HashMap<LeftRecTableKey, LeftRecTableEntry>,
+    // sum of the bounds of the left recursive calls on the stack,
+    // which is how much deeper than the stack their values nest
+    lrdepth: usize,
+    // outcomes of calls, see `Config::with_packrat`
+    packrat: HashMap<(usize, usize), PackratEntry>,
+    // what `Spacing` matched, see `Config::with_trivia`
+    trivia: Vec<Trivia>,
     // Where values returned from successful match operations are stored
     captures: Vec<CapStackFrame>,
-    // boolean flag that remembers if the VM is within a predicate
-    within_predicate: bool,
+    // number of predicate frames on the backtrack stack, which can be
+    // nested, e.g.: `!(A !B)`
+    predicates: usize,
     // expected_set keeps tabs on which tokens are expected but didn't
     // match the current tokens under the cursor
     expected_set: HashSet<String>,
     // expected_vec contains the ordered list of tokens that are
     // expected but didn't match the current token under the cursor
     expected_vec: Vec<String>,
+    // IDs of the productions of the program keyed by their address
+    rule_ids: HashMap<usize, RuleId>,
+    // Options set by the user
+    config: Config,
+    // How many times the recovery expression of a label was called
+    errors: usize,
+    // Productions entered and exited, if enabled in the config
+    events: Vec<RuleEvent>,
+    // Reports progress while consuming the input
+    progress: Option<ProgressCallback>,
+    // Counters of the work done by the last run
+    stats: Stats,
+    // Functions the program can call with the `Host` instruction
+    hosts: HostFunctions,
+    // Text of the leaves captured by the current run, if leaves are
+    // interned
+    interned: HashSet<Arc<str>>,
+    // Gets told about each step of matching
+    tracer: Option<BoxedTracer>,
 }
 
 impl<'a> VM<'a> {
     pub fn new(program: &'a Program) -> Self {
+        Self::new_with_config(program, Config::default())
+    }
+
+    pub fn new_with_config(program: &'a Program, config: Config) -> Self {
         VM {
             program,
             ffp: 0,
             cursor: 0,
             line: 0,
             column: 0,
-            source: vec![],
+            source: Input::Values(vec![]),
             program_counter: 0,
             stack: vec![],
             call_frames: vec![],
+            entry: None,
+            empty_iteration: None,
             lrmemo: HashMap::new(),
+            lrdepth: 0,
+            packrat: HashMap::new(),
+            trivia: vec![],
             captures: vec![],
-            within_predicate: false,
+            predicates: 0,
             expected_set: HashSet::new(),
             expected_vec: vec![],
+            rule_ids: program
+                .rule_addresses()
+                .into_iter()
+                .enumerate()
+                .map(|(i, address)| (address, RuleId(i as u32)))
+                .collect(),
+            config,
+            errors: 0,
+            events: vec![],
+            progress: None,
+            stats: Stats::default(),
+            hosts: HostFunctions::default(),
+            interned: HashSet::new(),
+            tracer: None,
         }
     }
 
+    /// Make `function` callable from the grammar with `%host(name)`.
+    /// It's called each time matching gets to it, including within
+    /// predicates and alternatives that end up failing, so changes it
+    /// makes to the context aren't undone by backtracking.  The
+    /// expression fails if the function returns `false`.
+    pub fn register_host<F>(&mut self, name: &str, function: F)
+    where
+        F: FnMut(&mut HostCall) -> bool + 'static,
+    {
+        self.hosts.0.insert(name.to_string(), Box::new(function));
+    }
+
+    /// Call `callback` each time the cursor moves `every` characters
+    /// further into the input than it has been before, so reports
+    /// don't repeat when the cursor backtracks.  Matching stops with
+    /// `Error::Cancelled` if the callback returns `false`.
+    pub fn on_progress<F>(&mut self, every: usize, callback: F)
+    where
+        F: FnMut(&Progress) -> bool + 'static,
+    {
+        let every = every.max(1);
+        self.progress = Some(ProgressCallback {
+            every,
+            next: every,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Tell `tracer` about each step matching takes from now on,
+    /// e.g.: `PrintTracer::stderr()` prints out a trace of the run
+    pub fn set_tracer<T: Tracer + 'static>(&mut self, tracer: T) {
+        self.tracer = Some(BoxedTracer(Box::new(tracer)));
+    }
+
     fn advance_cursor(&mut self) -> Result<(), Error> {
-        let c = &self.source[self.cursor];
+        let start = self.source.span_at(self.cursor).start;
         self.cursor += 1;
-        // re-use work done in run_str()
-        let start = c.span().start;
         self.line = start.line;
         self.column = start.column;
-        Ok(())
+        self.discard_input();
+        self.report_progress()
+    }
+
+    /// Let streams drop the chars before the oldest position the
+    /// machine can still go back to, which is the lowest cursor saved
+    /// by a backtrack entry or by a left recursive call
+    fn discard_input(&mut self) {
+        let stream = match &self.source {
+            Input::Stream(stream) if stream.borrow().should_discard() => stream,
+            _ => return,
+        };
+        let floor = self
+            .stack
+            .iter()
+            .filter(|f| f.ftype == StackFrameType::Backtrack || f.precedence > 0)
+            .map(|f| f.cursor)
+            .fold(self.cursor, usize::min);
+        stream.borrow_mut().discard(floor);
+    }
+
+    fn report_progress(&mut self) -> Result<(), Error> {
+        let progress = match &mut self.progress {
+            Some(p) if self.cursor >= p.next => p,
+            _ => return Ok(()),
+        };
+        progress.next = self.cursor + progress.every;
+        let proceed = (progress.callback)(&Progress {
+            cursor: self.cursor,
+            ffp: self.ffp,
+            stack_depth: self.stack.len(),
+        });
+        if proceed {
+            Ok(())
+        } else {
+            Err(Error::Cancelled(self.source.position_at(self.cursor)))
+        }
     }
 
     fn ffp_err(&mut self, expected: Value) -> Error {
@@ -419,16 +2987,21 @@ impl<'a> VM<'a> {
         // add the new term to the set of expected tokens that haven't
         // matched with the input
         let e = expected.to_string();
-        if self.expected_set.get(&e).is_none() {
-            self.expected_vec.push(format!("'{}'", e));
+        if !self.expected_set.contains(&e) {
+            self.expected_vec.push(format!("'{}'", e.escape_debug()));
             self.expected_set.insert(e);
         }
 
-        // fill up the error instance with the appropriate message
-        return Error::Matching(
-            self.ffp,
+        self.expected_error()
+    }
+
+    // error at the farther failure position, listing what was
+    // expected there
+    fn expected_error(&self) -> Error {
+        Error::Matching(
+            self.source.position_at(self.ffp),
             format!("syntax error, expecting: {}", self.expected_vec.join(", ")),
-        );
+        )
     }
 
     fn ffp_fail(&mut self, expected: Value) -> Result<(), Error> {
@@ -455,11 +3028,22 @@ impl<'a> VM<'a> {
         Ok(&self.stack[idx])
     }
 
-    fn stkpush(&mut self, frame: StackFrame) {
+    fn stkpush(&mut self, mut frame: StackFrame) {
+        frame.trivia = self.trivia.len();
+        if frame.predicate {
+            self.predicates += 1;
+        }
         if frame.ftype == StackFrameType::Call {
             self.call_frames.push(self.stack.len());
         }
         self.stack.push(frame);
+        self.stats.max_stack_depth = self.stats.max_stack_depth.max(self.stack.len());
+    }
+
+    /// Whether matching is within a predicate, where values aren't
+    /// captured and labels fail instead of being thrown
+    fn within_predicate(&self) -> bool {
+        self.predicates > 0
     }
 
     fn stkpop(&mut self) -> Result<StackFrame, Error> {
@@ -468,8 +3052,9 @@ impl<'a> VM<'a> {
             self.call_frames.pop().ok_or(Error::Index)?;
         }
         if frame.predicate {
-            self.within_predicate = false;
+            self.predicates -= 1;
         }
+        self.lrdepth -= frame.bound;
         Ok(frame)
     }
 
@@ -493,40 +3078,96 @@ impl<'a> VM<'a> {
 
     /// pushes a new value onto the frame on top of the capture stack
     fn capture(&mut self, v: Value) -> Result<(), Error> {
-        if self.within_predicate {
+        if self.within_predicate() {
             return Ok(());
         }
+        let v = self.intern(v);
         self.capstktop_mut()?.values.push(v);
         Ok(())
     }
 
-    fn capture_flatten(&mut self, address: usize, items: Vec<Value>) -> Result<(), Error> {
+    /// Point the text of `value` to the storage of the leaf captured
+    /// earlier with the same text, if leaves are interned
+    fn intern(&mut self, mut value: Value) -> Value {
+        if let Value::String(s) = &mut value {
+            if self.config.intern_leaves && s.value.len() <= MAX_INTERNED_LEN {
+                match self.interned.get(&s.value) {
+                    Some(shared) => s.value = shared.clone(),
+                    None => {
+                        self.interned.insert(s.value.clone());
+                    }
+                }
+            }
+        }
+        value
+    }
+
+    /// capture the character under the cursor, which matched
+    /// `canonical` once case folded
+    fn capture_char_ci(&mut self, canonical: char) -> Result<(), Error> {
+        if !self.config.canonical_case() {
+            return self.capture(self.source.value_at(self.cursor));
+        }
+        self.capture(value::Char::new_val(
+            self.source.span_at(self.cursor),
+            canonical,
+        ))
+    }
+
+    fn capture_flatten(&mut self, address: usize, mut items: Vec<Value>) -> Result<(), Error> {
         let name = self.program.identifier(address);
         match &items[..] {
             [] => Ok(()),
-            [Value::Node(n)] if n.name == name => self.capture(items[0].clone()),
+            // moved rather than cloned, which would copy the whole
+            // tree each time a left recursive call grows
+            [Value::Node(n)] if n.name == name => self.capture(items.remove(0)),
             _ => {
                 let start = items[0].span().start;
                 let end = items[items.len() - 1].span().end;
-                self.capture(value::Node::new_val(Span::new(start, end), name, items))
+                self.capture(self.node(address, Span::new(start, end), name, items))
+            }
+        }
+    }
+
+    /// hand the values captured by a production filtered out of the
+    /// output over to the enclosing production, keeping their text
+    /// only if a captured production is around to hold it
+    fn capture_filtered(&mut self, items: Vec<Value>) -> Result<(), Error> {
+        let within_captured = self.stack.iter().any(|f| {
+            f.ftype == StackFrameType::Call
+                && self.config.captures(&self.program.identifier(f.address))
+        });
+        for item in items {
+            if within_captured || matches!(item, Value::Node(_) | Value::Error(_)) {
+                self.capture(item)?;
             }
         }
+        Ok(())
+    }
+
+    fn enclosed_by_call(&self) -> bool {
+        self.stack.iter().any(|f| f.ftype == StackFrameType::Call)
+    }
+
+    /// build a node for the production at `address`, carrying the ID
+    /// of the production if it has one
+    fn node(&self, address: usize, span: Span, name: String, items: Vec<Value>) -> Value {
+        match self.rule_ids.get(&address) {
+            Some(id) => value::Node::new_val_with_rule(span, *id, name, items),
+            None => value::Node::new_val(span, name, items),
+        }
     }
 
     /// mark all values captured on the top of the stack as commited
     fn commit_captures(&mut self) -> Result<(), Error> {
         let top = self.capstktop_mut()?;
-        let (idx, len) = (top.index, top.values.len());
-        top.index = len;
-        if idx != len {
-            self.dbg_captures()?;
-        }
+        top.index = top.values.len();
         Ok(())
     }
 
     fn join_captures(&mut self) -> Result<(), Error> {
         let top = self.capstktop_mut()?;
-        if top.values.len() > 0 {
+        if !top.values.is_empty() {
             let (first, last) = (&top.values[0], &top.values[top.values.len() - 1]);
             let span = Span::new(first.span().start, last.span().end);
             let joined = top
@@ -535,322 +3176,818 @@ impl<'a> VM<'a> {
                 .map(|x| x.to_string())
                 .collect::<Vec<String>>()
                 .join("");
-            top.values = vec![value::String::new_val(span, joined)];
+            let joined = self.intern(value::String::new_val(span, joined));
+            self.capstktop_mut()?.values = vec![joined];
         }
         Ok(())
     }
 
+    fn text_captures(&mut self) -> Result<(), Error> {
+        let mut values = std::mem::take(&mut self.capstktop_mut()?.values);
+        fold_text(&mut values);
+        if let Some(text) = values.first_mut() {
+            *text = self.intern(text.clone());
+        }
+        self.capstktop_mut()?.values = values;
+        Ok(())
+    }
+
+    fn list_captures(&mut self) -> Result<(), Error> {
+        let pos = self.pos();
+        fold_list(&mut self.capstktop_mut()?.values, pos);
+        Ok(())
+    }
+
     // evaluation
 
+    /// Match the program against the text in `input`.  The text
+    /// is only indexed once; values are created for the parts of the
+    /// input that get captured.
+    pub fn run(&mut self, input: &str) -> Result<Option<Value>, Error> {
+        self.source = Input::from_str(input);
+        self.eval()
+    }
+
+    /// Same as `VM::run`, but matching starts from the production
+    /// named `rule` instead of the first one, e.g.: to match a
+    /// fragment of a file against the rule of one of its statements.
+    /// See `Program::entry_points` for the rules it can start from.
+    pub fn run_from(&mut self, rule: &str, input: &str) -> Result<Option<Value>, Error> {
+        let entry = self
+            .program
+            .entry_point(rule)
+            .ok_or_else(|| Error::RuleNotFound(rule.to_string()))?;
+        self.entry = Some(entry);
+        self.run(input)
+    }
+
+    /// Same as `VM::run`, but host functions get to use `ctx`, see
+    /// [`HostCall::ctx`]
+    pub fn run_with_ctx<C: Any>(
+        &mut self,
+        input: &str,
+        ctx: &mut C,
+    ) -> Result<Option<Value>, Error> {
+        self.source = Input::from_str(input);
+        self.eval_with(Some(ctx))
+    }
+
+    /// Match the program against binary input.  The program is
+    /// expected to be compiled with `compiler::Config::byte_mode`.
+    pub fn run_bytes(&mut self, input: &[u8]) -> Result<Option<Value>, Error> {
+        self.source = Input::from_bytes(input);
+        self.eval()
+    }
+
+    /// Match the program against text pulled from `reader` in
+    /// chunks, so inputs that don't fit in memory can be matched.
+    /// Only the text the machine can still backtrack to is kept
+    /// around.  Host functions can't push inputs into streams.
+    pub fn run_reader<R: Read + 'static>(&mut self, reader: R) -> Result<Option<Value>, Error> {
+        self.source = Input::from_reader(reader);
+        let value = self.eval();
+        match self.source.read_error() {
+            Some(e) => Err(e),
+            None => value,
+        }
+    }
+
+    /// Match the program against structured input, like the output
+    /// of a previous run.  That's the input the `Open` and `Close`
+    /// instructions descend into.
+    pub fn run_values(&mut self, source: Vec<Value>) -> Result<Option<Value>, Error> {
+        self.source = Input::Values(source);
+        self.eval()
+    }
+
+    /// Match the program against the output of an external lexer.
+    /// See [`Token`] for how grammar terminals match tokens.
+    pub fn run_tokens(&mut self, tokens: Vec<Token>) -> Result<Option<Value>, Error> {
+        self.source = Input::Tokens(tokens);
+        self.eval()
+    }
+
+    /// Same as `VM::run`, but also returns the span of the input that
+    /// was consumed, the farthest failure position and the stats
+    pub fn match_str(&mut self, input: &str) -> Result<Match, Error> {
+        self.source = Input::from_str(input);
+        self.eval_match()
+    }
+
+    /// Same as `VM::run_bytes`, but returns a [`Match`]
+    pub fn match_bytes(&mut self, input: &[u8]) -> Result<Match, Error> {
+        self.source = Input::from_bytes(input);
+        self.eval_match()
+    }
+
+    /// Same as `VM::run_values`, but returns a [`Match`]
+    pub fn match_values(&mut self, source: Vec<Value>) -> Result<Match, Error> {
+        self.source = Input::Values(source);
+        self.eval_match()
+    }
+
+    /// Same as `VM::run_tokens`, but returns a [`Match`].  Both the
+    /// span and the farthest failure position refer to tokens.
+    pub fn match_tokens(&mut self, tokens: Vec<Token>) -> Result<Match, Error> {
+        self.source = Input::Tokens(tokens);
+        self.eval_match()
+    }
+
+    #[deprecated(note = "use VM::run")]
     pub fn run_str(&mut self, input: &str) -> Result<Option<Value>, Error> {
-        let mut line = 0;
-        let mut column = 1;
-        let source = input
-            .chars()
-            .enumerate()
-            .map(|(i, c)| {
-                let start = Position::new(i, line, column);
-                let end = Position::new(i + 1, line, column + 1);
-                column += 1;
-                if c == '\n' {
-                    column = 1;
-                    line += 1;
-                }
-                value::Char::new_val(Span::new(start, end), c)
-            })
-            .collect::<Vec<Value>>();
-        self.run(source)
+        self.run(input)
+    }
+
+    /// Productions entered and exited so far.  Only recorded if
+    /// enabled with `Config::with_rule_events`.
+    pub fn events(&self) -> &[RuleEvent] {
+        &self.events
+    }
+
+    /// Whitespace and comments left out of the tree so far.  Only
+    /// kept if enabled with `Config::with_trivia`.
+    pub fn trivia(&self) -> &[Trivia] {
+        &self.trivia
+    }
+
+    /// Inputs pushed by host functions with [`HostCall::push_input`]
+    /// that were kept until the end of the last run, in the order
+    /// they were pushed
+    pub fn inclusions(&self) -> &[Inclusion] {
+        self.source.inclusions()
+    }
+
+    /// Innermost input pushed by a host function that the char at
+    /// `position` comes from.  `None` for chars of the input the
+    /// machine was run with.
+    pub fn source_of(&self, position: &Position) -> Option<&Inclusion> {
+        self.source
+            .inclusions()
+            .iter()
+            .filter(|i| i.start <= position.offset && position.offset < i.end)
+            .max_by_key(|i| i.start)
+    }
+
+    /// Position of the cursor within the input
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Names of the productions being matched, from the outermost
+    /// to the innermost one
+    pub fn rule_stack(&self) -> Vec<String> {
+        self.stack
+            .iter()
+            .filter(|f| f.ftype == StackFrameType::Call)
+            .map(|f| self.program.identifier(f.address))
+            .collect()
+    }
+
+    fn record(&mut self, event: RuleEvent) -> Result<(), Error> {
+        self.record_at(self.call_frames.len(), event)
     }
 
-    pub fn run(&mut self, source: Vec<Value>) -> Result<Option<Value>, Error> {
-        self.source = source;
+    /// Record `event` of a production at `depth`, which is only
+    /// different from the number of call frames when the frame of the
+    /// production was already popped
+    fn record_at(&mut self, depth: usize, event: RuleEvent) -> Result<(), Error> {
+        let step = Step {
+            depth,
+            ..self.step()
+        };
+        if let Some(tracer) = &mut self.tracer {
+            match &event {
+                RuleEvent::Enter { rule, .. } => tracer.0.on_call(&step, rule),
+                RuleEvent::Exit { rule, matched, .. } => tracer.0.on_return(&step, rule, *matched),
+                RuleEvent::EmptyIteration { rule, .. } => tracer.0.on_empty_iteration(&step, rule),
+            }
+        }
+        self.config.record(&mut self.events, event)
+    }
+
+    fn eval_match(&mut self) -> Result<Match, Error> {
+        #[cfg(feature = "alloc-stats")]
+        let start = crate::alloc_stats::snapshot();
+        let value = self.eval()?;
+        #[cfg(feature = "alloc-stats")]
+        {
+            self.stats.allocations = crate::alloc_stats::snapshot().since(&start);
+        }
+        Ok(Match {
+            value,
+            span: Span::new(
+                self.source.position_at(0),
+                self.source.position_at(self.cursor),
+            ),
+            ffp: self.ffp,
+            stats: self.config.stats.then(|| self.stats.clone()),
+            trivia: self.trivia.clone(),
+        })
+    }
+
+    fn eval(&mut self) -> Result<Option<Value>, Error> {
+        self.eval_with(None)
+    }
+
+    /// Clear what's left from a previous run, so the same machine,
+    /// and the host functions registered with it, can be reused
+    fn reset(&mut self) {
+        self.cursor = 0;
+        self.line = 0;
+        self.column = 0;
+        self.ffp = 0;
+        self.program_counter = 0;
+        self.stack.clear();
+        self.call_frames.clear();
+        self.empty_iteration = None;
+        self.lrmemo.clear();
+        self.lrdepth = 0;
+        self.packrat.clear();
+        self.trivia.clear();
+        self.captures.clear();
+        self.predicates = 0;
+        self.expected_set.clear();
+        self.expected_vec.clear();
+        self.errors = 0;
+        self.events.clear();
+        self.interned.clear();
+        self.stats = Stats::default();
+        if let Some(progress) = &mut self.progress {
+            progress.next = progress.every;
+        }
+    }
+
+    fn eval_with(&mut self, mut ctx: Option<&mut dyn Any>) -> Result<Option<Value>, Error> {
+        self.start();
+        // the call at the first instruction is made to the entry
+        // point instead, returning to the `Halt` right after it
+        if let Some((address, precedence)) = self.entry.take() {
+            self.inst_call(address, precedence, None)?;
+        }
+        while self.exec(ctx.as_deref_mut())? {}
+        let value = self.finish()?;
+        if self.config.lossless && matches!(self.source, Input::Chars { .. } | Input::Stream(_)) {
+            if let Some(offset) = uncovered(value.as_ref(), self.cursor) {
+                return Err(Error::Lossless(self.source.position_at(offset)));
+            }
+        }
+        Ok(value)
+    }
+
+    /// Get ready to run the program from its first instruction
+    fn start(&mut self) {
+        self.reset();
         self.capstkpush();
-        loop {
-            self.dbg_instruction();
-            match self.program.code[self.program_counter] {
-                Instruction::Halt => break,
+    }
 
-                // Terminal Matchers
-                Instruction::Any => {
-                    self.program_counter += 1;
-                    if self.cursor >= self.source.len() {
-                        self.fail(Error::EOF)?;
-                        continue;
-                    }
-                    self.capture(self.source[self.cursor].clone())?;
-                    self.advance_cursor()?;
+    /// Value captured by the program once it halts
+    fn finish(&mut self) -> Result<Option<Value>, Error> {
+        if !self.captures.is_empty() {
+            Ok(self.capstkpop()?.values.pop())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Run the instruction at the program counter, returning `false`
+    /// once the program halts
+    fn exec(&mut self, ctx: Option<&mut dyn Any>) -> Result<bool, Error> {
+        self.check_limits()?;
+        self.trace_instruction();
+        self.stats.instructions += 1;
+        let instruction = self.program.code.at(self.program_counter);
+        match instruction {
+            Instruction::Halt => return Ok(false),
+
+            // Terminal Matchers
+            Instruction::Any => {
+                self.program_counter += 1;
+                if self.source.at_end(self.cursor) {
+                    self.fail(Error::EOF)?;
+                    return Ok(true);
                 }
-                Instruction::Char(expected) => {
-                    let start = self.pos();
-                    self.program_counter += 1;
-                    if self.cursor >= self.source.len() {
+                self.capture(self.source.value_at(self.cursor))?;
+                self.advance_cursor()?;
+            }
+            Instruction::Char(expected) | Instruction::CharCI(expected) => {
+                let ignore_case = matches!(instruction, Instruction::CharCI(_));
+                let start = self.pos();
+                self.program_counter += 1;
+                if self.source.at_end(self.cursor) {
+                    self.ffp_fail(value::Char::new_val(Span::new(start, self.pos()), expected))?;
+                    return Ok(true);
+                }
+                match self.source.char_at(self.cursor) {
+                    Some(current) if current == expected => {
+                        self.capture(self.source.value_at(self.cursor))?;
+                        self.advance_cursor()?;
+                    }
+                    Some(current) if ignore_case && casefold::eq(current, expected) => {
+                        self.capture_char_ci(expected)?;
+                        self.advance_cursor()?;
+                    }
+                    _ => {
                         self.ffp_fail(value::Char::new_val(
                             Span::new(start, self.pos()),
                             expected,
                         ))?;
-                        continue;
-                    }
-                    match &self.source[self.cursor] {
-                        Value::Char(ref current) if current.value == expected => {
-                            self.capture(self.source[self.cursor].clone())?;
-                            self.advance_cursor()?;
-                        }
-                        _ => {
-                            self.ffp_fail(value::Char::new_val(
-                                Span::new(start, self.pos()),
-                                expected,
-                            ))?;
-                            continue;
-                        }
+                        return Ok(true);
                     }
                 }
-                Instruction::Span(start, end) => {
-                    let start_pos = self.pos();
-                    self.program_counter += 1;
-                    if self.cursor >= self.source.len() {
+            }
+            Instruction::Span(start, end) | Instruction::SpanCI(start, end) => {
+                let ignore_case = matches!(instruction, Instruction::SpanCI(..));
+                let start_pos = self.pos();
+                self.program_counter += 1;
+                if self.source.at_end(self.cursor) {
+                    self.ffp_fail(value::String::new_val(
+                        Span::new(start_pos.clone(), self.pos()),
+                        format!("[{}-{}]", start, end),
+                    ))?;
+                    return Ok(true);
+                }
+                let current = self.source.char_at(self.cursor);
+                let canonical = match current {
+                    Some(c) if ignore_case => casefold::in_range(c, start, end),
+                    _ => None,
+                };
+                match (current, canonical) {
+                    (Some(current), _) if current >= start && current <= end => {
+                        self.capture(self.source.value_at(self.cursor))?;
+                        self.advance_cursor()?;
+                    }
+                    (_, Some(canonical)) => {
+                        self.capture_char_ci(canonical)?;
+                        self.advance_cursor()?;
+                    }
+                    _ => {
                         self.ffp_fail(value::String::new_val(
                             Span::new(start_pos.clone(), self.pos()),
                             format!("[{}-{}]", start, end),
                         ))?;
-                        continue;
-                    }
-                    match &self.source[self.cursor] {
-                        Value::Char(ref current)
-                            if current.value >= start && current.value <= end =>
-                        {
-                            self.capture(self.source[self.cursor].clone())?;
-                            self.advance_cursor()?;
-                        }
-                        _ => {
-                            self.ffp_fail(value::String::new_val(
-                                Span::new(start_pos.clone(), self.pos()),
-                                format!("[{}-{}]", start, end),
-                            ))?;
-                            continue;
-                        }
+                        return Ok(true);
                     }
                 }
-                Instruction::String(id) => {
-                    self.program_counter += 1;
-                    let expected = self.program.string_at(id);
-                    let start = self.pos();
-
-                    if self.cursor >= self.source.len() {
+            }
+            Instruction::Property(id) => {
+                let start = self.pos();
+                self.program_counter += 1;
+                match self.source.char_at(self.cursor) {
+                    Some(c) if properties::contains(id, c) => {
+                        self.capture(self.source.value_at(self.cursor))?;
+                        self.advance_cursor()?;
+                    }
+                    _ => {
                         self.ffp_fail(value::String::new_val(
                             Span::new(start.clone(), self.pos()),
-                            expected.clone(),
+                            format!("[\\p{{{}}}]", properties::name(id)),
                         ))?;
-                        continue;
-                    }
-
-                    match &self.source[self.cursor] {
-                        Value::String(ref s) if &s.value == expected => {
-                            self.capture(self.source[self.cursor].clone())?;
-                            self.advance_cursor()?;
-                            continue;
-                        }
-                        _ => {
-                            let mut expected_chars = expected.chars();
-                            match loop {
-                                let current_char = match expected_chars.next() {
-                                    None => break Ok(()),
-                                    Some(c) => c,
-                                };
-                                if self.cursor >= self.source.len() {
-                                    break Err(Error::EOF);
-                                }
-                                match &self.source[self.cursor] {
-                                    Value::Char(ref current) if current.value == current_char => {
-                                        self.advance_cursor()?;
-                                    }
-                                    _ => {
-                                        break Err(self.ffp_err(value::String::new_val(
-                                            Span::new(start.clone(), self.pos()),
-                                            expected.clone(),
-                                        )));
-                                    }
-                                };
-                            } {
-                                Err(e) => self.fail(e)?,
-                                Ok(()) => self.capture(value::String::new_val(
-                                    Span::new(start, self.pos()),
-                                    expected.clone(),
-                                ))?,
-                            }
-                        }
+                        return Ok(true);
                     }
                 }
-
-                // Control flow
-                Instruction::Choice(offset) => {
-                    self.commit_captures()?;
-                    self.stkpush(StackFrame::new_backtrack(
-                        self.cursor,
-                        self.line,
-                        self.column,
-                        self.program_counter + offset,
-                        false,
-                    ));
-                    self.program_counter += 1;
-                }
-                Instruction::ChoiceP(offset) => {
-                    self.commit_captures()?;
-                    self.stkpush(StackFrame::new_backtrack(
-                        self.cursor,
-                        self.line,
-                        self.column,
-                        self.program_counter + offset,
-                        true,
-                    ));
-                    self.program_counter += 1;
-                    self.within_predicate = true;
-                }
-                Instruction::Commit(offset) => {
-                    self.stkpop()?;
-                    self.program_counter += offset;
-                }
-                Instruction::CommitB(offset) => {
-                    self.stkpop()?;
-                    self.program_counter -= offset;
+            }
+            Instruction::Class(id) => {
+                let start = self.pos();
+                self.program_counter += 1;
+                let ranges = self.program.class_at(id);
+                let matched = self.source.char_at(self.cursor).is_some_and(|c| {
+                    ranges
+                        .binary_search_by(|(start, end)| match (*start > c, *end < c) {
+                            (true, _) => std::cmp::Ordering::Greater,
+                            (_, true) => std::cmp::Ordering::Less,
+                            _ => std::cmp::Ordering::Equal,
+                        })
+                        .is_ok()
+                });
+                if matched {
+                    self.capture(self.source.value_at(self.cursor))?;
+                    self.advance_cursor()?;
+                    return Ok(true);
                 }
-                Instruction::PartialCommit(offset) => {
-                    let idx = self.stack.len() - 1;
-                    let f = &mut self.stack[idx];
-                    f.cursor = self.cursor;
-                    // always subtracts: this opcode is currently only
-                    // used when compiling the star operator (*),
-                    // which always needs to send the program counter
-                    // backwards.
-                    self.program_counter -= offset;
+                // expect each range, like the choice of chars and
+                // spans the class would otherwise compile into
+                let expected: Vec<Value> = ranges
+                    .iter()
+                    .map(|(a, b)| {
+                        let span = Span::new(start.clone(), start.clone());
+                        match a == b {
+                            true => value::Char::new_val(span, *a),
+                            false => value::String::new_val(span, format!("[{}-{}]", a, b)),
+                        }
+                    })
+                    .collect();
+                let mut err = Error::Fail;
+                for e in expected {
+                    err = self.ffp_err(e);
                 }
-                Instruction::BackCommit(offset) => {
-                    let f = self.stkpop()?;
-                    self.cursor = f.cursor;
-                    self.program_counter += offset;
+                self.fail(err)?;
+            }
+            Instruction::Int(..) | Instruction::Varint => {
+                let start = self.pos();
+                self.program_counter += 1;
+                // a varint takes 10 bytes at most
+                let max = match instruction {
+                    Instruction::Int(width, _) => width,
+                    _ => 10,
+                };
+                let mut bytes = vec![];
+                while bytes.len() < max {
+                    match self.source.char_at(self.cursor + bytes.len()) {
+                        Some(c) if u32::from(c) <= 0xff => bytes.push(c as u8),
+                        _ => break,
+                    }
                 }
-                Instruction::Fail => {
-                    self.fail(Error::Fail)?;
+                match read_int(&instruction, &bytes) {
+                    Some((value, len)) => {
+                        for _ in 0..len {
+                            self.advance_cursor()?;
+                        }
+                        self.capture(value::String::new_val(
+                            Span::new(start, self.pos()),
+                            value.to_string(),
+                        ))?;
+                    }
+                    None => {
+                        self.ffp_fail(value::String::new_val(
+                            Span::new(start, self.pos()),
+                            int_name(&instruction),
+                        ))?;
+                    }
                 }
-                Instruction::FailTwice => {
-                    self.stkpop()?;
+            }
+            Instruction::Host(id) => {
+                self.program_counter += 1;
+                let name = self.program.string_at(id);
+                let function = match self.hosts.0.get_mut(name) {
+                    Some(function) => function,
+                    None => return Err(Error::HostNotFound(name.clone())),
+                };
+                let mut call = HostCall {
+                    cursor: self.cursor,
+                    captures: self.captures.last().map_or(&[], |f| &f.values),
+                    ctx,
+                    inputs: vec![],
+                };
+                if !function(&mut call) {
                     self.fail(Error::Fail)?;
+                    return Ok(true);
                 }
-                Instruction::Jump(index) => {
-                    self.program_counter = index;
-                }
-                Instruction::Call(offset, precedence) => {
-                    self.inst_call(self.program_counter + offset, precedence, None)?;
+                // inputs are matched in the order they were pushed
+                for (name, text) in call.inputs.into_iter().rev() {
+                    self.source.include(self.cursor, name, &text);
                 }
-                Instruction::CallB(offset, precedence) => {
-                    self.inst_call(self.program_counter - offset, precedence, None)?;
+            }
+            Instruction::Take(id) => {
+                self.program_counter += 1;
+                let start = self.pos();
+                let name = self.program.string_at(id);
+                let text = self
+                    .captures
+                    .last()
+                    .and_then(|frame| captured_count(&frame.values, name))
+                    .and_then(|count| {
+                        (self.cursor..self.cursor + count)
+                            .map(|i| self.source.char_at(i))
+                            .collect::<Option<String>>()
+                    });
+                match text {
+                    Some(text) => {
+                        for _ in text.chars() {
+                            self.advance_cursor()?;
+                        }
+                        if !text.is_empty() {
+                            self.capture(value::String::new_val(
+                                Span::new(start, self.pos()),
+                                text,
+                            ))?;
+                        }
+                    }
+                    None => {
+                        self.ffp_fail(value::String::new_val(
+                            Span::new(start, self.pos()),
+                            format!("%take({})", name),
+                        ))?;
+                    }
                 }
-                Instruction::Return => {
-                    self.inst_return()?;
+            }
+            Instruction::String(id) | Instruction::StringCI(id) => {
+                let ignore_case = matches!(instruction, Instruction::StringCI(_));
+                let same = |a: &str, b: &str| a == b || (ignore_case && casefold::str_eq(a, b));
+                self.program_counter += 1;
+                let expected = self.program.string_at(id);
+                let start = self.pos();
+
+                if self.source.at_end(self.cursor) {
+                    self.ffp_fail(value::String::new_val(
+                        Span::new(start.clone(), self.pos()),
+                        expected.clone(),
+                    ))?;
+                    return Ok(true);
                 }
 
-                // Error Reporting/Recovery
-                Instruction::Throw(label) => {
-                    if self.within_predicate {
-                        self.program_counter += 1;
-                        self.fail(Error::Fail)?;
-                    } else {
-                        let message = self.program.label(label);
-                        match self.program.recovery.get(&label) {
-                            None => return Err(Error::Matching(self.ffp, message)),
-                            Some((addr, precedence)) => {
-                                self.inst_call(*addr, *precedence, Some(label))?
+                match &self.source {
+                    Input::Values(values) if matches!(&values[self.cursor], Value::String(s) if same(&s.value, expected)) =>
+                    {
+                        let value = if ignore_case && self.config.canonical_case() {
+                            value::String::new_val(values[self.cursor].span(), expected.clone())
+                        } else {
+                            values[self.cursor].clone()
+                        };
+                        self.capture(value)?;
+                        self.advance_cursor()?;
+                        return Ok(true);
+                    }
+                    Input::Tokens(tokens) if same(&tokens[self.cursor].kind, expected) => {
+                        self.capture(self.source.value_at(self.cursor))?;
+                        self.advance_cursor()?;
+                        return Ok(true);
+                    }
+                    _ => {
+                        let mut expected_chars = expected.chars();
+                        // what was matched, which differs from the
+                        // expected string when ignoring case
+                        let mut matched = String::new();
+                        match loop {
+                            let current_char = match expected_chars.next() {
+                                None => break Ok(()),
+                                Some(c) => c,
+                            };
+                            if self.source.at_end(self.cursor) {
+                                break Err(Error::EOF);
                             }
+                            match self.source.char_at(self.cursor) {
+                                Some(current)
+                                    if current == current_char
+                                        || (ignore_case && casefold::eq(current, current_char)) =>
+                                {
+                                    matched.push(current);
+                                    self.advance_cursor()?;
+                                }
+                                _ => {
+                                    break Err(self.ffp_err(value::String::new_val(
+                                        Span::new(start.clone(), self.pos()),
+                                        expected.clone(),
+                                    )));
+                                }
+                            };
+                        } {
+                            Err(e) => self.fail(e)?,
+                            Ok(()) => self.capture(value::String::new_val(
+                                Span::new(start, self.pos()),
+                                if ignore_case && !self.config.canonical_case() {
+                                    matched
+                                } else {
+                                    expected.clone()
+                                },
+                            ))?,
                         }
                     }
                 }
+            }
 
-                // Data Structure Matching
-                Instruction::Open => {
-                    self.program_counter += 1;
-                    match &self.source[self.cursor] {
-                        Value::List(list) => {
-                            let source = list.values.to_vec();
-                            self.capstkpush();
-                            self.stkpush(StackFrame::new_list(
-                                self.cursor,
-                                self.program_counter,
-                                self.source.to_vec(),
-                            ));
-                            self.cursor = 0;
-                            self.source = source;
-                        }
-                        Value::Node(n) => {
-                            let mut tmp =
-                                vec![value::String::new_val(Span::default(), n.name.clone())];
-                            tmp.extend(n.items.to_vec());
-                            self.capstkpush();
-                            self.stkpush(StackFrame::new_list(
-                                self.cursor,
-                                self.program_counter,
-                                self.source.to_vec(),
-                            ));
-                            self.source = tmp;
-                            self.cursor = 0;
-                        }
-                        _ => self.fail(Error::Matching(self.ffp, "Not a list".to_string()))?,
+            // Control flow
+            Instruction::Choice(offset) => {
+                self.commit_captures()?;
+                let captures = self.capstktop_mut()?.values.len();
+                self.stkpush(StackFrame::new_backtrack(
+                    self.cursor,
+                    self.line,
+                    self.column,
+                    self.program_counter + offset,
+                    false,
+                    captures,
+                    self.source.inclusions().len(),
+                ));
+                self.program_counter += 1;
+            }
+            Instruction::ChoiceP(offset) => {
+                self.commit_captures()?;
+                let captures = self.capstktop_mut()?.values.len();
+                self.stkpush(StackFrame::new_backtrack(
+                    self.cursor,
+                    self.line,
+                    self.column,
+                    self.program_counter + offset,
+                    true,
+                    captures,
+                    self.source.inclusions().len(),
+                ));
+                self.program_counter += 1;
+            }
+            Instruction::Commit(offset) => {
+                self.stkpop()?;
+                self.program_counter += offset;
+            }
+            Instruction::CommitB(offset) => {
+                let f = self.stkpop()?;
+                self.check_iteration(f.cursor)?;
+                self.program_counter -= offset;
+            }
+            Instruction::PartialCommit(offset) => {
+                let start = self.stack.last().ok_or(Error::Index)?.cursor;
+                self.check_iteration(start)?;
+                let captures = self.capstktop_mut()?.values.len();
+                let idx = self.stack.len() - 1;
+                let f = &mut self.stack[idx];
+                f.cursor = self.cursor;
+                f.line = self.line;
+                f.column = self.column;
+                f.captures = captures;
+                f.trivia = self.trivia.len();
+                f.inputs = self.source.inclusions().len();
+                // always subtracts: this opcode is currently only
+                // used when compiling the star operator (*),
+                // which always needs to send the program counter
+                // backwards.
+                self.program_counter -= offset;
+            }
+            Instruction::BackCommit(offset) => {
+                let f = self.stkpop()?;
+                self.cursor = f.cursor;
+                self.line = f.line;
+                self.column = f.column;
+                self.trivia.truncate(f.trivia);
+                self.source.exclude(f.inputs);
+                // `&e` leaves no values behind, the same way a frame
+                // that's backtracked to doesn't
+                let top = self.capstktop_mut()?;
+                top.values.truncate(f.captures);
+                top.index = top.index.min(f.captures);
+                self.program_counter += offset;
+            }
+            Instruction::TestChar(expected, offset) => {
+                // values and tokens aren't compared here, the
+                // instructions after the test match them instead
+                let mismatch = match &self.source {
+                    Input::Chars { .. } | Input::Stream(_) => {
+                        self.source.char_at(self.cursor) != Some(expected)
                     }
-                }
-                Instruction::Close(ref container_type) => {
+                    Input::Values(_) | Input::Tokens(_) => false,
+                };
+                if !mismatch {
                     self.program_counter += 1;
-                    let capsframe = self.capstkpop()?;
-                    self.capture(match container_type {
-                        ContainerType::List => {
-                            value::List::new_val(Span::default(), capsframe.values)
-                        }
-                        ContainerType::Node => value::Node::new_val(
-                            Span::default(),
-                            match &capsframe.values[0] {
-                                Value::String(s) => s.value.clone(),
-                                _ => panic!("node name must be a string"),
-                            },
-                            capsframe.values[1..].to_vec(),
-                        ),
-                    })?;
-                    let frame = self.stkpop()?;
-                    self.cursor = frame.cursor + 1;
-                    self.source = frame.list.ok_or(Error::Index)?;
+                    return Ok(true);
                 }
+                // the character is expected here as much as if the
+                // instruction that the test guards had failed
+                let start = self.pos();
+                self.ffp_err(value::Char::new_val(
+                    Span::new(start.clone(), start),
+                    expected,
+                ));
+                self.program_counter += offset;
+            }
+            Instruction::TestAny(offset) => match self.source.at_end(self.cursor) {
+                true => self.program_counter += offset,
+                false => self.program_counter += 1,
+            },
+            Instruction::Fail => {
+                self.fail(Error::Fail)?;
+            }
+            Instruction::FailTwice => {
+                self.stkpop()?;
+                self.fail(Error::Fail)?;
+            }
+            Instruction::Jump(offset) => {
+                self.program_counter += offset;
+            }
+            Instruction::JumpB(offset) => {
+                self.program_counter -= offset;
+            }
+            Instruction::Call(offset, precedence) => {
+                self.inst_call(self.program_counter + offset, precedence, None)?;
+            }
+            Instruction::CallB(offset, precedence) => {
+                self.inst_call(self.program_counter - offset, precedence, None)?;
+            }
+            Instruction::Return => {
+                self.inst_return(true)?;
+            }
+            Instruction::ReturnValues => {
+                self.inst_return(false)?;
+            }
 
-                // Capture Stack
-                Instruction::CapPush => {
+            // Error Reporting/Recovery
+            Instruction::Throw(label) => {
+                if self.within_predicate() {
                     self.program_counter += 1;
-                    if !self.within_predicate {
-                        self.capstkpush();
+                    self.fail(Error::Fail)?;
+                } else {
+                    let thrown = || {
+                        Error::Label(
+                            self.program.label(label),
+                            self.program.label_message(label),
+                            self.source.position_at(self.cursor),
+                        )
+                    };
+                    let recovery = match self.program.recovery.get(&label) {
+                        Some(recovery) => *recovery,
+                        None => return Err(thrown()),
+                    };
+                    let cursor = self.source.position_at(self.cursor);
+                    if !self.config.on_error.recovers(self.errors, cursor)? {
+                        return Err(thrown());
                     }
+                    self.errors += 1;
+                    let (addr, precedence) = recovery;
+                    self.inst_call(addr, precedence, Some(label))?
                 }
-                Instruction::CapPop => {
-                    self.program_counter += 1;
-                    if !self.within_predicate {
-                        for c in self.capstkpop()?.values {
-                            self.capture(c)?;
+            }
+
+            // Data Structure Matching
+            Instruction::Open => {
+                self.program_counter += 1;
+                let items = match &self.source {
+                    Input::Values(values) => match values.get(self.cursor) {
+                        Some(Value::List(list)) => Some(list.values.to_vec()),
+                        Some(Value::Node(n)) => {
+                            let mut tmp =
+                                vec![value::String::new_val(n.span.clone(), n.name.clone())];
+                            tmp.extend(n.items.to_vec());
+                            Some(tmp)
                         }
+                        _ => None,
+                    },
+                    Input::Chars { .. } | Input::Tokens(_) | Input::Stream(_) => None,
+                };
+                match items {
+                    Some(items) => {
+                        let parent = std::mem::replace(&mut self.source, Input::Values(items));
+                        self.capstkpush();
+                        self.stkpush(StackFrame::new_list(
+                            self.cursor,
+                            self.program_counter,
+                            parent,
+                        ));
+                        self.cursor = 0;
+                    }
+                    None => {
+                        let ffp = self.source.position_at(self.ffp);
+                        self.fail(Error::Matching(ffp, "Not a list".to_string()))?
                     }
                 }
-                Instruction::CapCommit => {
-                    self.program_counter += 1;
-                    if !self.within_predicate {
-                        self.commit_captures()?;
+            }
+            Instruction::Close(ref container_type) => {
+                self.program_counter += 1;
+                let capsframe = self.capstkpop()?;
+                let frame = self.stkpop()?;
+                self.source = frame.list.ok_or(Error::Index)?;
+                // the container rebuilt takes the place of the one that
+                // was opened, and so does its span
+                let span = self.source.span_at(frame.cursor);
+                self.cursor = frame.cursor + 1;
+                self.capture(match container_type {
+                    ContainerType::List => value::List::new_val(span, capsframe.values),
+                    ContainerType::Node => value::Node::new_val(
+                        span,
+                        match &capsframe.values[0] {
+                            Value::String(s) => s.value.to_string(),
+                            _ => panic!("node name must be a string"),
+                        },
+                        capsframe.values[1..].to_vec(),
+                    ),
+                })?;
+            }
+
+            // Capture Stack
+            Instruction::CapPush => {
+                self.program_counter += 1;
+                if !self.within_predicate() {
+                    self.capstkpush();
+                }
+            }
+            Instruction::CapPop => {
+                self.program_counter += 1;
+                if !self.within_predicate() {
+                    for c in self.capstkpop()?.values {
+                        self.capture(c)?;
                     }
                 }
-                Instruction::CapJoin => {
-                    self.program_counter += 1;
-                    self.join_captures()?;
-                    self.dbg_captures()?;
+            }
+            Instruction::CapCommit => {
+                self.program_counter += 1;
+                if !self.within_predicate() {
+                    self.commit_captures()?;
                 }
             }
+            Instruction::CapJoin => {
+                self.program_counter += 1;
+                self.join_captures()?;
+            }
+            Instruction::CapText => {
+                self.program_counter += 1;
+                self.text_captures()?;
+            }
+            Instruction::CapList => {
+                self.program_counter += 1;
+                self.list_captures()?;
+            }
+            Instruction::CapDrop => {
+                self.program_counter += 1;
+                self.capstktop_mut()?.values.clear();
+            }
         }
-
-        if !self.captures.is_empty() {
-            self.dbg_captures()?;
-            Ok(self.capstkpop()?.values.pop())
-        } else {
-            Ok(None)
-        }
+        Ok(true)
     }
 
     fn inst_call(
@@ -864,15 +4001,24 @@ impl<'a> VM<'a> {
         // new frame for both the capture and the backtrack/call stack
         // and set the program counter appropriately
         if precedence == 0 {
+            if recovery_label.is_none() && self.packrat_hit(address)? {
+                return Ok(());
+            }
             self.capstkpush();
             self.stkpush(StackFrame::new_call(
+                self.cursor,
+                self.line,
+                self.column,
                 self.program_counter + 1,
                 address,
                 precedence,
                 recovery_label,
             ));
             self.program_counter = address;
-            return Ok(());
+            return self.record(RuleEvent::Enter {
+                rule: self.program.identifier(address),
+                cursor: self.cursor,
+            });
         }
 
         // from this point on, we're handling left recursive calls.
@@ -885,10 +4031,11 @@ impl<'a> VM<'a> {
             // backtrack/call stack, point the program counter to
             // where the function being called is and move on.
             None => {
-                self.dbg("- lvar.{{1, 2}}");
                 self.capstkpush();
                 self.stkpush(StackFrame::new_lrcall(
                     cursor,
+                    self.line,
+                    self.column,
                     self.program_counter + 1,
                     address,
                     precedence,
@@ -896,6 +4043,10 @@ impl<'a> VM<'a> {
                 ));
                 self.program_counter = address;
                 self.lrmemo.insert(key, LeftRecTableEntry::new(precedence));
+                self.record(RuleEvent::Enter {
+                    rule: self.program.identifier(address),
+                    cursor,
+                })?;
             }
             // if there is already a leftrec entry in the memoization
             // table, it means that we're hitting a left recursive
@@ -906,54 +4057,67 @@ impl<'a> VM<'a> {
             // node and push it into the capture stack.
             Some(entry) => {
                 if matches!(entry.cursor, Err(Error::LeftRec)) || precedence < entry.precedence {
-                    self.dbg("- lvar.{{3,5}}");
                     self.fail(Error::Fail)?;
                 } else {
-                    self.dbg("- lvar.4");
                     self.program_counter += 1;
-                    self.cursor = entry.cursor.clone()?;
+                    let end = entry.cursor.clone()?;
+                    self.skip_to(end);
                     let capframe = self.capstktop_mut()?;
                     let values: Vec<_> = capframe.values.drain(..capframe.index).collect();
                     capframe.values.clear();
                     self.capture_flatten(address, values)?;
                     self.commit_captures()?;
+                    // with direct recursion, the longest match is now
+                    // wrapped within a single node, which is what's
+                    // left if the bound can't be incremented
+                    let captures = self.capstktop_mut()?.values.len();
+                    let call = self
+                        .stack
+                        .iter_mut()
+                        .rev()
+                        .find(|f| f.ftype == StackFrameType::Call);
+                    if let Some(frame) = call {
+                        if frame.address == address && frame.cursor == cursor {
+                            frame.captures = captures;
+                        }
+                    }
                 }
             }
         }
-        self.dbg_captures()?;
         Ok(())
     }
 
-    fn inst_return(&mut self) -> Result<(), Error> {
+    /// Return from the production on top of the stack.  Its values
+    /// are captured within a node named after it if `wrap` is true,
+    /// and as they are otherwise
+    fn inst_return(&mut self, wrap: bool) -> Result<(), Error> {
         let cursor = self.cursor;
         let frame = self.stkpeek()?;
         let address = frame.address;
+        self.record(RuleEvent::Exit {
+            rule: self.program.identifier(address),
+            cursor,
+            matched: true,
+        })?;
+        let frame = self.stkpeek()?;
 
         if frame.precedence == 0 {
-            let frame = self.stkpop()?;
-            let capframe = self.capstkpop()?;
-            self.program_counter = frame.program_counter;
-
-            // Recovery labels are captured as Error nodes
-            if let Some(label_id) = frame.recovery_label {
-                let label = self.program.identifier(address);
-                let message = self.program.label_message(label_id);
-                let start = Position::new(frame.cursor, frame.line, frame.column);
-                let span = Span::new(start, self.pos());
-                self.capture(value::Error::new_val(span, label, message))?;
-                return Ok(());
-            }
-
-            // base case for regular rules returning what's inside the
-            // capture frame that was just popped
-            let items = capframe.values;
-            if !items.is_empty() {
-                let name = self.program.identifier(address);
-                if name != WHITE_SPACE_RULE_NAME {
-                    let start = Position::new(frame.cursor, frame.line, frame.column);
-                    let span = Span::new(start, self.pos());
-                    self.capture(value::Node::new_val(span, name, items))?;
-                }
+            let key = (address, frame.cursor);
+            let memoize = frame.recovery_label.is_none() && self.packrat_stores();
+            // captures of the caller, once the frame of this call is
+            // popped, that were there before it returned
+            let before = match self.captures.len().checked_sub(2) {
+                Some(caller) => self.captures[caller].values.len(),
+                None => 0,
+            };
+            self.return_call(address, wrap)?;
+            if memoize {
+                let values = self.capstktop_mut()?.values[before..].to_vec();
+                let entry = PackratEntry::Matched {
+                    end: cursor,
+                    values,
+                };
+                self.packrat_insert(key, entry);
             }
             return Ok(());
         }
@@ -961,60 +4125,154 @@ impl<'a> VM<'a> {
         // left recursive cases
 
         if matches!(frame.result, Err(Error::LeftRec)) || cursor > frame.result.clone()? {
-            self.dbg("- {{lvar,inc}}.1");
             let frame = self.stkpeek_mut()?;
             frame.result = Ok(cursor);
-            let frame_cursor = frame.cursor;
+            frame.bound += 1;
+            let (frame_cursor, frame_line, frame_column) = (frame.cursor, frame.line, frame.column);
             let frame_precedence = frame.precedence;
             let key = (address, frame_cursor);
             let entry = &mut self.lrmemo.get_mut(&key).ok_or(Error::Fail)?;
             entry.cursor = Ok(cursor);
             entry.bound += 1;
             entry.precedence = frame_precedence;
+            self.lrdepth += 1;
 
             // call the same address we just returned from, to try to
             // increment the left recursive bound once more
             self.program_counter = address;
             self.cursor = frame_cursor;
+            self.line = frame_line;
+            self.column = frame_column;
             self.commit_captures()?;
+            let captures = self.capstktop_mut()?.values.len();
+            let trivia = self.trivia.len();
+            let frame = self.stkpeek_mut()?;
+            frame.captures = captures;
+            frame.trivia = trivia;
             return Ok(());
         }
-        self.dbg("- inc.3");
         let frame = self.stkpop()?;
-        self.cursor = frame.result?;
+        self.skip_to(frame.result.clone()?);
         self.program_counter = frame.program_counter;
+        // so is the trivia it matched
+        self.trivia.truncate(frame.trivia);
         let mut capframe = self.capstkpop()?;
         let key = (frame.address, frame.cursor);
         self.lrmemo.remove(&key);
-        let values = capframe.values.drain(..capframe.index).collect();
+        // values captured by the attempt that didn't increment the
+        // bound are dropped
+        let values = capframe.values.drain(..frame.captures).collect();
         capframe.values.clear();
         self.capture_flatten(address, values)?;
-        self.dbg_captures()?;
+        Ok(())
+    }
+
+    /// Pop the frame of a call to a production that isn't left
+    /// recursive, capturing its values within the caller
+    fn return_call(&mut self, address: usize, wrap: bool) -> Result<(), Error> {
+        let frame = self.stkpop()?;
+        let capframe = self.capstkpop()?;
+        self.program_counter = frame.program_counter;
+
+        // Recovery labels are captured as Error nodes
+        if let Some(label_id) = frame.recovery_label {
+            let label = self.program.identifier(address);
+            let message = self.program.label_message(label_id);
+            let start = Position::new(frame.cursor, frame.line, frame.column);
+            let span = Span::new(start, self.pos());
+            self.capture(value::Error::new_val(span, label, message))?;
+            return Ok(());
+        }
+
+        // base case for regular rules returning what's inside the
+        // capture frame that was just popped
+        let mut items = capframe.values;
+        if !items.is_empty() {
+            if !wrap
+                || (self.program.identifier_at(address).is_none()
+                    && self.program.unnamed == UnnamedProductions::Unwrap)
+            {
+                for item in items {
+                    self.capture(item)?;
+                }
+                return Ok(());
+            }
+            let name = self.program.identifier(address);
+            if name != WHITE_SPACE_RULE_NAME
+                && !self.config.captures(&name)
+                && !self.config.lossless
+            {
+                if self.enclosed_by_call() {
+                    return self.capture_filtered(items);
+                }
+                // The outermost production still becomes a node
+                // so the output is a single tree, but its text is
+                // dropped like the text of any other filtered one
+                items.retain(|i| matches!(i, Value::Node(_) | Value::Error(_)));
+            }
+            if name == WHITE_SPACE_RULE_NAME && self.config.trivia {
+                match self.config.lossless {
+                    true => self.keep_trivia(frame.cursor, items.clone()),
+                    false => self.keep_trivia(frame.cursor, std::mem::take(&mut items)),
+                }
+            }
+            if name != WHITE_SPACE_RULE_NAME || self.config.lossless {
+                let start = Position::new(frame.cursor, frame.line, frame.column);
+                let span = Span::new(start, self.pos());
+                self.capture(self.node(address, span, name, items))?;
+            }
+        }
         Ok(())
     }
 
     fn fail(&mut self, error: Error) -> Result<(), Error> {
-        self.dbg_instruction_fail();
+        self.trace_fail();
         let frame = loop {
             match self.stkpop() {
                 Err(_) => return Err(error),
                 Ok(f) => {
                     if matches!(f.result, Err(Error::LeftRec)) {
-                        self.dbg("- lvar.2");
                         let key = (f.address, f.cursor);
                         self.lrmemo.remove(&key);
                     }
+                    if f.ftype == StackFrameType::Call {
+                        if f.precedence == 0 && f.recovery_label.is_none() && self.packrat_stores()
+                        {
+                            let entry = PackratEntry::Failed(error.clone());
+                            self.packrat_insert((f.address, f.cursor), entry);
+                        }
+                        // left recursive calls that matched before
+                        // exit at the end of their longest match
+                        let (matched, cursor) = match f.result {
+                            Ok(result) if result > 0 => (true, result),
+                            _ => (false, self.cursor),
+                        };
+                        self.record_at(
+                            self.call_frames.len() + 1,
+                            RuleEvent::Exit {
+                                rule: self.program.identifier(f.address),
+                                cursor,
+                                matched,
+                            },
+                        )?;
+                    }
                     if f.ftype == StackFrameType::Backtrack {
+                        self.stats.backtracks += 1;
                         let top = self.capstktop_mut()?;
-                        top.values.drain(top.index..);
-                        self.dbg_captures()?;
+                        top.values.truncate(f.captures);
+                        top.index = top.index.min(f.captures);
                         break f;
                     } else {
                         self.capstkpop()?;
                     }
+                    // backtracking out of a list puts its parent
+                    // input back in place
+                    if let Some(parent) = f.list {
+                        self.source = parent;
+                        continue;
+                    }
                     if let Ok(result) = f.result {
                         if result > 0 {
-                            self.dbg("- inc.2");
                             self.cursor = result;
                             break f;
                         }
@@ -1024,75 +4282,413 @@ impl<'a> VM<'a> {
         };
         self.program_counter = frame.program_counter;
         self.cursor = frame.cursor;
+        self.line = frame.line;
+        self.column = frame.column;
+        self.trivia.truncate(frame.trivia);
+        if frame.ftype == StackFrameType::Backtrack {
+            self.source.exclude(frame.inputs);
+        }
         Ok(())
     }
 
+    /// Whether calls can be matched from the table of packrat mode,
+    /// see `Config::with_packrat`
+    fn packrat_applies(&self) -> bool {
+        self.config.packrat
+            && !self.config.trivia
+            && self.lrmemo.is_empty()
+            && matches!(self.config.captures, CaptureFilter::All)
+            && !matches!(self.source, Input::Values(_))
+    }
+
+    /// Whether the outcome of the call being returned from, or failed,
+    /// can be added to the table of packrat mode.  Within predicates,
+    /// labels fail instead of being thrown, so the outcome could be
+    /// different elsewhere.
+    fn packrat_stores(&self) -> bool {
+        self.packrat_applies() && !self.within_predicate()
+    }
+
+    fn packrat_insert(&mut self, key: (usize, usize), entry: PackratEntry) {
+        self.packrat.insert(key, entry);
+        self.stats.memo_entries = self.packrat.len();
+    }
+
+    /// Match the call to the production at `address` with the outcome
+    /// kept in the table of packrat mode, if there's one for where the
+    /// cursor is.  Returns whether there was.
+    fn packrat_hit(&mut self, address: usize) -> Result<bool, Error> {
+        if !self.packrat_applies() {
+            return Ok(false);
+        }
+        let cursor = self.cursor;
+        let (failed, end, values) = match self.packrat.get(&(address, cursor)) {
+            None => {
+                self.stats.memo_misses += 1;
+                return Ok(false);
+            }
+            Some(PackratEntry::Failed(error)) => (Some(error.clone()), cursor, vec![]),
+            Some(PackratEntry::Matched { end, values }) => (None, *end, values.clone()),
+        };
+        self.stats.memo_hits += 1;
+        // the production is entered and exited as if it was matched
+        // again, from within a frame that's never pushed
+        let rule = self.program.identifier(address);
+        let depth = self.call_frames.len() + 1;
+        self.record_at(
+            depth,
+            RuleEvent::Enter {
+                rule: rule.clone(),
+                cursor,
+            },
+        )?;
+        self.record_at(
+            depth,
+            RuleEvent::Exit {
+                rule,
+                cursor: end,
+                matched: failed.is_none(),
+            },
+        )?;
+        if let Some(error) = failed {
+            // the terminals that failed within the production added
+            // to what's expected at the farther failure position,
+            // which may have moved along since
+            let error = match error {
+                Error::Matching(..) => self.expected_error(),
+                error => error,
+            };
+            self.fail(error)?;
+            return Ok(true);
+        }
+        self.skip_to(end);
+        for value in values {
+            self.capture(value)?;
+        }
+        self.program_counter += 1;
+        Ok(true)
+    }
+
+    /// Keep the `items` captured by `Spacing` from `start` on as
+    /// trivia, split after the first one that breaks the line unless
+    /// there's no token before them
+    fn keep_trivia(&mut self, start: usize, mut items: Vec<Value>) {
+        let split = match start {
+            0 => 0,
+            _ => items
+                .iter()
+                .position(|i| i.to_text().contains('\n'))
+                .map_or(items.len(), |i| i + 1),
+        };
+        let trailing: Vec<_> = items.drain(..split).collect();
+        for (items, attachment) in [
+            (trailing, Attachment::Trailing),
+            (items, Attachment::Leading),
+        ] {
+            if let (Some(first), Some(last)) = (items.first(), items.last()) {
+                let span = Span::new(first.span().start, last.span().end);
+                self.trivia.push(Trivia {
+                    span,
+                    items,
+                    attachment,
+                });
+            }
+        }
+    }
+
+    /// Move the cursor forward to `cursor`, past input that was
+    /// already matched, with the line and column following along as
+    /// if each char was consumed by `advance_cursor`
+    fn skip_to(&mut self, cursor: usize) {
+        if cursor > self.cursor {
+            let last = self.source.span_at(cursor - 1).start;
+            self.line = last.line;
+            self.column = last.column;
+        }
+        self.cursor = cursor;
+    }
+
     fn pos(&self) -> Position {
         Position::new(self.cursor, self.line, self.column)
     }
 
-    fn dbg(&self, _m: &str) {
-        #[cfg(debug_assertions)]
-        {
-            for _ in 0..self.call_frames.len() {
-                eprint!("    ");
+    /// Fail with `Error::ResourceLimit` if the run went over any of
+    /// the limits set by the config
+    fn check_limits(&self) -> Result<(), Error> {
+        let exceeded = match (self.config.max_stack_depth, self.config.max_steps) {
+            (Some(max), _) if self.stack.len() + self.lrdepth > max => (Limit::StackDepth, max),
+            (_, Some(max)) if self.stats.instructions >= max => (Limit::Steps, max),
+            _ => return Ok(()),
+        };
+        let rule = match self.call_frames.last() {
+            Some(frame) => self.program.identifier(self.stack[*frame].address),
+            None => self.program.identifier(0),
+        };
+        Err(Error::ResourceLimit(
+            exceeded.0,
+            exceeded.1,
+            rule,
+            self.source.position_at(self.cursor),
+        ))
+    }
+
+    /// Record that the iteration of a repetition that started at
+    /// `start` and is about to be repeated didn't consume any input,
+    /// if matching is being traced or its rule events are recorded.
+    /// Nothing changes until the next iteration, so it's reported
+    /// once rather than for each time it repeats.
+    fn check_iteration(&mut self, start: usize) -> Result<(), Error> {
+        if start != self.cursor || (self.tracer.is_none() && !self.config.rule_events) {
+            return Ok(());
+        }
+        let key = (self.program_counter, self.cursor);
+        if self.empty_iteration == Some(key) {
+            return Ok(());
+        }
+        self.empty_iteration = Some(key);
+        let rule = match self.call_frames.last() {
+            Some(frame) => self.program.identifier(self.stack[*frame].address),
+            None => self.program.identifier(0),
+        };
+        self.record(RuleEvent::EmptyIteration {
+            rule,
+            cursor: self.cursor,
+        })
+    }
+
+    fn step(&self) -> Step<'a> {
+        Step {
+            program: self.program,
+            program_counter: self.program_counter,
+            cursor: self.cursor,
+            depth: self.call_frames.len(),
+        }
+    }
+
+    fn trace_instruction(&mut self) {
+        let step = self.step();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.0.on_instruction(&step);
+        }
+    }
+
+    fn trace_fail(&mut self) {
+        let step = self.step();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.0.on_fail(&step);
+        }
+    }
+}
+
+/// Debugger runs a program against an input one instruction at a
+/// time, stopping at breakpoints set on productions, so the state of
+/// the machine can be inspected in between.  It's meant to be driven
+/// by a front-end, e.g.: an interactive shell.
+#[derive(Debug)]
+pub struct Debugger<'a> {
+    vm: VM<'a>,
+    // addresses of the productions with breakpoints
+    breakpoints: HashSet<usize>,
+    // what the run ended with, once it ends
+    result: Option<Result<Option<Value>, Error>>,
+}
+
+/// Stop tells why the debugger handed control back
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    // An instruction ran and the program didn't halt
+    Step,
+    // Matching entered a production with a breakpoint (name)
+    Breakpoint(String),
+    // The run ended with this result
+    Halted(Result<Option<Value>, Error>),
+}
+
+/// Kind of an entry of the stack of the machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    // Where to go back to if what follows fails
+    Backtrack,
+    // Production being matched
+    Call,
+    // Items of a list or node of structured input being matched
+    List,
+}
+
+/// Frame is an entry of the stack of the machine, as the debugger
+/// shows it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    // name of the production of call frames
+    pub rule: Option<String>,
+    // where the cursor was when the frame was pushed
+    pub cursor: usize,
+    // where the program goes once the frame is popped
+    pub program_counter: usize,
+    pub precedence: usize,
+}
+
+/// Memo is an entry of the table of left recursive calls
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Memo {
+    pub rule: String,
+    // where the call started
+    pub cursor: usize,
+    // end of the longest match found so far, if any
+    pub end: Option<usize>,
+    pub precedence: usize,
+    pub bound: usize,
+}
+
+impl<'a> Debugger<'a> {
+    /// Debugger ready to run the program of `vm` against `input`
+    /// from its first instruction
+    pub fn new(mut vm: VM<'a>, input: &str) -> Self {
+        vm.source = Input::from_str(input);
+        vm.start();
+        Self {
+            vm,
+            breakpoints: HashSet::new(),
+            result: None,
+        }
+    }
+
+    /// Stop when matching enters the production `rule`.  Returns
+    /// `false` if the program has no production with that name.
+    pub fn add_breakpoint(&mut self, rule: &str) -> bool {
+        let addresses: Vec<usize> = self
+            .vm
+            .program
+            .identifiers
+            .keys()
+            .copied()
+            .filter(|a| self.vm.program.identifier_at(*a).map(String::as_str) == Some(rule))
+            .collect();
+        self.breakpoints.extend(&addresses);
+        !addresses.is_empty()
+    }
+
+    /// Stop stopping at the production `rule`
+    pub fn remove_breakpoint(&mut self, rule: &str) {
+        let program = self.vm.program;
+        self.breakpoints
+            .retain(|a| program.identifier_at(*a).map(String::as_str) != Some(rule));
+    }
+
+    /// Names of the productions with breakpoints, sorted
+    pub fn breakpoints(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .breakpoints
+            .iter()
+            .map(|a| self.vm.program.identifier(*a))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Run the next instruction.  Once the run ends, the result is
+    /// returned again without running anything else.
+    pub fn step(&mut self) -> Stop {
+        if let Some(result) = &self.result {
+            return Stop::Halted(result.clone());
+        }
+        let result = match self.vm.exec(None) {
+            Ok(true) => {
+                let pc = self.vm.program_counter;
+                return match self.breakpoints.contains(&pc) {
+                    true => Stop::Breakpoint(self.vm.program.identifier(pc)),
+                    false => Stop::Step,
+                };
+            }
+            Ok(false) => self.vm.finish(),
+            Err(e) => Err(e),
+        };
+        self.result = Some(result.clone());
+        Stop::Halted(result)
+    }
+
+    /// Run until matching enters a production with a breakpoint or
+    /// the run ends
+    pub fn resume(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step => continue,
+                stop => return stop,
             }
-            eprintln!("{}", _m);
         }
     }
 
-    fn dbg_instruction(&self) {
-        #[cfg(debug_assertions)]
-        {
-            eprint!("{:#04}, {:#04} ", self.program_counter, self.cursor);
+    /// The machine being debugged, e.g.: for its `cursor` and its
+    /// `rule_stack`
+    pub fn vm(&self) -> &VM<'a> {
+        &self.vm
+    }
 
-            self.dbg(&instruction_to_string(
-                self.program,
-                &self.program.code[self.program_counter],
-                self.program_counter,
-            ));
-        }
+    /// Position of the next instruction to run within the program
+    pub fn program_counter(&self) -> usize {
+        self.vm.program_counter
     }
 
-    fn dbg_instruction_fail(&self) {
-        #[cfg(debug_assertions)]
-        {
-            eprint!("{:#04}, {:#04} ", self.program_counter, self.cursor);
-            for _ in 0..self.call_frames.len() {
-                eprint!("    ");
-            }
-            eprintln!("fail");
-        }
+    /// Text of the next instruction to run
+    pub fn instruction(&self) -> String {
+        self.vm.step().describe()
     }
 
-    fn dbg_captures(&self) -> Result<(), Error> {
-        #[cfg(debug_assertions)]
-        {
-            let top = if self.captures.is_empty() {
-                return Err(Error::Index);
-            } else {
-                &self.captures[self.captures.len() - 1]
-            };
-            if top.values.is_empty() {
-                return Ok(());
-            }
-            self.dbg(&format!(
-                "- captures[{}]: {:?}",
-                top.index,
-                top.values
-                    .iter()
-                    .map(format::compact)
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            ));
-        }
-        Ok(())
+    /// Entries of the stack, from the bottom to the top
+    pub fn frames(&self) -> Vec<Frame> {
+        self.vm
+            .stack
+            .iter()
+            .map(|f| Frame {
+                kind: match f.ftype {
+                    StackFrameType::Backtrack => FrameKind::Backtrack,
+                    StackFrameType::Call => FrameKind::Call,
+                    StackFrameType::List => FrameKind::List,
+                },
+                rule: (f.ftype == StackFrameType::Call)
+                    .then(|| self.vm.program.identifier(f.address)),
+                cursor: f.cursor,
+                program_counter: f.program_counter,
+                precedence: f.precedence,
+            })
+            .collect()
+    }
+
+    /// Values captured so far by each production being matched, from
+    /// the outermost to the innermost one
+    pub fn captures(&self) -> Vec<&[Value]> {
+        self.vm
+            .captures
+            .iter()
+            .map(|f| f.values.as_slice())
+            .collect()
+    }
+
+    /// Entries of the table of left recursive calls, sorted by where
+    /// they started
+    pub fn memo(&self) -> Vec<Memo> {
+        let mut memo: Vec<Memo> = self
+            .vm
+            .lrmemo
+            .iter()
+            .map(|((address, cursor), entry)| Memo {
+                rule: self.vm.program.identifier(*address),
+                cursor: *cursor,
+                end: entry.cursor.clone().ok(),
+                precedence: entry.precedence,
+                bound: entry.bound,
+            })
+            .collect();
+        memo.sort_by(|a, b| (a.cursor, &a.rule).cmp(&(b.cursor, &b.rule)));
+        memo
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::xorshift::XorShift;
 
     // (ch.1)
     //
@@ -1106,17 +4702,22 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::Char('a'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("a");
+        let result = vm.run("a");
 
         assert!(result.is_ok());
         assert_eq!(1, vm.cursor);
@@ -1134,21 +4735,29 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::Char('a'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("b");
+        let result = vm.run("b");
 
         assert!(result.is_err());
         assert_eq!(
-            Error::Matching(0, "syntax error, expecting: 'a'".to_string()),
+            Error::Matching(
+                Position::new(0, 0, 1),
+                "syntax error, expecting: 'a'".to_string()
+            ),
             result.unwrap_err()
         );
     }
@@ -1165,17 +4774,22 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::Span('a', 'z'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("a");
+        let result = vm.run("a");
 
         assert!(result.is_ok());
         assert_eq!(1, vm.cursor);
@@ -1193,25 +4807,108 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::Span('a', 'z'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("9");
+        let result = vm.run("9");
 
         assert!(result.is_err());
         assert_eq!(
-            Error::Matching(0, "syntax error, expecting: '[a-z]'".to_string()),
+            Error::Matching(
+                Position::new(0, 0, 1),
+                "syntax error, expecting: '[a-z]'".to_string()
+            ),
             result.unwrap_err()
         );
     }
 
+    // (class.1)
+    //
+    // s[i] within one of the ranges of the class
+    // -------------------------------------------
+    // match [a-z_] s i = i+1
+    #[test]
+    fn class_1() {
+        // G <- [a-z_0-9]
+        let program = Program {
+            identifiers: HashMap::new(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![vec![('0', '9'), ('_', '_'), ('a', 'z')]],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                Instruction::Class(0),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+
+        for input in ["a", "z", "_", "0", "9"] {
+            let mut vm = VM::new(&program);
+            let result = vm.run(input);
+
+            assert!(result.is_ok());
+            assert_eq!(1, vm.cursor);
+        }
+    }
+
+    // (class.2)
+    //
+    // s[i] not within any of the ranges of the class
+    // -----------------------------------------------
+    // match [a-z_] s i = nil
+    #[test]
+    fn class_2() {
+        // G <- [a-z_0-9]
+        let program = Program {
+            identifiers: HashMap::new(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![vec![('0', '9'), ('_', '_'), ('a', 'z')]],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                Instruction::Class(0),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+
+        for input in ["A", "^", "`", "{", ""] {
+            let mut vm = VM::new(&program);
+            let result = vm.run(input);
+
+            assert_eq!(
+                Err(Error::Matching(
+                    Position::new(0, 0, 1),
+                    "syntax error, expecting: '[0-9]', '_', '[a-z]'".to_string()
+                )),
+                result
+            );
+        }
+    }
+
     // (any.1)
     //   i ≤ |s|
     // -----------------
@@ -1222,6 +4919,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1230,11 +4931,12 @@ mod tests {
                 Instruction::Any,
                 Instruction::Any,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("abcd");
+        let result = vm.run("abcd");
 
         assert!(result.is_ok());
         assert_eq!(3, vm.cursor);
@@ -1250,17 +4952,22 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::Any,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("");
+        let result = vm.run("");
 
         assert!(result.is_err());
         assert_eq!(Error::EOF, result.unwrap_err());
@@ -1279,6 +4986,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1288,11 +4999,12 @@ mod tests {
                 Instruction::Commit(1),
                 Instruction::Fail,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("foo");
+        let result = vm.run("foo");
 
         assert!(result.is_ok());
         assert_eq!(0, vm.cursor);
@@ -1310,6 +5022,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1319,11 +5035,12 @@ mod tests {
                 Instruction::Commit(1),
                 Instruction::Fail,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("foo");
+        let result = vm.run("foo");
 
         assert!(result.is_err());
         assert_eq!(Error::Fail, result.unwrap_err());
@@ -1342,6 +5059,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1352,16 +5073,20 @@ mod tests {
                 Instruction::Char('b'),
                 Instruction::Halt,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("c");
+        let result = vm.run("c");
 
         assert!(result.is_err());
         // currently shows the last error
         assert_eq!(
-            Error::Matching(0, "syntax error, expecting: 'a', 'b'".to_string()),
+            Error::Matching(
+                Position::new(0, 0, 1),
+                "syntax error, expecting: 'a', 'b'".to_string()
+            ),
             result.unwrap_err()
         );
     }
@@ -1377,6 +5102,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1387,11 +5116,12 @@ mod tests {
                 Instruction::Char('b'),
                 Instruction::Halt,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("a");
+        let result = vm.run("a");
 
         assert!(result.is_ok());
         assert_eq!(1, vm.cursor);
@@ -1408,6 +5138,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1418,11 +5152,12 @@ mod tests {
                 Instruction::Char('b'),
                 Instruction::Halt,
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("b");
+        let result = vm.run("b");
 
         assert!(result.is_ok());
         assert_eq!(1, vm.cursor);
@@ -1439,6 +5174,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1447,11 +5186,12 @@ mod tests {
                 Instruction::Char('a'),
                 Instruction::CommitB(2),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("aab");
+        let result = vm.run("aab");
 
         assert!(result.is_ok());
         assert_eq!(2, vm.cursor);
@@ -1469,6 +5209,10 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
@@ -1477,11 +5221,12 @@ mod tests {
                 Instruction::Char('a'),
                 Instruction::CommitB(2),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("b");
+        let result = vm.run("b");
 
         assert!(result.is_ok());
         assert_eq!(0, vm.cursor);
@@ -1500,10 +5245,14 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
-                Instruction::Jump(11),
+                Instruction::Jump(10),
                 // G
                 Instruction::Call(4, 0),
                 Instruction::Char('+'),
@@ -1516,11 +5265,12 @@ mod tests {
                 Instruction::Char('1'),
                 Instruction::Return,
                 Instruction::Halt,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("1+1");
+        let result = vm.run("1+1");
 
         assert!(result.is_ok());
         assert_eq!(3, vm.cursor);
@@ -1539,10 +5289,14 @@ mod tests {
             identifiers: HashMap::new(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
-                Instruction::Jump(11),
+                Instruction::Jump(10),
                 // G
                 Instruction::Call(4, 0),
                 Instruction::Char('+'),
@@ -1555,15 +5309,19 @@ mod tests {
                 Instruction::Char('1'),
                 Instruction::Return,
                 Instruction::Halt,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("1+2");
+        let result = vm.run("1+2");
 
         assert!(result.is_err());
         assert_eq!(
-            Error::Matching(2, "syntax error, expecting: '0', '1'".to_string()),
+            Error::Matching(
+                Position::new(2, 0, 3),
+                "syntax error, expecting: '0', '1'".to_string()
+            ),
             result.unwrap_err()
         );
     }
@@ -1577,6 +5335,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["E".to_string()],
             code: vec![
                 Instruction::Call(2, 1),
@@ -1588,11 +5350,12 @@ mod tests {
                 Instruction::Commit(2),
                 Instruction::Char('n'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("321");
+        let result = vm.run("321");
 
         assert!(result.is_err());
         // assert!(vm.cursor.is_err());
@@ -1609,6 +5372,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["E".to_string()],
             code: vec![
                 Instruction::Call(2, 1),
@@ -1620,11 +5387,12 @@ mod tests {
                 Instruction::Commit(2),
                 Instruction::Char('n'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("n+n+n");
+        let result = vm.run("n+n+n");
 
         assert!(result.is_ok());
         assert_eq!(5, vm.cursor);
@@ -1641,6 +5409,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["E".to_string(), "D".to_string()],
             code: vec![
                 Instruction::Call(2, 1),
@@ -1660,11 +5432,12 @@ mod tests {
                 Instruction::Commit(2),
                 Instruction::Char('1'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("0+1");
+        let result = vm.run("0+1");
 
         assert!(result.is_ok());
         assert_eq!(3, vm.cursor);
@@ -1682,6 +5455,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["E".to_string(), "D".to_string()],
             code: vec![
                 Instruction::Call(2, 1),
@@ -1707,21 +5484,67 @@ mod tests {
                 Instruction::Commit(2),
                 Instruction::Char('1'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("0+1*1");
+        let result = vm.run("0+1*1");
 
         assert!(result.is_ok());
         assert_eq!(5, vm.cursor);
     }
 
+    #[test]
+    fn lrvar_longest_match() {
+        // E <- E:1 '+' E:2 / '1'+
+        let program = Program {
+            identifiers: [(2, 0)].into_iter().collect(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["E".to_string()],
+            code: vec![
+                Instruction::Call(2, 1),
+                Instruction::Halt,
+                // E:1 '+' E:2
+                Instruction::Choice(5),
+                Instruction::CallB(1, 1),
+                Instruction::Char('+'),
+                Instruction::CallB(3, 2),
+                Instruction::Commit(6),
+                // '1'+, which commits the values of each iteration
+                Instruction::Char('1'),
+                Instruction::Choice(4),
+                Instruction::Char('1'),
+                Instruction::CapCommit,
+                Instruction::PartialCommit(2),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+        // the last attempt to grow the match fails to consume more
+        // input, and the values it committed used to be kept instead
+        // of the ones of the longest match, e.g.: `E[E[1]1]` for `1`
+        for (input, expected) in [
+            ("1", "E[1]"),
+            ("1+1", "E[E[1]+E[1]]"),
+            ("11+1", "E[E[11]+E[1]]"),
+        ] {
+            let mut vm = VM::new(&program);
+            let result = vm.run(input).unwrap().unwrap();
+            assert_eq!(expected, langlang_value::format::compact(&result));
+        }
+    }
+
     #[test]
     fn throw_1() {
         let identifiers = [(2, 0)].iter().cloned().collect();
-        let labels = [(1, 1)].iter().cloned().collect();
-        let strings = vec!["G".to_string(), "Not really b".to_string()];
+        let labels = [(1, 2)].iter().cloned().collect();
+        let strings = vec!["G".to_string(), "l".to_string(), "Not really b".to_string()];
 
         // G <- 'a' 'b'^l / 'c'
         let program = Program {
@@ -1729,6 +5552,10 @@ mod tests {
             labels,
             strings,
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
@@ -1742,14 +5569,19 @@ mod tests {
                 Instruction::Commit(2),
                 Instruction::Char('c'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
         let mut vm = VM::new(&program);
-        let result = vm.run_str("axyz");
+        let result = vm.run("axyz");
 
         assert!(result.is_err());
         assert_eq!(
-            Error::Matching(1, "Not really b".to_string()),
+            Error::Label(
+                "l".to_string(),
+                Some("Not really b".to_string()),
+                Position::new(1, 0, 2)
+            ),
             result.unwrap_err()
         );
     }
@@ -1760,17 +5592,22 @@ mod tests {
             identifiers: [(2, 0)].iter().cloned().collect(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string(), "abacate".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::String(1),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("abacate");
+        let result = vm.run("abacate");
 
         assert_eq!(7, vm.cursor);
         assert!(result.is_ok());
@@ -1779,8 +5616,9 @@ mod tests {
 
         let s = Span::new(Position::new(0, 0, 0), Position::new(7, 0, 7));
         assert_eq!(
-            value::Node::new_val(
+            value::Node::new_val_with_rule(
                 s.clone(),
+                RuleId(0),
                 "G".to_string(),
                 vec![value::String::new_val(s, "abacate".to_string())],
             ),
@@ -1794,21 +5632,29 @@ mod tests {
             identifiers: [(2, 0)].iter().cloned().collect(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string(), "abacate".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::String(1),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("abacaxi");
+        let result = vm.run("abacaxi");
 
         assert!(result.is_err());
         assert_eq!(
-            Error::Matching(5, "syntax error, expecting: 'abacate'".to_string()),
+            Error::Matching(
+                Position::new(5, 0, 6),
+                "syntax error, expecting: 'abacate'".to_string()
+            ),
             result.unwrap_err(),
         );
     }
@@ -1819,17 +5665,22 @@ mod tests {
             identifiers: [(2, 0)].iter().cloned().collect(),
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string(), "abacate".to_string()],
             code: vec![
                 Instruction::Call(2, 0),
                 Instruction::Halt,
                 Instruction::String(1),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("a");
+        let result = vm.run("a");
 
         assert!(result.is_err());
         assert_eq!(Error::EOF, result.unwrap_err());
@@ -1844,6 +5695,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string()],
             code: vec![
                 // Call to first production follwed by the end of the matching
@@ -1865,11 +5720,12 @@ mod tests {
                 Instruction::Char('d'),
                 Instruction::Char('a'),
                 Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("abada");
+        let result = vm.run("abada");
 
         assert_eq!(5, vm.cursor);
 
@@ -1900,6 +5756,10 @@ mod tests {
             identifiers,
             labels: HashMap::new(),
             recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
             strings: vec!["G".to_string(), "D".to_string()],
             code: vec![
                 /* 00 */ Instruction::Call(2, 0),
@@ -1913,11 +5773,12 @@ mod tests {
                 /* 06 */ Instruction::Commit(2),
                 /* 07 */ Instruction::Char('1'),
                 /* 08 */ Instruction::Return,
-            ],
+            ]
+            .into(),
         };
 
         let mut vm = VM::new(&program);
-        let result = vm.run_str("1");
+        let result = vm.run("1");
 
         assert_eq!(1, vm.cursor);
 
@@ -1935,4 +5796,434 @@ mod tests {
         //     r.unwrap(),
         // );
     }
+
+    #[test]
+    fn validate_ok() {
+        // G <- 'a' / 'b'
+        let program = Program {
+            identifiers: [(2, 0)].iter().cloned().collect(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                Instruction::Choice(3),
+                Instruction::Char('a'),
+                Instruction::Commit(2),
+                Instruction::Char('b'),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+        assert_eq!(Ok(()), program.validate());
+    }
+
+    #[test]
+    fn validate_out_of_bounds() {
+        let program = Program {
+            identifiers: HashMap::new(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                Instruction::Choice(30),
+                Instruction::Char('a'),
+                Instruction::Commit(2),
+                Instruction::String(7),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+        assert_eq!(
+            Err(Error::InvalidProgram(
+                2,
+                "target address out of bounds".to_string()
+            )),
+            program.validate()
+        );
+    }
+
+    #[test]
+    fn validate_commit_without_choice() {
+        let program = Program {
+            identifiers: HashMap::new(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                Instruction::Char('a'),
+                Instruction::Commit(1),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+        assert_eq!(
+            Err(Error::InvalidProgram(
+                3,
+                "commit without a matching choice".to_string()
+            )),
+            program.validate()
+        );
+    }
+
+    #[test]
+    fn validate_runs_past_the_end() {
+        let program = Program {
+            identifiers: HashMap::new(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![Instruction::Call(2, 0), Instruction::Halt, Instruction::Any].into(),
+        };
+        assert_eq!(
+            Err(Error::InvalidProgram(
+                3,
+                "execution runs past the end of the program".to_string()
+            )),
+            program.validate()
+        );
+    }
+
+    #[test]
+    fn validate_fuzz_mutations() {
+        // G <- D '+' D
+        // D <- '0' / '1'
+        let base = vec![
+            Instruction::Call(2, 0),
+            Instruction::Halt,
+            Instruction::Call(4, 0),
+            Instruction::Char('+'),
+            Instruction::Call(2, 0),
+            Instruction::Return,
+            Instruction::Choice(3),
+            Instruction::Char('0'),
+            Instruction::Commit(2),
+            Instruction::Char('1'),
+            Instruction::Return,
+        ];
+        let mut rng = XorShift::new(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let mut code = base.clone();
+            for _ in 0..1 + rng.below(3) {
+                let pc = rng.below(code.len());
+                let operand = rng.below(code.len() + 4);
+                code[pc] = match rng.below(15) {
+                    0 => Instruction::Choice(operand),
+                    1 => Instruction::ChoiceP(operand),
+                    2 => Instruction::Commit(operand),
+                    3 => Instruction::CommitB(operand),
+                    4 => Instruction::PartialCommit(operand),
+                    5 => Instruction::BackCommit(operand),
+                    6 => Instruction::Jump(operand),
+                    7 => Instruction::Call(operand, 0),
+                    8 => Instruction::CallB(operand, 0),
+                    9 => Instruction::String(operand),
+                    10 => Instruction::FailTwice,
+                    11 => Instruction::JumpB(operand),
+                    12 => Instruction::TestChar('0', operand),
+                    13 => Instruction::TestAny(operand),
+                    _ => Instruction::Close(ContainerType::List),
+                };
+            }
+            let program = Program {
+                identifiers: HashMap::new(),
+                labels: HashMap::new(),
+                recovery: HashMap::new(),
+                unnamed: UnnamedProductions::default(),
+                externals: HashMap::new(),
+                classes: vec![],
+                version: None,
+                strings: vec!["G".to_string()],
+                code: code.into(),
+            };
+            // must never panic, regardless of the outcome
+            let _ = program.validate();
+        }
+    }
+
+    fn unnamed_program(unnamed: UnnamedProductions) -> Program {
+        // G <- D
+        // D <- '1' (without an entry in the identifiers table)
+        Program {
+            identifiers: [(2, 0)].iter().cloned().collect(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed,
+            externals: HashMap::new(),
+            classes: vec![],
+            version: None,
+            strings: vec!["G".to_string()],
+            code: vec![
+                Instruction::Call(2, 0),
+                Instruction::Halt,
+                // G
+                Instruction::Call(2, 0),
+                Instruction::Return,
+                // D
+                Instruction::Char('1'),
+                Instruction::Return,
+            ]
+            .into(),
+        }
+    }
+
+    #[test]
+    fn unnamed_production_synthetic_name() {
+        let program = unnamed_program(UnnamedProductions::SyntheticName);
+        let result = VM::new(&program).run("1").unwrap().unwrap();
+        assert_eq!("G[<anon@4>[1]]", langlang_value::format::compact(&result));
+    }
+
+    #[test]
+    fn unnamed_production_unwrap() {
+        let program = unnamed_program(UnnamedProductions::Unwrap);
+        assert_eq!(UnnamedProductions::Unwrap, program.unnamed_productions());
+        let result = VM::new(&program).run("1").unwrap().unwrap();
+        assert_eq!("G[1]", langlang_value::format::compact(&result));
+    }
+
+    #[test]
+    fn test_instructions_skip_alternatives() {
+        // G <- 'a' / . 'b' / 'c', with and without testing the head
+        // of the first two alternatives
+        let program = |tested: bool| {
+            let mut code = vec![Instruction::Call(2, 0), Instruction::Halt];
+            if tested {
+                code.push(Instruction::TestChar('a', 4));
+            }
+            code.extend([
+                Instruction::Choice(3),
+                Instruction::Char('a'),
+                Instruction::Commit(if tested { 7 } else { 6 }),
+            ]);
+            if tested {
+                code.push(Instruction::TestAny(5));
+            }
+            code.extend([
+                Instruction::Choice(4),
+                Instruction::Any,
+                Instruction::Char('b'),
+                Instruction::Commit(2),
+                Instruction::Char('c'),
+                Instruction::Return,
+            ]);
+            Program {
+                identifiers: [(2, 0)].iter().cloned().collect(),
+                labels: HashMap::new(),
+                recovery: HashMap::new(),
+                unnamed: UnnamedProductions::default(),
+                externals: HashMap::new(),
+                classes: vec![],
+                version: None,
+                strings: vec!["G".to_string()],
+                code: code.into(),
+            }
+        };
+        let (plain, tested) = (program(false), program(true));
+        assert_eq!(Ok(()), tested.validate());
+        let config = Config::default().with_stats();
+        for input in ["a", "xb", "c", "xc", ""] {
+            let run = |p: &Program| VM::new_with_config(p, config.clone()).match_str(input);
+            let (p, t) = (run(&plain), run(&tested));
+            assert_eq!(
+                format!("{:?}", p.as_ref().map(|m| &m.value)),
+                format!("{:?}", t.as_ref().map(|m| &m.value)),
+                "{}",
+                input
+            );
+            if let (Ok(p), Ok(t)) = (p, t) {
+                let (p, t) = (p.stats.unwrap(), t.stats.unwrap());
+                assert!(t.backtracks <= p.backtracks, "{}", input);
+            }
+        }
+        // the first alternative is skipped without saving the state
+        let m = VM::new_with_config(&tested, config)
+            .match_str("xb")
+            .unwrap();
+        assert_eq!(0, m.stats.unwrap().backtracks);
+    }
+
+    #[test]
+    fn metadata_describes_every_instruction() {
+        let all = vec![
+            Instruction::Halt,
+            Instruction::Any,
+            Instruction::Char('a'),
+            Instruction::Span('a', 'z'),
+            Instruction::String(0),
+            Instruction::Class(0),
+            Instruction::CharCI('a'),
+            Instruction::SpanCI('a', 'z'),
+            Instruction::StringCI(0),
+            Instruction::Int(2, Endian::Big),
+            Instruction::Varint,
+            Instruction::Take(0),
+            Instruction::Host(0),
+            Instruction::Choice(1),
+            Instruction::ChoiceP(1),
+            Instruction::Commit(1),
+            Instruction::CommitB(1),
+            Instruction::Fail,
+            Instruction::FailTwice,
+            Instruction::PartialCommit(1),
+            Instruction::BackCommit(1),
+            Instruction::Jump(1),
+            Instruction::JumpB(1),
+            Instruction::Call(1, 0),
+            Instruction::CallB(1, 0),
+            Instruction::Return,
+            Instruction::ReturnValues,
+            Instruction::Throw(0),
+            Instruction::Open,
+            Instruction::Close(ContainerType::Node),
+            Instruction::CapPush,
+            Instruction::CapPop,
+            Instruction::CapCommit,
+            Instruction::CapJoin,
+            Instruction::CapText,
+            Instruction::CapList,
+            Instruction::CapDrop,
+            Instruction::Property(0),
+            Instruction::TestChar('a', 1),
+            Instruction::TestAny(1),
+        ];
+        assert_eq!(INSTRUCTION_SET.len(), all.len());
+        for (opcode, instruction) in all.iter().enumerate() {
+            let metadata = instruction.metadata();
+            assert!(std::ptr::eq(&INSTRUCTION_SET[opcode], metadata));
+            let operands = instruction.operands();
+            assert_eq!(
+                metadata.operands.len(),
+                operands.len(),
+                "{}",
+                metadata.mnemonic
+            );
+            for (info, operand) in metadata.operands.iter().zip(&operands) {
+                let expected = match info.kind {
+                    OperandKind::Char => matches!(operand, Operand::Char(_)),
+                    OperandKind::Endian => matches!(operand, Operand::Endian(_)),
+                    OperandKind::Container => matches!(operand, Operand::Container(_)),
+                    _ => matches!(operand, Operand::Index(_)),
+                };
+                assert!(expected, "{} {}", metadata.mnemonic, info.name);
+            }
+        }
+        let reference = instruction_reference();
+        assert!(reference.contains("\n### `call offset precedence`\n\nOpcode 23."));
+    }
+
+    #[test]
+    fn disassemble_operands() {
+        let program = Program {
+            identifiers: [(3, 0)].iter().cloned().collect(),
+            labels: HashMap::new(),
+            recovery: HashMap::new(),
+            unnamed: UnnamedProductions::default(),
+            externals: HashMap::new(),
+            classes: vec![vec![('a', 'z'), ('_', '_')]],
+            version: None,
+            strings: vec!["G".to_string(), "x".to_string()],
+            code: vec![
+                Instruction::Call(3, 0),
+                Instruction::Halt,
+                Instruction::Int(2, Endian::Big),
+                Instruction::Class(0),
+                Instruction::String(1),
+                Instruction::Close(ContainerType::List),
+                Instruction::Return,
+            ]
+            .into(),
+        };
+        let code = program
+            .code
+            .iter()
+            .enumerate()
+            .map(|(pc, i)| instruction_to_string(&program, &i, pc))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "call \"G\" 0",
+                "halt",
+                "int 2 Big",
+                "class [a-z_]",
+                "string \"x\"",
+                "close List",
+                "return",
+            ],
+            code
+        );
+        assert_eq!("string 1", Instruction::String(1).to_string());
+    }
+
+    #[test]
+    fn input_positions() {
+        let input = Input::from_str("ab\nc");
+        assert_eq!(4, input.len());
+        assert_eq!(Some('c'), input.char_at(3));
+        assert_eq!(None, input.char_at(4));
+        assert_eq!(Position::new(1, 0, 2), input.span_at(1).start);
+        assert_eq!(Position::new(2, 0, 3), input.span_at(2).start);
+        assert_eq!(Position::new(3, 1, 1), input.span_at(3).start);
+        assert_eq!(Position::new(4, 1, 2), input.span_at(3).end);
+    }
+
+    // reads at most 3 bytes at a time, splitting chars between reads
+    struct Trickle(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn stream_positions_and_discarding() {
+        let text = "añb\nçd\né";
+        let mut stream = Stream::new(Box::new(Trickle(std::io::Cursor::new(text.into()))));
+        assert_eq!(Some('ñ'), stream.char_at(1));
+        assert_eq!(Some('é'), stream.char_at(7));
+        assert_eq!(None, stream.char_at(8));
+        assert_eq!(None, stream.error);
+        assert_eq!(Position::new(4, 1, 1), stream.position(4));
+
+        stream.discard(5);
+        assert_eq!(None, stream.char_at(4));
+        assert_eq!(Some('d'), stream.char_at(5));
+        assert_eq!(Position::new(5, 1, 2), stream.position(5));
+        assert_eq!(Position::new(7, 2, 1), stream.position(7));
+        assert_eq!(3, stream.chars.len());
+    }
+
+    #[test]
+    fn stream_invalid_utf8() {
+        let mut stream = Stream::new(Box::new(std::io::Cursor::new(vec![b'a', 0xff])));
+        assert_eq!(Some('a'), stream.char_at(0));
+        assert_eq!(None, stream.char_at(1));
+        assert_eq!(
+            Some("stream did not contain valid UTF-8 at offset 1".to_string()),
+            stream.error
+        );
+    }
 }
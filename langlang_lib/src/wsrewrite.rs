@@ -1,131 +1,168 @@
-use std::collections::HashMap;
-
-use crate::consts::WHITE_SPACE_RULE_NAME;
+use crate::consts::{COMMENT_RULE_NAME, WHITE_SPACE_RULE_NAME};
 
 use langlang_syntax::ast;
 use langlang_syntax::ast::IsSyntactic;
+use langlang_syntax::fold::{self, Folder};
+use langlang_syntax::stack;
 use langlang_value::source_map::Span;
 
-#[derive(Default)]
 pub(crate) struct WhiteSpaceHandlerInjector {
     // depth of use of the lex ('#') operator
     lex_level: usize,
+    // false for the first item of the choice being folded, which
+    // doesn't need a call to the white space handler because the
+    // choice already emitted one before its alternatives
+    consume_first: bool,
+}
+
+impl Default for WhiteSpaceHandlerInjector {
+    fn default() -> Self {
+        Self {
+            lex_level: 0,
+            consume_first: true,
+        }
+    }
 }
 
 impl WhiteSpaceHandlerInjector {
     pub(crate) fn run(&mut self, grammar: &ast::Grammar) -> ast::Grammar {
-        let mut definitions = HashMap::new();
-        let mut definition_names = Vec::new();
-
-        for name in &grammar.definition_names {
-            let d = &grammar.definitions[name];
-            definition_names.push(name.clone());
+        self.fold_grammar(grammar)
+    }
 
-            if name == WHITE_SPACE_RULE_NAME {
-                definitions.insert(name.clone(), d.clone());
-                continue;
+    fn fold_sequence_items(
+        &mut self,
+        node: &ast::Sequence,
+        consume_first: bool,
+    ) -> ast::Expression {
+        let should_consume_spaces = self.lex_level == 0 && !node.is_lexical();
+        let mut items: Vec<ast::Expression> = vec![];
+        for (i, item) in node.items.iter().enumerate() {
+            let skip_first = !consume_first && i == 0;
+            if should_consume_spaces && !skip_first {
+                match item {
+                    // host functions don't consume input, so they
+                    // get to see the cursor right after the item
+                    // before them
+                    ast::Expression::Lex(_) | ast::Expression::Literal(ast::Literal::Host(_)) => {}
+                    _ => items.push(mkwscall(&node.span)),
+                }
             }
+            items.push(self.fold_expression(item));
+        }
+        ast::Sequence::new_expr(node.span.clone(), items)
+    }
+}
 
-            definitions.insert(
-                name.to_owned(),
-                ast::Definition::new(
-                    d.span.clone(),
-                    d.name.clone(),
-                    self.expand_expr(&d.expr, true),
-                ),
-            );
+impl Folder for WhiteSpaceHandlerInjector {
+    fn fold_definition(&mut self, d: &ast::Definition) -> ast::Definition {
+        if d.name == WHITE_SPACE_RULE_NAME {
+            return d.clone();
         }
+        fold::walk_definition(self, d)
+    }
 
-        ast::Grammar::new(
-            grammar.span.clone(),
-            grammar.imports.to_vec(),
-            definition_names,
-            definitions,
-        )
+    fn fold_expression(&mut self, expr: &ast::Expression) -> ast::Expression {
+        // the flag only applies to the expression being folded, its
+        // sub-expressions consume white space before their first item
+        let consume_first = std::mem::replace(&mut self.consume_first, true);
+        stack::guard(|| match expr {
+            ast::Expression::Sequence(node) => self.fold_sequence_items(node, consume_first),
+            _ => fold::walk_expression(self, expr),
+        })
     }
 
-    fn expand_expr(&mut self, expr: &ast::Expression, consume_first: bool) -> ast::Expression {
-        match expr {
-            ast::Expression::Lex(node) => {
-                self.lex_level += 1;
-                let expr = self.expand_expr(&node.expr, true);
-                self.lex_level -= 1;
-                ast::Lex::new_expr(node.span.clone(), Box::new(expr))
-            }
-            ast::Expression::Sequence(node) => {
-                let should_consume_spaces = self.lex_level == 0 && !node.is_lexical();
-                let mut items: Vec<ast::Expression> = vec![];
-                for (i, item) in node.items.iter().enumerate() {
-                    let skip_first = !consume_first && i == 0;
-                    if should_consume_spaces && !skip_first {
-                        match item {
-                            ast::Expression::Lex(_) => {}
-                            _ => items.push(mkwscall(&node.span)),
-                        }
-                    }
-                    items.push(self.expand_expr(item, true));
-                }
-                ast::Sequence::new_expr(node.span.clone(), items)
-            }
-            ast::Expression::Choice(node) => {
-                if expr.is_lexical() {
-                    return ast::Choice::new_expr(
-                        node.span.clone(),
-                        node.items
-                            .iter()
-                            .map(|i| self.expand_expr(i, true))
-                            .collect(),
-                    );
-                }
-                ast::Sequence::new_expr(
-                    node.span.clone(),
-                    vec![
-                        mkwscall(&node.span),
-                        ast::Choice::new_expr(
-                            node.span.clone(),
-                            node.items
-                                .iter()
-                                .map(|i| self.expand_expr(i, false))
-                                .collect(),
-                        ),
-                    ],
-                )
-            }
-            ast::Expression::And(node) => ast::And::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            ast::Expression::Not(node) => ast::Not::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            ast::Expression::Optional(node) => ast::Optional::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            ast::Expression::ZeroOrMore(node) => ast::ZeroOrMore::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            ast::Expression::OneOrMore(node) => ast::OneOrMore::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            ast::Expression::Precedence(node) => ast::Precedence::new_expr(
-                node.span.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-                node.precedence,
-            ),
-            ast::Expression::Label(node) => ast::Label::new_expr(
-                node.span.clone(),
-                node.label.clone(),
-                Box::new(self.expand_expr(&node.expr, true)),
-            ),
-            _ => expr.clone(),
+    fn fold_lex(&mut self, node: &ast::Lex) -> ast::Expression {
+        self.lex_level += 1;
+        let expr = self.fold_expression(&node.expr);
+        self.lex_level -= 1;
+        ast::Lex::new_expr(node.span.clone(), Box::new(expr))
+    }
+
+    fn fold_choice(&mut self, node: &ast::Choice) -> ast::Expression {
+        if node.items.iter().all(|i| i.is_lexical()) {
+            let items = node.items.iter().map(|i| self.fold_expression(i));
+            return ast::Choice::new_expr(node.span.clone(), items.collect());
+        }
+        let mut items = vec![];
+        for item in &node.items {
+            self.consume_first = false;
+            items.push(self.fold_expression(item));
         }
+        ast::Sequence::new_expr(
+            node.span.clone(),
+            vec![
+                mkwscall(&node.span),
+                ast::Choice::new_expr(node.span.clone(), items),
+            ],
+        )
+    }
+
+    // structural matching operates on values, not on text, so there's
+    // no white space to be handled within lists and nodes
+    fn fold_list(&mut self, node: &ast::List) -> ast::Expression {
+        ast::Expression::List(node.clone())
+    }
+
+    fn fold_node(&mut self, node: &ast::Node) -> ast::Expression {
+        ast::Expression::Node(node.clone())
     }
 }
 
 fn mkwscall(span: &Span) -> ast::Expression {
     ast::Identifier::new_expr(span.clone(), WHITE_SPACE_RULE_NAME.to_string())
 }
+
+// Generate `Spacing <- (Space / Comment)*` and a `Comment` rule with
+// one lexified alternative per syntax declared with `@comments`.
+// Nothing is generated without comments, leaving `Spacing` to the
+// builtins.
+pub(crate) fn comment_definitions(comments: &[ast::Comment]) -> Vec<ast::Definition> {
+    if comments.is_empty() {
+        return vec![];
+    }
+    let id = |span: &Span, name: &str| ast::Identifier::new_expr(span.clone(), name.to_string());
+    let string = |span: &Span, value: &str| ast::String::new_expr(span.clone(), value.to_string());
+    let mut alternatives: Vec<_> = comments
+        .iter()
+        .map(|c| {
+            let span = &c.span;
+            let stop = match &c.close {
+                Some(close) => string(span, close),
+                None => id(span, "EOL"),
+            };
+            let body = ast::Sequence::new_expr(
+                span.clone(),
+                vec![
+                    ast::Not::new_expr(span.clone(), Box::new(stop)),
+                    ast::Any::new_expr(span.clone()),
+                ],
+            );
+            let mut items = vec![
+                string(span, &c.open),
+                ast::ZeroOrMore::new_expr(span.clone(), Box::new(body)),
+            ];
+            if let Some(close) = &c.close {
+                items.push(string(span, close));
+            }
+            let seq = ast::Sequence::new_expr(span.clone(), items);
+            ast::Lex::new_expr(span.clone(), Box::new(seq))
+        })
+        .collect();
+    let span = comments[0].span.clone();
+    let comment = if alternatives.len() == 1 {
+        alternatives.remove(0)
+    } else {
+        ast::Choice::new_expr(span.clone(), alternatives)
+    };
+    let spacing = ast::ZeroOrMore::new_expr(
+        span.clone(),
+        Box::new(ast::Choice::new_expr(
+            span.clone(),
+            vec![id(&span, "Space"), id(&span, COMMENT_RULE_NAME)],
+        )),
+    );
+    vec![
+        ast::Definition::new(span.clone(), WHITE_SPACE_RULE_NAME.to_string(), spacing),
+        ast::Definition::new(span.clone(), COMMENT_RULE_NAME.to_string(), comment),
+    ]
+}
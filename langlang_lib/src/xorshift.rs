@@ -0,0 +1,26 @@
+// xorshift.rs --- pseudo random numbers for the tests
+//
+// Generates the same numbers on every run out of the same seed, so
+// the tests that build random cases are deterministic without
+// pulling a dependency.
+//
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    /// `seed` must not be zero, or every number generated will be
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Number within `0..n`
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
@@ -4,11 +4,30 @@ use std::string::{String as StdString, ToString};
 
 use langlang_value::source_map::Span;
 
+use crate::stack;
+
+pub use crate::fold::Folder;
+pub use crate::visitor::Visitor;
+
+/// Name of the rule the compiler calls between the items of
+/// sequences that aren't lexical, which grammars can define with the
+/// `%whitespace` directive
+pub const WHITE_SPACE_RULE_NAME: &str = "Spacing";
+
 /// Grammar is the top-level AST node for the input grammar language.
 #[derive(Debug)]
 pub struct Grammar {
     pub span: Span,
+    // Version of the grammar declared with `@version`, so the trees
+    // it produced can be told apart from the ones of other versions
+    pub version: Option<StdString>,
     pub imports: Vec<Import>,
+    // Comment syntax declared with `@comments`, used to generate the
+    // `Spacing` rule when the grammar doesn't write its own.
+    pub comments: Vec<Comment>,
+    // Messages of the labels declared with `label name = "message"`,
+    // reported when the labels are thrown
+    pub labels: Vec<LabelMessage>,
     pub definition_names: Vec<StdString>,
     pub definitions: HashMap<StdString, Definition>,
 }
@@ -22,23 +41,49 @@ impl Grammar {
     ) -> Self {
         Self {
             span,
+            version: None,
             imports,
+            comments: vec![],
+            labels: vec![],
             definition_names,
             definitions,
         }
     }
 
+    /// Set the version declared with `@version`
+    pub fn with_version(self, version: Option<StdString>) -> Self {
+        Self { version, ..self }
+    }
+
+    /// Version declared with `@version`, if there's one
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Set the comment syntax declared with `@comments`
+    pub fn with_comments(self, comments: Vec<Comment>) -> Self {
+        Self { comments, ..self }
+    }
+
+    /// Set the messages of the labels declared within the grammar
+    pub fn with_labels(self, labels: Vec<LabelMessage>) -> Self {
+        Self { labels, ..self }
+    }
+
     pub fn add_definition(&mut self, d: &Definition) {
-        if self.definitions.get(&d.name).is_none() {
+        if !self.definitions.contains_key(&d.name) {
             self.definition_names.push(d.name.clone());
             self.definitions.insert(d.name.clone(), d.clone());
         }
     }
 }
 
-impl ToString for Grammar {
-    fn to_string(&self) -> StdString {
+impl std::fmt::Display for Grammar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let mut output = StdString::new();
+        if let Some(version) = &self.version {
+            output.push_str(&format!("@version \"{}\"\n\n", version.escape_default()));
+        }
         for i in &self.imports {
             output.push_str(&i.to_string());
             output.push('\n');
@@ -46,12 +91,23 @@ impl ToString for Grammar {
         if !self.imports.is_empty() {
             output.push('\n');
         }
+        if !self.comments.is_empty() {
+            let comments: Vec<_> = self.comments.iter().map(|c| c.to_string()).collect();
+            output.push_str(&format!("@comments {}\n\n", comments.join(", ")));
+        }
+        for l in &self.labels {
+            output.push_str(&l.to_string());
+            output.push('\n');
+        }
+        if !self.labels.is_empty() {
+            output.push('\n');
+        }
         for name in &self.definition_names {
             let d = &self.definitions[name];
             output.push_str(&d.to_string());
             output.push('\n');
         }
-        output
+        write!(f, "{}", output)
     }
 }
 
@@ -61,12 +117,18 @@ impl ToString for Grammar {
 pub struct Import {
     pub span: Span,
     pub path: StdString,
+    // Empty when all the rules of the module are imported, with
+    // `@import "path"`
     pub names: Vec<StdString>,
 }
 
-impl ToString for Import {
-    fn to_string(&self) -> StdString {
-        format!(
+impl std::fmt::Display for Import {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.names.is_empty() {
+            return write!(f, "@import \"{}\"", self.path);
+        }
+        write!(
+            f,
             "@import {} from \"{}\"",
             fmtlistsep(", ", &self.names),
             self.path
@@ -80,6 +142,76 @@ impl Import {
     }
 }
 
+/// LabelMessage is the message declared for a label with
+/// `label name = "message"`, optionally followed by the expression
+/// that recovers from the label, e.g.: `recover (!';' .)* ';'`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelMessage {
+    pub span: Span,
+    pub name: StdString,
+    pub message: StdString,
+    // Expression called when the label is thrown, the same way a
+    // rule named after the label would be
+    pub recover: Option<Expression>,
+}
+
+impl LabelMessage {
+    pub fn new(span: Span, name: StdString, message: StdString) -> Self {
+        Self {
+            span,
+            name,
+            message,
+            recover: None,
+        }
+    }
+
+    /// Set the expression that recovers from the label
+    pub fn with_recover(self, recover: Option<Expression>) -> Self {
+        Self { recover, ..self }
+    }
+}
+
+impl std::fmt::Display for LabelMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "label {} = \"{}\"",
+            self.name,
+            self.message.escape_default()
+        )?;
+        if let Some(recover) = &self.recover {
+            write!(f, " recover {}", recover)?;
+        }
+        Ok(())
+    }
+}
+
+/// Comment represents one of the comment syntaxes declared with the
+/// `@comments` directive.  Comments without a `close` delimiter end
+/// at the end of the line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub span: Span,
+    pub open: StdString,
+    pub close: Option<StdString>,
+}
+
+impl Comment {
+    pub fn new(span: Span, open: StdString, close: Option<StdString>) -> Self {
+        Self { span, open, close }
+    }
+}
+
+impl std::fmt::Display for Comment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.open.escape_default())?;
+        match &self.close {
+            Some(close) => write!(f, " \"{}\"", close.escape_default()),
+            None => write!(f, " eol"),
+        }
+    }
+}
+
 /// Definition represents a single production definition.  It stores
 /// both the name and the expression associated with the production.
 #[derive(Clone, Debug)]
@@ -87,11 +219,43 @@ pub struct Definition {
     pub span: Span,
     pub name: StdString,
     pub expr: Expression,
+    // Input matched by this expression ends the recovery of labels
+    // thrown within the definition.  Set with `@sync(...)`.
+    pub sync: Option<Expression>,
+    // Names of the lints that aren't reported for this definition.
+    // Set with `@allow(...)`.
+    pub allow: Vec<StdString>,
+    // How the values captured within the definition are folded into
+    // what it produces.  Set with `@capture(...)`.
+    pub capture: CaptureMode,
 }
 
 impl Definition {
     pub fn new(span: Span, name: StdString, expr: Expression) -> Self {
-        Self { span, name, expr }
+        Self {
+            span,
+            name,
+            expr,
+            sync: None,
+            allow: vec![],
+            capture: CaptureMode::Node,
+        }
+    }
+
+    pub fn new_with_sync(
+        span: Span,
+        name: StdString,
+        expr: Expression,
+        sync: Option<Expression>,
+    ) -> Self {
+        Self {
+            span,
+            name,
+            expr,
+            sync,
+            allow: vec![],
+            capture: CaptureMode::Node,
+        }
     }
 }
 
@@ -105,9 +269,57 @@ impl IsSyntactic for Definition {
     }
 }
 
-impl ToString for Definition {
-    fn to_string(&self) -> StdString {
-        format!("{} <- {}", self.name, self.expr.to_string())
+impl std::fmt::Display for Definition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.allow.is_empty() {
+            write!(f, "@allow({}) ", self.allow.join(", "))?;
+        }
+        if let Some(sync) = &self.sync {
+            write!(f, "@sync({}) ", sync)?;
+        }
+        if self.capture != CaptureMode::Node {
+            write!(f, "@capture({}) ", self.capture)?;
+        }
+        write!(f, "{} <- {}", self.name, self.expr)
+    }
+}
+
+/// CaptureMode tells how the values captured while matching a
+/// definition are aggregated into what the definition produces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Wrap the captured values in a node named after the definition
+    #[default]
+    Node,
+    /// Flatten the captured values into a single string
+    String,
+    /// Collect the captured values in a list
+    List,
+    /// Drop the captured values
+    Skip,
+}
+
+impl CaptureMode {
+    /// Returns the mode spelled as `name` within `@capture(...)`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "node" => Some(CaptureMode::Node),
+            "string" => Some(CaptureMode::String),
+            "list" => Some(CaptureMode::List),
+            "skip" => Some(CaptureMode::Skip),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CaptureMode::Node => write!(f, "node"),
+            CaptureMode::String => write!(f, "string"),
+            CaptureMode::List => write!(f, "list"),
+            CaptureMode::Skip => write!(f, "skip"),
+        }
     }
 }
 
@@ -144,7 +356,7 @@ fn is_lexical_list<T: IsSyntactic>(items: &[T]) -> bool {
         .unwrap_or(false)
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum Expression {
     Sequence(Sequence),
     Choice(Choice),
@@ -154,6 +366,7 @@ pub enum Expression {
     Optional(Optional),
     ZeroOrMore(ZeroOrMore),
     OneOrMore(OneOrMore),
+    Repeat(Repeat),
     Precedence(Precedence),
     Label(Label),
     List(List),
@@ -163,9 +376,97 @@ pub enum Expression {
     Empty(Empty),
 }
 
+impl Expression {
+    /// Location of the expression within the grammar source
+    pub fn span(&self) -> Span {
+        match self {
+            Expression::Sequence(v) => v.span.clone(),
+            Expression::Choice(v) => v.span.clone(),
+            Expression::Lex(v) => v.span.clone(),
+            Expression::And(v) => v.span.clone(),
+            Expression::Not(v) => v.span.clone(),
+            Expression::Optional(v) => v.span.clone(),
+            Expression::ZeroOrMore(v) => v.span.clone(),
+            Expression::OneOrMore(v) => v.span.clone(),
+            Expression::Repeat(v) => v.span.clone(),
+            Expression::Precedence(v) => v.span.clone(),
+            Expression::Label(v) => v.span.clone(),
+            Expression::List(v) => v.span.clone(),
+            Expression::Node(v) => v.span.clone(),
+            Expression::Identifier(v) => v.span.clone(),
+            Expression::Literal(Literal::String(v)) => v.span.clone(),
+            Expression::Literal(Literal::Class(v)) => v.span.clone(),
+            Expression::Literal(Literal::Range(v)) => v.span.clone(),
+            Expression::Literal(Literal::Char(v)) => v.span.clone(),
+            Expression::Literal(Literal::Any(v)) => v.span.clone(),
+            Expression::Literal(Literal::Int(v)) => v.span.clone(),
+            Expression::Literal(Literal::Take(v)) => v.span.clone(),
+            Expression::Literal(Literal::Host(v)) => v.span.clone(),
+            Expression::Literal(Literal::NamedClass(v)) => v.span.clone(),
+            Expression::Literal(Literal::Property(v)) => v.span.clone(),
+            Expression::Empty(v) => v.span.clone(),
+        }
+    }
+}
+
+// cloning recurses once per level of nesting of the expression
+impl Clone for Expression {
+    fn clone(&self) -> Self {
+        stack::guard(|| match self {
+            Expression::Sequence(v) => Expression::Sequence(v.clone()),
+            Expression::Choice(v) => Expression::Choice(v.clone()),
+            Expression::Lex(v) => Expression::Lex(v.clone()),
+            Expression::And(v) => Expression::And(v.clone()),
+            Expression::Not(v) => Expression::Not(v.clone()),
+            Expression::Optional(v) => Expression::Optional(v.clone()),
+            Expression::ZeroOrMore(v) => Expression::ZeroOrMore(v.clone()),
+            Expression::OneOrMore(v) => Expression::OneOrMore(v.clone()),
+            Expression::Repeat(v) => Expression::Repeat(v.clone()),
+            Expression::Precedence(v) => Expression::Precedence(v.clone()),
+            Expression::Label(v) => Expression::Label(v.clone()),
+            Expression::List(v) => Expression::List(v.clone()),
+            Expression::Node(v) => Expression::Node(v.clone()),
+            Expression::Identifier(v) => Expression::Identifier(v.clone()),
+            Expression::Literal(v) => Expression::Literal(v.clone()),
+            Expression::Empty(v) => Expression::Empty(v.clone()),
+        })
+    }
+}
+
+// Dropping an expression recurses once per level of nesting too, so
+// the nodes that hold sub-expressions drop them within a new stack
+// segment when the current one is running out
+macro_rules! drop_deep_expr {
+    ($($node:ty),*) => {
+        $(impl Drop for $node {
+            fn drop(&mut self) {
+                if stack::is_low() {
+                    let empty = Expression::Empty(Empty { span: Span::default() });
+                    stack::drop_deep(std::mem::replace(&mut *self.expr, empty));
+                }
+            }
+        })*
+    };
+}
+
+macro_rules! drop_deep_items {
+    ($($node:ty),*) => {
+        $(impl Drop for $node {
+            fn drop(&mut self) {
+                if stack::is_low() {
+                    stack::drop_deep(std::mem::take(&mut self.items));
+                }
+            }
+        })*
+    };
+}
+
+drop_deep_expr!(Lex, And, Not, Optional, ZeroOrMore, OneOrMore, Repeat, Precedence, Label, Node);
+drop_deep_items!(Sequence, Choice, List);
+
 impl IsSyntactic for Expression {
     fn is_syntactic(&self) -> bool {
-        match self {
+        stack::guard(|| match self {
             Expression::Choice(v) => is_syntactic_list(&v.items),
             Expression::Sequence(v) => v.is_syntactic(),
             Expression::Lex(v) => v.expr.is_syntactic(),
@@ -174,6 +475,7 @@ impl IsSyntactic for Expression {
             Expression::Optional(v) => v.expr.is_syntactic(),
             Expression::ZeroOrMore(v) => v.expr.is_syntactic(),
             Expression::OneOrMore(v) => v.expr.is_syntactic(),
+            Expression::Repeat(v) => v.expr.is_syntactic(),
             Expression::Precedence(v) => v.expr.is_syntactic(),
             Expression::Label(v) => v.expr.is_syntactic(),
             Expression::List(v) => is_syntactic_list(&v.items),
@@ -181,11 +483,11 @@ impl IsSyntactic for Expression {
             Expression::Identifier(_) => false,
             Expression::Literal(_) => true,
             Expression::Empty(_) => true,
-        }
+        })
     }
 
     fn is_lexical(&self) -> bool {
-        match self {
+        stack::guard(|| match self {
             Expression::Choice(v) => is_lexical_list(&v.items),
             Expression::Sequence(v) => v.is_lexical(),
             Expression::Lex(_) => true,
@@ -194,6 +496,7 @@ impl IsSyntactic for Expression {
             Expression::Optional(v) => v.expr.is_lexical(),
             Expression::ZeroOrMore(v) => v.expr.is_lexical(),
             Expression::OneOrMore(v) => v.expr.is_lexical(),
+            Expression::Repeat(v) => v.expr.is_lexical(),
             Expression::Precedence(v) => v.expr.is_lexical(),
             Expression::Label(v) => v.expr.is_lexical(),
             Expression::List(v) => is_lexical_list(&v.items),
@@ -201,29 +504,37 @@ impl IsSyntactic for Expression {
             Expression::Identifier(_) => false,
             Expression::Literal(_) => true,
             Expression::Empty(_) => true,
-        }
+        })
     }
 }
 
-impl ToString for Expression {
-    fn to_string(&self) -> StdString {
-        match self {
-            Expression::Choice(v) => format!("({})", fmtlistsep(" / ", &v.items)),
-            Expression::Sequence(v) => fmtlistsep(" ", &v.items),
-            Expression::Lex(v) => fmtprefix("#", &v.expr),
-            Expression::And(v) => fmtprefix("&", &v.expr),
-            Expression::Not(v) => fmtprefix("!", &v.expr),
-            Expression::Optional(v) => fmtsuffix("?", &v.expr),
-            Expression::ZeroOrMore(v) => fmtsuffix("*", &v.expr),
-            Expression::OneOrMore(v) => fmtsuffix("+", &v.expr),
-            Expression::Precedence(v) => format!("{}{}", v.expr.to_string(), v.precedence),
-            Expression::Label(v) => format!("{}^{}", v.expr.to_string(), v.label),
-            Expression::List(v) => format!("[{}]", fmtlistsep(", ", &v.items)),
-            Expression::Node(v) => format!("{} {{{}}}", v.name, v.expr.to_string()),
-            Expression::Identifier(v) => v.name.to_string(),
-            Expression::Literal(v) => v.to_string(),
-            Expression::Empty(_) => "".to_string(),
-        }
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        stack::guard(|| match self {
+            Expression::Choice(v) => write!(f, "({})", fmtlistsep(" / ", &v.items)),
+            Expression::Sequence(v) => write!(f, "{}", fmtlistsep(" ", &v.items)),
+            Expression::Lex(v) => write!(f, "{}", fmtprefix("#", &v.expr)),
+            Expression::And(v) => write!(f, "{}", fmtprefix("&", &v.expr)),
+            Expression::Not(v) => write!(f, "{}", fmtprefix("!", &v.expr)),
+            Expression::Optional(v) => write!(f, "{}", fmtsuffix("?", &v.expr)),
+            Expression::ZeroOrMore(v) => write!(f, "{}", fmtsuffix("*", &v.expr)),
+            Expression::OneOrMore(v) => write!(f, "{}", fmtsuffix("+", &v.expr)),
+            Expression::Repeat(v) => {
+                let counts = match v.max {
+                    Some(max) if max == v.min => format!("{{{}}}", v.min),
+                    Some(max) => format!("{{{},{}}}", v.min, max),
+                    None => format!("{{{},}}", v.min),
+                };
+                write!(f, "{}", fmtsuffix(&counts, &v.expr))
+            }
+            Expression::Precedence(v) => write!(f, "{}{}", v.expr, v.precedence),
+            Expression::Label(v) => write!(f, "{}^{}", v.expr, v.label),
+            Expression::List(v) => write!(f, "[{}]", fmtlistsep(", ", &v.items)),
+            Expression::Node(v) => write!(f, "{} {{{}}}", v.name, v.expr),
+            Expression::Identifier(v) => write!(f, "{}", v.name),
+            Expression::Literal(v) => write!(f, "{}", v),
+            Expression::Empty(_) => Ok(()),
+        })
     }
 }
 
@@ -349,6 +660,32 @@ impl OneOrMore {
     }
 }
 
+/// Expression matched at least `min` times and at most `max` times,
+/// or as many times as it matches when there's no `max`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Repeat {
+    pub span: Span,
+    pub expr: Box<Expression>,
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Repeat {
+    pub fn new_expr(
+        span: Span,
+        expr: Box<Expression>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Expression {
+        Expression::Repeat(Self {
+            span,
+            expr,
+            min,
+            max,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Precedence {
     pub span: Span,
@@ -427,16 +764,30 @@ pub enum Literal {
     Range(Range),
     Char(Char),
     Any(Any),
+    Int(Int),
+    Take(Take),
+    Host(Host),
+    NamedClass(NamedClass),
+    Property(Property),
 }
 
-impl ToString for Literal {
-    fn to_string(&self) -> StdString {
+impl std::fmt::Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Literal::String(v) => format!("\"{}\"", v.to_string()),
-            Literal::Class(v) => v.to_string(),
-            Literal::Range(v) => format!("{}-{}", v.start, v.end),
-            Literal::Char(v) => v.to_string(),
-            Literal::Any(_) => ".".to_string(),
+            Literal::String(v) if v.ignore_case => write!(f, "\"{}\"i", v),
+            Literal::String(v) => write!(f, "\"{}\"", v),
+            Literal::Class(v) => write!(f, "{}", v),
+            Literal::Range(v) if v.bytes => {
+                write!(f, "{}-{}", ByteChar(v.start), ByteChar(v.end))
+            }
+            Literal::Range(v) => write!(f, "{}-{}", v.start, v.end),
+            Literal::Char(v) => write!(f, "{}", v),
+            Literal::Any(_) => write!(f, "."),
+            Literal::Int(v) => write!(f, "%{}", v.kind.name()),
+            Literal::Take(v) => write!(f, "%take({})", v.name),
+            Literal::Host(v) => write!(f, "%host({})", v.name),
+            Literal::NamedClass(v) => write!(f, "%{}", v.name),
+            Literal::Property(v) => write!(f, "{}", v),
         }
     }
 }
@@ -445,20 +796,42 @@ impl ToString for Literal {
 pub struct String {
     pub span: Span,
     pub value: StdString,
+    // matches regardless of case, written with the `i` suffix
+    pub ignore_case: bool,
+    // written with `\xHH` escapes, which match bytes
+    pub bytes: bool,
 }
 
 impl String {
     pub fn new_expr(span: Span, value: StdString) -> Expression {
-        Expression::Literal(Literal::String(Self { span, value }))
+        Expression::Literal(Literal::String(Self::new(span, value, false)))
+    }
+
+    pub fn new(span: Span, value: StdString, ignore_case: bool) -> Self {
+        Self {
+            span,
+            value,
+            ignore_case,
+            bytes: false,
+        }
+    }
+
+    /// Mark the literal as written with `\xHH` escapes or not
+    pub fn with_bytes(self, bytes: bool) -> Self {
+        Self { bytes, ..self }
     }
 }
 
-impl ToString for String {
-    fn to_string(&self) -> StdString {
-        self.value
-            .chars()
-            .flat_map(|c| c.escape_default())
-            .collect()
+impl std::fmt::Display for String {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for c in self.value.chars() {
+            if self.bytes {
+                write!(f, "{}", ByteChar(c))?;
+            } else {
+                write!(f, "{}", c.escape_default())?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -466,23 +839,51 @@ impl ToString for String {
 pub struct Class {
     pub span: Span,
     pub literals: Vec<Literal>,
+    // matches regardless of case, written with the `i` suffix
+    pub ignore_case: bool,
+    // matches any character but the ones listed, written as `[^...]`
+    pub negated: bool,
 }
 
 impl Class {
     pub fn new_expr(span: Span, literals: Vec<Literal>) -> Expression {
-        Expression::Literal(Literal::Class(Self { span, literals }))
+        Expression::Literal(Literal::Class(Self::new(span, literals, false)))
+    }
+
+    pub fn new(span: Span, literals: Vec<Literal>, ignore_case: bool) -> Self {
+        Self {
+            span,
+            literals,
+            ignore_case,
+            negated: false,
+        }
+    }
+
+    /// Mark the class as matching the characters it doesn't list
+    pub fn with_negated(self, negated: bool) -> Self {
+        Self { negated, ..self }
     }
 }
 
-impl ToString for Class {
-    fn to_string(&self) -> StdString {
+impl std::fmt::Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let mut output = StdString::new();
         output.push('[');
+        if self.negated {
+            output.push('^');
+        }
         for l in &self.literals {
             output.push_str(&l.to_string());
         }
+        // a leading `^` would negate the class when parsed back
+        if !self.negated && output.starts_with("[^") {
+            output.insert(1, '\\');
+        }
         output.push(']');
-        output
+        if self.ignore_case {
+            output.push('i');
+        }
+        write!(f, "{}", output)
     }
 }
 
@@ -491,11 +892,23 @@ pub struct Range {
     pub span: Span,
     pub start: char,
     pub end: char,
+    // written with `\xHH` escapes, which match bytes
+    pub bytes: bool,
 }
 
 impl Range {
     pub fn new(span: Span, start: char, end: char) -> Self {
-        Self { span, start, end }
+        Self {
+            span,
+            start,
+            end,
+            bytes: false,
+        }
+    }
+
+    /// Mark the range as written with `\xHH` escapes or not
+    pub fn with_bytes(self, bytes: bool) -> Self {
+        Self { bytes, ..self }
     }
 }
 
@@ -504,17 +917,82 @@ impl Range {
 pub struct Char {
     pub span: Span,
     pub value: char,
+    // written as a `\xHH` escape, which matches a byte
+    pub bytes: bool,
 }
 
 impl Char {
     pub fn new(span: Span, value: char) -> Self {
-        Self { span, value }
+        Self {
+            span,
+            value,
+            bytes: false,
+        }
+    }
+
+    /// Mark the character as written with a `\xHH` escape or not
+    pub fn with_bytes(self, bytes: bool) -> Self {
+        Self { bytes, ..self }
+    }
+}
+
+impl std::fmt::Display for Char {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.bytes {
+            return write!(f, "{}", ByteChar(self.value));
+        }
+        write!(f, "{}", self.value.escape_default())
+    }
+}
+
+/// Property matches a character with the Unicode property `name`
+/// within a class, e.g.: `[\p{L}\p{Nd}_]`, or one without it when
+/// written as `\P{..}`.  The compiler resolves the name to a general
+/// category, a script or a binary property.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Property {
+    pub span: Span,
+    pub name: StdString,
+    // matches the characters without the property, written as `\P{..}`
+    pub negated: bool,
+}
+
+impl Property {
+    pub fn new(span: Span, name: StdString, negated: bool) -> Self {
+        Self {
+            span,
+            name,
+            negated,
+        }
+    }
+
+    /// Mark the item as matching the characters without the property
+    pub fn with_negated(self, negated: bool) -> Self {
+        Self { negated, ..self }
     }
 }
 
-impl ToString for Char {
-    fn to_string(&self) -> StdString {
-        self.value.escape_default().collect()
+impl std::fmt::Display for Property {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let p = if self.negated { 'P' } else { 'p' };
+        write!(f, "\\{}{{{}}}", p, self.name)
+    }
+}
+
+/// Display a character of a literal written with `\xHH` escapes,
+/// using the escape for the ones that aren't printable ASCII
+struct ByteChar(char);
+
+impl std::fmt::Display for ByteChar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            c if c.is_ascii_graphic() && !matches!(c, '\\' | '\'' | '"' | '[' | ']') => {
+                write!(f, "{}", c)
+            }
+            ' ' => write!(f, " "),
+            c if (c as u32) <= 0xff => write!(f, "\\x{:02x}", c as u32),
+            c => write!(f, "{}", c.escape_default()),
+        }
     }
 }
 
@@ -530,6 +1008,187 @@ impl Any {
     }
 }
 
+/// Int is a built-in terminal that matches the bytes of an unsigned
+/// integer, e.g.: `%u16le`, and captures its value in decimal
+#[derive(Clone, Debug, PartialEq)]
+pub struct Int {
+    pub span: Span,
+    pub kind: IntKind,
+}
+
+impl Int {
+    pub fn new_expr(span: Span, kind: IntKind) -> Expression {
+        Expression::Literal(Literal::Int(Self { span, kind }))
+    }
+}
+
+/// How the bytes of an [`Int`] encode its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntKind {
+    U8,
+    U16Le,
+    U16Be,
+    U32Le,
+    U32Be,
+    U64Le,
+    U64Be,
+    // unsigned LEB128: seven bits per byte, least significant first,
+    // with the high bit set on all bytes but the last one
+    Varint,
+}
+
+impl IntKind {
+    pub const ALL: [IntKind; 8] = [
+        IntKind::U8,
+        IntKind::U16Le,
+        IntKind::U16Be,
+        IntKind::U32Le,
+        IntKind::U32Be,
+        IntKind::U64Le,
+        IntKind::U64Be,
+        IntKind::Varint,
+    ];
+
+    /// Name the terminal is written with, without the `%` prefix
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntKind::U8 => "u8",
+            IntKind::U16Le => "u16le",
+            IntKind::U16Be => "u16be",
+            IntKind::U32Le => "u32le",
+            IntKind::U32Be => "u32be",
+            IntKind::U64Le => "u64le",
+            IntKind::U64Be => "u64be",
+            IntKind::Varint => "varint",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+
+    /// Number of bytes of the fixed width integers, `None` for `Varint`
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            IntKind::U8 => Some(1),
+            IntKind::U16Le | IntKind::U16Be => Some(2),
+            IntKind::U32Le | IntKind::U32Be => Some(4),
+            IntKind::U64Le | IntKind::U64Be => Some(8),
+            IntKind::Varint => None,
+        }
+    }
+
+    pub fn big_endian(&self) -> bool {
+        matches!(self, IntKind::U16Be | IntKind::U32Be | IntKind::U64Be)
+    }
+
+    /// Read an integer from the start of `bytes`, returning its value
+    /// and how many bytes it took.  `None` if there aren't enough
+    /// bytes or a varint doesn't fit in 64 bits.
+    pub fn decode(&self, bytes: &[u8]) -> Option<(u64, usize)> {
+        match self.width() {
+            Some(width) => {
+                let bytes = bytes.get(..width)?;
+                let fold = |acc: u64, b: &u8| acc << 8 | u64::from(*b);
+                let value = match self.big_endian() {
+                    true => bytes.iter().fold(0, fold),
+                    false => bytes.iter().rev().fold(0, fold),
+                };
+                Some((value, width))
+            }
+            None => {
+                let mut value = 0u64;
+                for (i, b) in bytes.iter().enumerate().take(10) {
+                    let bits = u64::from(b & 0x7f);
+                    if i == 9 && bits > 1 {
+                        return None;
+                    }
+                    value |= bits << (7 * i);
+                    if b & 0x80 == 0 {
+                        return Some((value, i + 1));
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Bytes of `value`, or `None` if it doesn't fit in the width
+    pub fn encode(&self, mut value: u64) -> Option<Vec<u8>> {
+        match self.width() {
+            Some(width) => {
+                if width < 8 && value >> (8 * width) != 0 {
+                    return None;
+                }
+                let bytes = value.to_le_bytes()[..width].to_vec();
+                match self.big_endian() {
+                    true => Some(bytes.into_iter().rev().collect()),
+                    false => Some(bytes),
+                }
+            }
+            None => {
+                let mut bytes = vec![];
+                loop {
+                    let b = (value & 0x7f) as u8;
+                    value >>= 7;
+                    if value == 0 {
+                        bytes.push(b);
+                        return Some(bytes);
+                    }
+                    bytes.push(b | 0x80);
+                }
+            }
+        }
+    }
+}
+
+/// Take is a built-in terminal that matches as many characters, or
+/// bytes in byte mode, as the value of the last node named `name`
+/// captured before it within the same production, e.g.:
+/// `Chunk <- Length %take(Length)`.  The node's text must be a
+/// decimal number, like the values captured by [`Int`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Take {
+    pub span: Span,
+    pub name: StdString,
+}
+
+impl Take {
+    pub fn new_expr(span: Span, name: StdString) -> Expression {
+        Expression::Literal(Literal::Take(Self { span, name }))
+    }
+}
+
+/// Host calls the function registered with the virtual machine
+/// under `name`, e.g.: `Type <- Name %host(is_type)`.  It doesn't
+/// consume any input, and matches if the function returns `true`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Host {
+    pub span: Span,
+    pub name: StdString,
+}
+
+impl Host {
+    pub fn new_expr(span: Span, name: StdString) -> Expression {
+        Expression::Literal(Literal::Host(Self { span, name }))
+    }
+}
+
+/// NamedClass matches a character of the class the compiler gets
+/// from the providers registered by the embedder under `name`,
+/// e.g.: `Word <- %greek+`
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamedClass {
+    pub span: Span,
+    pub name: StdString,
+}
+
+impl NamedClass {
+    pub fn new_expr(span: Span, name: StdString) -> Expression {
+        Expression::Literal(Literal::NamedClass(Self { span, name }))
+    }
+}
+
 /// Empty represents the empty alternative of an ordered choice
 /// operator.  Both start and end of such span are the same as no
 /// input is consumed.
@@ -544,9 +1203,139 @@ impl Empty {
     }
 }
 
+/// Error returned by [`rename_rule`] when the grammar can't be
+/// refactored
+#[derive(Debug, PartialEq, Eq)]
+pub enum RenameError {
+    // The rule being renamed isn't defined within the grammar (name)
+    Undefined(StdString),
+    // The new name is already taken by a rule or a label (name)
+    AlreadyDefined(StdString),
+    // The new name isn't a valid identifier (name)
+    InvalidName(StdString),
+}
+
+impl std::error::Error for RenameError {}
+
+impl std::fmt::Display for RenameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenameError::Undefined(name) => write!(f, "rule `{}` isn't defined", name),
+            RenameError::AlreadyDefined(name) => write!(f, "`{}` is already defined", name),
+            RenameError::InvalidName(name) => write!(f, "`{}` isn't a valid identifier", name),
+        }
+    }
+}
+
+/// Rename the rule `old` to `new` within `grammar`.  Besides the
+/// definition itself, every identifier that references the rule is
+/// updated, including the ones within `@sync(...)` expressions.  A
+/// rule named after a label is the recovery expression of that label,
+/// so labels thrown with `e^old` and the message declared for them
+/// are renamed along with the rule.  Literals are left untouched.
+pub fn rename_rule(grammar: &mut Grammar, old: &str, new: &str) -> Result<(), RenameError> {
+    let mut chars = new.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(RenameError::InvalidName(new.to_string()));
+    }
+    if !grammar.definitions.contains_key(old) {
+        return Err(RenameError::Undefined(old.to_string()));
+    }
+    if old == new {
+        return Ok(());
+    }
+    if grammar.definitions.contains_key(new) || LabelNames::collect(grammar).contains(new) {
+        return Err(RenameError::AlreadyDefined(new.to_string()));
+    }
+
+    let mut renamer = Renamer { old, new };
+    let mut renamed = renamer.fold_grammar(grammar);
+    for name in renamed.definition_names.iter_mut() {
+        if name == old {
+            *name = new.to_string();
+        }
+    }
+    if let Some(mut d) = renamed.definitions.remove(old) {
+        d.name = new.to_string();
+        renamed.definitions.insert(new.to_string(), d);
+    }
+    for label in renamed.labels.iter_mut() {
+        if label.name == old {
+            label.name = new.to_string();
+        }
+    }
+    *grammar = renamed;
+    Ok(())
+}
+
+// Rewrites the references to the rule `old` into references to `new`
+struct Renamer<'a> {
+    old: &'a str,
+    new: &'a str,
+}
+
+impl Renamer<'_> {
+    fn rename(&self, name: &str) -> StdString {
+        if name == self.old {
+            self.new.to_string()
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+impl Folder for Renamer<'_> {
+    fn fold_definition(&mut self, n: &Definition) -> Definition {
+        Definition {
+            expr: self.fold_expression(&n.expr),
+            sync: n.sync.as_ref().map(|s| self.fold_expression(s)),
+            ..n.clone()
+        }
+    }
+
+    fn fold_label(&mut self, n: &Label) -> Expression {
+        Label::new_expr(
+            n.span.clone(),
+            self.rename(&n.label),
+            Box::new(self.fold_expression(&n.expr)),
+        )
+    }
+
+    fn fold_identifier(&mut self, n: &Identifier) -> Expression {
+        Identifier::new_expr(n.span.clone(), self.rename(&n.name))
+    }
+}
+
+// Names of the labels thrown or declared within a grammar
+#[derive(Default)]
+struct LabelNames<'a> {
+    names: std::collections::HashSet<&'a str>,
+}
+
+impl<'a> LabelNames<'a> {
+    fn collect(grammar: &'a Grammar) -> std::collections::HashSet<&'a str> {
+        let mut v = Self::default();
+        v.names
+            .extend(grammar.labels.iter().map(|l| l.name.as_str()));
+        v.visit_grammar(grammar);
+        v.names
+    }
+}
+
+impl<'a> Visitor<'a> for LabelNames<'a> {
+    fn visit_label(&mut self, n: &'a Label) {
+        self.names.insert(&n.label);
+        crate::visitor::walk_label(self, n);
+    }
+}
+
 // formatting functions
 
-fn fmtlistsep<T: ToString>(sep: &str, items: &Vec<T>) -> StdString {
+fn fmtlistsep<T: ToString>(sep: &str, items: &[T]) -> StdString {
     let mut output = StdString::new();
     let len = items.len();
 
@@ -562,26 +1351,26 @@ fn fmtlistsep<T: ToString>(sep: &str, items: &Vec<T>) -> StdString {
 
 fn fmtprefix(prefix: &str, node: &Expression) -> StdString {
     if tree_height(node) > 1 {
-        return format!("{}({})", prefix, node.to_string());
+        return format!("{}({})", prefix, node);
     }
     if let Expression::Sequence(seq) = node {
         if seq.items.len() > 1 {
-            return format!("{}({})", prefix, node.to_string());
+            return format!("{}({})", prefix, node);
         }
     }
-    format!("{}{}", prefix, node.to_string())
+    format!("{}{}", prefix, node)
 }
 
 fn fmtsuffix(suffix: &str, node: &Expression) -> StdString {
     if tree_height(node) > 1 {
-        return format!("({}){}", node.to_string(), suffix);
+        return format!("({}){}", node, suffix);
     }
     if let Expression::Sequence(seq) = node {
         if seq.items.len() > 1 {
-            return format!("({}){}", node.to_string(), suffix);
+            return format!("({}){}", node, suffix);
         }
     }
-    format!("{}{}", node.to_string(), suffix)
+    format!("{}{}", node, suffix)
 }
 
 fn tree_height(n: &Expression) -> usize {
@@ -594,6 +1383,7 @@ fn tree_height(n: &Expression) -> usize {
         Expression::Optional(v) => tree_height(&v.expr) + 1,
         Expression::ZeroOrMore(v) => tree_height(&v.expr) + 1,
         Expression::OneOrMore(v) => tree_height(&v.expr) + 1,
+        Expression::Repeat(v) => tree_height(&v.expr) + 1,
         Expression::Precedence(v) => tree_height(&v.expr) + 1,
         Expression::Label(v) => tree_height(&v.expr) + 1,
         Expression::List(v) => items_height(&v.items) + 1,
@@ -610,3 +1400,45 @@ fn items_height(items: &[Expression]) -> usize {
         .map(tree_height)
         .fold(usize::MIN, |a, b| a.max(b))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn rename_rule_updates_definition_and_references() {
+        let mut grammar = parser::parse(
+            "label num = \"expected a number\"\nA <- B 'B' B* num^num\n@sync(B) num <- B\nB <- 'b'\n",
+        )
+        .unwrap();
+        rename_rule(&mut grammar, "B", "Bee").unwrap();
+        rename_rule(&mut grammar, "num", "Number").unwrap();
+        assert_eq!(
+            "label Number = \"expected a number\"\n\nA <- Bee \"B\" Bee* Number^Number\n@sync(Bee) Number <- Bee\nBee <- \"b\"\n",
+            grammar.to_string(),
+        );
+    }
+
+    #[test]
+    fn rename_rule_errors() {
+        let mut grammar = parser::parse("A <- B^l\nB <- 'b'\n").unwrap();
+        assert_eq!(
+            Err(RenameError::Undefined("C".to_string())),
+            rename_rule(&mut grammar, "C", "D"),
+        );
+        assert_eq!(
+            Err(RenameError::AlreadyDefined("A".to_string())),
+            rename_rule(&mut grammar, "B", "A"),
+        );
+        assert_eq!(
+            Err(RenameError::AlreadyDefined("l".to_string())),
+            rename_rule(&mut grammar, "B", "l"),
+        );
+        assert_eq!(
+            Err(RenameError::InvalidName("1B".to_string())),
+            rename_rule(&mut grammar, "B", "1B"),
+        );
+        assert_eq!("A <- B^l\nB <- \"b\"\n", grammar.to_string());
+    }
+}
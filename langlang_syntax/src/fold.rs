@@ -0,0 +1,219 @@
+// fold.rs --- build new grammars out of existing ones
+//
+// The `Folder` trait is the rewriting counterpart of the `Visitor`:
+// each method takes a node by reference and returns the node that
+// replaces it in the output tree.  The default methods rebuild the
+// node out of its folded children, so implementations only override
+// the nodes they want to change.  Since `fold_*` methods that take
+// expressions also return expressions, a node can be replaced by one
+// of a different kind (e.g.: wrapping a rule body within a `Node`).
+//
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::stack;
+
+pub trait Folder: Sized {
+    fn fold_grammar(&mut self, n: &Grammar) -> Grammar {
+        walk_grammar(self, n)
+    }
+
+    fn fold_import(&mut self, n: &Import) -> Import {
+        n.clone()
+    }
+
+    fn fold_definition(&mut self, n: &Definition) -> Definition {
+        walk_definition(self, n)
+    }
+
+    fn fold_label_message(&mut self, n: &LabelMessage) -> LabelMessage {
+        walk_label_message(self, n)
+    }
+
+    fn fold_expression(&mut self, n: &Expression) -> Expression {
+        walk_expression(self, n)
+    }
+
+    fn fold_sequence(&mut self, n: &Sequence) -> Expression {
+        Sequence::new_expr(n.span.clone(), fold_items(self, &n.items))
+    }
+
+    fn fold_choice(&mut self, n: &Choice) -> Expression {
+        Choice::new_expr(n.span.clone(), fold_items(self, &n.items))
+    }
+
+    fn fold_lex(&mut self, n: &Lex) -> Expression {
+        Lex::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_and(&mut self, n: &And) -> Expression {
+        And::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_not(&mut self, n: &Not) -> Expression {
+        Not::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_optional(&mut self, n: &Optional) -> Expression {
+        Optional::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_zero_or_more(&mut self, n: &ZeroOrMore) -> Expression {
+        ZeroOrMore::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_one_or_more(&mut self, n: &OneOrMore) -> Expression {
+        OneOrMore::new_expr(n.span.clone(), Box::new(self.fold_expression(&n.expr)))
+    }
+
+    fn fold_repeat(&mut self, n: &Repeat) -> Expression {
+        Repeat::new_expr(
+            n.span.clone(),
+            Box::new(self.fold_expression(&n.expr)),
+            n.min,
+            n.max,
+        )
+    }
+
+    fn fold_precedence(&mut self, n: &Precedence) -> Expression {
+        Precedence::new_expr(
+            n.span.clone(),
+            Box::new(self.fold_expression(&n.expr)),
+            n.precedence,
+        )
+    }
+
+    fn fold_label(&mut self, n: &Label) -> Expression {
+        Label::new_expr(
+            n.span.clone(),
+            n.label.clone(),
+            Box::new(self.fold_expression(&n.expr)),
+        )
+    }
+
+    fn fold_list(&mut self, n: &List) -> Expression {
+        List::new_expr(n.span.clone(), fold_items(self, &n.items))
+    }
+
+    fn fold_node(&mut self, n: &Node) -> Expression {
+        Node::new_expr(
+            n.span.clone(),
+            n.name.clone(),
+            Box::new(self.fold_expression(&n.expr)),
+        )
+    }
+
+    fn fold_identifier(&mut self, n: &Identifier) -> Expression {
+        Expression::Identifier(n.clone())
+    }
+
+    fn fold_literal(&mut self, n: &Literal) -> Expression {
+        Expression::Literal(n.clone())
+    }
+
+    fn fold_empty(&mut self, n: &Empty) -> Expression {
+        Expression::Empty(n.clone())
+    }
+}
+
+pub fn walk_grammar<F: Folder>(folder: &mut F, g: &Grammar) -> Grammar {
+    let imports = g.imports.iter().map(|i| folder.fold_import(i)).collect();
+    let mut definitions = HashMap::new();
+    for name in &g.definition_names {
+        let d = folder.fold_definition(&g.definitions[name]);
+        definitions.insert(name.clone(), d);
+    }
+    Grammar::new(
+        g.span.clone(),
+        imports,
+        g.definition_names.clone(),
+        definitions,
+    )
+    .with_version(g.version.clone())
+    .with_comments(g.comments.clone())
+    .with_labels(
+        g.labels
+            .iter()
+            .map(|l| folder.fold_label_message(l))
+            .collect(),
+    )
+}
+
+pub fn walk_label_message<F: Folder>(folder: &mut F, l: &LabelMessage) -> LabelMessage {
+    LabelMessage {
+        recover: l.recover.as_ref().map(|e| folder.fold_expression(e)),
+        ..l.clone()
+    }
+}
+
+pub fn walk_definition<F: Folder>(folder: &mut F, d: &Definition) -> Definition {
+    Definition {
+        expr: folder.fold_expression(&d.expr),
+        ..d.clone()
+    }
+}
+
+pub fn walk_expression<F: Folder>(folder: &mut F, e: &Expression) -> Expression {
+    stack::guard(|| match e {
+        Expression::Sequence(n) => folder.fold_sequence(n),
+        Expression::Choice(n) => folder.fold_choice(n),
+        Expression::Lex(n) => folder.fold_lex(n),
+        Expression::And(n) => folder.fold_and(n),
+        Expression::Not(n) => folder.fold_not(n),
+        Expression::Optional(n) => folder.fold_optional(n),
+        Expression::ZeroOrMore(n) => folder.fold_zero_or_more(n),
+        Expression::OneOrMore(n) => folder.fold_one_or_more(n),
+        Expression::Repeat(n) => folder.fold_repeat(n),
+        Expression::Precedence(n) => folder.fold_precedence(n),
+        Expression::Label(n) => folder.fold_label(n),
+        Expression::List(n) => folder.fold_list(n),
+        Expression::Node(n) => folder.fold_node(n),
+        Expression::Identifier(n) => folder.fold_identifier(n),
+        Expression::Literal(n) => folder.fold_literal(n),
+        Expression::Empty(n) => folder.fold_empty(n),
+    })
+}
+
+fn fold_items<F: Folder>(folder: &mut F, items: &[Expression]) -> Vec<Expression> {
+    items.iter().map(|i| folder.fold_expression(i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    // Rename every reference to the rule `from`
+    struct Rename<'a> {
+        from: &'a str,
+        to: &'a str,
+    }
+
+    impl Folder for Rename<'_> {
+        fn fold_identifier(&mut self, n: &Identifier) -> Expression {
+            let name = if n.name == self.from {
+                self.to
+            } else {
+                &n.name
+            };
+            Identifier::new_expr(n.span.clone(), name.to_string())
+        }
+    }
+
+    #[test]
+    fn default_fold_rebuilds_the_same_grammar() {
+        struct Noop;
+        impl Folder for Noop {}
+
+        let input = "A <- B / (C 'c')* &D !E #{ F^l } [a-z]? { G }\nB <- 'b'\n";
+        let grammar = parser::parse(input).unwrap();
+        assert_eq!(grammar.to_string(), Noop.fold_grammar(&grammar).to_string());
+    }
+
+    #[test]
+    fn fold_replaces_nested_nodes() {
+        let grammar = parser::parse("A <- (B / 'x') B+\nB <- 'b'\n").unwrap();
+        let output = Rename { from: "B", to: "C" }.fold_grammar(&grammar);
+        assert_eq!("A <- (C / \"x\") C+\nB <- \"b\"\n", output.to_string());
+    }
+}
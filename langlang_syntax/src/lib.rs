@@ -1,3 +1,5 @@
 pub mod ast;
+pub mod fold;
 pub mod parser;
+pub mod stack;
 pub mod visitor;
@@ -1,12 +1,18 @@
 use std::collections::HashMap;
 
 use crate::ast;
+use crate::stack;
 
 use langlang_value::source_map::{Position, Span};
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    BacktrackError(usize, String),
+    // The grammar doesn't parse (farthest failure position, expected)
+    BacktrackError(Position, String),
+    // Input that starts a construct but doesn't finish it correctly,
+    // which choices don't backtrack over (position, message)
+    Fatal(Position, String),
 }
 
 impl std::error::Error for Error {}
@@ -14,7 +20,8 @@ impl std::error::Error for Error {}
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::BacktrackError(i, m) => write!(f, "Syntax Error: {}: {}", i, m),
+            Error::BacktrackError(p, m) => write!(f, "Syntax Error: {}: {}", p, m),
+            Error::Fatal(p, m) => write!(f, "Syntax Error: {}: {}", p, m),
         }
     }
 }
@@ -25,68 +32,197 @@ pub fn parse(input: &str) -> Result<ast::Grammar, Error> {
 }
 
 pub struct Parser {
-    ffp: usize,
+    // farthest failure position
+    ffp: Position,
     cursor: usize,
     line: usize,
     column: usize,
     source: Vec<char>,
+    // whether a `\xHH` escape was parsed since the start of the
+    // literal being parsed
+    byte_escape: bool,
 }
 
 type ParseFn<T> = fn(&mut Parser) -> Result<T, Error>;
 
+// Annotations that can precede a definition
+enum Attribute {
+    Sync(ast::Expression),
+    Allow(Vec<String>),
+    Capture(ast::CaptureMode),
+}
+
 impl Parser {
     pub fn new(s: &str) -> Self {
-        return Parser {
-            ffp: 0,
+        Parser {
+            ffp: Position::default(),
             cursor: 0,
             line: 0,
             column: 0,
             source: s.chars().collect(),
-        };
+            byte_escape: false,
+        }
     }
 
-    // GR: Grammar <- Spacing Import* Definition* EndOfFile
+    // GR: Grammar <- Spacing Version? Import* Comments? (LabelMessage / Definition)* EndOfFile
     pub fn parse_grammar(&mut self) -> Result<ast::Grammar, Error> {
         self.parse_spacing()?;
         let start = self.pos();
+        let version = self.choice(vec![|p| Ok(Some(p.parse_version()?)), |_| Ok(None)])?;
         let imports = self.zero_or_more(|p| p.parse_import())?;
+        let comments = self.choice(vec![|p| p.parse_comments(), |_| Ok(vec![])])?;
         let mut defs = HashMap::new();
         let mut def_names = Vec::new();
+        let mut labels = Vec::new();
         self.zero_or_more(|p| {
-            let def = p.parse_definition()?;
-            def_names.push(def.name.clone());
-            defs.insert(def.name.clone(), def);
+            match p.choice(vec![|p| Ok(Err(p.parse_label_message()?)), |p| {
+                Ok(Ok(p.parse_definition()?))
+            }])? {
+                Ok(def) => {
+                    def_names.push(def.name.clone());
+                    defs.insert(def.name.clone(), def);
+                }
+                Err(label) => labels.push(label),
+            }
             Ok(())
         })?;
         self.parse_eof()?;
         let span = self.span_from(start);
-        Ok(ast::Grammar::new(span, imports, def_names, defs))
+        Ok(ast::Grammar::new(span, imports, def_names, defs)
+            .with_version(version)
+            .with_comments(comments)
+            .with_labels(labels))
     }
 
-    // GR: Import <- "@import" Identifier ("," Identifier)* "from" Literal
-    fn parse_import(&mut self) -> Result<ast::Import, Error> {
+    // GR: Version <- "@version" Literal
+    fn parse_version(&mut self) -> Result<String, Error> {
+        self.parse_spacing()?;
+        self.expect_str("@version")?;
+        self.parse_spacing()?;
+        self.parse_literal_string()
+    }
+
+    // GR: LabelMessage <- "label" Identifier "=" Literal Recover?
+    fn parse_label_message(&mut self) -> Result<ast::LabelMessage, Error> {
         self.parse_spacing()?;
         let start = self.pos();
-        self.expect_str("@import")?;
-        let mut names = vec![self.parse_identifier()?];
-        names.append(&mut self.zero_or_more(|p| {
+        if self.parse_identifier()? != "label" {
+            return Err(self.err("label".to_string()));
+        }
+        self.parse_spacing()?;
+        let name = self.parse_identifier()?;
+        self.parse_spacing()?;
+        self.expect('=')?;
+        self.parse_spacing()?;
+        let message = self.parse_literal_string()?;
+        let recover = self.choice(vec![|p| Ok(Some(p.parse_recover()?)), |_| Ok(None)])?;
+        let span = self.span_from(start);
+        Ok(ast::LabelMessage::new(span, name, message).with_recover(recover))
+    }
+
+    // GR: Recover <- "recover" !LEFTARROW Expression
+    fn parse_recover(&mut self) -> Result<ast::Expression, Error> {
+        self.parse_spacing()?;
+        if self.parse_identifier()? != "recover" {
+            return Err(self.err("recover".to_string()));
+        }
+        self.not(|p| {
+            p.parse_spacing()?;
+            p.expect_str("<-")
+        })?;
+        self.parse_spacing()?;
+        let start = self.cursor;
+        let expr = self.parse_expression()?;
+        if self.cursor == start {
+            return Err(self.err("expected a recovery expression".to_string()));
+        }
+        Ok(expr)
+    }
+
+    // GR: Comments <- "@comments" CommentSyntax ("," CommentSyntax)*
+    fn parse_comments(&mut self) -> Result<Vec<ast::Comment>, Error> {
+        self.parse_spacing()?;
+        self.expect_str("@comments")?;
+        let mut comments = vec![self.parse_comment_syntax()?];
+        comments.append(&mut self.zero_or_more(|p| {
             p.parse_spacing()?;
             p.expect(',')?;
-            p.parse_identifier()
+            p.parse_comment_syntax()
         })?);
+        Ok(comments)
+    }
+
+    // GR: CommentSyntax <- Literal ("eol" / Literal)
+    fn parse_comment_syntax(&mut self) -> Result<ast::Comment, Error> {
         self.parse_spacing()?;
-        self.expect_str("from")?;
+        let start = self.pos();
+        let open = self.parse_literal_string()?;
         self.parse_spacing()?;
+        let close = self.choice(vec![
+            |p| match p.parse_identifier()?.as_str() {
+                "eol" => Ok(None),
+                _ => Err(p.err("eol".to_string())),
+            },
+            |p| Ok(Some(p.parse_literal_string()?)),
+        ])?;
+        let span = self.span_from(start);
+        Ok(ast::Comment::new(span, open, close))
+    }
+
+    // GR: Import <- "@import" (Identifier ("," Identifier)* "from")? Literal
+    fn parse_import(&mut self) -> Result<ast::Import, Error> {
+        self.parse_spacing()?;
+        let start = self.pos();
+        self.expect_str("@import")?;
+        self.parse_spacing()?;
+        let names = self.choice(vec![
+            |p| {
+                let mut names = vec![p.parse_identifier()?];
+                names.append(&mut p.zero_or_more(|p| {
+                    p.parse_spacing()?;
+                    p.expect(',')?;
+                    p.parse_identifier()
+                })?);
+                p.parse_spacing()?;
+                p.expect_str("from")?;
+                p.parse_spacing()?;
+                Ok(names)
+            },
+            // without names, all the rules of the module are imported
+            |_| Ok(vec![]),
+        ])?;
         let path = self.parse_literal_string()?;
         let span = self.span_from(start);
         Ok(ast::Import::new(span, path, names))
     }
 
-    // GR: Definition <- Identifier LEFTARROW Expression
+    // GR: Definition <- Attribute* (Identifier / "%whitespace") LEFTARROW Expression
     fn parse_definition(&mut self) -> Result<ast::Definition, Error> {
         self.parse_spacing()?;
         let start = self.pos();
-        let id = self.parse_identifier()?;
+        let (mut sync, mut allow, mut capture) = (None, vec![], ast::CaptureMode::Node);
+        let attributes = self.zero_or_more(|p| {
+            p.parse_spacing()?;
+            p.parse_attribute()
+        })?;
+        for attribute in attributes {
+            match attribute {
+                Attribute::Sync(expr) => sync = Some(expr),
+                Attribute::Allow(mut names) => allow.append(&mut names),
+                Attribute::Capture(mode) => capture = mode,
+            }
+        }
+
+        self.parse_spacing()?;
+        let id = self.choice(vec![
+            |p| p.parse_identifier(),
+            // the white space handler is defined with its own
+            // directive, which names the rule the compiler calls
+            |p| {
+                p.expect_str("%whitespace")?;
+                Ok(ast::WHITE_SPACE_RULE_NAME.to_string())
+            },
+        ])?;
 
         self.parse_spacing()?;
         self.expect('<')?;
@@ -94,11 +230,76 @@ impl Parser {
 
         let expr = self.parse_expression()?;
         let span = self.span_from(start);
-        Ok(ast::Definition::new(span, id, expr))
+        let mut def = ast::Definition::new_with_sync(span, id, expr, sync);
+        def.allow = allow;
+        def.capture = capture;
+        Ok(def)
     }
 
-    // GR: Expression <- Sequence (SLASH Sequence)*
+    // GR: Attribute <- Sync / Allow / Capture
+    fn parse_attribute(&mut self) -> Result<Attribute, Error> {
+        self.choice(vec![
+            |p| Ok(Attribute::Sync(p.parse_sync()?)),
+            |p| Ok(Attribute::Allow(p.parse_allow()?)),
+            |p| Ok(Attribute::Capture(p.parse_capture()?)),
+        ])
+    }
+
+    // GR: Capture <- "@capture" OPEN ("node" / "string" / "list" / "skip") CLOSE
+    fn parse_capture(&mut self) -> Result<ast::CaptureMode, Error> {
+        self.expect_str("@capture")?;
+        self.parse_spacing()?;
+        self.expect('(')?;
+        self.parse_spacing()?;
+        let name = self.parse_identifier()?;
+        let Some(mode) = ast::CaptureMode::from_name(&name) else {
+            return Err(self.err(format!(
+                "Expected capture mode `node`, `string`, `list` or `skip`, got `{}`",
+                name
+            )));
+        };
+        self.parse_spacing()?;
+        self.expect(')')?;
+        Ok(mode)
+    }
+
+    // GR: Allow <- "@allow" OPEN Identifier ("," Identifier)* CLOSE
+    fn parse_allow(&mut self) -> Result<Vec<String>, Error> {
+        self.expect_str("@allow")?;
+        self.parse_spacing()?;
+        self.expect('(')?;
+        self.parse_spacing()?;
+        let mut names = vec![self.parse_identifier()?];
+        names.append(&mut self.zero_or_more(|p| {
+            p.parse_spacing()?;
+            p.expect(',')?;
+            p.parse_spacing()?;
+            p.parse_identifier()
+        })?);
+        self.parse_spacing()?;
+        self.expect(')')?;
+        Ok(names)
+    }
+
+    // GR: Sync <- "@sync" OPEN Expression CLOSE
+    fn parse_sync(&mut self) -> Result<ast::Expression, Error> {
+        self.expect_str("@sync")?;
+        self.parse_spacing()?;
+        self.expect('(')?;
+        let expr = self.parse_expression()?;
+        self.parse_spacing()?;
+        self.expect(')')?;
+        Ok(expr)
+    }
+
+    // expressions nest within parenthesis, so machine generated
+    // grammars can get deep enough to need more stack
     fn parse_expression(&mut self) -> Result<ast::Expression, Error> {
+        stack::guard(|| self.parse_choice())
+    }
+
+    // GR: Expression <- Sequence (SLASH Sequence)*
+    fn parse_choice(&mut self) -> Result<ast::Expression, Error> {
         let start = self.pos();
         let first = self.parse_sequence()?;
         let mut choices = vec![first];
@@ -162,19 +363,27 @@ impl Parser {
         })
     }
 
-    // GR: Label <- [^⇑] Identifier
+    // GR: Label <- [⇑^] Identifier
     fn parse_label(&mut self) -> Result<String, Error> {
         self.choice(vec![|p| p.expect_str("^"), |p| p.expect_str("⇑")])?;
         self.parse_identifier()
     }
 
-    // GR: Suffix <- Primary (QUESTION / STAR / PLUS / Superscript)?
+    // GR: Suffix <- Primary (QUESTION / STAR / PLUS / Superscript / Counts)?
     fn parse_suffix(&mut self) -> Result<ast::Expression, Error> {
         self.parse_spacing()?;
         let start = self.pos();
         let primary = self.parse_primary()?;
 
         self.parse_spacing()?;
+        match self.choice(vec![|p| p.parse_counts()]) {
+            Ok((min, max)) => {
+                let span = self.span_from(start);
+                return Ok(ast::Repeat::new_expr(span, Box::new(primary), min, max));
+            }
+            Err(e @ Error::Fatal(..)) => return Err(e),
+            Err(_) => {}
+        }
         let suffix = self.choice(vec![
             |p| p.expect_str("?"),
             |p| p.expect_str("*"),
@@ -191,7 +400,7 @@ impl Parser {
             |_| Ok(""),
         ])?;
         let span = self.span_from(start);
-        Ok(match suffix.as_ref() {
+        Ok(match suffix {
             "?" => ast::Optional::new_expr(span, Box::new(primary)),
             "*" => ast::ZeroOrMore::new_expr(span, Box::new(primary)),
             "+" => ast::OneOrMore::new_expr(span, Box::new(primary)),
@@ -208,9 +417,48 @@ impl Parser {
         })
     }
 
+    // GR: Counts <- OPENC Number (COMMA Number?)? CLOSEC
+    fn parse_counts(&mut self) -> Result<(usize, Option<usize>), Error> {
+        self.expect('{')?;
+        self.parse_spacing()?;
+        let min = self.parse_number()?;
+        self.parse_spacing()?;
+        // `{n}` matches exactly `n` times, `{n,}` at least `n` times
+        let max = self.choice(vec![
+            |p| {
+                p.expect(',')?;
+                p.parse_spacing()?;
+                let max = p.choice(vec![|p| p.parse_number().map(Some), |_| Ok(None)])?;
+                p.parse_spacing()?;
+                Ok(Some(max))
+            },
+            |_| Ok(None),
+        ])?;
+        self.expect('}')?;
+        let max = max.unwrap_or(Some(min));
+        if max.is_some_and(|max| max < min) {
+            return Err(self.fatal(format!(
+                "repetition can't match at least {} times and at most {}",
+                min,
+                max.unwrap_or_default()
+            )));
+        }
+        Ok((min, max))
+    }
+
+    // GR: Number <- [0-9]+
+    fn parse_number(&mut self) -> Result<usize, Error> {
+        let first = self.expect_range('0', '9')?;
+        let rest = self.zero_or_more(|p| p.expect_range('0', '9'))?;
+        let digits: String = std::iter::once(first).chain(rest).collect();
+        digits
+            .parse()
+            .map_err(|_| self.fatal(format!("number `{}` is too large", digits)))
+    }
+
     // GR: Primary <- Identifier !(LEFTARROW / (Identifier EQ))
     // GR:          / OPEN Expression CLOSE
-    // GR:          / Node / List / Literal / Class / DOT
+    // GR:          / Node / List / Literal / Class / DOT / Builtin
     fn parse_primary(&mut self) -> Result<ast::Expression, Error> {
         self.parse_spacing()?;
         self.choice(vec![
@@ -219,7 +467,15 @@ impl Parser {
                 let id = p.parse_identifier()?;
                 p.not(|p| {
                     p.parse_spacing()?;
-                    p.expect_str("<-")
+                    p.choice(vec![
+                        |p| p.expect_str("<-"),
+                        // `label name = ...` starts the next label
+                        |p| {
+                            p.parse_identifier()?;
+                            p.parse_spacing()?;
+                            p.expect_str("=")
+                        },
+                    ])
                 })?;
                 let span = p.span_from(start);
                 Ok(ast::Identifier::new_expr(span, id))
@@ -237,6 +493,7 @@ impl Parser {
             |p| p.parse_literal(),
             |p| p.parse_class(),
             |p| p.parse_dot(),
+            |p| p.parse_builtin(),
         ])
     }
 
@@ -264,7 +521,14 @@ impl Parser {
         self.expect('{')?;
         let exprs = self.zero_or_more(|p| {
             p.not(|p| p.expect('}'))?;
-            p.parse_expression()
+            // an item that doesn't consume anything would be parsed
+            // over and over again
+            let cursor = p.cursor;
+            let expr = p.parse_expression()?;
+            match p.cursor == cursor {
+                true => Err(p.err("list item".to_string())),
+                false => Ok(expr),
+            }
         })?;
         self.parse_spacing()?;
         self.expect('}')?;
@@ -295,14 +559,38 @@ impl Parser {
         Ok(id)
     }
 
-    // GR: Literal <- [’] (![’]Char)* [’] Spacing
-    // GR:          / ["] (!["]Char)* ["] Spacing
+    // GR: Literal <- [’] (![’]Char)* [’] IgnoreCase? Spacing
+    // GR:          / ["] (!["]Char)* ["] IgnoreCase? Spacing
     fn parse_literal(&mut self) -> Result<ast::Expression, Error> {
         self.parse_spacing()?;
         let start = self.pos();
+        self.byte_escape = false;
         let value = self.parse_literal_string()?;
+        let bytes = self.byte_escape;
+        let ignore_case = self.parse_ignore_case();
         let span = self.span_from(start);
-        Ok(ast::String::new_expr(span, value))
+        let literal = ast::String::new(span, value, ignore_case).with_bytes(bytes);
+        Ok(ast::Expression::Literal(ast::Literal::String(literal)))
+    }
+
+    // GR: IgnoreCase <- 'i' !IdentCont
+    fn parse_ignore_case(&mut self) -> bool {
+        self.choice(vec![
+            |p| {
+                p.expect('i')?;
+                p.not(|p| {
+                    p.choice(vec![
+                        |p| p.expect_range('a', 'z'),
+                        |p| p.expect_range('A', 'Z'),
+                        |p| p.expect_range('0', '9'),
+                        |p| p.expect('_'),
+                    ])
+                })?;
+                Ok(true)
+            },
+            |_| Ok(false),
+        ])
+        .unwrap_or(false)
     }
 
     fn parse_literal_string(&mut self) -> Result<String, Error> {
@@ -339,18 +627,51 @@ impl Parser {
         Ok(r)
     }
 
-    // GR: Class <- ’[’ (!’]’Range)* ’]’ Spacing
+    // GR: Class <- ’[’ ’^’? (!’]’ (Property / Range))* ’]’ IgnoreCase? Spacing
     fn parse_class(&mut self) -> Result<ast::Expression, Error> {
         self.parse_spacing()?;
         let start = self.pos();
         self.expect('[')?;
+        let negated = self.expect('^').is_ok();
         let ranges = self.zero_or_more::<ast::Literal, _>(|p| {
             p.not(|pp| pp.expect(']'))?;
-            p.parse_range()
+            p.choice(vec![|p| p.parse_property(), |p| p.parse_range()])
         })?;
         self.expect(']')?;
+        let ignore_case = self.parse_ignore_case();
         let span = self.span_from(start);
-        Ok(ast::Class::new_expr(span, ranges))
+        let class = ast::Class::new(span, ranges, ignore_case).with_negated(negated);
+        Ok(ast::Expression::Literal(ast::Literal::Class(class)))
+    }
+
+    // GR: Property <- ’\\’ [pP] ’{’ [-a-zA-Z0-9_ =]+ ’}’
+    fn parse_property(&mut self) -> Result<ast::Literal, Error> {
+        let start = self.pos();
+        self.expect('\\')?;
+        let negated = self.choice(vec![|p| p.expect('p'), |p| p.expect('P')])? == 'P';
+        self.expect('{')?;
+        let name: String = self
+            .zero_or_more(|p| {
+                p.choice(vec![
+                    |p| p.expect_range('a', 'z'),
+                    |p| p.expect_range('A', 'Z'),
+                    |p| p.expect_range('0', '9'),
+                    |p| p.expect('_'),
+                    |p| p.expect(' '),
+                    |p| p.expect('='),
+                    |p| p.expect('-'),
+                ])
+            })?
+            .into_iter()
+            .collect();
+        if name.is_empty() {
+            return Err(self.err("expected the name of a Unicode property".to_string()));
+        }
+        self.expect('}')?;
+        let span = self.span_from(start);
+        Ok(ast::Literal::Property(ast::Property::new(
+            span, name, negated,
+        )))
     }
 
     // GR: Range <- Char ’-’ Char / Char
@@ -358,22 +679,27 @@ impl Parser {
         self.choice(vec![
             |p| {
                 let start = p.pos();
+                p.byte_escape = false;
                 let left = p.parse_char()?;
                 p.expect('-')?;
                 let right = p.parse_char()?;
                 let span = p.span_from(start);
-                Ok(ast::Literal::Range(ast::Range::new(span, left, right)))
+                let range = ast::Range::new(span, left, right).with_bytes(p.byte_escape);
+                Ok(ast::Literal::Range(range))
             },
             |p| {
                 let start = p.pos();
+                p.byte_escape = false;
                 let c = p.parse_char()?;
                 let s = p.span_from(start);
-                Ok(ast::Literal::Char(ast::Char::new(s, c)))
+                let c = ast::Char::new(s, c).with_bytes(p.byte_escape);
+                Ok(ast::Literal::Char(c))
             },
         ])
     }
 
     // GR: Char <- ’\\’ [nrt’"\[\]\\]
+    // GR:       / ’\\’ ’x’ Hex Hex
     // GR:       / ’\\’ [0-2][0-7][0-7]
     // GR:       / ’\\’ [0-7][0-7]?
     // GR:       / !’\\’ .
@@ -383,7 +709,7 @@ impl Parser {
         }])
     }
 
-    // ’\\’ [nrt’"\[\]\\]
+    // ’\\’ [nrt’"\[\]\\^]
     fn parse_char_escaped(&mut self) -> Result<char, Error> {
         self.expect('\\')?;
         self.choice(vec![
@@ -419,6 +745,10 @@ impl Parser {
                 p.expect('\\')?;
                 Ok('\\')
             },
+            |p| {
+                p.expect('^')?;
+                Ok('^')
+            },
             |p| {
                 p.expect('\'')?;
                 Ok('\'')
@@ -427,9 +757,27 @@ impl Parser {
                 p.expect('"')?;
                 Ok('"')
             },
+            |p| p.parse_byte_escape(),
         ])
     }
 
+    // ’x’ Hex Hex
+    // GR: Hex <- [0-9a-fA-F]
+    fn parse_byte_escape(&mut self) -> Result<char, Error> {
+        self.expect('x')?;
+        let mut value = 0;
+        for _ in 0..2 {
+            let digit = self.choice(vec![
+                |p| p.expect_range('0', '9'),
+                |p| p.expect_range('a', 'f'),
+                |p| p.expect_range('A', 'F'),
+            ])?;
+            value = value * 16 + digit.to_digit(16).unwrap_or(0);
+        }
+        self.byte_escape = true;
+        Ok(char::from(value as u8))
+    }
+
     // !’\\’ .
     fn parse_char_non_escaped(&mut self) -> Result<char, Error> {
         self.not(|p| p.expect('\\'))?;
@@ -445,6 +793,41 @@ impl Parser {
         Ok(ast::Any::new_expr(span))
     }
 
+    // GR: Builtin <- '%' Identifier (OPEN Identifier CLOSE)?
+    // Names that aren't built-in terminals are named classes
+    fn parse_builtin(&mut self) -> Result<ast::Expression, Error> {
+        self.parse_spacing()?;
+        let start = self.pos();
+        self.expect('%')?;
+        let name = self.parse_identifier()?;
+        if name == "take" || name == "host" {
+            self.parse_spacing()?;
+            self.expect('(')?;
+            self.parse_spacing()?;
+            let arg = self.parse_identifier()?;
+            self.parse_spacing()?;
+            self.expect(')')?;
+            let span = self.span_from(start);
+            return Ok(match name.as_str() {
+                "take" => ast::Take::new_expr(span, arg),
+                _ => ast::Host::new_expr(span, arg),
+            });
+        }
+        let span = self.span_from(start);
+        if let Some(kind) = ast::IntKind::from_name(&name) {
+            return Ok(ast::Int::new_expr(span, kind));
+        }
+        // names shaped like integer terminals are never classes,
+        // e.g.: `%u24le`, and `%whitespace` starts a definition
+        let mut chars = name.chars();
+        let int_like = matches!(chars.next(), Some('u' | 'i'))
+            && chars.next().is_some_and(|c| c.is_ascii_digit());
+        if int_like || name == "whitespace" {
+            return Err(self.err(format!("unknown built-in terminal `%{}`", name)));
+        }
+        Ok(ast::NamedClass::new_expr(span, name))
+    }
+
     // GR: Spacing <- (Space/ Comment)*
     fn parse_spacing(&mut self) -> Result<(), Error> {
         self.zero_or_more(|p| p.choice(vec![|p| p.parse_space(), |p| p.parse_comment()]))?;
@@ -503,6 +886,7 @@ impl Parser {
         for func in &funcs {
             match func(self) {
                 Ok(o) => return Ok(o),
+                Err(e @ Error::Fatal(..)) => return Err(e),
                 Err(_) => {
                     self.cursor = cursor;
                     self.column = column;
@@ -514,10 +898,13 @@ impl Parser {
     }
 
     fn not<T>(&mut self, func: ParseFn<T>) -> Result<(), Error> {
-        let cursor = self.cursor;
+        let (cursor, line, column) = (self.cursor, self.line, self.column);
         let out = func(self);
         self.cursor = cursor;
+        self.line = line;
+        self.column = column;
         match out {
+            Err(e @ Error::Fatal(..)) => Err(e),
             Err(_) => Ok(()),
             Ok(_) => Err(self.err("NOT".to_string())),
         }
@@ -539,6 +926,7 @@ impl Parser {
                 Ok(ch) => output.push(ch),
                 Err(e) => match e {
                     Error::BacktrackError(..) => break,
+                    Error::Fatal(..) => return Err(e),
                 },
             }
         }
@@ -563,8 +951,16 @@ impl Parser {
     /// Tries to match each character within `expected` against the
     /// input source.  It starts from where the read cursor currently is.
     fn expect_str(&mut self, expected: &'static str) -> Result<&'static str, Error> {
+        let (cursor, line, column) = (self.cursor, self.line, self.column);
         for c in expected.chars() {
-            self.expect(c)?;
+            if let Err(e) = self.expect(c) {
+                // strings either match as a whole or don't consume
+                // any input, so `@import` and `@sync` can share `@`
+                self.cursor = cursor;
+                self.line = line;
+                self.column = column;
+                return Err(e);
+            }
         }
         Ok(expected)
     }
@@ -616,8 +1012,8 @@ impl Parser {
             self.column = 0;
             self.line += 1;
         }
-        if self.cursor > self.ffp {
-            self.ffp = self.cursor;
+        if self.cursor > self.ffp.offset {
+            self.ffp = self.pos();
         }
         Ok(())
     }
@@ -632,7 +1028,13 @@ impl Parser {
 
     /// produce a backtracking error with `message` attached to it
     fn err(&mut self, msg: String) -> Error {
-        Error::BacktrackError(self.ffp, msg)
+        Error::BacktrackError(self.ffp.clone(), msg)
+    }
+
+    /// Error reported where the cursor is rather than at the farthest
+    /// failure position, and that isn't backtracked over
+    fn fatal(&mut self, msg: String) -> Error {
+        Error::Fatal(self.pos(), msg)
     }
 }
 
@@ -648,6 +1050,31 @@ mod tests {
             ("A <- 'a'\n", "A <- \"a\"\n"),
             ("A <- [a-z]\n", "A <- [a-z]\n"),
             ("A <- 'a' / [b-e]\n", "A <- (\"a\" / [b-e])\n"),
+            ("A <- [^a-z\\n]\n", "A <- [^a-z\\n]\n"),
+            ("A <- [\\^a]\n", "A <- [\\^a]\n"),
+            ("A <- [a^]\n", "A <- [a^]\n"),
+            ("@sync(';') A <- 'a'\n", "@sync(\";\") A <- \"a\"\n"),
+            (
+                "@allow(unused_rule) @allow(a, b)\n@sync(';') A <- 'a'\n",
+                "@allow(unused_rule, a, b) @sync(\";\") A <- \"a\"\n",
+            ),
+            (
+                "label eq = 'missing \"=\"'\nA <- 'a' '='^eq\n",
+                "label eq = \"missing \\\"=\\\"\"\n\nA <- \"a\" \"=\"^eq\n",
+            ),
+            ("label <- 'a'\n", "label <- \"a\"\n"),
+            (
+                "label semi = 'missing ;' recover (!';' .)* ';'\nlabel eq = 'missing ='\nrecover <- 'a' ';'^semi\n",
+                "label semi = \"missing ;\" recover (!\";\" .)* \";\"\nlabel eq = \"missing =\"\n\nrecover <- \"a\" \";\"^semi\n",
+            ),
+            (
+                "@capture(string) A <- 'a'\n@capture(node) B <- 'b'\n",
+                "@capture(string) A <- \"a\"\nB <- \"b\"\n",
+            ),
+            (
+                "@version '1.2'\nA <- 'a'\n",
+                "@version \"1.2\"\n\nA <- \"a\"\n",
+            ),
         ];
         for (input, expected) in &tests {
             let output = parse(input);
@@ -796,4 +1223,177 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn ignore_case() {
+        let input = "A <- 'a'i [a-z]i 'b' [c] 'c' i";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- \"a\"i [a-z]i \"b\" [c] \"c\" i",
+            grammar.definitions["A"].to_string()
+        );
+    }
+
+    #[test]
+    fn byte_escapes() {
+        let input = r"A <- '\x7fELF' [\x00-\x1f\xff] '\x41' 'x'";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            r#"A <- "\x7fELF" [\x00-\x1f\xff] "A" "x""#,
+            grammar.definitions["A"].to_string()
+        );
+        assert!(Parser::new(r"A <- '\x7'").parse_grammar().is_err());
+    }
+
+    #[test]
+    fn int_terminals() {
+        let input = "A <- %u8 %u16le %u64be+ %varint";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- %u8 %u16le %u64be+ %varint",
+            grammar.definitions["A"].to_string()
+        );
+        assert!(Parser::new("A <- %u24le").parse_grammar().is_err());
+    }
+
+    #[test]
+    fn repetition_counts() {
+        let input = "A <- [0-9a-f]{4} 'x'{ 1 , 3 } B{2,} {x: 'y'}{0,1}";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- [0-9a-f]{4} \"x\"{1,3} B{2,} (x {\"y\"}){0,1}",
+            grammar.definitions["A"].to_string()
+        );
+        let ast::Expression::Sequence(seq) = &grammar.definitions["A"].expr else {
+            panic!("expected a sequence");
+        };
+        let counts: Vec<_> = seq
+            .items
+            .iter()
+            .map(|e| match e {
+                ast::Expression::Repeat(r) => (r.min, r.max),
+                e => panic!("expected a repetition, got {}", e),
+            })
+            .collect();
+        assert_eq!(
+            vec![(4, Some(4)), (1, Some(3)), (2, None), (0, Some(1))],
+            counts
+        );
+
+        // lists can't start with a number, so `{` after an expression
+        // still starts a list when it doesn't hold counts
+        let grammar = Parser::new("A <- B {C}").parse_grammar().unwrap();
+        assert_eq!("A <- B [C]", grammar.definitions["A"].to_string());
+        assert!(Parser::new("A <- 'a'{,1}").parse_grammar().is_err());
+
+        // errors within counts aren't backtracked over by the choices
+        // the repetition is parsed within
+        let err = Parser::new("A <- 'a'{3,1}").parse_grammar().unwrap_err();
+        assert_eq!(
+            "Syntax Error: 0:13: repetition can't match at least 3 times and at most 1",
+            err.to_string()
+        );
+        let err = Parser::new("A <- 'a'{99999999999999999999999}")
+            .parse_grammar()
+            .unwrap_err();
+        assert_eq!(
+            "Syntax Error: 0:32: number `99999999999999999999999` is too large",
+            err.to_string()
+        );
+        let err = Parser::new("A <- 'a' ('b' / 'c'{2,1})")
+            .parse_grammar()
+            .unwrap_err();
+        assert_eq!(
+            "Syntax Error: 0:24: repetition can't match at least 2 times and at most 1",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn take() {
+        let input = "A <- Length %take( Length ) %take(B)*";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- Length %take(Length) %take(B)*",
+            grammar.definitions["A"].to_string()
+        );
+        assert!(Parser::new("A <- %take").parse_grammar().is_err());
+    }
+
+    #[test]
+    fn named_classes() {
+        let input = "A <- %greek+ !%hostname_char";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- %greek+ !%hostname_char",
+            grammar.definitions["A"].to_string()
+        );
+        assert!(Parser::new("A <- %i32").parse_grammar().is_err());
+    }
+
+    #[test]
+    fn properties() {
+        let input = "A <- [\\p{L}\\P{ Script = Greek }_]+ [^\\p{Nd}]";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- [\\p{L}\\P{ Script = Greek }_]+ [^\\p{Nd}]",
+            grammar.definitions["A"].to_string()
+        );
+        assert!(Parser::new("A <- [\\p{}]").parse_grammar().is_err());
+        assert!(Parser::new("A <- [\\pL]").parse_grammar().is_err());
+    }
+
+    #[test]
+    fn host() {
+        let input = "A <- Name &%host(is_type) %host( declare )";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            "A <- Name &%host(is_type) %host(declare)",
+            grammar.definitions["A"].to_string()
+        );
+    }
+
+    #[test]
+    fn imports() {
+        let input = "@import A, B from \"./a.peg\"\n@import \"./c.peg\"\nD <- A B C";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        let imports: Vec<_> = grammar.imports.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            vec!["@import A, B from \"./a.peg\"", "@import \"./c.peg\""],
+            imports
+        );
+        assert!(grammar.imports[1].names.is_empty());
+    }
+
+    #[test]
+    fn comments() {
+        let input = "@comments '//' eol, \"/*\" '*/'\nA <- 'a'";
+        let grammar = Parser::new(input).parse_grammar().unwrap();
+        assert_eq!(
+            vec![
+                ("//".to_string(), None),
+                ("/*".to_string(), Some("*/".to_string())),
+            ],
+            grammar
+                .comments
+                .iter()
+                .map(|c| (c.open.clone(), c.close.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "@comments \"//\" eol, \"/*\" \"*/\"\n\nA <- \"a\"\n",
+            grammar.to_string()
+        );
+        assert!(Parser::new("@comments '#' eolx\nA <- 'a'")
+            .parse_grammar()
+            .is_err());
+    }
+
+    #[test]
+    fn deeply_nested_expression() {
+        let depth = 5_000;
+        let input = format!("A <- {}'a'{}", "(".repeat(depth), ")".repeat(depth));
+        let grammar = Parser::new(&input).parse_grammar().unwrap();
+        assert_eq!("A <- \"a\"", grammar.definitions["A"].to_string());
+    }
 }
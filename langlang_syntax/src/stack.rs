@@ -0,0 +1,29 @@
+// stack.rs --- room for the recursion over deeply nested expressions
+//
+// The parser and the passes over the AST recurse once per level of
+// nesting, so machine generated grammars with very deep expressions
+// would overflow the call stack.  Recursive functions call `guard`,
+// which moves the rest of the recursion onto a new stack segment once
+// the current one is about to run out.
+//
+
+// how close to the end of the stack the recursion can get
+const RED_ZONE: usize = 128 * 1024;
+
+// size of each new stack segment
+const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Run `f` making sure there's room in the stack for it
+pub fn guard<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(RED_ZONE, SEGMENT_SIZE, f)
+}
+
+/// Whether recursing any deeper would need a new stack segment
+pub(crate) fn is_low() -> bool {
+    stacker::remaining_stack().is_some_and(|remaining| remaining < RED_ZONE)
+}
+
+/// Drop `value` within a new stack segment
+pub(crate) fn drop_deep<T>(value: T) {
+    stacker::grow(SEGMENT_SIZE, move || drop(value))
+}
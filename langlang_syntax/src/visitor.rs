@@ -1,4 +1,5 @@
 use crate::ast::*;
+use crate::stack;
 
 pub trait Visitor<'ast>: Sized {
     fn visit_grammar(&mut self, n: &'ast Grammar) {
@@ -49,6 +50,10 @@ pub trait Visitor<'ast>: Sized {
         walk_one_or_more(self, n);
     }
 
+    fn visit_repeat(&mut self, n: &'ast Repeat) {
+        walk_repeat(self, n);
+    }
+
     fn visit_list(&mut self, n: &'ast List) {
         walk_list(self, n);
     }
@@ -83,6 +88,16 @@ pub trait Visitor<'ast>: Sized {
 
     fn visit_any(&mut self, _: &'ast Any) {}
 
+    fn visit_int(&mut self, _: &'ast Int) {}
+
+    fn visit_take(&mut self, _: &'ast Take) {}
+
+    fn visit_host(&mut self, _: &'ast Host) {}
+
+    fn visit_named_class(&mut self, _: &'ast NamedClass) {}
+
+    fn visit_property(&mut self, _: &'ast Property) {}
+
     fn visit_empty(&mut self, n: &'ast Empty) {
         walk_empty(self, n);
     }
@@ -106,7 +121,7 @@ pub fn walk_definition<'a, V: Visitor<'a>>(visitor: &mut V, d: &'a Definition) {
 }
 
 pub fn walk_expression<'a, V: Visitor<'a>>(visitor: &mut V, e: &'a Expression) {
-    match e {
+    stack::guard(|| match e {
         Expression::Sequence(n) => visitor.visit_sequence(n),
         Expression::Choice(n) => visitor.visit_choice(n),
         Expression::Lex(n) => visitor.visit_lex(n),
@@ -115,6 +130,7 @@ pub fn walk_expression<'a, V: Visitor<'a>>(visitor: &mut V, e: &'a Expression) {
         Expression::Optional(n) => visitor.visit_optional(n),
         Expression::ZeroOrMore(n) => visitor.visit_zero_or_more(n),
         Expression::OneOrMore(n) => visitor.visit_one_or_more(n),
+        Expression::Repeat(n) => visitor.visit_repeat(n),
         Expression::Precedence(n) => visitor.visit_precedence(n),
         Expression::Label(n) => visitor.visit_label(n),
         Expression::List(n) => visitor.visit_list(n),
@@ -122,7 +138,7 @@ pub fn walk_expression<'a, V: Visitor<'a>>(visitor: &mut V, e: &'a Expression) {
         Expression::Identifier(n) => visitor.visit_identifier(n),
         Expression::Literal(n) => visitor.visit_literal(n),
         Expression::Empty(n) => visitor.visit_empty(n),
-    }
+    })
 }
 
 pub fn walk_sequence<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a Sequence) {
@@ -161,6 +177,10 @@ pub fn walk_one_or_more<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a OneOrMore) {
     visitor.visit_expression(&n.expr)
 }
 
+pub fn walk_repeat<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a Repeat) {
+    visitor.visit_expression(&n.expr)
+}
+
 pub fn walk_list<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a List) {
     for i in &n.items {
         visitor.visit_expression(i)
@@ -188,6 +208,11 @@ pub fn walk_literal<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a Literal) {
         Literal::Range(v) => visitor.visit_range(v),
         Literal::Char(v) => visitor.visit_char(v),
         Literal::Any(v) => visitor.visit_any(v),
+        Literal::Int(v) => visitor.visit_int(v),
+        Literal::Take(v) => visitor.visit_take(v),
+        Literal::Host(v) => visitor.visit_host(v),
+        Literal::NamedClass(v) => visitor.visit_named_class(v),
+        Literal::Property(v) => visitor.visit_property(v),
     }
 }
 
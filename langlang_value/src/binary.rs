@@ -0,0 +1,450 @@
+// binary.rs --- compact binary encoding of values
+//
+// Each value is encoded as a record made of a one byte tag, the length
+// of the payload as a little endian `u32`, and the payload itself.
+// Since every record carries its length, a reader can jump over the
+// children of a node or a list without decoding them, which is what
+// `LazyValue` does to navigate the tree without materializing it.
+//
+// Payloads start with the span of the value, followed by what's
+// specific to each type.  Numbers within payloads are encoded as
+// unsigned LEB128, and text as its length followed by UTF-8 bytes:
+//
+//     Char:   span, code point
+//     String: span, text
+//     List:   span, number of items, items
+//     Node:   span, rule ID + 1 (0 if none), name, number of items, items
+//     Error:  span, label, 0 or 1 followed by the message
+//
+use crate::source_map::{Position, Span};
+use crate::value::{self, RuleId, Value};
+
+const TAG_CHAR: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_LIST: u8 = 2;
+const TAG_NODE: u8 = 3;
+const TAG_ERROR: u8 = 4;
+
+// size of the tag and of the length that precede each payload
+const HEADER_SIZE: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // Input ended before the value was complete (offset)
+    Truncated(usize),
+    // Record type isn't known (offset, tag)
+    InvalidTag(usize, u8),
+    // Text isn't valid UTF-8 or isn't a valid char (offset)
+    InvalidText(usize),
+    // Number doesn't fit within the target type (offset)
+    Overflow(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Binary Error")?;
+        match self {
+            Error::Truncated(offset) => write!(f, "[Truncated]: {}", offset),
+            Error::InvalidTag(offset, tag) => write!(f, "[InvalidTag]: {}: {}", offset, tag),
+            Error::InvalidText(offset) => write!(f, "[InvalidText]: {}", offset),
+            Error::Overflow(offset) => write!(f, "[Overflow]: {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Encode `value` and all its children
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = vec![];
+    encode_into(value, &mut output);
+    output
+}
+
+/// Decode the whole tree out of the output of `encode`
+pub fn decode(bytes: &[u8]) -> Result<Value, Error> {
+    LazyValue::new(bytes)?.to_value()
+}
+
+fn encode_into(value: &Value, output: &mut Vec<u8>) {
+    let start = output.len();
+    output.extend_from_slice(&[0; HEADER_SIZE]);
+    let tag = match value {
+        Value::Char(v) => {
+            write_span(&v.span, output);
+            write_uint(v.value as u64, output);
+            TAG_CHAR
+        }
+        Value::String(v) => {
+            write_span(&v.span, output);
+            write_text(&v.value, output);
+            TAG_STRING
+        }
+        Value::List(v) => {
+            write_span(&v.span, output);
+            write_uint(v.values.len() as u64, output);
+            v.values.iter().for_each(|i| encode_into(i, output));
+            TAG_LIST
+        }
+        Value::Node(v) => {
+            write_span(&v.span, output);
+            write_uint(v.rule.map(|r| r.0 as u64 + 1).unwrap_or(0), output);
+            write_text(&v.name, output);
+            write_uint(v.items.len() as u64, output);
+            v.items.iter().for_each(|i| encode_into(i, output));
+            TAG_NODE
+        }
+        Value::Error(v) => {
+            write_span(&v.span, output);
+            write_text(&v.label, output);
+            match &v.message {
+                Some(message) => {
+                    output.push(1);
+                    write_text(message, output);
+                }
+                None => output.push(0),
+            }
+            TAG_ERROR
+        }
+    };
+    // backpatch the header now that the size of the payload is known
+    let size = (output.len() - start - HEADER_SIZE) as u32;
+    output[start] = tag;
+    output[start + 1..start + HEADER_SIZE].copy_from_slice(&size.to_le_bytes());
+}
+
+fn write_span(span: &Span, output: &mut Vec<u8>) {
+    for p in [&span.start, &span.end] {
+        write_uint(p.offset as u64, output);
+        write_uint(p.line as u64, output);
+        write_uint(p.column as u64, output);
+    }
+}
+
+fn write_text(text: &str, output: &mut Vec<u8>) {
+    write_uint(text.len() as u64, output);
+    output.extend_from_slice(text.as_bytes());
+}
+
+fn write_uint(mut n: u64, output: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            output.push(byte);
+            return;
+        }
+        output.push(byte | 0x80);
+    }
+}
+
+/// The type of an encoded value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Char,
+    String,
+    List,
+    Node,
+    Error,
+}
+
+/// LazyValue is a view over a single encoded value.  Reading its
+/// fields only decodes what's needed to reach them, and children are
+/// views over slices of the same buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct LazyValue<'a> {
+    // the buffer the value was read from, for reporting offsets
+    buffer: &'a [u8],
+    // offset of the header of this value within the buffer
+    offset: usize,
+    kind: Kind,
+    // payload of this value, without its header
+    payload: &'a [u8],
+}
+
+impl<'a> LazyValue<'a> {
+    /// View the value encoded at the start of `bytes`
+    pub fn new(bytes: &'a [u8]) -> Result<Self, Error> {
+        Self::at(bytes, 0)
+    }
+
+    fn at(buffer: &'a [u8], offset: usize) -> Result<Self, Error> {
+        let header = buffer
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(Error::Truncated(offset))?;
+        let kind = match header[0] {
+            TAG_CHAR => Kind::Char,
+            TAG_STRING => Kind::String,
+            TAG_LIST => Kind::List,
+            TAG_NODE => Kind::Node,
+            TAG_ERROR => Kind::Error,
+            tag => return Err(Error::InvalidTag(offset, tag)),
+        };
+        let size = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let start = offset + HEADER_SIZE;
+        let payload = buffer
+            .get(start..start + size)
+            .ok_or(Error::Truncated(buffer.len()))?;
+        Ok(Self {
+            buffer,
+            offset,
+            kind,
+            payload,
+        })
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    /// Number of bytes taken by the value, including its header
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE + self.payload.len()
+    }
+
+    pub fn span(&self) -> Result<Span, Error> {
+        self.reader().read_span()
+    }
+
+    /// Name of the production that captured a node
+    pub fn name(&self) -> Result<Option<&'a str>, Error> {
+        if self.kind != Kind::Node {
+            return Ok(None);
+        }
+        let mut r = self.reader();
+        r.read_span()?;
+        r.read_uint()?;
+        Ok(Some(r.read_text()?))
+    }
+
+    /// ID of the production that captured a node
+    pub fn rule(&self) -> Result<Option<RuleId>, Error> {
+        if self.kind != Kind::Node {
+            return Ok(None);
+        }
+        let mut r = self.reader();
+        r.read_span()?;
+        match r.read_uint()? {
+            0 => Ok(None),
+            id => Ok(Some(RuleId(r.to_u32(id - 1)?))),
+        }
+    }
+
+    /// Text of strings, or label of errors
+    pub fn text(&self) -> Result<Option<&'a str>, Error> {
+        let mut r = self.reader();
+        r.read_span()?;
+        match self.kind {
+            Kind::String | Kind::Error => Ok(Some(r.read_text()?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Value of chars
+    pub fn char(&self) -> Result<Option<char>, Error> {
+        if self.kind != Kind::Char {
+            return Ok(None);
+        }
+        let mut r = self.reader();
+        r.read_span()?;
+        Ok(Some(r.read_char()?))
+    }
+
+    /// Number of items within lists and nodes
+    pub fn len(&self) -> Result<usize, Error> {
+        match self.items_reader()? {
+            Some((_, count)) => Ok(count),
+            None => Ok(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Items of lists and nodes.  Items that precede the ones being
+    /// read are skipped over by their length, without being decoded.
+    pub fn children(&self) -> Result<Children<'a>, Error> {
+        let (offset, remaining) = match self.items_reader()? {
+            Some((r, count)) => (r.offset(), count),
+            None => (self.offset + self.encoded_len(), 0),
+        };
+        Ok(Children {
+            buffer: self.buffer,
+            offset,
+            remaining,
+        })
+    }
+
+    /// Item at position `index` within lists and nodes
+    pub fn child(&self, index: usize) -> Result<Option<LazyValue<'a>>, Error> {
+        self.children()?.nth(index).transpose()
+    }
+
+    /// Decode the value and all its children
+    pub fn to_value(&self) -> Result<Value, Error> {
+        let mut r = self.reader();
+        let span = r.read_span()?;
+        Ok(match self.kind {
+            Kind::Char => value::Char::new_val(span, r.read_char()?),
+            Kind::String => value::String::new_val(span, r.read_text()?.to_string()),
+            Kind::List => value::List::new_val(span, self.children_values()?),
+            Kind::Node => {
+                let items = self.children_values()?;
+                let name = self.name()?.unwrap_or_default().to_string();
+                match self.rule()? {
+                    Some(rule) => value::Node::new_val_with_rule(span, rule, name, items),
+                    None => value::Node::new_val(span, name, items),
+                }
+            }
+            Kind::Error => {
+                let label = r.read_text()?.to_string();
+                let message = match r.read_byte()? {
+                    0 => None,
+                    _ => Some(r.read_text()?.to_string()),
+                };
+                value::Error::new_val(span, label, message)
+            }
+        })
+    }
+
+    fn children_values(&self) -> Result<Vec<Value>, Error> {
+        self.children()?.map(|c| c?.to_value()).collect()
+    }
+
+    /// Reader positioned at the first item of lists and nodes, along
+    /// with the number of items
+    fn items_reader(&self) -> Result<Option<(Reader<'a>, usize)>, Error> {
+        let mut r = self.reader();
+        r.read_span()?;
+        match self.kind {
+            Kind::List => {}
+            Kind::Node => {
+                r.read_uint()?;
+                r.read_text()?;
+            }
+            _ => return Ok(None),
+        }
+        let count = r.read_uint()?;
+        Ok(Some((r, r.to_usize(count)?)))
+    }
+
+    fn reader(&self) -> Reader<'a> {
+        Reader {
+            buffer: self.buffer,
+            cursor: self.offset + HEADER_SIZE,
+            end: self.offset + self.encoded_len(),
+        }
+    }
+}
+
+/// Iterator over the items of an encoded list or node
+pub struct Children<'a> {
+    buffer: &'a [u8],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Result<LazyValue<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        match LazyValue::at(self.buffer, self.offset) {
+            Ok(v) => {
+                self.offset += v.encoded_len();
+                Some(Ok(v))
+            }
+            Err(e) => {
+                self.remaining = 0;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// Reads fields out of a payload.  Reading past the end of the payload
+// is reported as truncated input.
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    buffer: &'a [u8],
+    cursor: usize,
+    end: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn offset(&self) -> usize {
+        self.cursor
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        if self.cursor >= self.end {
+            return Err(Error::Truncated(self.cursor));
+        }
+        let byte = self.buffer[self.cursor];
+        self.cursor += 1;
+        Ok(byte)
+    }
+
+    fn read_uint(&mut self) -> Result<u64, Error> {
+        let start = self.cursor;
+        let mut n: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            if shift >= 64 {
+                return Err(Error::Overflow(start));
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_text(&mut self) -> Result<&'a str, Error> {
+        let len = self.read_uint()?;
+        let len = self.to_usize(len)?;
+        let start = self.cursor;
+        if start + len > self.end {
+            return Err(Error::Truncated(self.end));
+        }
+        self.cursor += len;
+        std::str::from_utf8(&self.buffer[start..start + len]).map_err(|_| Error::InvalidText(start))
+    }
+
+    fn read_char(&mut self) -> Result<char, Error> {
+        let start = self.cursor;
+        let n = self.read_uint()?;
+        char::from_u32(self.to_u32(n)?).ok_or(Error::InvalidText(start))
+    }
+
+    fn read_span(&mut self) -> Result<Span, Error> {
+        let start = self.read_position()?;
+        let end = self.read_position()?;
+        Ok(Span::new(start, end))
+    }
+
+    fn read_position(&mut self) -> Result<Position, Error> {
+        let offset = self.read_uint()?;
+        let line = self.read_uint()?;
+        let column = self.read_uint()?;
+        Ok(Position::new(
+            self.to_usize(offset)?,
+            self.to_usize(line)?,
+            self.to_usize(column)?,
+        ))
+    }
+
+    fn to_usize(self, n: u64) -> Result<usize, Error> {
+        usize::try_from(n).map_err(|_| Error::Overflow(self.cursor))
+    }
+
+    fn to_u32(self, n: u64) -> Result<u32, Error> {
+        u32::try_from(n).map_err(|_| Error::Overflow(self.cursor))
+    }
+}
@@ -0,0 +1,311 @@
+// extract.rs --- convert values into typed structures
+//
+// Types implement `FromValue` to be built out of the values a grammar
+// captures, so programs can work with their own AST instead of
+// matching on the generic tree.  `node` checks a value is a node with
+// the expected name and returns its `Items`, which hand its items
+// out one at a time, converted to whatever type is asked for.  Each
+// conversion that doesn't find the shape it expects fails with the
+// span of the value it was looking at and the path to it from where
+// the conversion started, e.g.:
+//
+//     struct Assign { name: String, value: Expr }
+//
+//     impl FromValue for Assign {
+//         fn from_value(value: &Value) -> Result<Self, Error> {
+//             let mut items = extract::node(value, "Assign")?;
+//             let assign = Assign { name: items.field()?, value: items.field()? };
+//             items.finish()?;
+//             Ok(assign)
+//         }
+//     }
+//
+use std::sync::Arc;
+
+use crate::source_map::Span;
+use crate::value::{Node, Value};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // Value doesn't have the expected shape (expected, found, span)
+    Mismatch(String, String, Span),
+    // Node has fewer items than the ones asked for (node, index, span)
+    MissingItem(String, usize, Span),
+    // Node has items left after all its fields were taken (node, index, span)
+    UnexpectedItem(String, usize, Span),
+    // Value has the expected shape but can't be converted (message, span)
+    Invalid(String, Span),
+    // Item of a node or of a list can't be converted (where the item
+    // is within its container, e.g.: `Assign[2]`, error)
+    Within(String, Box<Error>),
+}
+
+impl Error {
+    /// Path from the value conversion started at to the one that
+    /// failed, e.g.: `Assigns[0]/Assign[2]` for the third item of the
+    /// first node within `Assigns`.  It's empty if the value
+    /// conversion started at is the one that failed.
+    pub fn path(&self) -> String {
+        let mut path = vec![];
+        let mut error = self;
+        while let Error::Within(at, inner) = error {
+            path.push(at.as_str());
+            error = inner;
+        }
+        path.join("/")
+    }
+
+    /// Error of the value that failed to be converted, without the
+    /// path to it
+    pub fn cause(&self) -> &Error {
+        match self {
+            Error::Within(_, inner) => inner.cause(),
+            error => error,
+        }
+    }
+
+    fn within(self, at: impl FnOnce() -> String) -> Error {
+        Error::Within(at(), Box::new(self))
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Error::Within(..) = self {
+            return write!(f, "{} in {}", self.cause(), self.path());
+        }
+        write!(f, "Extract Error")?;
+        match self {
+            Error::Mismatch(expected, found, span) => {
+                write!(
+                    f,
+                    "[Mismatch]: expected {}, found {} at {}",
+                    expected, found, span
+                )
+            }
+            Error::MissingItem(node, index, span) => {
+                write!(
+                    f,
+                    "[MissingItem]: {} has no item {} at {}",
+                    node, index, span
+                )
+            }
+            Error::UnexpectedItem(node, index, span) => {
+                write!(f, "[UnexpectedItem]: {} item {} at {}", node, index, span)
+            }
+            Error::Invalid(msg, span) => write!(f, "[Invalid]: {} at {}", msg, span),
+            Error::Within(..) => unreachable!(),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Types that can be built out of a value
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, Error>;
+}
+
+/// Convert `value` into `T`
+pub fn from_value<T: FromValue>(value: &Value) -> Result<T, Error> {
+    T::from_value(value)
+}
+
+/// Check that `value` is a node named `name` and return its items
+pub fn node<'a>(value: &'a Value, name: &str) -> Result<Items<'a>, Error> {
+    match value {
+        Value::Node(n) if n.name == name => Ok(Items { node: n, next: 0 }),
+        _ => Err(mismatch(&format!("node {}", name), value)),
+    }
+}
+
+/// Items of a node, handed out in order by `Items::field`
+pub struct Items<'a> {
+    node: &'a Node,
+    // index of the item `field` returns
+    next: usize,
+}
+
+impl<'a> Items<'a> {
+    /// Name of the node the items belong to
+    pub fn name(&self) -> &'a str {
+        &self.node.name
+    }
+
+    /// Span of the node the items belong to
+    pub fn span(&self) -> &'a Span {
+        &self.node.span
+    }
+
+    /// Next item, without converting it
+    pub fn field_value(&mut self) -> Result<&'a Value, Error> {
+        let item = self.node.items.get(self.next).ok_or_else(|| {
+            Error::MissingItem(self.node.name.clone(), self.next, self.node.span.clone())
+        })?;
+        self.next += 1;
+        Ok(item)
+    }
+
+    /// Convert the next item into `T`
+    pub fn field<T: FromValue>(&mut self) -> Result<T, Error> {
+        let item = self.field_value()?;
+        T::from_value(item).map_err(|e| e.within(|| self.at(self.next - 1)))
+    }
+
+    /// Convert the next item into `T` if it's a node named `name`,
+    /// for items that a grammar only captures sometimes
+    pub fn optional<T: FromValue>(&mut self, name: &str) -> Result<Option<T>, Error> {
+        match self.node.items.get(self.next) {
+            Some(Value::Node(n)) if n.name == name => self.field().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Convert all the items left into `T`
+    pub fn rest<T: FromValue>(&mut self) -> Result<Vec<T>, Error> {
+        let start = self.next.min(self.node.items.len());
+        self.next = self.node.items.len();
+        self.node.items[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, item)| T::from_value(item).map_err(|e| e.within(|| self.at(start + i))))
+            .collect()
+    }
+
+    // where the item at `index` is within the node
+    fn at(&self, index: usize) -> String {
+        format!("{}[{}]", self.node.name, index)
+    }
+
+    /// Fail if any item wasn't taken
+    pub fn finish(self) -> Result<(), Error> {
+        match self.node.items.get(self.next) {
+            None => Ok(()),
+            Some(item) => Err(Error::UnexpectedItem(
+                self.node.name.clone(),
+                self.next,
+                item.span(),
+            )),
+        }
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for Span {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        Ok(value.span())
+    }
+}
+
+impl<T: FromValue> FromValue for Box<T> {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        T::from_value(value).map(Box::new)
+    }
+}
+
+impl FromValue for char {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Char(c) => Ok(c.value),
+            _ => Err(mismatch("char", value)),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::Char(c) => Ok(c.value.to_string()),
+            Value::String(s) => Ok(s.value.to_string()),
+            _ => Err(mismatch("string", value)),
+        }
+    }
+}
+
+impl FromValue for Arc<str> {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::String(s) => Ok(s.value.clone()),
+            _ => String::from_value(value).map(Arc::from),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        match value {
+            Value::List(l) => l
+                .values
+                .iter()
+                .enumerate()
+                .map(|(i, item)| T::from_value(item).map_err(|e| e.within(|| format!("[{}]", i))))
+                .collect(),
+            _ => Err(mismatch("list", value)),
+        }
+    }
+}
+
+// numbers are parsed out of the text of chars and strings
+macro_rules! from_text {
+    ($($t:ty),*) => {$(
+        impl FromValue for $t {
+            fn from_value(value: &Value) -> Result<Self, Error> {
+                let text = String::from_value(value)?;
+                text.parse().map_err(|e| {
+                    let msg = format!("{:?} isn't a valid {}: {}", text, stringify!($t), e);
+                    Error::Invalid(msg, value.span())
+                })
+            }
+        }
+    )*};
+}
+
+from_text!(i32, i64, u32, u64, usize, f64, bool);
+
+// `TryFrom` is implemented for the types std defines with `FromValue`,
+// so conversions can also be written as `String::try_from(value)?`
+macro_rules! try_from {
+    ($($t:ty),*) => {$(
+        impl TryFrom<&Value> for $t {
+            type Error = Error;
+
+            fn try_from(value: &Value) -> Result<Self, Error> {
+                <$t>::from_value(value)
+            }
+        }
+    )*};
+}
+
+try_from!(String, char, i32, i64, u32, u64, usize, f64, bool);
+
+impl<'a, T: TryFrom<&'a Value, Error = Error>> TryFrom<&'a Value> for Vec<T> {
+    type Error = Error;
+
+    fn try_from(value: &'a Value) -> Result<Self, Error> {
+        match value {
+            Value::List(l) => l
+                .values
+                .iter()
+                .enumerate()
+                .map(|(i, item)| T::try_from(item).map_err(|e| e.within(|| format!("[{}]", i))))
+                .collect(),
+            _ => Err(mismatch("list", value)),
+        }
+    }
+}
+
+fn mismatch(expected: &str, found: &Value) -> Error {
+    let description = match found {
+        Value::Char(_) => "char".to_string(),
+        Value::String(_) => "string".to_string(),
+        Value::List(_) => "list".to_string(),
+        Value::Node(n) => format!("node {}", n.name),
+        Value::Error(e) => format!("error {}", e.label),
+    };
+    Error::Mismatch(expected.to_string(), description, found.span())
+}
@@ -1,7 +1,9 @@
+use crate::source_map::{Position, Span};
 use crate::value::{self, Value};
 use crate::visitor::{walk_list, walk_node, Visitor};
 
-// The raw formater uses the host language's formatting function
+// The raw formater uses the `Debug` implementation of values, which
+// prints each one with its type and span, one item per line
 pub fn raw(value: &Value) -> String {
     format!("{:#?}", value)
 }
@@ -15,6 +17,7 @@ pub fn compact(value: &Value) -> String {
 
 // The indented formatter will print out values spanning multiple
 // lines if container objects like lists or nodes are present
+#[cfg(feature = "formats")]
 pub fn indented(value: &Value) -> String {
     let mut f = IndentedFormatter::default();
     f.visit_value(value);
@@ -23,6 +26,7 @@ pub fn indented(value: &Value) -> String {
 
 // The html formatter will wrapp all node objects around a span tag
 // with containing a class attribute that's named after the node.
+#[cfg(feature = "formats")]
 pub fn html(value: &Value) -> String {
     let mut s = String::new();
     match value {
@@ -83,12 +87,14 @@ impl<'a> Visitor<'a> for CompactFormatter {
     }
 }
 
+#[cfg(feature = "formats")]
 #[derive(Default)]
 struct IndentedFormatter {
     output: String,
     depth: usize,
 }
 
+#[cfg(feature = "formats")]
 impl IndentedFormatter {
     fn indent(&mut self) {
         self.depth += 1
@@ -110,6 +116,7 @@ impl IndentedFormatter {
     }
 }
 
+#[cfg(feature = "formats")]
 impl<'a> Visitor<'a> for IndentedFormatter {
     fn visit_char(&mut self, n: &'a value::Char) {
         self.writes(&format!("'{}'\n", n.value));
@@ -143,6 +150,412 @@ impl<'a> Visitor<'a> for IndentedFormatter {
             self.output.push_str(": ");
             self.output.push_str(m);
         }
+        self.output.push_str("}\n");
+    }
+}
+
+// The json formatter prints values out as a single line of JSON.
+// Nodes become objects with their name and items, lists become
+// arrays and both characters and strings become JSON strings
+#[cfg(feature = "formats")]
+pub fn json(value: &Value) -> String {
+    let mut f = JsonFormatter::default();
+    f.visit_value(value);
+    f.output
+}
+
+#[cfg(feature = "formats")]
+#[derive(Default)]
+struct JsonFormatter {
+    output: String,
+}
+
+#[cfg(feature = "formats")]
+impl JsonFormatter {
+    fn write_string(&mut self, s: &str) {
+        write_json_string(&mut self.output, s);
+    }
+
+    fn write_items(&mut self, items: &[Value]) {
+        self.output.push('[');
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.output.push(',');
+            }
+            self.visit_value(item);
+        }
+        self.output.push(']');
+    }
+}
+
+#[cfg(feature = "formats")]
+impl<'a> Visitor<'a> for JsonFormatter {
+    fn visit_char(&mut self, n: &'a value::Char) {
+        self.write_string(&n.value.to_string());
+    }
+
+    fn visit_string(&mut self, n: &'a value::String) {
+        self.write_string(&n.value);
+    }
+
+    fn visit_list(&mut self, n: &'a value::List) {
+        self.write_items(&n.values);
+    }
+
+    fn visit_node(&mut self, n: &'a value::Node) {
+        self.output.push_str("{\"name\":");
+        self.write_string(&n.name);
+        self.output.push_str(",\"items\":");
+        self.write_items(&n.items);
         self.output.push('}');
     }
+
+    fn visit_error(&mut self, n: &'a value::Error) {
+        self.output.push_str("{\"error\":");
+        self.write_string(&n.label);
+        self.output.push_str(",\"message\":");
+        match &n.message {
+            Some(m) => self.write_string(m),
+            None => self.output.push_str("null"),
+        }
+        self.output.push('}');
+    }
+}
+
+// The sexpr formatter prints values out as S-expressions.  Nodes
+// become lists headed by their name, lists have no head, characters
+// and strings are quoted and errors become `(error label "message")`
+#[cfg(feature = "formats")]
+pub fn value_to_sexpr(value: &Value) -> String {
+    let mut f = SexprFormatter::default();
+    f.visit_value(value);
+    f.output
+}
+
+#[cfg(feature = "formats")]
+#[derive(Default)]
+struct SexprFormatter {
+    output: String,
+}
+
+#[cfg(feature = "formats")]
+impl SexprFormatter {
+    fn write_items(&mut self, items: &[Value]) {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.output.push(' ');
+            }
+            self.visit_value(item);
+        }
+    }
+}
+
+#[cfg(feature = "formats")]
+impl<'a> Visitor<'a> for SexprFormatter {
+    fn visit_char(&mut self, n: &'a value::Char) {
+        write_json_string(&mut self.output, &n.value.to_string());
+    }
+
+    fn visit_string(&mut self, n: &'a value::String) {
+        write_json_string(&mut self.output, &n.value);
+    }
+
+    fn visit_list(&mut self, n: &'a value::List) {
+        self.output.push('(');
+        self.write_items(&n.values);
+        self.output.push(')');
+    }
+
+    fn visit_node(&mut self, n: &'a value::Node) {
+        self.output.push('(');
+        self.output.push_str(&n.name);
+        if !n.items.is_empty() {
+            self.output.push(' ');
+            self.write_items(&n.items);
+        }
+        self.output.push(')');
+    }
+
+    fn visit_error(&mut self, n: &'a value::Error) {
+        self.output.push_str("(error ");
+        self.output.push_str(&n.label);
+        if let Some(m) = &n.message {
+            self.output.push(' ');
+            write_json_string(&mut self.output, m);
+        }
+        self.output.push(')');
+    }
+}
+
+// The dot formatter prints values out as a GraphViz digraph, with an
+// edge from each node or list to each of its items.  Nodes are
+// labelled with their names, characters and strings are quoted within
+// boxes, and errors are red
+#[cfg(feature = "formats")]
+pub fn value_to_dot(value: &Value) -> String {
+    let mut f = DotFormatter {
+        output: "digraph {\n".to_string(),
+        ..Default::default()
+    };
+    f.visit_value(value);
+    f.output.push_str("}\n");
+    f.output
+}
+
+#[cfg(feature = "formats")]
+#[derive(Default)]
+struct DotFormatter {
+    output: String,
+    // ID the next vertex gets
+    next: usize,
+    // vertices of the nodes and lists the items being visited belong to
+    parents: Vec<usize>,
+}
+
+#[cfg(feature = "formats")]
+impl DotFormatter {
+    // add a vertex with `label` and an edge to it from its parent,
+    // and return its ID
+    fn vertex(&mut self, label: &str, attributes: &str) -> usize {
+        let id = self.next;
+        self.next += 1;
+        self.output.push_str(&format!("  n{} [label=\"", id));
+        for c in label.chars() {
+            match c {
+                '"' => self.output.push_str("\\\""),
+                '\\' => self.output.push_str("\\\\"),
+                c => self.output.push(c),
+            }
+        }
+        self.output.push('"');
+        self.output.push_str(attributes);
+        self.output.push_str("];\n");
+        if let Some(parent) = self.parents.last() {
+            self.output
+                .push_str(&format!("  n{} -> n{};\n", parent, id));
+        }
+        id
+    }
+
+    fn leaf(&mut self, text: &str) {
+        let mut label = String::new();
+        write_json_string(&mut label, text);
+        self.vertex(&label, ", shape=box");
+    }
+}
+
+#[cfg(feature = "formats")]
+impl<'a> Visitor<'a> for DotFormatter {
+    fn visit_char(&mut self, n: &'a value::Char) {
+        self.leaf(&n.value.to_string());
+    }
+
+    fn visit_string(&mut self, n: &'a value::String) {
+        self.leaf(&n.value);
+    }
+
+    fn visit_list(&mut self, n: &'a value::List) {
+        let id = self.vertex("[]", "");
+        self.parents.push(id);
+        walk_list(self, n);
+        self.parents.pop();
+    }
+
+    fn visit_node(&mut self, n: &'a value::Node) {
+        let id = self.vertex(&n.name, "");
+        self.parents.push(id);
+        walk_node(self, n);
+        self.parents.pop();
+    }
+
+    fn visit_error(&mut self, n: &'a value::Error) {
+        let label = match &n.message {
+            Some(m) => format!("error {}: {}", n.label, m),
+            None => format!("error {}", n.label),
+        };
+        self.vertex(&label, ", color=red, fontcolor=red");
+    }
+}
+
+// The value_to_json formatter prints values out as a single line of
+// JSON meant to be read by other programs, so its shape only ever
+// gets extended.  Each value becomes an object with its `type`, what's
+// specific to the type and its `span`:
+//
+//     {"type":"char","value":"a","span":..}
+//     {"type":"string","value":"abc","span":..}
+//     {"type":"list","items":[..],"span":..}
+//     {"type":"node","name":"A","items":[..],"span":..}
+//     {"type":"error","label":"eq","message":"missing =","span":..}
+//
+// Messages of errors without one are `null`, and spans are objects
+// with the `start` and `end` positions, each with its `offset`,
+// `line` and `column`.
+pub fn value_to_json(value: &Value) -> String {
+    let mut f = TreeJsonFormatter::default();
+    f.visit_value(value);
+    f.output
+}
+
+#[derive(Default)]
+struct TreeJsonFormatter {
+    output: String,
+}
+
+impl TreeJsonFormatter {
+    fn open(&mut self, kind: &str) {
+        self.output.push_str("{\"type\":\"");
+        self.output.push_str(kind);
+        self.output.push('"');
+    }
+
+    fn field(&mut self, name: &str) {
+        self.output.push_str(",\"");
+        self.output.push_str(name);
+        self.output.push_str("\":");
+    }
+
+    fn close(&mut self, span: &Span) {
+        self.field("span");
+        self.output.push_str("{\"start\":");
+        self.write_position(&span.start);
+        self.output.push_str(",\"end\":");
+        self.write_position(&span.end);
+        self.output.push_str("}}");
+    }
+
+    fn write_position(&mut self, p: &Position) {
+        self.output.push_str(&format!(
+            "{{\"offset\":{},\"line\":{},\"column\":{}}}",
+            p.offset, p.line, p.column
+        ));
+    }
+
+    fn write_items(&mut self, items: &[Value]) {
+        self.field("items");
+        self.output.push('[');
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                self.output.push(',');
+            }
+            self.visit_value(item);
+        }
+        self.output.push(']');
+    }
+}
+
+impl<'a> Visitor<'a> for TreeJsonFormatter {
+    fn visit_char(&mut self, n: &'a value::Char) {
+        self.open("char");
+        self.field("value");
+        write_json_string(&mut self.output, &n.value.to_string());
+        self.close(&n.span);
+    }
+
+    fn visit_string(&mut self, n: &'a value::String) {
+        self.open("string");
+        self.field("value");
+        write_json_string(&mut self.output, &n.value);
+        self.close(&n.span);
+    }
+
+    fn visit_list(&mut self, n: &'a value::List) {
+        self.open("list");
+        self.write_items(&n.values);
+        self.close(&n.span);
+    }
+
+    fn visit_node(&mut self, n: &'a value::Node) {
+        self.open("node");
+        self.field("name");
+        write_json_string(&mut self.output, &n.name);
+        self.write_items(&n.items);
+        self.close(&n.span);
+    }
+
+    fn visit_error(&mut self, n: &'a value::Error) {
+        self.open("error");
+        self.field("label");
+        write_json_string(&mut self.output, &n.label);
+        self.field("message");
+        match &n.message {
+            Some(m) => write_json_string(&mut self.output, m),
+            None => self.output.push_str("null"),
+        }
+        self.close(&n.span);
+    }
+}
+
+fn write_json_string(output: &mut String, s: &str) {
+    output.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
+
+// Values serialized with serde take the shape `value_to_json` prints
+// them out with
+#[cfg(feature = "serde")]
+mod ser {
+    use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+    use crate::source_map::{Position, Span};
+    use crate::value::Value;
+
+    impl Serialize for Value {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let (kind, len, span) = match self {
+                Value::Char(v) => ("char", 3, &v.span),
+                Value::String(v) => ("string", 3, &v.span),
+                Value::List(v) => ("list", 3, &v.span),
+                Value::Node(v) => ("node", 4, &v.span),
+                Value::Error(v) => ("error", 4, &v.span),
+            };
+            let mut s = serializer.serialize_struct("Value", len)?;
+            s.serialize_field("type", kind)?;
+            match self {
+                Value::Char(v) => s.serialize_field("value", &v.value)?,
+                Value::String(v) => s.serialize_field("value", &*v.value)?,
+                Value::List(v) => s.serialize_field("items", &v.values)?,
+                Value::Node(v) => {
+                    s.serialize_field("name", &v.name)?;
+                    s.serialize_field("items", &v.items)?;
+                }
+                Value::Error(v) => {
+                    s.serialize_field("label", &v.label)?;
+                    s.serialize_field("message", &v.message)?;
+                }
+            }
+            s.serialize_field("span", span)?;
+            s.end()
+        }
+    }
+
+    impl Serialize for Span {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut s = serializer.serialize_struct("Span", 2)?;
+            s.serialize_field("start", &self.start)?;
+            s.serialize_field("end", &self.end)?;
+            s.end()
+        }
+    }
+
+    impl Serialize for Position {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut s = serializer.serialize_struct("Position", 3)?;
+            s.serialize_field("offset", &self.offset)?;
+            s.serialize_field("line", &self.line)?;
+            s.serialize_field("column", &self.column)?;
+            s.end()
+        }
+    }
 }
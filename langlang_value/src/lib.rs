@@ -1,4 +1,7 @@
+pub mod binary;
+pub mod extract;
 pub mod format;
+pub mod migrate;
 pub mod source_map;
 pub mod value;
 pub mod visitor;
@@ -0,0 +1,116 @@
+// migrate.rs --- upgrade values produced by older grammars
+//
+// Grammars declare their version with `@version "1.2"`, and programs
+// compiled from them report it with `Program::version`.  Systems that
+// keep values around for longer than a grammar stays the same
+// register functions that rewrite the values produced by one version
+// into what the next one produces, and `Migrations::migrate` chains
+// them together to bring a value from any version up to date.
+//
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::value::Value;
+
+type Migration = Box<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // No chain of migrations leads from a version to the other (from, to)
+    NoPath(String, String),
+    // Migration between two versions rejected the value (from, to, message)
+    Failed(String, String, String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Migration Error")?;
+        match self {
+            Error::NoPath(from, to) => write!(f, "[NoPath]: {} -> {}", from, to),
+            Error::Failed(from, to, msg) => write!(f, "[Failed]: {} -> {}: {}", from, to, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Registry of the functions that rewrite values produced by one
+/// version of a grammar into values of another version
+#[derive(Default)]
+pub struct Migrations {
+    // versions each version has migrations to, with the functions
+    // that perform them, in the order they were registered
+    edges: HashMap<String, Vec<(String, Migration)>>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `migration` as the way to turn values produced by
+    /// version `from` into values of version `to`.  Registering
+    /// another migration between the same versions replaces the
+    /// previous one.
+    pub fn register<F>(&mut self, from: &str, to: &str, migration: F)
+    where
+        F: Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        let edges = self.edges.entry(from.to_string()).or_default();
+        edges.retain(|(target, _)| target != to);
+        edges.push((to.to_string(), Box::new(migration)));
+    }
+
+    /// Versions, in the order they'd be visited, that `migrate`
+    /// takes a value through to bring it from `from` to `to`.  It's
+    /// the shortest chain of registered migrations, and doesn't
+    /// include `from`.
+    pub fn path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+        if from == to {
+            return Some(vec![]);
+        }
+        let mut previous: HashMap<&str, &str> = HashMap::new();
+        let mut seen = HashSet::from([from]);
+        let mut queue = VecDeque::from([from]);
+        while let Some(version) = queue.pop_front() {
+            for (next, _) in self.edges.get(version).into_iter().flatten() {
+                if !seen.insert(next) {
+                    continue;
+                }
+                previous.insert(next, version);
+                if next == to {
+                    let mut path = vec![to.to_string()];
+                    let mut current = to;
+                    while let Some(prev) = previous.get(current).filter(|p| **p != from) {
+                        path.push(prev.to_string());
+                        current = prev;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                queue.push_back(next);
+            }
+        }
+        None
+    }
+
+    /// Rewrite `value`, produced by version `from` of a grammar, into
+    /// what version `to` produces by running each migration along
+    /// the path returned by `Migrations::path`
+    pub fn migrate(&self, value: Value, from: &str, to: &str) -> Result<Value, Error> {
+        let path = self
+            .path(from, to)
+            .ok_or_else(|| Error::NoPath(from.to_string(), to.to_string()))?;
+        let mut current = from;
+        let mut value = value;
+        for next in &path {
+            let (_, migration) = self.edges[current]
+                .iter()
+                .find(|(target, _)| target == next)
+                .expect("path only goes through registered migrations");
+            value = migration(value)
+                .map_err(|msg| Error::Failed(current.to_string(), next.clone(), msg))?;
+            current = next;
+        }
+        Ok(value)
+    }
+}
@@ -18,9 +18,9 @@ impl Position {
     }
 }
 
-impl ToString for Position {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.line, self.column)
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
     }
 }
 
@@ -36,8 +36,8 @@ impl Span {
     }
 }
 
-impl ToString for Span {
-    fn to_string(&self) -> String {
-        format!("{}-{}", self.start.to_string(), self.end.to_string())
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
     }
 }
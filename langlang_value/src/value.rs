@@ -1,9 +1,10 @@
 use std::string::String as StdString;
+use std::sync::Arc;
 
 use crate::format;
 use crate::source_map::Span;
 
-#[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Hash)]
+#[derive(Clone, PartialEq, PartialOrd, Eq, Hash)]
 pub enum Value {
     Char(Char),
     String(String),
@@ -23,6 +24,43 @@ impl Value {
         }
     }
 
+    /// Text matched by the value, which is what its chars and
+    /// strings add up to in the order they were captured.  Names of
+    /// nodes and errors don't add anything to it, and neither does
+    /// the input that wasn't captured, like the one skipped by
+    /// `Spacing`.  See [`Value::text_in`] for that.
+    pub fn to_text(&self) -> StdString {
+        let mut text = StdString::new();
+        self.push_text(&mut text);
+        text
+    }
+
+    fn push_text(&self, text: &mut StdString) {
+        match self {
+            Value::Char(v) => text.push(v.value),
+            Value::String(v) => text.push_str(&v.value),
+            Value::List(v) => v.values.iter().for_each(|i| i.push_text(text)),
+            Value::Node(v) => v.items.iter().for_each(|i| i.push_text(text)),
+            Value::Error(_) => {}
+        }
+    }
+
+    /// Slice of `source` covered by the span of the value, including
+    /// what was matched but not captured.  `source` must be the text
+    /// the value was matched against.  `None` if the span is out of
+    /// its bounds.
+    pub fn text_in<'s>(&self, source: &'s str) -> Option<&'s str> {
+        let span = self.span();
+        // offsets count chars, and slices take bytes
+        let byte = |offset: usize| match source.char_indices().nth(offset) {
+            Some((i, _)) => Some(i),
+            None if offset == source.chars().count() => Some(source.len()),
+            None => None,
+        };
+        let (start, end) = (byte(span.start.offset)?, byte(span.end.offset)?);
+        source.get(start..end)
+    }
+
     pub fn compare(&self, other: Value) -> bool {
         match (self, other) {
             (Value::Char(a), Value::Char(b)) => a.value == b.value,
@@ -32,12 +70,106 @@ impl Value {
     }
 }
 
-impl ToString for Value {
-    fn to_string(&self) -> std::string::String {
-        format::compact(self)
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", format::compact(self))
+    }
+}
+
+// Values are printed out by hand rather than with the derived
+// implementation, so snapshots of trees don't change along with the
+// compiler or with fields added to the types of values.  Each value
+// is its type, what's specific to it and its span, e.g.:
+// `Node("A") @ 0:0-0:1 [String("a") @ 0:0-0:1]`.  Containers print
+// one item per line when formatted with `{:#?}`.  The rules that
+// captured nodes aren't printed, since their IDs change whenever
+// rules are added to the grammar.
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_debug(f, 0)
+    }
+}
+
+impl Value {
+    fn write_debug(&self, f: &mut std::fmt::Formatter, depth: usize) -> std::fmt::Result {
+        let items = match self {
+            Value::Char(v) => {
+                f.write_str("Char(")?;
+                write_quoted(f, &v.value.to_string(), '\'')?;
+                return write!(f, ") @ {}", v.span);
+            }
+            Value::String(v) => {
+                f.write_str("String(")?;
+                write_quoted(f, &v.value, '"')?;
+                return write!(f, ") @ {}", v.span);
+            }
+            Value::Error(v) => {
+                f.write_str("Error(")?;
+                write_quoted(f, &v.label, '"')?;
+                if let Some(message) = &v.message {
+                    f.write_str(", ")?;
+                    write_quoted(f, message, '"')?;
+                }
+                return write!(f, ") @ {}", v.span);
+            }
+            Value::List(v) => {
+                write!(f, "List @ {} ", v.span)?;
+                &v.values
+            }
+            Value::Node(v) => {
+                f.write_str("Node(")?;
+                write_quoted(f, &v.name, '"')?;
+                write!(f, ") @ {} ", v.span)?;
+                &v.items
+            }
+        };
+        if items.is_empty() {
+            return f.write_str("[]");
+        }
+        f.write_str("[")?;
+        for (i, item) in items.iter().enumerate() {
+            if f.alternate() {
+                f.write_str("\n")?;
+                write_indent(f, depth + 1)?;
+            } else if i > 0 {
+                f.write_str(", ")?;
+            }
+            item.write_debug(f, depth + 1)?;
+            if f.alternate() {
+                f.write_str(",")?;
+            }
+        }
+        if f.alternate() {
+            f.write_str("\n")?;
+            write_indent(f, depth)?;
+        }
+        f.write_str("]")
     }
 }
 
+fn write_indent(f: &mut std::fmt::Formatter, depth: usize) -> std::fmt::Result {
+    (0..depth).try_for_each(|_| f.write_str("    "))
+}
+
+// write `text` within `quote`, escaping the quote, backslashes and
+// control characters the same way regardless of the version of Rust
+fn write_quoted(f: &mut std::fmt::Formatter, text: &str, quote: char) -> std::fmt::Result {
+    use std::fmt::Write;
+    f.write_char(quote)?;
+    for c in text.chars() {
+        match c {
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c == quote => write!(f, "\\{}", c)?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char(quote)
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Hash)]
 pub struct Char {
     pub span: Span,
@@ -57,16 +189,21 @@ impl Char {
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Hash)]
 pub struct String {
     pub span: Span,
-    pub value: StdString,
+    // shared, so strings with the same text can point to the same
+    // storage, e.g. the tokens interned by the virtual machine
+    pub value: Arc<str>,
 }
 
 impl String {
-    pub fn new_val(span: Span, value: StdString) -> Value {
+    pub fn new_val(span: Span, value: impl Into<Arc<str>>) -> Value {
         Value::String(Self::new(span, value))
     }
 
-    pub fn new(span: Span, value: StdString) -> Self {
-        Self { span, value }
+    pub fn new(span: Span, value: impl Into<Arc<str>>) -> Self {
+        Self {
+            span,
+            value: value.into(),
+        }
     }
 }
 
@@ -86,9 +223,25 @@ impl List {
     }
 }
 
+/// RuleId is the dense index assigned by the compiler to each
+/// production of a grammar, in the order they're defined.  Comparing
+/// IDs is cheaper than comparing names, and the program that produced
+/// a value can map the ID back to the name of the production.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
+pub struct RuleId(pub u32);
+
+impl std::fmt::Display for RuleId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Hash)]
 pub struct Node {
     pub span: Span,
+    // production that captured the node.  It's empty for nodes that
+    // weren't produced by a rule, like the ones built by hand.
+    pub rule: Option<RuleId>,
     pub name: StdString,
     pub items: Vec<Value>,
 }
@@ -99,7 +252,26 @@ impl Node {
     }
 
     pub fn new(span: Span, name: StdString, items: Vec<Value>) -> Self {
-        Self { span, name, items }
+        Self {
+            span,
+            rule: None,
+            name,
+            items,
+        }
+    }
+
+    pub fn new_val_with_rule(
+        span: Span,
+        rule: RuleId,
+        name: StdString,
+        items: Vec<Value>,
+    ) -> Value {
+        Value::Node(Self {
+            span,
+            rule: Some(rule),
+            name,
+            items,
+        })
     }
 }
 
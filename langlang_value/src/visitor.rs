@@ -1,3 +1,4 @@
+use crate::source_map::Span;
 use crate::value::*;
 
 pub trait Visitor<'a>: Sized {
@@ -41,3 +42,49 @@ pub fn walk_node<'a, V: Visitor<'a>>(visitor: &mut V, n: &'a Node) {
         visitor.visit_value(v)
     }
 }
+
+/// ValueVisitor turns each value into an output, e.g.: the typed AST
+/// of a language.  Unlike [`Visitor`], each method gets what's within
+/// the value rather than the value itself, and containers decide
+/// whether and when their items are visited, usually with
+/// [`walk_items`].  Errors are skipped unless `visit_error` is
+/// implemented.
+pub trait ValueVisitor: Sized {
+    type Output;
+
+    fn visit_value(&mut self, n: &Value) -> Option<Self::Output> {
+        match n {
+            Value::Char(v) => Some(self.visit_char(v.value, &v.span)),
+            Value::String(v) => Some(self.visit_string(&v.value, &v.span)),
+            Value::List(v) => Some(self.visit_list(&v.values, &v.span)),
+            Value::Node(v) => Some(self.visit_node(&v.name, &v.items, &v.span)),
+            Value::Error(v) => self.visit_error(&v.label, v.message.as_deref(), &v.span),
+        }
+    }
+
+    fn visit_char(&mut self, value: char, span: &Span) -> Self::Output;
+
+    fn visit_string(&mut self, value: &str, span: &Span) -> Self::Output;
+
+    fn visit_list(&mut self, items: &[Value], span: &Span) -> Self::Output;
+
+    fn visit_node(&mut self, name: &str, items: &[Value], span: &Span) -> Self::Output;
+
+    fn visit_error(
+        &mut self,
+        _label: &str,
+        _message: Option<&str>,
+        _span: &Span,
+    ) -> Option<Self::Output> {
+        None
+    }
+}
+
+/// Visit each one of `items`, returning the outputs of the ones that
+/// produced any
+pub fn walk_items<V: ValueVisitor>(visitor: &mut V, items: &[Value]) -> Vec<V::Output> {
+    items
+        .iter()
+        .filter_map(|v| visitor.visit_value(v))
+        .collect()
+}
@@ -0,0 +1,68 @@
+mod helpers;
+
+use helpers::{assert_allocations_below, assert_no_leaks, compile};
+use langlang_lib::{alloc_stats, compiler, vm};
+
+#[global_allocator]
+static ALLOCATOR: alloc_stats::CountingAllocator = alloc_stats::CountingAllocator;
+
+const GRAMMAR: &str = "
+    List   <- Item (',' Item)*
+    Item   <- Name / Number
+    Name   <- [a-z]+
+    Number <- [0-9]+
+";
+
+fn program() -> vm::Program {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    compile(&cc, GRAMMAR, "List")
+}
+
+fn input(items: usize) -> String {
+    (0..items)
+        .map(|i| match i % 2 {
+            0 => "abc".to_string(),
+            _ => i.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[test]
+fn match_reports_allocations() {
+    let program = program();
+    let config = vm::Config::default().with_stats();
+    let small = vm::VM::new_with_config(&program, config.clone())
+        .match_str(&input(10))
+        .unwrap();
+    let large = vm::VM::new_with_config(&program, config)
+        .match_str(&input(100))
+        .unwrap();
+    let (small, large) = (small.stats.unwrap(), large.stats.unwrap());
+    assert!(small.allocations.count > 0);
+    assert!(large.allocations.count > small.allocations.count);
+}
+
+#[test]
+fn captures_stay_within_allocation_ceiling() {
+    let program = program();
+    let input = input(100);
+    // a little over what capturing each item costs today, so it
+    // fails once capturing starts allocating more per node
+    assert_allocations_below(4_000, || vm::VM::new(&program).run(&input).unwrap());
+}
+
+#[test]
+fn matching_does_not_leak() {
+    let program = program();
+    let input = input(100);
+    // the first match initializes what stays around for the lifetime
+    // of the process, like the thread local counters themselves
+    vm::VM::new(&program).run(&input).unwrap();
+    assert_no_leaks(|| {
+        vm::VM::new(&program).run(&input).unwrap();
+    });
+    assert_no_leaks(|| {
+        vm::VM::new(&program).run("abc,").ok();
+    });
+}
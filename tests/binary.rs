@@ -0,0 +1,84 @@
+mod helpers;
+
+use langlang_lib::compiler;
+use langlang_value::binary::{self, Kind, LazyValue};
+use langlang_value::source_map::Span;
+use langlang_value::value::{self, Value};
+
+fn parse(input: &str) -> Value {
+    let grammar = "
+        List  <- '[' Item (',' Item)* ']'
+        Item  <- Name / Num
+        Name  <- [a-zé]+
+        Num   <- [0-9]+
+    ";
+    let cc = compiler::Config::default();
+    let program = helpers::compile(&cc, grammar, "List");
+    helpers::run_str(&program, input).unwrap().unwrap()
+}
+
+#[test]
+fn binary_roundtrip() {
+    let value = parse("[a, 12, café]");
+    let bytes = binary::encode(&value);
+    assert_eq!(value, binary::decode(&bytes).unwrap());
+
+    // all types of values are supported
+    let value = value::List::new_val(
+        Span::default(),
+        vec![
+            value::Char::new_val(Span::default(), 'λ'),
+            value::Error::new_val(Span::default(), "label".to_string(), None),
+            value::Error::new_val(
+                Span::default(),
+                "label".to_string(),
+                Some("message".to_string()),
+            ),
+            value::Node::new_val(Span::default(), "N".to_string(), vec![]),
+        ],
+    );
+    assert_eq!(value, binary::decode(&binary::encode(&value)).unwrap());
+}
+
+#[test]
+fn binary_lazy_navigation() {
+    let value = parse("[a, 12, café]");
+    let bytes = binary::encode(&value);
+    let root = LazyValue::new(&bytes).unwrap();
+
+    assert_eq!(Kind::Node, root.kind());
+    assert_eq!(Some("List"), root.name().unwrap());
+    assert_eq!(value.span(), root.span().unwrap());
+
+    // the last item is reached without decoding the ones before it
+    let children: Vec<LazyValue> = root.children().unwrap().map(|c| c.unwrap()).collect();
+    let last = children
+        .iter()
+        .rev()
+        .find(|c| c.kind() == Kind::Node)
+        .unwrap();
+    assert_eq!(Some("Item"), last.name().unwrap());
+    let name = last.child(0).unwrap().unwrap();
+    assert_eq!(Some("Name"), name.name().unwrap());
+    assert_eq!(
+        "Name[café]",
+        langlang_value::format::compact(&name.to_value().unwrap())
+    );
+
+    // items of lists and nodes are counted without being decoded
+    assert_eq!(children.len(), root.len().unwrap());
+    assert!(root.child(children.len()).unwrap().is_none());
+}
+
+#[test]
+fn binary_errors() {
+    let bytes = binary::encode(&parse("[a]"));
+    assert_eq!(
+        Err(binary::Error::Truncated(bytes.len() - 1)),
+        binary::decode(&bytes[..bytes.len() - 1])
+    );
+    assert_eq!(
+        Err(binary::Error::InvalidTag(0, 9)),
+        binary::decode(&[9, 0, 0, 0, 0])
+    );
+}
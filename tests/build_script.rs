@@ -0,0 +1,39 @@
+mod helpers;
+
+use helpers::{assert_match, run_str};
+use langlang_lib::vm;
+
+#[test]
+fn build_script_compile_grammar() {
+    let dir = std::env::temp_dir().join(format!("langlang-build-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("sum.peg"),
+        "@import Num from \"./num.peg\"\nSum <- Num '+' Num",
+    )
+    .unwrap();
+    std::fs::write(dir.join("num.peg"), "Num <- [0-9]+").unwrap();
+
+    // directories the program is saved within get created
+    let output = dir.join("out").join("sum.llb");
+    let saved = langlang_build::compile_grammar(dir.join("sum.peg"), &output).unwrap();
+    assert_eq!(output, saved);
+    let bytes = std::fs::read(&output).unwrap();
+    let program = vm::Program::from_bytes(&bytes).unwrap();
+    assert_match("Sum[Num[1]+Num[2]]", run_str(&program, "1 + 2"));
+
+    // compiling the same grammar again leaves the file as it was
+    let modified = std::fs::metadata(&output).unwrap().modified().unwrap();
+    langlang_build::compile_grammar(dir.join("sum.peg"), &output).unwrap();
+    assert_eq!(bytes, std::fs::read(&output).unwrap());
+    assert_eq!(
+        modified,
+        std::fs::metadata(&output).unwrap().modified().unwrap()
+    );
+
+    // missing imports fail the build
+    std::fs::remove_file(dir.join("num.peg")).unwrap();
+    let r = langlang_build::compile_grammar(dir.join("sum.peg"), &output);
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(matches!(r, Err(langlang_build::Error::ImportError(_))));
+}
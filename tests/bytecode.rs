@@ -0,0 +1,183 @@
+mod helpers;
+
+use helpers::{assert_match, run_str};
+use langlang_lib::{bytecode, compiler, vm};
+use langlang_syntax::parser;
+
+const GRAMMAR: &str = "
+    Stms       <- Stm+
+    @sync(';')
+    Stm        <- Name '='^eq Value ';'
+    @capture(string)
+    Name       <- [a-zA-Z_]+ ('.' [a-z]+)*
+    Value      <- 'nil'i / Number / List
+    @capture(list)
+    List       <- '(' Value* ')'
+    Number     <- [0-9]+
+";
+
+fn compile() -> vm::Program {
+    let cc = compiler::Config::default();
+    helpers::compile(&cc, GRAMMAR, "Stms")
+}
+
+#[test]
+fn bytecode_roundtrip() {
+    let program = compile();
+    let bytes = program.to_bytes();
+    let loaded = vm::Program::from_bytes(&bytes).unwrap();
+    loaded.validate().unwrap();
+    // tables are saved in order, so saving the loaded program again,
+    // with its tables in other hash maps, gives back the same bytes
+    assert_eq!(bytes, loaded.to_bytes());
+
+    // the loaded program matches what the compiled one does
+    for input in ["a.b = 1; c = (2 NIL);", "a 1; b = x; c = 3;", "= 1;"] {
+        assert_eq!(run_str(&program, input), run_str(&loaded, input));
+    }
+    assert_match(
+        "Stms[Stm[a.b=Value[Number[1]];]Stm[c=Value[[(Value[Number[2]]Value[NIL])]];]]",
+        run_str(&loaded, "a.b = 1; c = (2 NIL);"),
+    );
+}
+
+#[test]
+fn bytecode_roundtrip_unlinked() {
+    // calls to rules defined by other programs are saved too, so
+    // programs can be linked after they're loaded
+    let compile_unit = |grammar: &str| {
+        let ast = parser::parse(grammar).unwrap();
+        let cc = compiler::Config::default().defer_unresolved();
+        compiler::Compiler::new(cc).compile(&ast, None).unwrap()
+    };
+    let main = compile_unit("Main <- 'let' Name");
+    let names = compile_unit("Name <- [a-z]+ Spacing <- ' '*");
+    let main = vm::Program::from_bytes(&main.to_bytes()).unwrap();
+    assert_eq!(vec!["Name", "Spacing"], main.unresolved());
+    let program = vm::Program::concat(&[&main, &names]).unwrap();
+    assert_match("Main[letName[x]]", run_str(&program, "let x"));
+}
+
+#[test]
+fn bytecode_errors() {
+    let bytes = compile().to_bytes();
+    assert_eq!(
+        bytecode::Error::InvalidMagic,
+        vm::Program::from_bytes(b"LLB\0").unwrap_err()
+    );
+    let mut future = bytes.clone();
+    future[4] = 99;
+    assert_eq!(
+        bytecode::Error::UnsupportedVersion(99),
+        vm::Program::from_bytes(&future).unwrap_err()
+    );
+
+    // cut anywhere, the program can't be loaded
+    for len in 0..bytes.len() {
+        assert!(vm::Program::from_bytes(&bytes[..len]).is_err());
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(
+        bytecode::Error::TrailingBytes(bytes.len()),
+        vm::Program::from_bytes(&trailing).unwrap_err()
+    );
+
+    // each instruction is saved to a record of 12 bytes that starts
+    // with its opcode
+    let mut invalid = bytes.clone();
+    let last = invalid.len() - 12;
+    invalid[last] = 200;
+    assert_eq!(
+        bytecode::Error::InvalidOpcode(last, 200),
+        vm::Program::from_bytes(&invalid).unwrap_err()
+    );
+
+    // the last instruction is a `Return`, which has no operands
+    let mut invalid = bytes.clone();
+    invalid[last + 4] = 1;
+    assert_eq!(
+        bytecode::Error::InvalidReference(last + 4),
+        vm::Program::from_bytes(&invalid).unwrap_err()
+    );
+}
+
+#[test]
+fn bytecode_invalid_code() {
+    // well formed instructions that would send the virtual machine
+    // out of the code are rejected when the program is loaded, rather
+    // than when it's executed.  The last instruction is a `Return`,
+    // replaced by jumps forward and backwards
+    let bytes = compile().to_bytes();
+    let last = bytes.len() - 12;
+    for opcode in [21, 22] {
+        let mut invalid = bytes.clone();
+        invalid[last] = opcode;
+        invalid[last + 4..last + 8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            vm::Program::from_bytes(&invalid),
+            Err(bytecode::Error::InvalidCode(_, _))
+        ));
+        assert!(matches!(
+            vm::Program::from_mapped(invalid),
+            Err(bytecode::Error::InvalidCode(_, _))
+        ));
+    }
+
+    // and so are paths that run past the end of the code, e.g.: an
+    // `Any` in its place
+    let mut invalid = bytes.clone();
+    invalid[last] = 1;
+    assert!(matches!(
+        vm::Program::from_bytes(&invalid),
+        Err(bytecode::Error::InvalidCode(_, _))
+    ));
+}
+
+#[test]
+fn bytecode_mapped() {
+    let program = compile();
+    let bytes = program.to_bytes();
+    let mapped = vm::Program::from_mapped(bytes.clone()).unwrap();
+    mapped.validate().unwrap();
+    assert_eq!(bytes, mapped.to_bytes());
+    assert_eq!(program.to_string(), mapped.to_string());
+    for input in ["a.b = 1; c = (2 NIL);", "a 1; b = x; c = 3;", "= 1;"] {
+        assert_eq!(run_str(&program, input), run_str(&mapped, input));
+    }
+
+    // instructions are checked when the program is loaded, not when
+    // they're executed
+    let mut invalid = bytes.clone();
+    let last = invalid.len() - 12;
+    invalid[last] = 200;
+    assert_eq!(
+        bytecode::Error::InvalidOpcode(last, 200),
+        vm::Program::from_mapped(invalid).unwrap_err()
+    );
+    for len in 0..bytes.len() {
+        assert!(vm::Program::from_mapped(bytes[..len].to_vec()).is_err());
+    }
+}
+
+#[test]
+fn bytecode_map_file() {
+    let program = compile();
+    let path = std::env::temp_dir().join(format!("langlang-{}.llb", std::process::id()));
+    std::fs::write(&path, program.to_bytes()).unwrap();
+    let mapped = vm::Program::map_file(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_match("Stms[Stm[a=Value[Number[1]];]]", run_str(&mapped, "a = 1;"));
+}
+
+#[test]
+fn bytecode_version() {
+    let ast = parser::parse("@version \"2.0\"\nA <- 'a'").unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&ast, None)
+        .unwrap();
+    assert_eq!(Some("2.0"), program.version());
+    let loaded = vm::Program::from_bytes(&program.to_bytes()).unwrap();
+    assert_eq!(Some("2.0"), loaded.version());
+    assert_eq!(None, compile().version());
+}
@@ -0,0 +1,139 @@
+mod helpers;
+
+use std::sync::Arc;
+use std::thread;
+
+use helpers::{compile, run_str};
+use langlang_lib::{compiler, vm};
+use langlang_value::{format, value::Value};
+
+// exercises calls, left recursion, labels with recovery, and the
+// tables of strings and classes, which are all read by every match
+const GRAMMAR: &str = "
+    Stms    <- Stm+
+    @sync(';')
+    Stm     <- Name '='^eq Expr ';'
+    Expr    <- Expr '+' Term / Term
+    Term    <- Number / List / 'nil'i
+    @capture(list)
+    List    <- '(' Expr* ')'
+    Number  <- [0-9]+
+    Name    <- [a-zA-Z_]+
+";
+
+const INPUTS: &[&str] = &[
+    "a = 1;",
+    "a = 1 + 2 + 3; b = (1 2 NIL);",
+    "a 1; b = x; c = 3;",
+    "x = ((1) (2 + 3));",
+    "= 1;",
+    "",
+];
+
+fn program() -> vm::Program {
+    compile(&compiler::Config::default(), GRAMMAR, "Stms")
+}
+
+// what matching `input` gives, in a form threads can compare
+fn outcome(program: &vm::Program, input: &str) -> String {
+    match run_str(program, input) {
+        Ok(Some(value)) => format::compact(&value),
+        Ok(None) => String::new(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn programs_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<vm::Program>();
+    assert_send_sync::<vm::Config>();
+    assert_send_sync::<vm::Error>();
+    assert_send_sync::<Value>();
+}
+
+#[test]
+fn concurrent_matches_over_one_program() {
+    let program = Arc::new(program());
+    let expected: Arc<Vec<String>> =
+        Arc::new(INPUTS.iter().map(|i| outcome(&program, i)).collect());
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let (program, expected) = (Arc::clone(&program), Arc::clone(&expected));
+            thread::spawn(move || {
+                // each thread walks the inputs in a different order,
+                // so different matches overlap in each round
+                for round in 0..10 {
+                    for k in 0..INPUTS.len() {
+                        let i = (k + t + round) % INPUTS.len();
+                        assert_eq!(expected[i], outcome(&program, INPUTS[i]));
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn scoped_matches_borrow_one_program() {
+    // matches borrow the program for as long as the scope lasts, and
+    // programs mapped from their bytes are shared the same way
+    let compiled = program();
+    let mapped = vm::Program::from_mapped(compiled.to_bytes()).unwrap();
+    let config = vm::Config::default().with_interned_leaves().with_stats();
+    let expected: Vec<String> = INPUTS.iter().map(|i| outcome(&compiled, i)).collect();
+    thread::scope(|scope| {
+        for program in [&compiled, &mapped, &compiled, &mapped] {
+            let (config, expected) = (&config, &expected);
+            scope.spawn(move || {
+                for (input, expected) in INPUTS.iter().zip(expected) {
+                    let result = vm::VM::new_with_config(program, config.clone()).run(input);
+                    let found = match result {
+                        Ok(Some(value)) => format::compact(&value),
+                        Ok(None) => String::new(),
+                        Err(e) => e.to_string(),
+                    };
+                    assert_eq!(expected, &found);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(loom)]
+mod models {
+    use super::*;
+
+    // every interleaving of matches over a program shared with
+    // loom's `Arc`.  Programs are only ever read by matches, so the
+    // interleavings explored are the ones of sharing and dropping the
+    // program.  State that matches write to, like caches, must use
+    // the primitives of `loom` to be checked by this model.
+    #[test]
+    fn matches_over_one_program() {
+        let program = program();
+        let expected: Vec<String> = INPUTS.iter().map(|i| outcome(&program, i)).collect();
+        loom::model(move || {
+            let shared = loom::sync::Arc::new(program.clone());
+            let threads: Vec<_> = [0, 1, 2]
+                .into_iter()
+                .map(|t| {
+                    let (shared, expected) = (shared.clone(), expected.clone());
+                    // matching recurses through the stack, which is
+                    // smaller for the threads of the model by default
+                    loom::thread::Builder::new()
+                        .stack_size(8 << 20)
+                        .spawn(move || assert_eq!(expected[t], outcome(&shared, INPUTS[t])))
+                        .unwrap()
+                })
+                .collect();
+            drop(shared);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,78 @@
+// examples.rs --- golden tests for the grammars within `examples/`
+//
+// Each directory within `examples/` has a `grammar.peg` file and a
+// few `.input` files.  The output of matching each input, either the
+// indented value or the error, must be the same as the content of
+// the `.expected` file next to it.  Set `LANGLANG_BLESS=1` to write
+// the `.expected` files out instead of comparing them.
+
+mod helpers;
+
+use std::fs;
+use std::path::Path;
+
+use langlang_lib::compiler;
+use langlang_value::format;
+
+#[test]
+fn calculator() {
+    check_example("calculator", "Calc");
+}
+
+#[test]
+fn json() {
+    check_example("json", "JSON");
+}
+
+#[test]
+fn ini() {
+    check_example("ini", "File");
+}
+
+#[test]
+fn csv() {
+    check_example("csv", "File");
+}
+
+#[test]
+fn url() {
+    check_example("url", "URL");
+}
+
+fn check_example(name: &str, start: &str) {
+    let dir = Path::new("examples").join(name);
+    let grammar = dir.join("grammar.peg");
+    let cc = compiler::Config::default();
+    let program = helpers::compile_file(&cc, grammar.to_str().unwrap(), Some(start));
+    let bless = std::env::var_os("LANGLANG_BLESS").is_some();
+
+    let mut inputs: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "input"))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "example `{}` has no inputs", name);
+
+    for input in inputs {
+        let output = match helpers::run_str(&program, &fs::read_to_string(&input).unwrap()) {
+            Ok(Some(value)) => format::indented(&value),
+            Ok(None) => "no value\n".to_string(),
+            Err(e) => format!("{}\n", e),
+        };
+        let expected = input.with_extension("expected");
+        if bless {
+            fs::write(&expected, &output).unwrap();
+            continue;
+        }
+        let expected_output = fs::read_to_string(&expected)
+            .unwrap_or_else(|_| panic!("missing {}", expected.display()));
+        assert_eq!(
+            expected_output,
+            output,
+            "output of {} doesn't match {}",
+            input.display(),
+            expected.display()
+        );
+    }
+}
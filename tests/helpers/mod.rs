@@ -1,4 +1,4 @@
-use langlang_lib::{compiler, import, vm};
+use langlang_lib::{alloc_stats, compiler, import, vm};
 use langlang_value::format;
 use langlang_value::value::Value;
 use std::path::Path;
@@ -9,10 +9,11 @@ pub fn compile(cc: &compiler::Config, grammar: &str, start: &str) -> vm::Program
     loader.add_grammar("main", grammar);
     let importer = import::ImportResolver::new(loader);
     let ast = importer.resolve(Path::new("main")).unwrap();
-    println!("PEG:\n{}", ast.to_string());
+    println!("PEG:\n{}", ast);
     let mut c = compiler::Compiler::new(cc.clone());
     let program = c.compile(&ast, Some(start)).unwrap();
     println!("PROGRAM:\n{}", program);
+    program.validate().unwrap();
     program
 }
 
@@ -22,16 +23,18 @@ pub fn compile_file(
     grammar_file: &str,
     start_rule: Option<&str>,
 ) -> vm::Program {
-    let importer = import::ImportResolver::new(import::RelativeImportLoader::default());
+    let importer = import::ImportResolver::new(import::RelativeImportLoader);
     let ast = importer.resolve(Path::new(grammar_file)).unwrap();
     let mut c = compiler::Compiler::new(cc.clone());
-    c.compile(&ast, start_rule).unwrap()
+    let program = c.compile(&ast, start_rule).unwrap();
+    program.validate().unwrap();
+    program
 }
 
 #[allow(dead_code)]
 pub fn run_str(program: &vm::Program, input: &str) -> Result<Option<Value>, vm::Error> {
     let mut machine = vm::VM::new(program);
-    machine.run_str(input)
+    machine.run(input)
 }
 
 #[allow(dead_code)]
@@ -43,9 +46,10 @@ pub fn cc_run(
 ) -> Result<Option<Value>, vm::Error> {
     let prog = compile(cc, grammar, start);
     let mut machine = vm::VM::new(&prog);
-    machine.run_str(input)
+    machine.run(input)
 }
 
+#[allow(dead_code)]
 pub fn assert_match(expected: &str, r: Result<Option<Value>, vm::Error>) {
     assert!(r.is_ok());
     let o = r.unwrap();
@@ -60,3 +64,31 @@ pub fn assert_err(expected: vm::Error, r: Result<Option<Value>, vm::Error>) {
     let e = r.unwrap_err();
     assert_eq!(expected, e);
 }
+
+// the allocation helpers need `alloc_stats::CountingAllocator` to be
+// the global allocator of the test binary, otherwise nothing's counted
+#[allow(dead_code)]
+pub fn assert_allocations_below<T>(max: usize, f: impl FnOnce() -> T) -> T {
+    assert!(
+        alloc_stats::installed(),
+        "CountingAllocator isn't installed"
+    );
+    let (output, allocations) = alloc_stats::measure(f);
+    assert!(
+        allocations.count <= max,
+        "expected at most {} allocations, found {}",
+        max,
+        allocations
+    );
+    output
+}
+
+#[allow(dead_code)]
+pub fn assert_no_leaks(f: impl FnOnce()) {
+    assert!(
+        alloc_stats::installed(),
+        "CountingAllocator isn't installed"
+    );
+    let ((), allocations) = alloc_stats::measure(f);
+    assert_eq!(0, allocations.live_bytes(), "leaked: {}", allocations);
+}
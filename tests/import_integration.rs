@@ -1,7 +1,9 @@
 mod helpers;
 use helpers::{assert_match, compile_file, run_str};
 
-use langlang_lib::compiler;
+use std::path::Path;
+
+use langlang_lib::{compiler, import};
 
 #[test]
 fn test_import() {
@@ -15,3 +17,201 @@ fn test_import() {
         run_str(&program, "0xG + 3"),
     )
 }
+
+fn search_path_loader() -> import::SearchPathImportLoader<import::MemoryFileSystem> {
+    let mut fs = import::MemoryFileSystem::default();
+    fs.add_file(
+        Path::new("/proj/main.peg"),
+        "@import Num from \"./num.peg\"\n@import Word from \"words.peg\"\nMain <- Num Word",
+    );
+    fs.add_file(Path::new("/proj/num.peg"), "Num <- [0-9]+");
+    fs.add_file(Path::new("/lib/a/words.peg"), "Word <- [a-z]+");
+    fs.add_file(Path::new("/lib/b/words.peg"), "Word <- [A-Z]+");
+    import::SearchPathImportLoader::new(fs)
+}
+
+#[test]
+fn test_import_mutual_recursion() {
+    // each module references a rule the other one defines
+    let mut fs = import::MemoryFileSystem::default();
+    fs.add_file(
+        Path::new("/proj/main.peg"),
+        "@import Items from \"./items.peg\"\nList <- '[' Items ']'\nNumber <- [0-9]+",
+    );
+    fs.add_file(
+        Path::new("/proj/items.peg"),
+        "@import List, Number from \"./main.peg\"\nItems <- (List / Number)*",
+    );
+    let grammar = import::ImportResolver::new(import::SearchPathImportLoader::new(fs))
+        .resolve(Path::new("/proj/main.peg"))
+        .unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+    assert_match(
+        "List[[Items[Number[1]List[[Items[Number[2]]]]]]]",
+        run_str(&program, "[1 [2]]"),
+    );
+}
+
+#[test]
+fn test_import_search_paths() {
+    let mut loader = search_path_loader();
+    loader.add_search_path(Path::new("/lib/a"));
+    loader.add_search_path(Path::new("/lib/b"));
+    let grammar = import::ImportResolver::new(loader)
+        .resolve(Path::new("/proj/main.peg"))
+        .unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+
+    // the first search path that has the module wins
+    assert_match("Main[Num[42]Word[abc]]", run_str(&program, "42 abc"));
+
+    // modules missing from all search paths are reported
+    let r = import::ImportResolver::new(search_path_loader()).resolve(Path::new("/proj/main.peg"));
+    assert!(matches!(r, Err(import::Error::FileNotFound(_))));
+}
+
+#[test]
+fn test_import_env_search_paths() {
+    std::env::set_var("LANGLANG_PATH", "/lib/b");
+    let mut loader = search_path_loader();
+    loader.add_env_search_paths();
+    let grammar = import::ImportResolver::new(loader)
+        .resolve(Path::new("/proj/main.peg"))
+        .unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+    assert_match("Main[Num[42]Word[ABC]]", run_str(&program, "42 ABC"));
+}
+
+#[test]
+fn test_import_registry() {
+    let mut fs = import::MemoryFileSystem::default();
+    fs.add_file(
+        Path::new("/proj/main.peg"),
+        "@import Pair from \"pairs\"\nMain <- Pair+",
+    );
+    fs.add_file(Path::new("/lib/pairs"), "Pair <- 'never used'");
+    let mut loader = import::SearchPathImportLoader::new(fs);
+    loader.add_search_path(Path::new("/lib"));
+
+    // registered grammars are found before the loader is asked for them
+    let mut registry = import::Registry::default();
+    registry.register("pairs", "Pair <- Digit '=' Digit\nDigit <- [0-9]");
+    let grammar = import::ImportResolver::new_with_registry(loader, registry)
+        .resolve(Path::new("/proj/main.peg"))
+        .unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+    assert_match(
+        "Main[Pair[Digit[1]=Digit[2]]Pair[Digit[3]=Digit[4]]]",
+        run_str(&program, "1=2 3=4"),
+    );
+}
+
+#[test]
+fn test_import_lockfile() {
+    let loader = || {
+        let mut loader = search_path_loader();
+        loader.add_search_path(Path::new("/lib/a"));
+        loader
+    };
+    let resolver = import::ImportResolver::new(loader());
+    resolver.resolve(Path::new("/proj/main.peg")).unwrap();
+    let lockfile = resolver.lockfile();
+    assert_eq!(2, lockfile.len());
+    assert!(lockfile.hash("/proj/num.peg").is_some());
+    assert!(lockfile.hash("/lib/a/words.peg").is_some());
+    assert_eq!(
+        vec!["/lib/a/words.peg", "/proj/num.peg"],
+        lockfile.paths().collect::<Vec<_>>(),
+    );
+
+    // the lockfile survives a round trip through its text form
+    let lockfile = import::Lockfile::parse(&lockfile.to_string()).unwrap();
+    assert_eq!(resolver.lockfile(), lockfile);
+
+    // modules that still match their hashes are imported
+    let r = import::ImportResolver::new(loader())
+        .with_lockfile(lockfile.clone())
+        .resolve(Path::new("/proj/main.peg"));
+    assert!(r.is_ok());
+
+    // modules edited after being locked are rejected
+    let mut loader = search_path_loader();
+    loader.add_search_path(Path::new("/lib/b"));
+    let r = import::ImportResolver::new(loader)
+        .with_lockfile(lockfile)
+        .resolve(Path::new("/proj/main.peg"));
+    assert!(matches!(r, Err(import::Error::LockMismatch(_))));
+}
+
+fn resolve_memory(files: &[(&str, &str)]) -> Result<langlang_syntax::ast::Grammar, import::Error> {
+    let mut fs = import::MemoryFileSystem::default();
+    for (path, content) in files {
+        fs.add_file(Path::new(path), content);
+    }
+    import::ImportResolver::new(import::SearchPathImportLoader::new(fs))
+        .resolve(Path::new(files[0].0))
+}
+
+#[test]
+fn test_import_whole_module() {
+    let grammar = resolve_memory(&[
+        (
+            "/proj/main.peg",
+            "@import \"./num.peg\"\nMain <- Num (',' Hex)*",
+        ),
+        (
+            "/proj/num.peg",
+            "Num <- Digit+\nHex <- '0x' Digit+\nDigit <- [0-9]",
+        ),
+    ])
+    .unwrap();
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+    assert_match(
+        "Main[Num[Digit[1]],Hex[0xDigit[2]]]",
+        run_str(&program, "1,0x2"),
+    );
+}
+
+#[test]
+fn test_import_renames_colliding_deps() {
+    // both modules have a `Digit` rule, and each imported rule keeps
+    // using the one of its own module
+    let grammar = resolve_memory(&[
+        (
+            "/proj/main.peg",
+            "@import Num from \"./num.peg\"\n@import Hex from \"./hex.peg\"\nMain <- Num '-' Hex",
+        ),
+        ("/proj/num.peg", "Num <- Digit+\nDigit <- [0-9]"),
+        ("/proj/hex.peg", "Hex <- Digit+\nDigit <- [0-9a-f]"),
+    ])
+    .unwrap();
+    assert!(grammar.definitions.contains_key("hex_Digit"));
+    let program = compiler::Compiler::new(compiler::Config::default())
+        .compile(&grammar, None)
+        .unwrap();
+    assert_match(
+        "Main[Num[Digit[1]Digit[2]]-Hex[hex_Digit[a]]]",
+        run_str(&program, "12-a"),
+    );
+    assert!(run_str(&program, "1a-a").is_err());
+
+    // rules imported by name can't replace the ones already defined
+    let r = resolve_memory(&[
+        (
+            "/proj/main.peg",
+            "@import Digit from \"./num.peg\"\nMain <- Digit\nDigit <- [a-z]",
+        ),
+        ("/proj/num.peg", "Num <- Digit+\nDigit <- [0-9]"),
+    ]);
+    assert!(matches!(r, Err(import::Error::NameError(_))));
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use langlang_lib::compiler::{self, LintLevel, WarningCode};
+use langlang_lib::import;
+use langlang_syntax::ast;
+
+#[test]
+fn test_unused_rule() {
+    let w = compile("A <- 'a'\nB <- 'b'\nC <- A", "C");
+    assert_eq!(vec![WarningCode::UnusedRule], codes(&w));
+    assert_eq!("rule `B` is never used", w[0].message);
+    assert_eq!(1, w[0].span.start.line);
+}
+
+#[test]
+fn test_recovery_rules_are_used() {
+    let w = compile("A <- 'a' 'b'^lb\nlb <- (!';' .)*", "A");
+    assert!(w.is_empty());
+}
+
+#[test]
+fn test_unreachable_alternative() {
+    let w = compile("A <- 'a'? / 'b'", "A");
+    assert_eq!(vec![WarningCode::UnreachableAlternative], codes(&w));
+
+    // through other rules
+    let w = compile("A <- B / 'b'\nB <- 'x'*", "A");
+    assert_eq!(vec![WarningCode::UnreachableAlternative], codes(&w));
+
+    // predicates can fail
+    let w = compile("A <- !'a' / 'b'", "A");
+    assert!(w.is_empty());
+}
+
+#[test]
+fn test_shadowed_alternative() {
+    let w = compile("A <- '<' / '<='", "A");
+    assert_eq!(vec![WarningCode::ShadowedAlternative], codes(&w));
+    assert_eq!(
+        r#"`"<="` is never matched because `"<"` matches its start first; try `"<="` before `"<"`"#,
+        w[0].message,
+    );
+    assert_eq!(11, w[0].span.start.column);
+
+    // sequences that start the same way
+    let w = compile("A <- B 'x' / B 'x' 'y' / 'c'\nB <- 'b'", "A");
+    assert_eq!(vec![WarningCode::ShadowedAlternative], codes(&w));
+    let w = compile("A <- 'a' / 'a'", "A");
+    assert_eq!(vec![WarningCode::ShadowedAlternative], codes(&w));
+
+    // the longer alternative first is how it's meant to be written,
+    // and literals that differ in case sensitivity match differently
+    let w = compile("A <- '<=' / '<'", "A");
+    assert!(w.is_empty());
+    let w = compile("A <- 'a' / 'AB'i", "A");
+    assert!(w.is_empty());
+}
+
+#[test]
+fn test_nullable_repetition() {
+    // these loop forever, so they're errors rather than warnings
+    let cc = compiler::Config::default();
+    let r = compile_with(&cc, "A <- ('a'?)*");
+    assert!(matches!(r, Err(compiler::Error::Analysis(_))));
+    let r = compile_with(&cc, "A <- B+\nB <- &'b'");
+    assert!(matches!(r, Err(compiler::Error::Analysis(_))));
+
+    let w = compile("A <- 'a'*", "A");
+    assert!(w.is_empty());
+}
+
+#[test]
+fn test_lint_levels() {
+    let grammar = "A <- 'a'? / 'b'\nB <- 'b'";
+    let cc = compiler::Config::default();
+    let w = compile_with(&cc, grammar).unwrap();
+    assert_eq!(
+        vec![WarningCode::UnreachableAlternative, WarningCode::UnusedRule],
+        sorted(codes(&w)),
+    );
+
+    // allowed lints aren't reported
+    let allow = cc.with_lint_level(WarningCode::UnusedRule, LintLevel::Allow);
+    let w = compile_with(&allow, grammar).unwrap();
+    assert_eq!(vec![WarningCode::UnreachableAlternative], codes(&w));
+
+    // denied lints fail compilation
+    let deny = cc.with_lint_level(WarningCode::UnusedRule, LintLevel::Deny);
+    match compile_with(&deny, grammar) {
+        Err(compiler::Error::Lint(denied)) => {
+            assert_eq!(vec![WarningCode::UnusedRule], codes(&denied))
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // strict mode denies everything that's not explicitly allowed
+    let strict = allow.strict();
+    match compile_with(&strict, grammar) {
+        Err(compiler::Error::Lint(denied)) => {
+            assert_eq!(vec![WarningCode::UnreachableAlternative], codes(&denied))
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_allow_attribute() {
+    let cc = compiler::Config::default().strict();
+    let w = compile_with(&cc, "A <- 'a'\n@allow(unused_rule) B <- 'b'").unwrap();
+    assert!(w.is_empty());
+
+    // only the lints listed are allowed
+    let r = compile_with(&cc, "A <- 'a'\n@allow(nullable_repetition) B <- 'b'");
+    assert!(matches!(r, Err(compiler::Error::Lint(_))));
+
+    // unknown lint names are errors
+    let r = compile_with(&cc, "A <- 'a'\n@allow(unused) B <- 'b'");
+    assert!(matches!(r, Err(compiler::Error::NotFound(_))));
+}
+
+fn sorted(mut codes: Vec<WarningCode>) -> Vec<WarningCode> {
+    codes.sort_by_key(|c| c.as_str());
+    codes
+}
+
+fn codes(warnings: &[compiler::Warning]) -> Vec<WarningCode> {
+    warnings.iter().map(|w| w.code).collect()
+}
+
+fn compile(grammar: &str, start: &str) -> Vec<compiler::Warning> {
+    let mut c = compiler::Compiler::new(compiler::Config::default());
+    c.compile_with_warnings(&resolve(grammar), Some(start))
+        .unwrap()
+        .warnings
+}
+
+fn compile_with(
+    cc: &compiler::Config,
+    grammar: &str,
+) -> Result<Vec<compiler::Warning>, compiler::Error> {
+    let mut c = compiler::Compiler::new(cc.clone());
+    Ok(c.compile_with_warnings(&resolve(grammar), None)?.warnings)
+}
+
+fn resolve(grammar: &str) -> ast::Grammar {
+    let mut loader = import::InMemoryImportLoader::default();
+    loader.add_grammar("main", grammar);
+    import::ImportResolver::new(loader)
+        .resolve(Path::new("main"))
+        .unwrap()
+}
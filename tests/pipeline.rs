@@ -0,0 +1,65 @@
+mod helpers;
+
+use std::path::Path;
+
+use langlang_lib::{compiler, import, pipeline, vm};
+use langlang_value::format;
+
+const GRAMMAR: &str = "
+    Expr   <- Term (Plus Term^term)*
+    Term   <- Number / Name
+    Token  <- Plus / Number / Name
+    Number <- [0-9]+
+    Name   <- [a-z]+
+    Plus   <- '+'
+";
+
+#[test]
+fn test_pipeline_match_str() {
+    let p = pipeline("Expr");
+    let m = p.match_str("1 + foo  +\n22").unwrap();
+    assert_eq!(
+        "Expr[Term[Number[1]]Plus[+]Term[Name[foo]]Plus[+]Term[Number[22]]]",
+        format::compact(&m.value.unwrap()),
+    );
+    // offsets within the input, not indexes of tokens
+    assert_eq!((0, 13), (m.span.start.offset, m.span.end.offset));
+    assert_eq!(13, m.ffp);
+}
+
+#[test]
+fn test_pipeline_scan() {
+    let tokens = pipeline("Expr").scan("a +\n12").unwrap();
+    let kinds: Vec<_> = tokens
+        .iter()
+        .map(|t| (t.kind.as_str(), t.text.as_str()))
+        .collect();
+    assert_eq!(vec![("Name", "a"), ("Plus", "+"), ("Number", "12")], kinds);
+    assert_eq!(4, tokens[2].span.start.offset);
+    assert_eq!(1, tokens[2].span.start.line);
+}
+
+#[test]
+fn test_pipeline_errors() {
+    let p = pipeline("Expr");
+    // the scanner doesn't know what `*` is
+    assert!(p.match_str("1 * 2").is_err());
+
+    // parser errors point at the position of the token in the input
+    match p.match_str("1 + + 2") {
+        Err(langlang_lib::Error::RuntimeError(vm::Error::Label(label, _, position))) => {
+            assert_eq!(("term", 4), (label.as_str(), position.offset))
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+fn pipeline(start: &str) -> pipeline::Pipeline {
+    let mut loader = import::InMemoryImportLoader::default();
+    loader.add_grammar("main", GRAMMAR);
+    let ast = import::ImportResolver::new(loader)
+        .resolve(Path::new("main"))
+        .unwrap();
+    let cc = compiler::Config::default();
+    pipeline::Pipeline::new(&ast, Some(start), &cc).unwrap()
+}
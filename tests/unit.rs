@@ -1,10 +1,15 @@
 mod helpers;
-use helpers::{assert_match, cc_run, compile, run_str};
+use helpers::{assert_err, assert_match, cc_run, compile, run_str};
 
-use langlang_lib::{compiler, vm};
-use langlang_syntax::parser;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use langlang_lib::{analysis, compiler, vm};
+use langlang_syntax::{ast, parser};
 use langlang_value::source_map::{Position, Span};
-use langlang_value::value;
+use langlang_value::{extract, format, value};
 
 #[test]
 fn test_char() {
@@ -17,8 +22,8 @@ fn test_str() {
     let cc = compiler::Config::default();
     let p = compile(&cc, "A <- '0x' [0-9a-fA-F]+ / '0'", "A");
 
-    // Note: run_str uses VM::run_str, which maps each character
-    // of the input string into a `Value::Char`, and the fact that
+    // Note: run_str uses VM::run, which reads each character
+    // of the input string as a `Value::Char`, and the fact that
     // `Instruction::String` can read both an entire string or a
     // set of chars allows this example to work, as the `0x` piece
     // is compiled into an `Instruction::String` call.
@@ -34,7 +39,7 @@ fn test_str() {
     // a time.
     assert_match(
         "A[0xff]",
-        vm::VM::new(&p).run(vec![
+        vm::VM::new(&p).run_values(vec![
             value::String::new_val(
                 Span::new(Position::new(0, 0, 0), Position::new(2, 0, 2)),
                 "0x".to_string(),
@@ -53,7 +58,7 @@ fn test_str() {
     // Easiest case
     assert_match(
         "A[0]",
-        vm::VM::new(&p).run(vec![value::String::new_val(
+        vm::VM::new(&p).run_values(vec![value::String::new_val(
             Span::new(Position::new(0, 0, 0), Position::new(1, 0, 2)),
             "0".to_string(),
         )]),
@@ -72,6 +77,14 @@ fn test_not_opt() {
     assert_match("A[c]", cc_run(&cc, "A <- (!('a' / 'b') .)", "A", "c"));
 }
 
+#[test]
+fn test_not_nested() {
+    // the predicate within the outer one ending doesn't take matching
+    // out of the outer one, so the label fails instead of being thrown
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    assert_match("A[a]", cc_run(&cc, "A <- !(!'x' 'c'^l) 'a'", "A", "a"));
+}
+
 #[test]
 fn test_not_at_the_end() {
     let cc = compiler::Config::default();
@@ -97,6 +110,7 @@ fn test_and_0() {
 fn test_and_opt() {
     let cc = compiler::Config::o1();
     assert_match("A[a]", cc_run(&cc, "A <- &'a' .", "A", "a"));
+    assert_match("A[b]", cc_run(&cc, "A <- &'a' . / 'b'", "A", "b"));
 }
 
 #[test]
@@ -153,6 +167,53 @@ fn test_var_ending_with_option() {
     assert_match("A[1]", run_str(&program, "1"));
 }
 
+#[test]
+fn test_repetition_counts() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "A <- 'U+' [0-9A-F]{4}", "A");
+    assert_match("A[U+00E9]", run_str(&program, "U+00E9"));
+    assert!(run_str(&program, "U+0E9").is_err());
+
+    // at most `m` copies get matched, and the expression that follows
+    // the repetition gets the rest
+    let program = compile(&cc, "A <- 'a'{1,3} 'a'*", "A");
+    assert_match("A[aaaaa]", run_str(&program, "aaaaa"));
+    let program = compile(&cc, "A <- 'a'{1,3} !.", "A");
+    assert_match("A[aaa]", run_str(&program, "aaa"));
+    assert!(run_str(&program, "aaaa").is_err());
+    assert!(run_str(&program, "").is_err());
+
+    // `{n,}` matches at least `n` copies
+    let program = compile(&cc, "A <- 'a'{2,} 'b'", "A");
+    assert_match("A[aaaab]", run_str(&program, "aaaab"));
+    assert!(run_str(&program, "ab").is_err());
+
+    // counts get unrolled into copies of the expression, so they're
+    // bounded instead of taking all the memory there is
+    for (grammar, column) in [
+        ("A <- 'a'{4294967296}", 5),
+        ("A <- 'a'{1,4294967296}", 5),
+        ("A <- 'b' ('a'{300}){300}", 10),
+    ] {
+        let ast = parser::parse(grammar).unwrap();
+        let err = compiler::Compiler::new(cc.clone())
+            .compile(&ast, Some("A"))
+            .unwrap_err();
+        assert_eq!(
+            format!(
+                "Compiler Error[RepeatTooLarge]: repetition in `A` at 0:{} unrolls into more than 65536 copies",
+                column
+            ),
+            err.to_string(),
+        );
+    }
+    let program = compile(&cc, "A <- ('a'{256}){256}", "A");
+    assert_match(
+        &format!("A[{}]", "a".repeat(65536)),
+        run_str(&program, &"a".repeat(65536)),
+    );
+}
+
 // -- Unicode --------------------------------------------------------------
 
 #[test]
@@ -162,6 +223,543 @@ fn test_unicode_0() {
     assert_match("A[♡]", cc_run(&cc, "A <- '♡'", "A", "♡"));
 }
 
+#[test]
+fn test_ignore_case() {
+    let cc = compiler::Config::default();
+    let p = compile(&cc, "A <- 'select'i !.", "A");
+    // the capture holds the text as it was written in the input
+    assert_match("A[SeLeCt]", run_str(&p, "SeLeCt"));
+    assert_match("A[select]", run_str(&p, "select"));
+    assert!(run_str(&p, "selec").is_err());
+
+    let p = compile(&cc, "A <- [a-z]i+ !.", "A");
+    assert_match("A[aQz]", run_str(&p, "aQz"));
+    // KELVIN SIGN folds to `k`
+    assert_match("A[\u{212a}]", run_str(&p, "\u{212a}"));
+    assert!(run_str(&p, "0").is_err());
+}
+
+#[test]
+fn test_ignore_case_canonical_capture() {
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "Select <- 'select'i Name\nName <- [a-z]+ / 'ΣΑ'i",
+        "Select",
+    );
+    let config = vm::Config::default().with_case_capture(vm::CaseCapture::Canonical);
+
+    // keywords get captured as written in the grammar, identifiers as
+    // written in the input
+    let r = vm::VM::new_with_config(&p, config.clone()).run("SeLeCT abc");
+    assert_match("Select[selectName[abc]]", r);
+    let r = vm::VM::new_with_config(&p, config.clone()).run("Select σα");
+    assert_match("Select[selectName[ΣΑ]]", r);
+    assert_match("Select[SeLeCTName[abc]]", run_str(&p, "SeLeCT abc"));
+
+    let p = compile(&cc, "A <- [a-c]i 'x'i [\u{212a}]i", "A");
+    let r = vm::VM::new_with_config(&p, config).run("BXk");
+    assert_match("A[bx\u{212a}]", r);
+    assert_match("A[BXk]", run_str(&p, "BXk"));
+}
+
+#[test]
+fn test_ignore_case_turkish_i() {
+    let cc = compiler::Config::default();
+
+    // folding isn't Turkic, so the dotted and dotless forms of `i`
+    // don't match the ASCII ones
+    let p = compile(&cc, "A <- 'i'i !.", "A");
+    assert_match("A[i]", run_str(&p, "i"));
+    assert_match("A[I]", run_str(&p, "I"));
+    assert!(run_str(&p, "\u{131}").is_err());
+    assert!(run_str(&p, "\u{130}").is_err());
+
+    let p = compile(&cc, "A <- '\u{131}'i !.", "A");
+    assert_match("A[\u{131}]", run_str(&p, "\u{131}"));
+    assert!(run_str(&p, "i").is_err());
+    assert!(run_str(&p, "I").is_err());
+}
+
+#[test]
+fn test_ignore_case_greek_sigma() {
+    let cc = compiler::Config::default();
+
+    // the final form `ς` folds to `σ` as well as the capital `Σ`
+    for grammar in [
+        "A <- 'σ'i !.",
+        "A <- 'Σ'i !.",
+        "A <- 'ς'i !.",
+        "A <- [σ]i !.",
+    ] {
+        let p = compile(&cc, grammar, "A");
+        for input in ["Σ", "σ", "ς"] {
+            assert_match(&format!("A[{}]", input), run_str(&p, input));
+        }
+    }
+    let p = compile(&cc, "A <- 'ΟΔΟΣ'i !.", "A");
+    assert_match("A[οδος]", run_str(&p, "οδος"));
+    assert_match("A[οδοσ]", run_str(&p, "οδοσ"));
+}
+
+#[test]
+fn test_byte_mode() {
+    let cc = compiler::Config::default()
+        .byte_mode()
+        .disable_injecting_whitespace_handling();
+    let p = compile(
+        &cc,
+        "ELF <- '\\x7fELF' Class Data\nClass <- [\\x01-\\x02]\nData <- [\\x00-\\xff]*",
+        "ELF",
+    );
+    let r = vm::VM::new(&p).run_bytes(b"\x7fELF\x02\xfe\x00");
+    assert_match("ELF[\u{7f}ELFClass[\u{2}]Data[\u{fe}\u{0}]]", r);
+    let r = vm::VM::new(&p).match_bytes(b"\x7fELF\x03");
+    assert!(matches!(r, Err(vm::Error::Matching(p, _)) if p.offset == 4));
+
+    // byte escapes need byte mode, and byte mode needs ASCII
+    let compile_err = |cc: compiler::Config, grammar: &str| {
+        let ast = parser::parse(grammar).unwrap();
+        let mut c = compiler::Compiler::new(cc);
+        c.compile(&ast, Some("Magic")).unwrap_err().to_string()
+    };
+    assert_eq!(
+        "Compiler Error[Semantic]: `\\xHH` escapes are only allowed in byte mode in `\"\\x7fELF\"`, referenced from `Magic` at 0:9",
+        compile_err(compiler::Config::default(), r"Magic <- '\x7fELF'"),
+    );
+    assert_eq!(
+        "Compiler Error[Semantic]: only ASCII characters and `\\xHH` escapes are allowed in byte mode in `[\\u{e9}]`, referenced from `Magic` at 0:9",
+        compile_err(cc.clone(), "Magic <- [é]"),
+    );
+    assert_eq!(
+        "Compiler Error[Semantic]: only ASCII characters and `\\xHH` escapes are allowed in byte mode in `\"\\u{101}\"`, referenced from `Magic` at 0:9",
+        compile_err(cc, "Magic <- 'ā'"),
+    );
+}
+
+#[test]
+fn test_int_terminals() {
+    let cc = compiler::Config::default()
+        .byte_mode()
+        .disable_injecting_whitespace_handling();
+    let p = compile(
+        &cc,
+        "Packet <- Kind Length Tag Size\nKind <- %u8\nLength <- %u32be\nTag <- %varint\nSize <- %u16le",
+        "Packet",
+    );
+    let input = [0x07, 0x00, 0x00, 0x01, 0x02, 0xac, 0x02, 0x34, 0x12];
+    let r = vm::VM::new(&p).run_bytes(&input);
+    assert_match("Packet[Kind[7]Length[258]Tag[300]Size[4660]]", r);
+
+    // the integers need all their bytes, and varints can't overflow
+    let r = vm::VM::new(&p).run_bytes(&input[..8]);
+    assert!(matches!(r, Err(vm::Error::Matching(p, _)) if p.offset == 7));
+    let mut overflow = input[..5].to_vec();
+    overflow.extend([0xff; 9]);
+    overflow.push(0x02);
+    let r = vm::VM::new(&p).run_bytes(&overflow);
+    assert!(matches!(r, Err(vm::Error::Matching(p, _)) if p.offset == 5));
+
+    let ast = parser::parse("Magic <- %u16le").unwrap();
+    let err = compiler::Compiler::new(compiler::Config::default())
+        .compile(&ast, Some("Magic"))
+        .unwrap_err();
+    assert_eq!(
+        "Compiler Error[Semantic]: integer terminals are only allowed in byte mode in `%u16le`, referenced from `Magic` at 0:9",
+        err.to_string(),
+    );
+}
+
+#[test]
+fn test_take() {
+    let cc = compiler::Config::default()
+        .byte_mode()
+        .disable_injecting_whitespace_handling();
+    let p = compile(
+        &cc,
+        "Chunks <- Chunk*\nChunk <- Length %take(Length)\nLength <- %u8",
+        "Chunks",
+    );
+    let r = vm::VM::new(&p).run_bytes(b"\x03abc\x00\x02xy");
+    assert_match(
+        "Chunks[Chunk[Length[3]abc]Chunk[Length[0]]Chunk[Length[2]xy]]",
+        r,
+    );
+    let r = vm::VM::new(&p).match_bytes(b"\x03ab").unwrap();
+    assert_eq!(0, r.span.end.offset);
+
+    // counts can be captured by any rule with a decimal number as text
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "Field <- Count ':' %take(Count)\nCount <- [0-9]+",
+        "Field",
+    );
+    assert_match(
+        "Field[Count[12]:hello world!]",
+        run_str(&p, "12:hello world!"),
+    );
+
+    // the count has to be captured before it's used
+    let ast = parser::parse("Chunk <- %take(Length) Length\nLength <- [0-9]").unwrap();
+    let err = compiler::Compiler::new(cc)
+        .compile(&ast, Some("Chunk"))
+        .unwrap_err();
+    assert_eq!(
+        "Compiler Error[Semantic]: `%take(Length)` in `Chunk` at 0:9 needs `Length` to be matched before it in the same rule",
+        err.to_string(),
+    );
+}
+
+#[test]
+fn test_negated_class() {
+    let cc = compiler::Config::default();
+    let p = compile(&cc, "Word <- #([^ \\t\\n]+)", "Word");
+    assert_match("Word[héllo]", run_str(&p, "héllo world"));
+    assert!(run_str(&p, " ").is_err());
+
+    // other cases of the listed characters aren't matched either
+    let p = compile(&cc, "Word <- #([^a-c]i+)", "Word");
+    assert_match("Word[xyz]", run_str(&p, "xyzBa"));
+    assert!(run_str(&p, "B").is_err());
+
+    // a leading `^` is matched when it's escaped
+    let p = compile(&cc, "Caret <- [\\^]", "Caret");
+    assert_match("Caret[^]", run_str(&p, "^"));
+
+    // the complement covers all scalar values but the listed ones
+    let p = compile(&cc, "C <- [^\0-\u{7f}\u{d7ff}\u{10ffff}]", "C");
+    for c in ['\u{80}', 'é', '\u{d7fe}', '\u{e000}', '\u{10fffe}'] {
+        assert_match(&format!("C[{}]", c), run_str(&p, &c.to_string()));
+    }
+    for c in ['\0', 'a', '\u{7f}', '\u{d7ff}', '\u{10ffff}'] {
+        assert!(run_str(&p, &c.to_string()).is_err());
+    }
+}
+
+#[test]
+fn test_class_ranges() {
+    // overlapping and adjacent ranges are folded into one
+    let cc = compiler::Config::default();
+    let p = compile(&cc, "Word <- [x-za-mn-qc-f0-9]+", "Word");
+    assert_match("Word[a9zqx]", run_str(&p, "a9zqx"));
+    assert!(run_str(&p, "r").is_err());
+
+    // ranges must be written from their lowest to their highest character
+    let ast = parser::parse("Word <- [a-cz-a]+").unwrap();
+    let err = compiler::Compiler::new(cc)
+        .compile(&ast, Some("Word"))
+        .unwrap_err();
+    assert_eq!(
+        "Compiler Error[EmptyRange]: range `z-a` in `Word` at 0:12 is empty",
+        err.to_string(),
+    );
+}
+
+#[test]
+fn test_capture_modes() {
+    let cc = compiler::Config::default();
+    let grammar = "
+        Call <- Name Args Comment?
+        @capture(string) Name <- Id ('.' Id)*
+        @capture(list) Args <- '(' (Id (',' Id)*)? ')'
+        @capture(skip) Comment <- '#' (!'\n' .)*
+        Id <- [a-z]+
+    ";
+    let p = compile(&cc, grammar, "Call");
+    assert_match(
+        "Call[io.out[(Id[a],Id[b])]]",
+        run_str(&p, "io . out(a, b) # prints"),
+    );
+    // lists are captured even when nothing is captured within them
+    let p = compile(&cc, "A <- 'a' L\n@capture(list) L <- 'b'*", "A");
+    assert_match("A[a[]]", run_str(&p, "a"));
+
+    // left recursive rules can only be folded into nodes
+    let ast = parser::parse("@capture(list) E <- E '+' 'n' / 'n'").unwrap();
+    let err = compiler::Compiler::new(cc)
+        .compile(&ast, Some("E"))
+        .unwrap_err();
+    assert_eq!(
+        "Compiler Error[Semantic]: `@capture(list)` in `E` at 0:0 can't be used on a left recursive rule",
+        err.to_string(),
+    );
+}
+
+// reader that hands out at most 5 bytes per read
+struct Trickle(Vec<u8>, usize);
+
+impl std::io::Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(5).min(self.0.len() - self.1);
+        buf[..len].copy_from_slice(&self.0[self.1..self.1 + len]);
+        self.1 += len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_run_reader() {
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "Log <- (Entry / Other)* !.
+         Entry <- #('ERROR ' Code '\\n')
+         Other <- (!'\\n' .)* '\\n'
+         Code <- [0-9]+",
+        "Log",
+    );
+    // the input is pulled a few bytes at a time
+    let input = "INFO ok\n".repeat(50) + "ERROR 42\n";
+    let mut vm = vm::VM::new(&p);
+    let value = vm
+        .run_reader(Trickle(input.as_bytes().to_vec(), 0))
+        .unwrap();
+    let codes: Vec<_> = match value {
+        Some(value::Value::Node(log)) => log
+            .items
+            .iter()
+            .filter(|i| matches!(i, value::Value::Node(n) if n.name == "Entry"))
+            .map(format::compact)
+            .collect(),
+        _ => vec![],
+    };
+    assert_eq!(vec!["Entry[ERROR Code[42]\n]".to_string()], codes);
+    assert_eq!(input.len(), vm.cursor());
+
+    // reading errors are reported after matching stops
+    let reader = std::io::Cursor::new(b"INFO \xff\n".to_vec());
+    assert!(matches!(vm.run_reader(reader), Err(vm::Error::Read(_))));
+}
+
+#[test]
+fn test_value_to_text() {
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "Call <- Name '(' Args? ')'
+         Args <- Name (',' Name)*
+         Name <- [a-zA-Zé]+",
+        "Call",
+    );
+    let input = "f( été ,\n b )";
+    let value = run_str(&p, input).unwrap().unwrap();
+    // node names and the spaces that weren't captured are left out
+    assert_eq!("f(été,b)", value.to_text());
+    assert_eq!(Some(input), value.text_in(input));
+
+    let args = match &value {
+        value::Value::Node(call) => call.items[2].clone(),
+        _ => unreachable!(),
+    };
+    assert_eq!("été,b", args.to_text());
+    assert_eq!(Some("été ,\n b"), args.text_in(input));
+    assert_eq!(None, args.text_in("f("));
+
+    // errors don't add anything to the text
+    let error = value::Error::new_val(Span::default(), "label".to_string(), None);
+    let list = value::List::new_val(Span::default(), vec![args, error]);
+    assert_eq!("été,b", list.to_text());
+}
+
+// classes named after a prefix of their characters, e.g.: `%digits_5`
+struct Digits;
+
+impl compiler::ClassProvider for Digits {
+    fn ranges(&self, name: &str) -> Option<Vec<(char, char)>> {
+        let n: u32 = name.strip_prefix("digits_")?.parse().ok()?;
+        Some(vec![('0', char::from_digit(n.checked_sub(1)?, 10)?)])
+    }
+}
+
+#[test]
+fn test_named_classes() {
+    let greek = compiler::Classes::default().with("greek", &[('α', 'ω'), ('Α', 'Ω')]);
+    let cc = compiler::Config::default()
+        .with_class_provider(greek)
+        .with_class_provider(Digits);
+    let p = compile(&cc, "W <- #(%greek+ %digits_3)", "W");
+    assert_match("W[αβΓ2]", run_str(&p, "αβΓ2"));
+    assert!(run_str(&p, "αβΓ3").is_err());
+    assert!(run_str(&p, "ab1").is_err());
+
+    let grammar = parser::parse("W <- %latin+").unwrap();
+    let r = compiler::Compiler::new(cc).compile(&grammar, None);
+    match r {
+        Err(compiler::Error::UnknownClass(class)) => assert_eq!("latin", class.name),
+        _ => panic!("expected an unknown class error"),
+    }
+}
+
+#[test]
+fn test_unicode_properties() {
+    let cc = compiler::Config::default();
+    let p = compile(&cc, "Id <- #([\\p{L}_][\\p{L}\\p{Nd}_]*) !.", "Id");
+    assert_match("Id[_ñandú2]", run_str(&p, "_ñandú2"));
+    assert_match("Id[λόγος]", run_str(&p, "λόγος"));
+    assert_match("Id[变量٣]", run_str(&p, "变量٣"));
+    assert!(run_str(&p, "2x").is_err());
+    assert!(run_str(&p, "a-b").is_err());
+
+    // scripts, binary properties and loose names, negated items
+    // and negated classes
+    let p = compile(
+        &cc,
+        "A <- #([\\p{sc=Greek}]+ [\\P{White Space}] [^\\p{alphabetic}]) !.",
+        "A",
+    );
+    assert_match("A[αβ1!]", run_str(&p, "αβ1!"));
+    assert!(run_str(&p, "ab1!").is_err());
+    assert!(run_str(&p, "αβ !").is_err());
+    assert!(run_str(&p, "αβ1x").is_err());
+
+    // programs with properties survive being saved
+    let loaded = vm::Program::from_bytes(&p.to_bytes()).unwrap();
+    loaded.validate().unwrap();
+    assert_match("A[αβ1!]", run_str(&loaded, "αβ1!"));
+
+    let ast = parser::parse("Word <- [\\p{Klingon}]+").unwrap();
+    let err = compiler::Compiler::new(cc)
+        .compile(&ast, Some("Word"))
+        .unwrap_err();
+    assert_eq!(
+        "Compiler Error[UnknownProperty]: `\\p{Klingon}` at 0:9 isn't a known Unicode property",
+        err.to_string(),
+    );
+    let err = compiler::Compiler::new(compiler::Config::default().byte_mode())
+        .compile(&ast, Some("Word"))
+        .unwrap_err();
+    assert!(err.to_string().contains("only allowed in text mode"));
+}
+
+#[test]
+fn test_host_functions() {
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "Stmts <- Stmt+
+         Stmt <- Typedef / Decl / Expr
+         Typedef <- 'typedef' Name %host(declare) ';'
+         Decl <- Name %host(is_type) Name ';'
+         Expr <- Name ';'
+         Name <- [a-z]+",
+        "Stmts",
+    );
+    let last_name = |call: &vm::HostCall| match call.captures.last() {
+        Some(value::Value::Node(n)) => n.items[0].to_string(),
+        _ => unreachable!(),
+    };
+    let mut machine = vm::VM::new(&p);
+    machine.register_host("declare", move |call| {
+        let name = last_name(call);
+        if let Some(types) = call.ctx::<HashSet<String>>() {
+            types.insert(name);
+        }
+        true
+    });
+    machine.register_host("is_type", move |call| {
+        let name = last_name(call);
+        call.ctx::<HashSet<String>>()
+            .is_some_and(|types| types.contains(&name))
+    });
+
+    // `a b;` is only a declaration once `a` is known to be a type
+    let mut types = HashSet::<String>::new();
+    let r = machine.run_with_ctx("a b; typedef a; a b;", &mut types);
+    assert!(r.is_err());
+    let r = machine.run_with_ctx("typedef a; a b; b;", &mut types);
+    assert_match(
+        "Stmts[Stmt[Typedef[typedefName[a];]]Stmt[Decl[Name[a]Name[b];]]Stmt[Expr[Name[b];]]]",
+        r,
+    );
+    assert!(types.contains("a"));
+
+    // without a context, no names are types
+    assert_match("Stmts[Stmt[Expr[Name[b];]]]", machine.run("b;"));
+    assert!(machine.run("typedef a;").is_ok());
+    assert_eq!(
+        Err(vm::Error::HostNotFound("declare".to_string())),
+        vm::VM::new(&p).run("typedef a;"),
+    );
+}
+
+#[test]
+fn test_host_push_input() {
+    let cc = compiler::Config::default();
+    let p = compile(
+        &cc,
+        "File <- Line*
+         Line <- Include ';' / Include / Word
+         Include <- '#include' Name %host(include)
+         Name <- [a-z.]+
+         Word <- [a-z]+",
+        "File",
+    );
+    let mut machine = vm::VM::new(&p);
+    machine.register_host("include", |call| {
+        let name = match call.captures.last() {
+            Some(value::Value::Node(n)) => n.items[0].to_string(),
+            _ => unreachable!(),
+        };
+        let files = call.ctx::<HashMap<&str, &str>>().unwrap();
+        match files.get(name.as_str()).copied() {
+            Some(text) => {
+                call.push_input(&name, text);
+                true
+            }
+            None => false,
+        }
+    });
+    let mut files = HashMap::from([("lib.h", "x #include inner.h\ny"), ("inner.h", "w")]);
+    let r = machine.run_with_ctx("a #include lib.h b", &mut files);
+    let words = "Line[Word[x]]Line[Include[#includeName[inner.h]]]Line[Word[w]]Line[Word[y]]";
+    assert_match(
+        &format!(
+            "File[Line[Word[a]]Line[Include[#includeName[lib.h]]]{}Line[Word[b]]]",
+            words
+        ),
+        r.clone(),
+    );
+
+    // positions are counted within the input each line comes from
+    fn first_leaf(v: &value::Value) -> &value::Value {
+        match v {
+            value::Value::Node(n) => first_leaf(&n.items[0]),
+            v => v,
+        }
+    }
+    let items = match r.unwrap().unwrap() {
+        value::Value::Node(n) => n.items,
+        _ => unreachable!(),
+    };
+    let located: Vec<_> = items
+        .iter()
+        .filter(|line| matches!(line, value::Value::Node(n) if n.items[0].to_string().starts_with("Word")))
+        .map(|line| {
+            let start = first_leaf(line).span().start;
+            let source = machine.source_of(&start).map(|i| i.name.as_str());
+            (source, start.line, start.column)
+        })
+        .collect();
+    assert_eq!(
+        vec![
+            (None, 0, 1),
+            (Some("lib.h"), 0, 1),
+            (Some("inner.h"), 0, 1),
+            (Some("lib.h"), 1, 1),
+            (None, 0, 18),
+        ],
+        located,
+    );
+    assert_eq!(2, machine.inclusions().len());
+
+    // the first alternative of `Line` fails after pushing the file,
+    // and backtracking drops it so it isn't matched twice
+    let r = machine.run_with_ctx("#include inner.h", &mut files);
+    assert_match("File[Line[Include[#includeName[inner.h]]]Line[Word[w]]]", r);
+    assert_eq!(1, machine.inclusions().len());
+}
+
 // -- Left Recursion -------------------------------------------------------
 
 #[test]
@@ -268,6 +866,19 @@ fn test_lr4() {
     assert_match("E[E[(E[E[3]+E[5]])]*E[2]]", run_str(&program, "(3+5)*2"));
 }
 
+#[test]
+fn test_lr_keeps_longest_match() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "E <- E¹ '+' E² / E² '*' E³ / [0-9]+", "E");
+
+    // the attempt that fails to match more input than the longest
+    // match found so far doesn't leave its values behind
+    assert_match("E[1]", run_str(&program, "1"));
+    assert_match("E[E[1]+E[2]]", run_str(&program, "1+2"));
+    assert_match("E[E[E[1]+E[2]]+E[3]]", run_str(&program, "1+2+3"));
+    assert_match("E[E[1]+E[E[2]*E[3]]]", run_str(&program, "1+2*3"));
+}
+
 #[test]
 fn test_lr5() {
     let cc = compiler::Config::default();
@@ -291,7 +902,7 @@ fn test_lr5() {
 fn test_list_with_no_list() {
     let cc = compiler::Config::default();
     let program = compile(&cc, "A <- { 'aba' }", "A");
-    let result = vm::VM::new(&program).run(vec![
+    let result = vm::VM::new(&program).run_values(vec![
         value::Char::new_val(Span::default(), 'a'),
         value::Char::new_val(Span::default(), 'b'),
         value::Char::new_val(Span::default(), 'a'),
@@ -299,7 +910,7 @@ fn test_list_with_no_list() {
     assert!(result.is_err());
     assert_eq!(
         result.unwrap_err(),
-        vm::Error::Matching(0, "Not a list".to_string())
+        vm::Error::Matching(Position::default(), "Not a list".to_string())
     );
 }
 
@@ -316,13 +927,13 @@ fn test_list_0() {
             value::Char::new_val(Span::default(), 'a'),
         ],
     )];
-    assert_match("A[[aba]]", vm::VM::new(&p).run(input_with_chr));
+    assert_match("A[[aba]]", vm::VM::new(&p).run_values(input_with_chr));
 
     let input_with_str = vec![value::List::new_val(
         Span::default(),
         vec![value::String::new_val(Span::default(), "aba".to_string())],
     )];
-    assert_match("A[[aba]]", vm::VM::new(&p).run(input_with_str))
+    assert_match("A[[aba]]", vm::VM::new(&p).run_values(input_with_str))
 }
 
 #[test]
@@ -347,7 +958,7 @@ fn test_list_nested_0() {
             value::Char::new_val(Span::default(), 'e'),
         ],
     )];
-    assert_match("A[[[aba]cate]]", vm::VM::new(&p).run(input_with_chr));
+    assert_match("A[[[aba]cate]]", vm::VM::new(&p).run_values(input_with_chr));
 
     let input_with_str = vec![value::List::new_val(
         Span::default(),
@@ -359,7 +970,7 @@ fn test_list_nested_0() {
             value::String::new_val(Span::default(), "cate".to_string()),
         ],
     )];
-    assert_match("A[[[aba]cate]]", vm::VM::new(&p).run(input_with_str));
+    assert_match("A[[[aba]cate]]", vm::VM::new(&p).run_values(input_with_str));
 }
 
 #[test]
@@ -368,7 +979,7 @@ fn test_node_0() {
     let p = compile(&cc, "A <- { A: 'aba' }", "A");
     assert_match(
         "A[A[aba]]",
-        vm::VM::new(&p).run(vec![value::Node::new_val(
+        vm::VM::new(&p).run_values(vec![value::Node::new_val(
             Span::default(),
             "A".to_string(),
             vec![
@@ -392,7 +1003,7 @@ fn test_reporting_0() {
     assert_eq!(
         result.unwrap_err(),
         vm::Error::Matching(
-            0,
+            Position::new(0, 0, 1),
             "syntax error, expecting: 'abada', 'abacate', 'abadia', 'aba'".to_string()
         )
     );
@@ -476,24 +1087,1544 @@ fn test_manual_recovery() {
     );
 }
 
-// -- Expand Grammar -------------------------------------------------------
+#[test]
+fn test_sync_recovery() {
+    let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "
+            P          <- Stm+
+            @sync(';')
+            Stm        <- Identifier EQ^assigneq Number^assignexpr SEMI
+            Identifier <- [a-z]+
+            Number     <- [0-9]+
+            EQ         <- '='
+            SEMI       <- ';'
+            ",
+        "P",
+    );
+
+    // the input up to the `;` is discarded and the next statement
+    // is parsed as usual
+    assert_match(
+        "P[Stm[Identifier[a]EQ[=]Error[assignexpr]SEMI[;]]Stm[Identifier[b]EQ[=]Number[2]SEMI[;]]]",
+        run_str(&program, "a = x y; b = 2;"),
+    );
+    assert_match(
+        "P[Stm[Identifier[a]Error[assigneq]Error[assignexpr]SEMI[;]]]",
+        run_str(&program, "a 1;"),
+    );
+}
 
 #[test]
-fn test_expand_tree_0() {
+fn test_label_messages() {
     let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "
+            label assigneq = \"expected `=` after the name\"
+            label assignexpr = \"expected a number\"
 
-    // Program that parses the initial input
-    let input_grammar = "A <- 'F'";
-    let program = compile(&cc, input_grammar, "A");
-    let output = run_str(&program, "F");
+            P          <- Stm+
+            Stm        <- Identifier EQ^assigneq Number^assignexpr SEMI
+            Identifier <- [a-z]+
+            Number     <- [0-9]+
+            EQ         <- '='
+            SEMI       <- ';'
 
-    // Program that parses the output obtained upon successful
-    // parsing with the initial program
-    let original_ast = parser::parse(input_grammar).unwrap();
-    let rewrite = compiler::expand(&original_ast);
+            assignexpr <- (!SEMI .)*
+            ",
+        "P",
+    );
+
+    // labels without recovery expressions stop matching with the
+    // message declared for them
+    assert_eq!(
+        Err(vm::Error::Label(
+            "assigneq".to_string(),
+            Some("expected `=` after the name".to_string()),
+            Position::new(2, 0, 3),
+        )),
+        run_str(&program, "a 1;"),
+    );
+
+    // errors captured after recovering carry the message as well
+    let value = run_str(&program, "a = x;").unwrap().unwrap();
+    let mut errors = vec![];
+    collect_errors(&value, &mut errors);
+    assert_eq!(
+        vec![value::Error::new(
+            errors[0].span.clone(),
+            "assignexpr".to_string(),
+            Some("expected a number".to_string()),
+        )],
+        errors,
+    );
 
+    // a label can only have one message
+    let ast = parser::parse("label l = \"a\"\nlabel l = \"b\"\nA <- 'a'^l").unwrap();
     let mut c = compiler::Compiler::new(cc);
-    let list_program = c.compile(&rewrite, Some("A")).unwrap();
-    let value = vm::VM::new(&list_program).run(vec![output.unwrap().unwrap()]);
-    assert_match("A[A[F]]", value);
+    assert_eq!(
+        "Compiler Error[Semantic]: label `l` at 1:0 already has a message",
+        c.compile(&ast, Some("A")).unwrap_err().to_string(),
+    );
+}
+
+#[test]
+fn test_label_recovery() {
+    let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "
+            label semi = \"missing `;`\" recover (!';' .)* ';'
+            label expr = \"expected a number\" recover (!';' .)*
+
+            P          <- Stm+
+            Stm        <- Identifier '=' Number^expr ';'^semi
+            Identifier <- [a-z]+
+            Number     <- [0-9]+
+            ",
+        "P",
+    );
+
+    // every failure is recorded, and matching carries on after the
+    // input the recovery expressions skip
+    assert_match(
+        "P[Stm[Identifier[a]=Error[expr: expected a number];]\
+           Stm[Identifier[b]=Number[2]Error[semi: missing `;`]]\
+           Stm[Identifier[d]=Number[4];]]",
+        run_str(&program, "a = x; b = 2 c = 3; d = 4;"),
+    );
+
+    // a label can't recover with both an expression and a rule
+    let ast = parser::parse("label l = \"m\" recover 'x'\nA <- 'a'^l\nl <- 'y'").unwrap();
+    let mut c = compiler::Compiler::new(cc);
+    assert_eq!(
+        "Compiler Error[Semantic]: label `l` at 0:0 has a recovery expression and a rule named after it",
+        c.compile(&ast, Some("A")).unwrap_err().to_string(),
+    );
+}
+
+fn collect_errors(value: &value::Value, errors: &mut Vec<value::Error>) {
+    match value {
+        value::Value::Error(e) => errors.push(e.clone()),
+        value::Value::Node(n) => n.items.iter().for_each(|i| collect_errors(i, errors)),
+        value::Value::List(l) => l.values.iter().for_each(|i| collect_errors(i, errors)),
+        _ => {}
+    }
+}
+
+#[test]
+fn test_max_errors() {
+    let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "
+            P          <- Stm+
+            @sync(';')
+            Stm        <- Identifier EQ^assigneq Number^assignexpr SEMI
+            Identifier <- [a-z]+
+            Number     <- [0-9]+
+            EQ         <- '='
+            SEMI       <- ';'
+            ",
+        "P",
+    );
+    let input = "a = x; b = y; c = z;";
+
+    // up to the limit, errors are recovered from as usual
+    let config = vm::Config::default().with_max_errors(3);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert_match(
+        "P[Stm[Identifier[a]EQ[=]Error[assignexpr]SEMI[;]]Stm[Identifier[b]EQ[=]Error[assignexpr]SEMI[;]]Stm[Identifier[c]EQ[=]Error[assignexpr]SEMI[;]]]",
+        r,
+    );
+
+    // the label thrown after the limit is reached stops matching
+    let config = vm::Config::default().with_max_errors(2);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert_eq!(
+        Err(vm::Error::TooManyErrors(2, Position::new(18, 0, 19))),
+        r
+    );
+
+    // failing fast stops at the first label, even if it could be
+    // recovered from
+    let config = vm::Config::default().with_on_error(vm::OnError::FailFast);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert_eq!(
+        Err(vm::Error::Label(
+            "assignexpr".to_string(),
+            None,
+            Position::new(4, 0, 5)
+        )),
+        r
+    );
+    let config = vm::Config::default().with_on_error(vm::OnError::FailFast);
+    let r = vm::VM::new_with_config(&program, config).run("a = 1;");
+    assert_match("P[Stm[Identifier[a]EQ[=]Number[1]SEMI[;]]]", r);
+}
+
+#[test]
+fn test_resource_limits() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- '(' A ')' / 'x'", "A");
+    let input = format!("{}x{}", "(".repeat(20), ")".repeat(20));
+    let input = input.as_str();
+
+    // generous limits don't get in the way of matching
+    let config = vm::Config::default()
+        .with_max_stack_depth(100)
+        .with_max_steps(1000);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert!(r.is_ok());
+
+    // recursing deeper than the stack allows stops within the rule
+    let config = vm::Config::default().with_max_stack_depth(10);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert!(matches!(
+        r,
+        Err(vm::Error::ResourceLimit(vm::Limit::StackDepth, 10, ref rule, _)) if rule == "A"
+    ));
+
+    // so does running more instructions than allowed
+    let config = vm::Config::default().with_max_steps(20);
+    let r = vm::VM::new_with_config(&program, config).run(input);
+    assert!(matches!(
+        r,
+        Err(vm::Error::ResourceLimit(vm::Limit::Steps, 20, ref rule, _)) if rule == "A"
+    ));
+
+    // left recursion grows the tree one level deeper for each term
+    // without growing the stack, so each term counts as a frame too
+    let program = compile(&cc, "E <- E '+' 'n' / 'n'", "E");
+    let input = format!("n{}", "+n".repeat(50));
+    let config = vm::Config::default().with_max_stack_depth(100);
+    let r = vm::VM::new_with_config(&program, config).run(&input);
+    assert!(r.is_ok());
+    let config = vm::Config::default().with_max_stack_depth(20);
+    let r = vm::VM::new_with_config(&program, config).run(&input);
+    assert!(matches!(
+        r,
+        Err(vm::Error::ResourceLimit(vm::Limit::StackDepth, 20, ref rule, _)) if rule == "E"
+    ));
+}
+
+#[test]
+fn test_empty_iteration_events() {
+    let empty = vm::RuleEvent::EmptyIteration {
+        rule: "A".to_string(),
+        cursor: 2,
+    };
+    for cc in [compiler::Config::o0(), compiler::Config::o1()] {
+        // the compiler rejects repetitions of expressions that match
+        // without consuming input, but it can't see into the rules
+        // of programs linked afterwards
+        let cc = cc
+            .disable_injecting_whitespace_handling()
+            .defer_unresolved();
+        let unit = |grammar: &str| {
+            let ast = parser::parse(grammar).unwrap();
+            compiler::Compiler::new(cc.clone())
+                .compile(&ast, None)
+                .unwrap()
+        };
+        let (main, b) = (unit("A <- B* 'x'"), unit("B <- 'b'?"));
+        let program = vm::Program::concat(&[&main, &b]).unwrap();
+        let config = vm::Config::default()
+            .with_rule_events()
+            .with_max_steps(1000);
+        let mut m = vm::VM::new_with_config(&program, config);
+        // the repetition still loops until the limit stops it, but
+        // it's only reported once
+        assert!(matches!(
+            m.run("bbx"),
+            Err(vm::Error::ResourceLimit(vm::Limit::Steps, ..))
+        ));
+        let found: Vec<_> = m
+            .events()
+            .iter()
+            .filter(|e| matches!(e, vm::RuleEvent::EmptyIteration { .. }))
+            .collect();
+        assert_eq!(vec![&empty], found);
+    }
+
+    // nothing is reported for repetitions that make progress
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- B* 'x'\nB <- 'b'", "A");
+    let mut m = vm::VM::new_with_config(&program, vm::Config::default().with_rule_events());
+    assert!(m.run("bbx").is_ok());
+    assert!(!m
+        .events()
+        .iter()
+        .any(|e| matches!(e, vm::RuleEvent::EmptyIteration { .. })));
+}
+
+#[test]
+fn test_run_from() {
+    let grammar = "
+        Module    <- Statement+
+        Statement <- Name '=' Expr ';'
+        Expr      <- Expr '+' Num / Num
+        Name      <- [a-z]+
+        Num       <- [0-9]+
+    ";
+    let cc = compiler::Config::default();
+    let program = compile(&cc, grammar, "Module");
+    let entry_points = program.entry_points();
+    assert_eq!(
+        vec!["Module", "Statement", "Expr", "Name", "Num"],
+        entry_points[..5].to_vec()
+    );
+
+    let mut m = vm::VM::new(&program);
+    assert_match("Expr[Expr[Num[1]]+Num[2]]", m.run_from("Expr", "1 + 2"));
+    assert_match(
+        "Statement[Name[a]=Expr[Num[1]];]",
+        m.run_from("Statement", "a = 1;"),
+    );
+    assert_eq!(
+        Err(vm::Error::RuleNotFound("Stmt".to_string())),
+        m.run_from("Stmt", "a = 1;")
+    );
+
+    // runs without a rule still start from the first one
+    assert!(m.run("1 + 2").is_err());
+    assert_match("Module[Statement[Name[a]=Expr[Num[1]];]]", m.run("a = 1;"));
+}
+
+#[test]
+fn test_rule_events() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- B C\nB <- 'b'\nC <- 'c' / 'x'", "A");
+    let enter = |rule: &str, cursor| vm::RuleEvent::Enter {
+        rule: rule.to_string(),
+        cursor,
+    };
+    let exit = |rule: &str, cursor, matched| vm::RuleEvent::Exit {
+        rule: rule.to_string(),
+        cursor,
+        matched,
+    };
+
+    let mut m = vm::VM::new_with_config(&program, vm::Config::default().with_rule_events());
+    assert!(m.run("bd").is_err());
+    assert_eq!(
+        vec![
+            enter("A", 0),
+            enter("B", 0),
+            exit("B", 1, true),
+            enter("C", 1),
+            exit("C", 1, false),
+            exit("A", 1, false),
+        ],
+        m.events()
+    );
+
+    // going back to an earlier event re-runs the program up to it
+    let mut m = vm::VM::new_with_config(&program, vm::Config::default().with_stop_at_event(3));
+    assert_eq!(Err(vm::Error::Stopped(3)), m.run("bd"));
+    assert_eq!(4, m.events().len());
+    assert_eq!(1, m.cursor());
+    assert_eq!(vec!["A".to_string(), "C".to_string()], m.rule_stack());
+}
+
+#[test]
+fn test_choice_drops_captures_of_failed_alternative() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- B* 'x' / B*\nB <- 'b'", "A");
+    assert_match("A[B[b]B[b]]", run_str(&program, "bb"));
+    assert_match("A[B[b]B[b]x]", run_str(&program, "bbx"));
+}
+
+#[test]
+fn test_capture_filters() {
+    let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "Call <- Name '(' Args ')'\nArgs <- Arg (',' Arg)*\nArg <- Name / Number\nName <- [a-z]+\nNumber <- Digit+\nDigit <- [0-9]",
+        "Call",
+    );
+
+    let config = vm::Config::default().with_capture_only(&["Name", "Number"]);
+    let mut m = vm::VM::new_with_config(&program, config);
+    assert_match("Call[Name[f]Name[x]Number[12]]", m.run("f(x, 12)"));
+
+    let config = vm::Config::default().with_capture_except(&["Args", "Arg", "Digit"]);
+    let mut m = vm::VM::new_with_config(&program, config);
+    assert_match("Call[Name[f](Name[x],Number[12])]", m.run("f(x, 12)"));
+}
+
+#[test]
+fn test_interned_leaves() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "L <- N (',' N)*\nN <- [0-9]+", "L");
+    let leaves = |config: vm::Config| {
+        let value = vm::VM::new_with_config(&program, config)
+            .run("10,7,10,10")
+            .unwrap()
+            .unwrap();
+        let mut leaves = vec![];
+        fn collect(value: &value::Value, leaves: &mut Vec<Arc<str>>) {
+            match value {
+                value::Value::String(s) => leaves.push(s.value.clone()),
+                value::Value::List(l) => l.values.iter().for_each(|v| collect(v, leaves)),
+                value::Value::Node(n) => n.items.iter().for_each(|v| collect(v, leaves)),
+                _ => {}
+            }
+        }
+        collect(&value, &mut leaves);
+        leaves
+    };
+
+    let plain = leaves(vm::Config::default());
+    let interned = leaves(vm::Config::default().with_interned_leaves());
+    assert_eq!(plain, interned);
+    let tens: Vec<_> = interned.iter().filter(|l| &***l == "10").collect();
+    assert_eq!(3, tens.len());
+    assert!(tens.windows(2).all(|w| Arc::ptr_eq(w[0], w[1])));
+    let tens: Vec<_> = plain.iter().filter(|l| &***l == "10").collect();
+    assert!(!Arc::ptr_eq(tens[0], tens[1]));
+}
+
+#[test]
+fn test_progress() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- !(B* 'x') B*\nB <- 'b'", "A");
+
+    // reports don't repeat when the cursor backtracks after the predicate
+    let reports = Rc::new(RefCell::new(vec![]));
+    let mut m = vm::VM::new(&program);
+    let r = reports.clone();
+    m.on_progress(2, move |p| {
+        r.borrow_mut().push(p.cursor);
+        true
+    });
+    assert_match("A[B[b]B[b]B[b]B[b]B[b]]", m.run("bbbbb"));
+    assert_eq!(vec![2, 4], *reports.borrow());
+
+    // the callback can stop matching
+    let mut m = vm::VM::new(&program);
+    m.on_progress(3, |p| p.cursor < 3);
+    assert_eq!(
+        Err(vm::Error::Cancelled(Position::new(3, 0, 4))),
+        m.run("bbbbb")
+    );
+}
+
+#[test]
+fn test_tracer() {
+    #[derive(Default)]
+    struct Calls(Rc<RefCell<Vec<String>>>);
+
+    impl vm::Tracer for Calls {
+        fn on_call(&mut self, step: &vm::Step, rule: &str) {
+            self.0
+                .borrow_mut()
+                .push(format!("{}> {}", step.depth, rule));
+        }
+
+        fn on_return(&mut self, step: &vm::Step, rule: &str, matched: bool) {
+            let mark = if matched { "<" } else { "<!" };
+            self.0
+                .borrow_mut()
+                .push(format!("{}{} {}@{}", step.depth, mark, rule, step.cursor));
+        }
+    }
+
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- B C / B\nB <- 'b'\nC <- 'c'", "A");
+    let calls = Calls::default();
+    let seen = calls.0.clone();
+    let mut m = vm::VM::new(&program);
+    m.set_tracer(calls);
+    assert_match("A[B[b]]", m.run("bd"));
+    assert_eq!(
+        vec!["1> A", "2> B", "2< B@1", "2> C", "2<! C@1", "2> B", "2< B@1", "1< A@1"],
+        *seen.borrow()
+    );
+
+    // the built-in tracer prints a line per step, indented by depth
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let program = compile(&cc, "A <- 'a'", "A");
+    let out = Rc::new(RefCell::new(vec![]));
+    let mut m = vm::VM::new(&program);
+    m.set_tracer(vm::PrintTracer::new(Shared(out.clone())));
+    assert_match("A[a]", m.run("a"));
+    assert_eq!(
+        "     0        0     0  call \"A\" 0
+     2        0     1  > A
+     2        0     1    string \"a\"
+     3        1     1    capjoin
+     4        1     1    return
+     4        1     1  < A
+     1        1     0  halt
+",
+        String::from_utf8(out.borrow().clone()).unwrap()
+    );
+}
+
+#[test]
+fn test_debugger() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "E <- E '+' N / N\nN <- [0-9]", "E");
+    let mut d = vm::Debugger::new(vm::VM::new(&program), "1+2");
+    assert!(d.add_breakpoint("N"));
+    assert!(!d.add_breakpoint("M"));
+    assert_eq!(vec!["N".to_string()], d.breakpoints());
+
+    // the first instruction calls the start rule
+    assert_eq!(0, d.program_counter());
+    assert_eq!("call \"E\" 1", d.instruction());
+    assert_eq!(vm::Stop::Step, d.step());
+    assert_eq!(vec!["E".to_string()], d.vm().rule_stack());
+
+    // resuming runs until a production with a breakpoint is entered
+    assert_eq!(vm::Stop::Breakpoint("N".to_string()), d.resume());
+    assert_eq!(0, d.vm().cursor());
+    let calls: Vec<_> = d
+        .frames()
+        .into_iter()
+        .filter(|f| f.kind == vm::FrameKind::Call)
+        .map(|f| f.rule.unwrap())
+        .collect();
+    assert_eq!(vec!["E", "N"], calls);
+    let memo = d.memo();
+    assert_eq!(1, memo.len());
+    assert_eq!(
+        ("E", 0, None),
+        (memo[0].rule.as_str(), memo[0].cursor, memo[0].end)
+    );
+
+    // the left recursive call grows its match one `+` at a time
+    assert_eq!(vm::Stop::Breakpoint("N".to_string()), d.resume());
+    assert_eq!(Some(1), d.memo()[0].end);
+    assert_eq!(3, d.captures().len());
+
+    d.remove_breakpoint("N");
+    assert!(d.breakpoints().is_empty());
+    match d.resume() {
+        vm::Stop::Halted(result) => assert_match("E[E[N[1]]+N[2]]", result),
+        stop => panic!("unexpected stop: {:?}", stop),
+    }
+    // the run is over, so stepping returns the same result
+    assert!(matches!(d.step(), vm::Stop::Halted(Ok(Some(_)))));
+}
+
+#[test]
+fn test_match() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "A <- 'ab'*", "A");
+
+    let m = vm::VM::new(&program).match_str("abab").unwrap();
+    assert_match("A[abab]", Ok(m.value.clone()));
+    assert_eq!((0, 4), (m.span.start.offset, m.span.end.offset));
+    assert!(!m.failed_after_end());
+    assert_eq!(None, m.stats);
+
+    // matching stops after the first `ab`, but got as far as `c`
+    let m = vm::VM::new(&program).match_str("abac").unwrap();
+    assert_match("A[ab]", Ok(m.value.clone()));
+    assert_eq!((0, 2), (m.span.start.offset, m.span.end.offset));
+    assert_eq!(3, m.ffp);
+    assert!(m.failed_after_end());
+
+    // the span of the consumed input is where the cursor stopped
+    let program = compile(&cc, "A <- 'a\nb'", "A");
+    let m = vm::VM::new(&program).match_str("a\nbc").unwrap();
+    assert_eq!(Position::new(3, 1, 2), m.span.end);
+
+    let config = vm::Config::default().with_stats();
+    let program = compile(&compiler::Config::o0(), "A <- ('a' / 'b')*", "A");
+    let m = vm::VM::new_with_config(&program, config.clone())
+        .match_str("abba")
+        .unwrap();
+    let stats = m.stats.unwrap();
+    assert!(stats.instructions > 0);
+    // each `b` is tried after backtracking from `a`, and both
+    // alternatives fail at the end of the input
+    assert_eq!(4, stats.backtracks);
+    assert!(stats.max_stack_depth > 1);
+
+    // `a` is checked before saving the state of the VM, so only
+    // the repetition backtracks at the end of the input
+    let program = compile(&cc, "A <- ('a' / 'b')*", "A");
+    let m = vm::VM::new_with_config(&program, config)
+        .match_str("abba")
+        .unwrap();
+    assert_eq!(1, m.stats.unwrap().backtracks);
+}
+
+#[test]
+fn test_trivia() {
+    let cc = compiler::Config::default();
+    let grammar = "@comments '#' eol\nList <- Item (',' Item)*\nItem <- [a-z]+";
+    let program = compile(&cc, grammar, "List");
+    let input = "a, # one\n  b # two\n,c";
+    let config = vm::Config::default().with_trivia();
+    let m = vm::VM::new_with_config(&program, config)
+        .match_str(input)
+        .unwrap();
+    assert_match("List[Item[a],Item[b],Item[c]]", Ok(m.value));
+    // what's matched after a token up to the end of the line trails
+    // it, and what's left leads the next one
+    let trivia: Vec<_> = m.trivia.iter().map(|t| (t.attachment, t.text())).collect();
+    assert_eq!(
+        vec![
+            (vm::Attachment::Trailing, " # one\n".to_string()),
+            (vm::Attachment::Leading, "  ".to_string()),
+            (vm::Attachment::Trailing, " # two\n".to_string()),
+        ],
+        trivia
+    );
+    assert_eq!(
+        (2, 9),
+        (m.trivia[0].span.start.offset, m.trivia[0].span.end.offset)
+    );
+    assert!(matches!(&m.trivia[0].items[1], value::Value::Node(n) if n.name == "Comment"));
+
+    // trivia matched by alternatives that fail is dropped with them
+    let grammar = "@comments '#' eol\nP <- A ';' / A\nA <- B B\nB <- [a-z]";
+    let program = compile(&cc, grammar, "P");
+    let config = vm::Config::default().with_trivia();
+    let m = vm::VM::new_with_config(&program, config.clone())
+        .match_str("a # one\nb")
+        .unwrap();
+    let trivia: Vec<_> = m.trivia.iter().map(|t| t.text()).collect();
+    assert_eq!(vec![" # one\n".to_string()], trivia);
+
+    // and it isn't kept unless asked for
+    let m = vm::VM::new(&program).match_str("a # one\nb").unwrap();
+    assert!(m.trivia.is_empty());
+}
+
+#[test]
+fn test_lossless() {
+    let cc = compiler::Config::default();
+    let program = helpers::compile_file(&cc, "./examples/json/grammar.peg", Some("JSON"));
+    let config = vm::Config::default().with_lossless();
+    for input in [
+        " {\n  \"a\": [1, 2.5e3, true],\n\t\"b\": null\n}\n",
+        "{\"a\": , \"b\": 2}",
+    ] {
+        let value = vm::VM::new_with_config(&program, config.clone())
+            .run(input)
+            .unwrap()
+            .unwrap();
+        // whitespace is kept within `Spacing` nodes
+        assert_eq!(input, value.to_text());
+    }
+
+    // productions that drop what they consume break the tree
+    let cc = cc.disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- 'a' B 'c'\n@capture(skip) B <- 'b'", "A");
+    let r = vm::VM::new_with_config(&program, config).run("abc");
+    assert_err(vm::Error::Lossless(Position::new(1, 0, 2)), r);
+    assert_match("A[ac]", run_str(&program, "abc"));
+}
+
+#[test]
+fn test_packrat() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    // each `T` is matched again by every alternative of `E` that
+    // backtracks, which is exponential on how deep the input nests
+    let grammar = "E <- T '+' E / T '-' E / T\nT <- '(' E ')' / 'n'";
+    let program = compile(&cc, grammar, "E");
+    let input = format!("{}n{}", "(".repeat(8), ")".repeat(8));
+
+    let config = vm::Config::default().with_stats();
+    let plain = vm::VM::new_with_config(&program, config.clone())
+        .match_str(&input)
+        .unwrap();
+    let packrat = vm::VM::new_with_config(&program, config.with_packrat())
+        .match_str(&input)
+        .unwrap();
+    assert_eq!(plain.value, packrat.value);
+    let (plain, packrat) = (plain.stats.unwrap(), packrat.stats.unwrap());
+    assert_eq!(0, plain.memo_hits + plain.memo_misses + plain.memo_entries);
+    assert!(packrat.memo_hits > 0);
+    assert!(packrat.memo_entries > 0);
+    assert!(packrat.instructions * 10 < plain.instructions);
+
+    // failures are memoized too, and reused ones fail at the same
+    // position a plain run does
+    let input = format!("{}n{}", "(".repeat(8), ")".repeat(7));
+    let expected = match run_str(&program, &input) {
+        Err(vm::Error::Matching(at, _)) => at,
+        r => panic!("unexpected {:?}", r),
+    };
+    let config = vm::Config::default().with_packrat();
+    let r = vm::VM::new_with_config(&program, config).run(&input);
+    assert!(matches!(r, Err(vm::Error::Matching(at, _)) if at == expected));
+
+    // calls made within predicates, including nested ones, where
+    // nothing gets captured, aren't reused by calls made outside
+    for (grammar, start, input, expected) in [
+        ("A <- !(B 'b') B\nB <- 'a' B / !'a'", "A", "ac", "A[B[a]]"),
+        (
+            "R0 <- !(R1 'b') R1\nR1 <- 'a' R1 / !('a')",
+            "R0",
+            "acab",
+            "R0[R1[a]]",
+        ),
+    ] {
+        let program = compile(&cc, grammar, start);
+        let config = vm::Config::default().with_packrat();
+        assert_match(expected, run_str(&program, input));
+        assert_match(
+            expected,
+            vm::VM::new_with_config(&program, config).run(input),
+        );
+    }
+}
+
+#[test]
+fn test_head_fail() {
+    let grammar = "
+        Value   <- '[' Items? ']' / '-' Num / Num / '#' Comment
+        Items   <- Value (',' Value)*
+        Num     <- [0-9]+
+        Comment <- . Comment / ''
+    ";
+    let o0 = compiler::Config::o0().disable_injecting_whitespace_handling();
+    let o1 = compiler::Config::o1().disable_injecting_whitespace_handling();
+    let (plain, tested) = (
+        compile(&o0, grammar, "Value"),
+        compile(&o1, grammar, "Value"),
+    );
+    assert!(tested.to_string().contains("testchar '['"));
+    assert!(tested.to_string().contains("testany"));
+
+    // alternatives skipped by the tests match the same values and
+    // fail at the same positions as the ones that backtrack
+    let at = |r: Result<Option<value::Value>, vm::Error>| match r {
+        Err(vm::Error::Matching(at, _)) => Err(at),
+        Ok(v) => Ok(format!("{:?}", v)),
+        r => panic!("unexpected {:?}", r),
+    };
+    for input in ["[1,-2,[3]]", "x[1]", "[1,", "[1;2]", "-", "", "[#x]", "#x"] {
+        assert_eq!(
+            at(run_str(&plain, input)),
+            at(run_str(&tested, input)),
+            "{}",
+            input
+        );
+    }
+
+    let config = vm::Config::default().with_stats();
+    let input = "[1,-2,[3,4],5]";
+    let plain = vm::VM::new_with_config(&plain, config.clone())
+        .match_str(input)
+        .unwrap()
+        .stats
+        .unwrap();
+    let tested = vm::VM::new_with_config(&tested, config)
+        .match_str(input)
+        .unwrap()
+        .stats
+        .unwrap();
+    assert!(tested.backtracks < plain.backtracks);
+}
+
+#[test]
+fn test_inline_rules() {
+    let grammar = "
+        Stmt    <- Keyword Name / Name '=' Name
+        Keyword <- #('let' !Letter)
+        Name    <- !Keyword #(Letter+)
+        Letter  <- [a-z]
+    ";
+    let cc = compiler::Config::default();
+    let (plain, inlined) = (
+        compile(&cc, grammar, "Stmt"),
+        compile(&cc.with_inline_threshold(4), grammar, "Stmt"),
+    );
+
+    // the calls within predicates are gone, but the values matched
+    // and the errors stay the same
+    for input in ["let x", "letter = lets", "x = y", "let", "x =", "let 1", ""] {
+        assert_eq!(
+            format!("{:?}", run_str(&plain, input)),
+            format!("{:?}", run_str(&inlined, input)),
+            "{}",
+            input
+        );
+    }
+    assert_match(
+        "Stmt[Keyword[let]Name[Letter[x]]]",
+        run_str(&inlined, "let x"),
+    );
+
+    let config = vm::Config::default().with_stats();
+    let count = |program: &vm::Program| {
+        vm::VM::new_with_config(program, config.clone())
+            .match_str("letter = lets")
+            .unwrap()
+            .stats
+            .unwrap()
+            .instructions
+    };
+    assert!(count(&inlined) < count(&plain));
+}
+
+#[test]
+fn test_remove_unused_rules() {
+    let grammar = "
+        Pair  <- Key ':' Value
+        Key   <- [a-z]+
+        Value <- [0-9]+
+        Old   <- Key '=' Value
+    ";
+    let cc = compiler::Config::default();
+    let (all, used) = (
+        compile(&cc, grammar, "Pair"),
+        compile(&cc.remove_unused_rules(), grammar, "Pair"),
+    );
+    // the builtins the grammar doesn't use are gone too
+    assert!(used.to_string().len() < all.to_string().len());
+    assert!(!used.to_string().contains("Old:"));
+    assert_match("Pair[Key[ab]:Value[12]]", run_str(&used, "ab: 12"));
+}
+
+#[test]
+fn test_tail_calls() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let grammar = "List <- 'a' List / 'b'";
+    assert_match("List[aList[aList[b]]]", cc_run(&cc, grammar, "List", "aab"));
+
+    let cc = cc.enable_tail_calls();
+    assert_match("List[aab]", cc_run(&cc, grammar, "List", "aab"));
+    assert!(cc_run(&cc, grammar, "List", "aaa").is_err());
+
+    // the stack doesn't grow with the input
+    let program = compile(&cc, grammar, "List");
+    let depths = Rc::new(RefCell::new(vec![]));
+    let mut m = vm::VM::new(&program);
+    let d = depths.clone();
+    m.on_progress(500, move |p| {
+        d.borrow_mut().push(p.stack_depth);
+        true
+    });
+    let input = format!("{}b", "a".repeat(2_000));
+    assert!(m.run(&input).unwrap().is_some());
+    assert!(depths.borrow().iter().all(|depth| *depth < 4));
+
+    // the call isn't the last thing the rule does
+    let program = compile(&cc, "List <- 'a' List 'c' / 'b'", "List");
+    assert_match("List[aList[b]c]", run_str(&program, "abc"));
+}
+
+#[test]
+fn test_deeply_nested_expression() {
+    // 'a' / ('a' / (... / 'c'?)?)?, built by hand as parsing it takes a while
+    let span = Span::default();
+    let mut expr = ast::String::new_expr(span.clone(), "c".to_string());
+    for _ in 0..100_000 {
+        let optional = ast::Optional::new_expr(span.clone(), Box::new(expr));
+        let a = ast::String::new_expr(span.clone(), "a".to_string());
+        expr = ast::Choice::new_expr(span.clone(), vec![a, optional]);
+    }
+    let mut definitions = HashMap::new();
+    definitions.insert(
+        "A".to_string(),
+        ast::Definition::new(span.clone(), "A".to_string(), expr),
+    );
+    let grammar = ast::Grammar::new(span, vec![], vec!["A".to_string()], definitions);
+
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let expanded = compiler::expand(&grammar).unwrap();
+    let program = compiler::Compiler::new(cc.clone())
+        .compile(&expanded, None)
+        .unwrap();
+    program.validate().unwrap();
+
+    let program = compiler::Compiler::new(cc).compile(&grammar, None).unwrap();
+    program.validate().unwrap();
+    assert_match("A[a]", run_str(&program, "a"));
+}
+
+// -- Tokens ---------------------------------------------------------------
+
+#[test]
+fn test_tokens_0() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "Sum <- Num ('+' Num)*\nNum <- 'NUM'", "Sum");
+    let tokens = vec![
+        vm::Token::new("NUM", "1", Span::default()),
+        vm::Token::new("+", "+", Span::default()),
+        vm::Token::new("NUM", "22", Span::default()),
+    ];
+    assert_match(
+        "Sum[Num[1]+Num[22]]",
+        vm::VM::new(&program).run_tokens(tokens),
+    );
+}
+
+#[test]
+fn test_tokens_match_kind_not_text() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "A <- 'x' / .", "A");
+    let tokens = vec![vm::Token::new("ID", "x", Span::default())];
+    // the literal 'x' doesn't match the token of kind `ID`, so the
+    // any operator takes it
+    assert_match("A[x]", vm::VM::new(&program).run_tokens(tokens));
+
+    let program = compile(&cc, "A <- 'x'", "A");
+    let tokens = vec![vm::Token::new("ID", "x", Span::default())];
+    assert!(vm::VM::new(&program).run_tokens(tokens).is_err());
+}
+
+#[test]
+fn test_undefined_rule_suggestion() {
+    let grammar =
+        parser::parse("Value <- Identifer / Number\nIdentifier <- [a-z]+\nNumber <- [0-9]+")
+            .unwrap();
+    let r = compiler::Compiler::new(compiler::Config::default()).compile(&grammar, None);
+    assert!(matches!(
+        r,
+        Err(compiler::Error::UnresolvedReference(refs))
+            if refs.len() == 1 && refs[0].suggestion.as_deref() == Some("Identifier")
+    ));
+
+    // names too far from any defined rule get no suggestion
+    let grammar = parser::parse("Value <- Whatever\nNumber <- [0-9]+").unwrap();
+    let r = compiler::Compiler::new(compiler::Config::default()).compile(&grammar, None);
+    assert!(matches!(
+        r,
+        Err(compiler::Error::UnresolvedReference(refs))
+            if refs.len() == 1 && refs[0].suggestion.is_none()
+    ));
+}
+
+#[test]
+fn test_compile_and_parse_value() {
+    // builtins and `@comments` are available without the importer
+    let program =
+        langlang_lib::compile("@comments \"#\" eol\nList <- Item (',' Item)*\nItem <- [a-z]+\n")
+            .unwrap();
+    let value = program.parse_value("a, # comment\nbc").unwrap();
+    assert_eq!(
+        Some("List[Item[a],Item[bc]]".to_string()),
+        value.map(|v| format::compact(&v))
+    );
+
+    let e = langlang_lib::Error::from(program.parse_value(",").unwrap_err());
+    assert_eq!(langlang_lib::ErrorKind::Runtime, e.kind());
+
+    let e = langlang_lib::compile("List <- (").unwrap_err();
+    assert_eq!(langlang_lib::ErrorKind::Syntax, e.kind());
+    let e = langlang_lib::compile("List <- Item").unwrap_err();
+    assert_eq!(langlang_lib::ErrorKind::Compile, e.kind());
+}
+
+#[test]
+fn test_compile_rejects_problems() {
+    // repetitions of expressions that match without consuming input
+    // would loop forever, so compiling fails instead
+    let e = langlang_lib::compile("A <- (''*)*").unwrap_err();
+    assert_eq!(
+        "Compiler Error[Analysis]:\n\
+         `(\"\"*)*` within rule `A` loops forever once it matches without consuming input at 0:5\n\
+         `\"\"*` within rule `A` loops forever once it matches without consuming input at 0:6",
+        e.to_string()
+    );
+    assert_eq!(Some("A"), e.rule());
+    assert_eq!(Some(Position::new(5, 0, 5)), e.position());
+
+    // so do rules that never match, all of them reported at once
+    let grammar = parser::parse("A <- 'a' B\nB <- B 'b'\nC <- (&'c')+").unwrap();
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let r = compiler::Compiler::new(cc).compile(&grammar, None);
+    assert!(matches!(
+        r,
+        Err(compiler::Error::Analysis(ref problems)) if matches!(
+            &problems[..],
+            [
+                analysis::Problem::UnguardedLeftRecursion(b, _),
+                analysis::Problem::EmptyLoop(c, ..),
+            ] if b == "B" && c == "C"
+        )
+    ));
+}
+
+#[test]
+fn test_error_getters() {
+    use std::error::Error as _;
+
+    let grammar = parser::parse("A <- 'a'\nB <- C").unwrap();
+    let r = compiler::Compiler::new(compiler::Config::default()).compile(&grammar, None);
+    let e = langlang_lib::Error::from(r.unwrap_err());
+    assert_eq!(langlang_lib::ErrorKind::Compile, e.kind());
+    assert_eq!(Some("B"), e.rule());
+    assert_eq!(Some(Position::new(14, 1, 5)), e.span().map(|s| s.start));
+    assert!(e.source().unwrap().is::<compiler::Error>());
+
+    let program = compile(&compiler::Config::default(), "A <- 'ab'", "A");
+    let e = langlang_lib::Error::from(run_str(&program, "ac").unwrap_err());
+    assert_eq!(langlang_lib::ErrorKind::Runtime, e.kind());
+    assert_eq!(Some(1), e.offset());
+    assert_eq!(None, e.rule());
+    assert!(e.source().unwrap().is::<vm::Error>());
+
+    let e = langlang_lib::Error::from(parser::parse("A <- (").unwrap_err());
+    assert_eq!(langlang_lib::ErrorKind::Syntax, e.kind());
+    assert!(e.offset().is_some());
+}
+
+#[test]
+fn test_unresolved_references() {
+    let grammar = parser::parse("Expr <- Term Op / Atom\nAtom <- Term Number\nOp <- '+'").unwrap();
+    let r = compiler::Compiler::new(compiler::Config::default()).compile(&grammar, None);
+    let refs = match r {
+        Err(compiler::Error::UnresolvedReference(refs)) => refs,
+        r => panic!("expected unresolved references, got {:?}", r),
+    };
+    // every site is reported, in the order it's found in the grammar
+    let sites: Vec<(&str, &str)> = refs
+        .iter()
+        .map(|r| (r.name.as_str(), r.referenced_from.as_str()))
+        .collect();
+    assert_eq!(
+        vec![("Term", "Expr"), ("Term", "Atom"), ("Number", "Atom")],
+        sites
+    );
+    assert_eq!(
+        "rule `Number` referenced from `Atom` at 1:13 isn't defined",
+        refs[2].to_string()
+    );
+}
+
+#[test]
+fn test_forward_references() {
+    let cc = compiler::Config::default();
+
+    // rules are referenced before they're defined
+    let p = compile(&cc, "A <- B C\nB <- 'b' C?\nC <- 'c'", "A");
+    assert_match("A[B[bC[c]]C[c]]", run_str(&p, "bcc"));
+
+    // mutual recursion
+    let p = compile(
+        &cc,
+        "Value <- List / [0-9]\nList <- '(' Items ')'\nItems <- Value*",
+        "Value",
+    );
+    assert_match(
+        "Value[List[(Items[Value[1]Value[List[(Items[Value[2]])]]])]]",
+        run_str(&p, "(1(2))"),
+    );
+}
+
+#[test]
+fn test_rule_ids() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "A <- B C\nB <- 'b'\nC <- 'c'", "A");
+    let a = match run_str(&program, "bc").unwrap() {
+        Some(value::Value::Node(n)) => n,
+        v => panic!("expected node, got {:?}", v),
+    };
+    let (b, c) = match &a.items[..] {
+        [value::Value::Node(b), value::Value::Node(c)] => (b, c),
+        items => panic!("expected two nodes, got {:?}", items),
+    };
+
+    // IDs follow the order of definition and map back to the names
+    assert!(a.rule < b.rule && b.rule < c.rule);
+    for node in [&a, b, c] {
+        assert_eq!(Some(&node.name), program.rule_name(node.rule.unwrap()));
+    }
+
+    // nodes built by hand don't have an ID
+    let node = value::Node::new(Span::default(), "A".to_string(), vec![]);
+    assert_eq!(None, node.rule);
+}
+
+// -- Linking --------------------------------------------------------------
+
+fn compile_unit(grammar: &str, start: &str) -> vm::Program {
+    let cc = compiler::Config::default().defer_unresolved();
+    let ast = parser::parse(grammar).unwrap();
+    compiler::Compiler::new(cc)
+        .compile(&ast, Some(start))
+        .unwrap()
+}
+
+#[test]
+fn test_concat_programs() {
+    let main = compile_unit("Main <- 'let' Name '=' Expr", "Main");
+    let names = compile_unit("Name <- [a-z]+ Spacing <- ' '*", "Name");
+    let exprs = compile_unit("Expr <- Expr '+' Num / Num  Num <- [0-9]+", "Expr");
+
+    // calls to rules other programs define can't run until linked
+    assert_eq!(vec!["Expr", "Name", "Spacing"], main.unresolved());
+    assert!(main.validate().is_err());
+
+    // linking can be done in steps
+    let partial = vm::Program::concat(&[&main, &names]).unwrap();
+    assert_eq!(vec!["Expr"], partial.unresolved());
+    assert!(partial.validate().is_err());
+
+    let program = vm::Program::concat(&[&partial, &exprs]).unwrap();
+    assert!(program.unresolved().is_empty());
+    program.validate().unwrap();
+    assert_match(
+        "Main[letName[x]=Expr[Expr[Expr[Num[1]]+Num[2]]+Num[30]]]",
+        run_str(&program, "let x = 1 + 2 + 30"),
+    );
+
+    // the first program defining a rule wins
+    let other_names = compile_unit("Name <- [A-Z]+", "Name");
+    let program = vm::Program::concat(&[&main, &other_names, &names, &exprs]).unwrap();
+    assert_match(
+        "Main[letName[X]=Expr[Num[1]]]",
+        run_str(&program, "let X = 1"),
+    );
+    assert!(run_str(&program, "let x = 1").is_err());
+}
+
+#[test]
+fn test_concat_position_independent_code() {
+    // the code of the second program has jumps both ways, and runs
+    // as it is when appended after the first one
+    let cc = compiler::Config::default()
+        .disable_injecting_whitespace_handling()
+        .enable_tail_calls()
+        .defer_unresolved();
+    let mut c = compiler::Compiler::new(cc.clone());
+    let main = c
+        .compile(&parser::parse("Main <- '[' List ']'").unwrap(), None)
+        .unwrap();
+    let mut c = compiler::Compiler::new(cc);
+    let list = c
+        .compile(
+            &parser::parse("List <- 'a' List / 'b' / 'c'").unwrap(),
+            None,
+        )
+        .unwrap();
+    let jumps = |p: &vm::Program| {
+        let code = p.to_string();
+        (code.contains("jump "), code.contains("jumpb "))
+    };
+    assert_eq!((true, true), jumps(&list));
+
+    let program = vm::Program::concat(&[&main, &list]).unwrap();
+    program.validate().unwrap();
+    assert_match("Main[[List[aac]]]", run_str(&program, "[aac]"));
+}
+
+#[test]
+fn test_concat_programs_labels() {
+    // the label is thrown by one program and recovered from by a rule
+    // within the other one
+    let main = compile_unit("Main <- 'a' 'b'^MissingB 'c'", "Main");
+    let recovery = compile_unit("MissingB <- (!'c' .)*", "MissingB");
+    let program = vm::Program::concat(&[&main, &recovery]).unwrap();
+    program.validate().unwrap();
+    assert_match("Main[aError[MissingB]c]", run_str(&program, "axc"));
+    assert!(vm::Program::concat(&[]).is_err());
+}
+
+#[test]
+fn test_concat_programs_classes() {
+    // each program points into its own table of classes
+    let main = compile_unit("Main <- [a-z_]+ Num", "Main");
+    let nums = compile_unit("Num <- [0-9.]+ Spacing <- [ \\t]*", "Num");
+    let program = vm::Program::concat(&[&main, &nums]).unwrap();
+    program.validate().unwrap();
+    assert_match("Main[x_yNum[1.5]]", run_str(&program, "x_y 1.5"));
+    assert!(run_str(&program, "x_y a").is_err());
+}
+
+// -- JSON -----------------------------------------------------------------
+
+#[test]
+fn test_value_to_json() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, "A <- 'a' B\nB <- 'b\"'^quote", "A");
+    let value = run_str(&program, "a\nb\"").unwrap().unwrap();
+    let span = |start: (usize, usize, usize), end: (usize, usize, usize)| {
+        format!(
+            "{{\"start\":{{\"offset\":{},\"line\":{},\"column\":{}}},\"end\":{{\"offset\":{},\"line\":{},\"column\":{}}}}}",
+            start.0, start.1, start.2, end.0, end.1, end.2
+        )
+    };
+    let json = format::value_to_json(&value);
+    assert!(json.starts_with("{\"type\":\"node\",\"name\":\"A\",\"items\":["));
+    assert!(json.contains(&format!(
+        "{{\"type\":\"string\",\"value\":\"a\",\"span\":{}}}",
+        span((0, 0, 0), (1, 0, 1))
+    )));
+
+    // errors carry their label, their message and where they were
+    // thrown from
+    let errors = value::Node::new_val(
+        Span::default(),
+        "E".to_string(),
+        vec![
+            value::Error::new_val(Span::default(), "eq".to_string(), None),
+            value::Char::new_val(
+                Span::new(Position::new(0, 0, 0), Position::new(1, 0, 1)),
+                '\n',
+            ),
+        ],
+    );
+    assert_eq!(
+        format!(
+            "{{\"type\":\"node\",\"name\":\"E\",\"items\":[{{\"type\":\"error\",\"label\":\"eq\",\"message\":null,\"span\":{}}},{{\"type\":\"char\",\"value\":\"\\n\",\"span\":{}}}],\"span\":{}}}",
+            span((0, 0, 0), (0, 0, 0)),
+            span((0, 0, 0), (1, 0, 1)),
+            span((0, 0, 0), (0, 0, 0)),
+        ),
+        format::value_to_json(&errors)
+    );
+
+    // serde gives back the same tree
+    for v in [&value, &errors] {
+        assert_eq!(format::value_to_json(v), serde_json::to_string(v).unwrap());
+    }
+}
+
+#[test]
+fn test_value_to_sexpr_and_dot() {
+    let cc = compiler::Config::default();
+    let program = compile(
+        &cc,
+        "A <- B* 'x'?\n@capture(list) B <- '(' C ')'\nC <- '\"'",
+        "A",
+    );
+    let value = run_str(&program, "(\")").unwrap().unwrap();
+    assert_eq!(
+        "(A (\"(\" (C \"\\\"\") \")\"))",
+        format::value_to_sexpr(&value)
+    );
+    assert_eq!(
+        "digraph {
+  n0 [label=\"A\"];
+  n1 [label=\"[]\"];
+  n0 -> n1;
+  n2 [label=\"\\\"(\\\"\", shape=box];
+  n1 -> n2;
+  n3 [label=\"C\"];
+  n1 -> n3;
+  n4 [label=\"\\\"\\\\\\\"\\\"\", shape=box];
+  n3 -> n4;
+  n5 [label=\"\\\")\\\"\", shape=box];
+  n1 -> n5;
+}
+",
+        format::value_to_dot(&value)
+    );
+
+    let error = value::Error::new_val(Span::default(), "eq".to_string(), Some("no =".to_string()));
+    assert_eq!("(error eq \"no =\")", format::value_to_sexpr(&error));
+    assert_eq!(
+        "digraph {\n  n0 [label=\"error eq: no =\", color=red, fontcolor=red];\n}\n",
+        format::value_to_dot(&error)
+    );
+}
+
+#[test]
+fn test_value_debug() {
+    let cc = compiler::Config::default().disable_injecting_whitespace_handling();
+    let program = compile(&cc, "A <- 'a' B*\n@capture(list) B <- [\\t\"]", "A");
+    let value = run_str(&program, "a\t\"").unwrap().unwrap();
+    // the columns within the spans of chars count from one
+    assert_eq!(
+        "Node(\"A\") @ 0:0-0:3 [String(\"a\") @ 0:0-0:1, List @ 0:2-0:3 [Char('\\t') @ 0:2-0:3], List @ 0:3-0:4 [Char('\"') @ 0:3-0:4]]",
+        format!("{:?}", value)
+    );
+    assert_eq!(
+        r#"Node("A") @ 0:0-0:3 [
+    String("a") @ 0:0-0:1,
+    List @ 0:2-0:3 [
+        Char('\t') @ 0:2-0:3,
+    ],
+    List @ 0:3-0:4 [
+        Char('"') @ 0:3-0:4,
+    ],
+]"#,
+        format!("{:#?}", value)
+    );
+
+    let error = value::Error::new_val(Span::default(), "eq".to_string(), Some("\"=\"".to_string()));
+    let empty = value::Node::new_val(Span::default(), "E".to_string(), vec![error]);
+    assert_eq!(
+        "Node(\"E\") @ 0:0-0:0 [Error(\"eq\", \"\\\"=\\\"\") @ 0:0-0:0]",
+        format!("{:?}", empty)
+    );
+    let empty = value::List::new_val(Span::default(), vec![]);
+    assert_eq!("List @ 0:0-0:0 []", format!("{:#?}", empty));
+}
+
+// -- Extract --------------------------------------------------------------
+
+const ASSIGNS: &str = "
+    Assigns <- Assign*
+    Assign  <- Name '=' Number ';'
+    @capture(string) Name   <- [a-z]+
+    @capture(string) Number <- [0-9]+
+";
+
+#[derive(Debug, PartialEq)]
+struct Assign {
+    name: String,
+    value: u32,
+}
+
+impl extract::FromValue for Assign {
+    fn from_value(value: &value::Value) -> Result<Self, extract::Error> {
+        let mut items = extract::node(value, "Assign")?;
+        let name = items.field()?;
+        let _: String = items.field()?;
+        let value = items.field()?;
+        let _: String = items.field()?;
+        items.finish()?;
+        Ok(Assign { name, value })
+    }
+}
+
+#[test]
+fn test_extract() {
+    let cc = compiler::Config::default();
+    let program = compile(&cc, ASSIGNS, "Assigns");
+    let value = run_str(&program, "a = 1; bc = 23;").unwrap().unwrap();
+    let assigns = extract::node(&value, "Assigns").unwrap().rest::<Assign>();
+    assert_eq!(
+        Ok(vec![
+            Assign {
+                name: "a".to_string(),
+                value: 1
+            },
+            Assign {
+                name: "bc".to_string(),
+                value: 23
+            },
+        ]),
+        assigns
+    );
+
+    // mismatches point at the value that didn't have the expected shape
+    let value = run_str(&program, "a = 99999999999;").unwrap().unwrap();
+    let err = extract::node(&value, "Assigns").unwrap().rest::<Assign>();
+    let span = Span::new(Position::new(4, 0, 5), Position::new(15, 0, 16));
+    let err = err.unwrap_err();
+    assert_eq!(
+        &extract::Error::Invalid(
+            "\"99999999999\" isn't a valid u32: number too large to fit in target type".to_string(),
+            span
+        ),
+        err.cause()
+    );
+    assert_eq!("Assigns[0]/Assign[2]", err.path());
+    assert_eq!(
+        "Extract Error[Invalid]: \"99999999999\" isn't a valid u32: number too large to fit in target type at 0:5-0:16 in Assigns[0]/Assign[2]",
+        err.to_string()
+    );
+    assert_eq!(
+        Err(extract::Error::Mismatch(
+            "node Assign".to_string(),
+            "node Assigns".to_string(),
+            value.span()
+        )),
+        extract::from_value::<Assign>(&value)
+    );
+    let mut items = extract::node(&value, "Assigns").unwrap();
+    items.field_value().unwrap();
+    assert!(matches!(
+        items.field_value(),
+        Err(extract::Error::MissingItem(name, 1, _)) if name == "Assigns"
+    ));
+}
+
+#[test]
+fn test_try_from_value() {
+    let span = Span::default();
+    let string = |s: &str| value::String::new_val(span.clone(), s);
+    assert_eq!(Ok("a".to_string()), String::try_from(&string("a")));
+    assert_eq!(
+        Ok('a'),
+        char::try_from(&value::Char::new_val(span.clone(), 'a'))
+    );
+    assert_eq!(Ok(-42), i64::try_from(&string("-42")));
+    assert_eq!(Ok(1.5), f64::try_from(&string("1.5")));
+    assert_eq!(Ok(true), bool::try_from(&string("true")));
+
+    let list = value::List::new_val(span.clone(), vec![string("1"), string("2")]);
+    assert_eq!(Ok(vec![1, 2]), Vec::<i64>::try_from(&list));
+
+    // errors within lists say which item failed
+    let list = value::List::new_val(
+        span.clone(),
+        vec![
+            value::List::new_val(span.clone(), vec![string("1")]),
+            value::List::new_val(span.clone(), vec![string("2"), string("x")]),
+        ],
+    );
+    let err = Vec::<Vec<i64>>::try_from(&list).unwrap_err();
+    assert_eq!("[1]/[1]", err.path());
+    assert!(matches!(err.cause(), extract::Error::Invalid(..)));
+    let node = value::Node::new_val(span.clone(), "N".to_string(), vec![]);
+    assert_eq!(
+        Err(extract::Error::Mismatch(
+            "string".to_string(),
+            "node N".to_string(),
+            span.clone()
+        )),
+        String::try_from(&node)
+    );
+}
+
+#[test]
+fn test_value_visitor() {
+    use langlang_value::visitor::{walk_items, ValueVisitor};
+
+    // sums the numbers assigned, and counts the names
+    #[derive(Default)]
+    struct Sum {
+        names: usize,
+    }
+
+    impl ValueVisitor for Sum {
+        type Output = u32;
+
+        fn visit_char(&mut self, _: char, _: &Span) -> u32 {
+            0
+        }
+
+        fn visit_string(&mut self, value: &str, _: &Span) -> u32 {
+            value.parse().unwrap_or(0)
+        }
+
+        fn visit_list(&mut self, items: &[value::Value], _: &Span) -> u32 {
+            walk_items(self, items).into_iter().sum()
+        }
+
+        fn visit_node(&mut self, name: &str, items: &[value::Value], _: &Span) -> u32 {
+            match name {
+                "Assign" => {
+                    self.names += 1;
+                    walk_items(self, &items[2..]).into_iter().sum()
+                }
+                _ => walk_items(self, items).into_iter().sum(),
+            }
+        }
+    }
+
+    let cc = compiler::Config::default();
+    let program = compile(&cc, ASSIGNS, "Assigns");
+    let value = run_str(&program, "a = 1; bc = 23; d = 4;")
+        .unwrap()
+        .unwrap();
+    let mut sum = Sum::default();
+    assert_eq!(Some(28), sum.visit_value(&value));
+    assert_eq!(3, sum.names);
+}
+
+// -- Migrations -----------------------------------------------------------
+
+#[test]
+fn test_migrations() {
+    use langlang_value::migrate::{Error, Migrations};
+
+    // version 1 called numbers `Num`, version 2 renamed them to
+    // `Number` and version 3 wraps them within a `Literal`
+    let cc = compiler::Config::default();
+    let v1 = compile(&cc, "@version '1'\nNum <- [0-9]+", "Num");
+    assert_eq!(Some("1"), v1.version());
+    let value = run_str(&v1, "42").unwrap().unwrap();
+
+    let mut migrations = Migrations::new();
+    migrations.register("1", "2", |value| match value {
+        value::Value::Node(mut node) if node.name == "Num" => {
+            node.name = "Number".to_string();
+            Ok(value::Value::Node(node))
+        }
+        other => Err(format!("expected Num, got {}", other)),
+    });
+    migrations.register("2", "3", |value| {
+        let span = value.span();
+        Ok(value::Node::new_val(
+            span,
+            "Literal".to_string(),
+            vec![value],
+        ))
+    });
+
+    assert_eq!(
+        Some(vec!["2".to_string(), "3".to_string()]),
+        migrations.path("1", "3")
+    );
+    let migrated = migrations.migrate(value.clone(), "1", "3").unwrap();
+    assert_eq!("Literal[Number[42]]", format::compact(&migrated));
+    let same = migrations.migrate(value.clone(), "1", "1").unwrap();
+    assert_eq!(value, same);
+    assert_eq!(
+        Err(Error::NoPath("3".to_string(), "1".to_string())),
+        migrations.migrate(value.clone(), "3", "1"),
+    );
+    let number = value::String::new_val(value.span(), "42");
+    assert_eq!(
+        Err(Error::Failed(
+            "1".to_string(),
+            "2".to_string(),
+            "expected Num, got 42".to_string()
+        )),
+        migrations.migrate(number, "1", "3"),
+    );
+}
+
+// -- Expand Grammar -------------------------------------------------------
+
+#[test]
+fn test_expand_tree_0() {
+    let cc = compiler::Config::default();
+
+    // Program that parses the initial input
+    let input_grammar = "A <- 'F'";
+    let program = compile(&cc, input_grammar, "A");
+    let output = run_str(&program, "F");
+
+    // Program that parses the output obtained upon successful
+    // parsing with the initial program
+    let original_ast = parser::parse(input_grammar).unwrap();
+    let rewrite = compiler::expand(&original_ast).unwrap();
+
+    let mut c = compiler::Compiler::new(cc);
+    let list_program = c.compile(&rewrite, Some("A")).unwrap();
+    let value = vm::VM::new(&list_program).run_values(vec![output.unwrap().unwrap()]);
+    assert_match("A[A[F]]", value);
+}
+
+#[test]
+fn test_expand_tree_keeps_spans() {
+    let cc = compiler::Config::default();
+    let input_grammar = "A <- 'F' B\nB <- 'G'";
+    let program = compile(&cc, input_grammar, "A");
+    let output = run_str(&program, "FG").unwrap().unwrap();
+
+    let original_ast = parser::parse(input_grammar).unwrap();
+    let rewrite = compiler::expand(&original_ast).unwrap();
+    let mut c = compiler::Compiler::new(cc);
+    let list_program = c.compile(&rewrite, Some("A")).unwrap();
+    let value = vm::VM::new(&list_program)
+        .run_values(vec![output.clone()])
+        .unwrap()
+        .unwrap();
+
+    // nodes matched within the tree are rebuilt with the spans of
+    // the nodes they came from
+    let value::Value::Node(outer) = &value else {
+        panic!("expected a node, got {:?}", value);
+    };
+    assert_eq!(output.span(), outer.items[0].span());
 }
@@ -0,0 +1,101 @@
+mod helpers;
+
+use langlang_lib::{compiler, parser, unparse};
+use unparse::Unparse;
+
+#[test]
+fn unparse_literals_and_classes() {
+    let grammar = "Number <- '0x' [0-9a-fA-F]+ / [0-9]+";
+    let text = roundtrip(grammar, "Number", "0xff", unparse::Config::default());
+    assert_eq!("0xff", text);
+}
+
+#[test]
+fn unparse_int_terminals() {
+    // the digits of each integer are told apart by their own rules
+    let grammar = "Header <- '\\x7f' Length Tag\nLength <- %u16be\nTag <- %varint";
+    let cc = compiler::Config::default()
+        .byte_mode()
+        .disable_injecting_whitespace_handling();
+    let program = helpers::compile(&cc, grammar, "Header");
+    let input = "\x7f\x01\x02\u{ac}\x02";
+    let value = helpers::run_str(&program, input).unwrap().unwrap();
+    let ast = parser::parse(grammar).unwrap();
+    let glued = unparse::Unparser::new(&ast, unparse::Config::default().with_separator(""));
+    assert_eq!(input, glued.unparse(&value).unwrap());
+}
+
+#[test]
+fn unparse_take() {
+    let grammar = "Field <- Count ':' %take(Count)\nCount <- [0-9]+";
+    let text = roundtrip(grammar, "Field", "5:a bcd", unparse::Config::default());
+    assert_eq!("5 : a bcd", text);
+}
+
+#[test]
+fn unparse_with_separator() {
+    let grammar = "
+        Assign <- Name '=' Value ';'
+        Name   <- [a-z]+
+        Value  <- [0-9]+
+    ";
+    let cc = compiler::Config::default();
+    let program = helpers::compile(&cc, grammar, "Assign");
+    let value = helpers::run_str(&program, "x=1;").unwrap().unwrap();
+    let ast = parser::parse(grammar).unwrap();
+
+    assert_eq!("x = 1 ;", ast.unparse(&value).unwrap());
+
+    let glued = unparse::Unparser::new(&ast, unparse::Config::default().with_separator(""));
+    assert_eq!("x=1;", glued.unparse(&value).unwrap());
+}
+
+#[test]
+fn unparse_left_recursive_rules() {
+    let grammar = "
+        E <- M '+' E / M
+        M <- M '-n' / 'n'
+    ";
+    let text = roundtrip(grammar, "E", "n+n-n-n", unparse::Config::default());
+    assert_eq!("n + n -n -n", text);
+}
+
+#[test]
+fn unparse_lexical_sequences() {
+    let grammar = "
+        Ordinal <- Decimal #('st' / 'nd' / 'rd' / 'th')
+        Decimal <- [0-9]+
+    ";
+    let text = roundtrip(grammar, "Ordinal", "1st", unparse::Config::default());
+    assert_eq!("1st", text);
+}
+
+#[test]
+fn unparse_mismatch() {
+    let grammar = "A <- 'a' B\nB <- 'b'";
+    let cc = compiler::Config::default();
+    let program = helpers::compile(&cc, grammar, "A");
+    let value = helpers::run_str(&program, "ab").unwrap().unwrap();
+    let other = parser::parse("A <- 'a' 'c'").unwrap();
+    assert_eq!(
+        Err(unparse::Error::Mismatch(
+            "value doesn't match rule `A`".to_string()
+        )),
+        other.unparse(&value),
+    );
+    let other = parser::parse("C <- 'c'").unwrap();
+    assert_eq!(
+        Err(unparse::Error::NotFound("A".to_string())),
+        other.unparse(&value),
+    );
+}
+
+fn roundtrip(grammar: &str, start: &str, input: &str, config: unparse::Config) -> String {
+    let cc = compiler::Config::default();
+    let program = helpers::compile(&cc, grammar, start);
+    let value = helpers::run_str(&program, input).unwrap().unwrap();
+    let ast = parser::parse(grammar).unwrap();
+    unparse::Unparser::new(&ast, config)
+        .unparse(&value)
+        .unwrap()
+}
@@ -1,6 +1,7 @@
 mod helpers;
 
 use langlang_lib::{compiler, vm};
+use langlang_value::source_map::Position;
 use langlang_value::value::Value;
 
 #[test]
@@ -10,7 +11,10 @@ fn is_syntactic_sequence_with_literals() {
 
     // It doesn't expect spaces between the sequence items
     helpers::assert_err(
-        vm::Error::Matching(1, "syntax error, expecting: 'b'".to_string()),
+        vm::Error::Matching(
+            Position::new(1, 0, 2),
+            "syntax error, expecting: 'b'".to_string(),
+        ),
         run("Syntactic0", "a b c"),
     );
 }
@@ -37,7 +41,7 @@ fn test_lexification_on_single_item() {
 
     // There should be no spaces between the decimal and ordinal string
     helpers::assert_err(
-        vm::Error::Matching(1, "ord".to_string()),
+        vm::Error::Label("ord".to_string(), None, Position::new(1, 0, 2)),
         run("Ordinal", "1 st"),
     );
 }
@@ -47,3 +51,61 @@ fn run(start: &str, input: &str) -> Result<Option<Value>, vm::Error> {
     let program = helpers::compile_file(&cc, "wshinsert.peg", Some(start));
     helpers::run_str(&program, input)
 }
+
+#[test]
+fn test_spacing_generated_from_comments() {
+    let cc = compiler::Config::default();
+    let grammar = "
+        @comments '//' eol, '/*' '*/'
+        Call <- Name '(' Name ')'
+        Name <- [a-z]+
+    ";
+    let program = helpers::compile(&cc, grammar, "Call");
+
+    // comments are skipped wherever spaces are
+    helpers::assert_match(
+        "Call[Name[f](Name[x])]",
+        helpers::run_str(&program, "f /* args */ ( // one\n x)"),
+    );
+
+    // line comments end at the end of the line
+    assert!(helpers::run_str(&program, "f(x // )").is_err());
+
+    // block comments must be closed
+    assert!(helpers::run_str(&program, "f( /* x)").is_err());
+}
+
+#[test]
+fn test_spacing_written_by_grammar_wins_over_comments() {
+    let cc = compiler::Config::default();
+    let grammar = "
+        @comments '#' eol
+        Pair <- Name Name
+        Name <- [a-z]+
+        Spacing <- ' '*
+    ";
+    let program = helpers::compile(&cc, grammar, "Pair");
+    helpers::assert_match("Pair[Name[a]Name[b]]", helpers::run_str(&program, "a b"));
+    assert!(helpers::run_str(&program, "a #\nb").is_err());
+}
+
+#[test]
+fn test_whitespace_directive() {
+    let cc = compiler::Config::default();
+    let grammar = "
+        Pair <- Name Name
+        Name <- #(Letter Digits?)
+        Letter <- [a-z]
+        Digits <- [0-9]+
+        %whitespace <- [ \\t]*
+    ";
+    let program = helpers::compile(&cc, grammar, "Pair");
+    helpers::assert_match(
+        "Pair[Name[Letter[a]Digits[1]]Name[Letter[b]]]",
+        helpers::run_str(&program, "a1 \t b"),
+    );
+    // new lines aren't white space anymore
+    assert!(helpers::run_str(&program, "a1\nb").is_err());
+    // and rules marked as lexical don't skip any
+    assert!(helpers::run_str(&program, "a 1 b").is_err());
+}